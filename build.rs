@@ -1,5 +1,15 @@
 use std::process::Command;
 
+#[cfg(any(
+    all(feature = "sqlite", feature = "postgresql"),
+    all(feature = "sqlite", feature = "mysql"),
+    all(feature = "postgresql", feature = "mysql")
+))]
+compile_error!("Only one database backend can be enabled, use `--no-default-features` to disable SQLite");
+
+#[cfg(not(any(feature = "sqlite", feature = "postgresql", feature = "mysql")))]
+compile_error!("A database backend needs to be enabled, use the `sqlite`, `postgresql` or `mysql` feature");
+
 fn main() {
     read_git_info().ok();
 }
@@ -0,0 +1,20 @@
+DROP TABLE events;
+DROP TABLE client_certificates;
+DROP TABLE provider_organizations;
+DROP TABLE provider_users;
+DROP TABLE providers;
+DROP TABLE org_domains;
+DROP TABLE invitations;
+DROP TABLE twofactor;
+DROP TABLE ciphers_collections;
+DROP TABLE users_organizations;
+DROP TABLE users_collections;
+DROP TABLE collections;
+DROP TABLE folders_ciphers;
+DROP TABLE folders;
+DROP TABLE attachment_uploads;
+DROP TABLE attachments;
+DROP TABLE ciphers;
+DROP TABLE organizations;
+DROP TABLE devices;
+DROP TABLE users;
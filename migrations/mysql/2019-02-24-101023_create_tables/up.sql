@@ -0,0 +1,240 @@
+-- MySQL ignores the inline REFERENCES clauses, so the foreign keys are declared separately.
+-- The TEXT columns can't be part of a key, the ids and the unique values use VARCHAR instead.
+CREATE TABLE users (
+  uuid                 VARCHAR(40)  NOT NULL PRIMARY KEY,
+  created_at           DATETIME     NOT NULL,
+  updated_at           DATETIME     NOT NULL,
+  email                VARCHAR(255) NOT NULL UNIQUE,
+  name                 TEXT         NOT NULL,
+  password_hash        BLOB         NOT NULL,
+  salt                 BLOB         NOT NULL,
+  password_iterations  INTEGER      NOT NULL,
+  password_hint        TEXT,
+  `key`                TEXT         NOT NULL,
+  private_key          TEXT,
+  public_key           TEXT,
+  totp_secret          TEXT,
+  totp_recover         TEXT,
+  security_stamp       TEXT         NOT NULL,
+  equivalent_domains   TEXT         NOT NULL,
+  excluded_globals     TEXT         NOT NULL,
+  client_kdf_type      INTEGER      NOT NULL DEFAULT 0, -- PBKDF2
+  client_kdf_iter      INTEGER      NOT NULL DEFAULT 5000,
+  force_password_reset BOOLEAN      NOT NULL DEFAULT 0,
+  login_failures       INTEGER      NOT NULL DEFAULT 0,
+  locked_until         DATETIME,
+  deleted_at           DATETIME,
+  locale               TEXT,
+  disabled             BOOLEAN      NOT NULL DEFAULT 0
+);
+
+CREATE TABLE devices (
+  uuid                  VARCHAR(40) NOT NULL PRIMARY KEY,
+  created_at            DATETIME    NOT NULL,
+  updated_at            DATETIME    NOT NULL,
+  user_uuid             VARCHAR(40) NOT NULL,
+  name                  TEXT        NOT NULL,
+  type                  INTEGER     NOT NULL,
+  push_token            TEXT,
+  refresh_token         TEXT        NOT NULL,
+  twofactor_remember    TEXT,
+  twofactor_remember_at DATETIME,
+  last_ip               TEXT,
+  last_active_at        DATETIME,
+  token_issued_at       DATETIME,
+
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE organizations (
+  uuid            VARCHAR(40) NOT NULL PRIMARY KEY,
+  name            TEXT        NOT NULL,
+  billing_email   TEXT        NOT NULL,
+  allowed_domains TEXT        NOT NULL -- TEXT columns can't have a default, it's always set by the server
+);
+
+CREATE TABLE ciphers (
+  uuid              VARCHAR(40) NOT NULL PRIMARY KEY,
+  created_at        DATETIME    NOT NULL,
+  updated_at        DATETIME    NOT NULL,
+  user_uuid         VARCHAR(40),
+  organization_uuid VARCHAR(40),
+  type              INTEGER     NOT NULL,
+  name              TEXT        NOT NULL,
+  notes             TEXT,
+  fields            TEXT,
+  data              TEXT        NOT NULL,
+  favorite          BOOLEAN     NOT NULL,
+  password_history  TEXT,
+
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+  FOREIGN KEY (organization_uuid) REFERENCES organizations (uuid)
+);
+
+CREATE TABLE attachments (
+  id          VARCHAR(40) NOT NULL PRIMARY KEY,
+  cipher_uuid VARCHAR(40) NOT NULL,
+  file_name   TEXT        NOT NULL,
+  file_size   INTEGER     NOT NULL,
+  `key`       TEXT,
+  checksum    TEXT,
+
+  FOREIGN KEY (cipher_uuid) REFERENCES ciphers (uuid)
+);
+
+CREATE TABLE attachment_uploads (
+  id          VARCHAR(40) NOT NULL PRIMARY KEY,
+  cipher_uuid VARCHAR(40) NOT NULL,
+  user_uuid   VARCHAR(40) NOT NULL,
+  file_name   TEXT        NOT NULL,
+  file_size   INTEGER     NOT NULL,
+  `key`       TEXT,
+  created_at  DATETIME    NOT NULL,
+
+  FOREIGN KEY (cipher_uuid) REFERENCES ciphers (uuid),
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE folders (
+  uuid       VARCHAR(40) NOT NULL PRIMARY KEY,
+  created_at DATETIME    NOT NULL,
+  updated_at DATETIME    NOT NULL,
+  user_uuid  VARCHAR(40) NOT NULL,
+  name       TEXT        NOT NULL,
+
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE folders_ciphers (
+  cipher_uuid VARCHAR(40) NOT NULL,
+  folder_uuid VARCHAR(40) NOT NULL,
+
+  PRIMARY KEY (cipher_uuid, folder_uuid),
+  FOREIGN KEY (cipher_uuid) REFERENCES ciphers (uuid),
+  FOREIGN KEY (folder_uuid) REFERENCES folders (uuid)
+);
+
+CREATE TABLE collections (
+  uuid     VARCHAR(40) NOT NULL PRIMARY KEY,
+  org_uuid VARCHAR(40) NOT NULL,
+  name     TEXT        NOT NULL,
+
+  FOREIGN KEY (org_uuid) REFERENCES organizations (uuid)
+);
+
+CREATE TABLE users_collections (
+  user_uuid       VARCHAR(40) NOT NULL,
+  collection_uuid VARCHAR(40) NOT NULL,
+  read_only       BOOLEAN     NOT NULL DEFAULT 0,
+
+  PRIMARY KEY (user_uuid, collection_uuid),
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+  FOREIGN KEY (collection_uuid) REFERENCES collections (uuid)
+);
+
+CREATE TABLE users_organizations (
+  uuid       VARCHAR(40) NOT NULL PRIMARY KEY,
+  user_uuid  VARCHAR(40) NOT NULL,
+  org_uuid   VARCHAR(40) NOT NULL,
+
+  access_all BOOLEAN     NOT NULL,
+  `key`      TEXT        NOT NULL,
+  status     INTEGER     NOT NULL,
+  type       INTEGER     NOT NULL,
+
+  UNIQUE (user_uuid, org_uuid),
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid),
+  FOREIGN KEY (org_uuid) REFERENCES organizations (uuid)
+);
+
+CREATE TABLE ciphers_collections (
+  cipher_uuid     VARCHAR(40) NOT NULL,
+  collection_uuid VARCHAR(40) NOT NULL,
+
+  PRIMARY KEY (cipher_uuid, collection_uuid),
+  FOREIGN KEY (cipher_uuid) REFERENCES ciphers (uuid),
+  FOREIGN KEY (collection_uuid) REFERENCES collections (uuid)
+);
+
+CREATE TABLE twofactor (
+  uuid      VARCHAR(40) NOT NULL PRIMARY KEY,
+  user_uuid VARCHAR(40) NOT NULL,
+  type      INTEGER     NOT NULL,
+  enabled   BOOLEAN     NOT NULL,
+  data      TEXT        NOT NULL,
+  last_used INTEGER     NOT NULL DEFAULT 0,
+
+  UNIQUE (user_uuid, type),
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE invitations (
+  email VARCHAR(255) NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE org_domains (
+  uuid            VARCHAR(40)  NOT NULL PRIMARY KEY,
+  org_uuid        VARCHAR(40)  NOT NULL,
+  domain_name     VARCHAR(255) NOT NULL,
+  txt_token       TEXT         NOT NULL,
+  created_at      DATETIME     NOT NULL,
+  verified_at     DATETIME,
+  last_checked_at DATETIME,
+
+  UNIQUE (org_uuid, domain_name),
+  FOREIGN KEY (org_uuid) REFERENCES organizations (uuid)
+);
+
+CREATE TABLE providers (
+  uuid          VARCHAR(40) NOT NULL PRIMARY KEY,
+  name          TEXT        NOT NULL,
+  business_name TEXT,
+  billing_email TEXT        NOT NULL,
+  status        INTEGER     NOT NULL,
+  enabled       BOOLEAN     NOT NULL DEFAULT 1,
+  created_at    DATETIME    NOT NULL
+);
+
+CREATE TABLE provider_users (
+  uuid          VARCHAR(40) NOT NULL PRIMARY KEY,
+  provider_uuid VARCHAR(40) NOT NULL,
+  user_uuid     VARCHAR(40) NOT NULL,
+  `key`         TEXT        NOT NULL,
+  status        INTEGER     NOT NULL,
+  type          INTEGER     NOT NULL,
+
+  UNIQUE (provider_uuid, user_uuid),
+  FOREIGN KEY (provider_uuid) REFERENCES providers (uuid),
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE provider_organizations (
+  uuid          VARCHAR(40) NOT NULL PRIMARY KEY,
+  provider_uuid VARCHAR(40) NOT NULL,
+  org_uuid      VARCHAR(40) NOT NULL UNIQUE,
+  `key`         TEXT        NOT NULL,
+
+  FOREIGN KEY (provider_uuid) REFERENCES providers (uuid),
+  FOREIGN KEY (org_uuid) REFERENCES organizations (uuid)
+);
+
+CREATE TABLE client_certificates (
+  uuid        VARCHAR(40)  NOT NULL PRIMARY KEY,
+  user_uuid   VARCHAR(40)  NOT NULL,
+  name        TEXT         NOT NULL,
+  fingerprint VARCHAR(255) NOT NULL UNIQUE,
+  created_at  DATETIME     NOT NULL,
+
+  FOREIGN KEY (user_uuid) REFERENCES users (uuid)
+);
+
+CREATE TABLE events (
+  uuid        VARCHAR(40) NOT NULL PRIMARY KEY,
+  event_type  INTEGER     NOT NULL,
+  user_uuid   VARCHAR(40),
+  org_uuid    VARCHAR(40),
+  cipher_uuid VARCHAR(40),
+  device_type INTEGER,
+  ip_address  TEXT,
+  event_date  DATETIME    NOT NULL
+);
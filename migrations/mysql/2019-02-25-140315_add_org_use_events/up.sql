@@ -0,0 +1,3 @@
+ALTER TABLE organizations
+    ADD COLUMN
+    use_events BOOLEAN NOT NULL DEFAULT 0;
\ No newline at end of file
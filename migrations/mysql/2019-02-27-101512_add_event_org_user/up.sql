@@ -0,0 +1,3 @@
+ALTER TABLE events
+    ADD COLUMN
+    org_user_uuid VARCHAR(40);
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE queued_notifications;
\ No newline at end of file
@@ -0,0 +1,8 @@
+CREATE TABLE queued_notifications (
+  uuid        VARCHAR(40) NOT NULL PRIMARY KEY,
+  device_uuid VARCHAR(40) NOT NULL,
+  seq         BIGINT      NOT NULL,
+  data        BLOB        NOT NULL,
+
+  FOREIGN KEY (device_uuid) REFERENCES devices (uuid)
+);
\ No newline at end of file
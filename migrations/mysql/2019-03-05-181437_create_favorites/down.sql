@@ -0,0 +1,3 @@
+UPDATE ciphers SET favorite = TRUE WHERE uuid IN (SELECT cipher_uuid FROM favorites);
+
+DROP TABLE favorites;
@@ -0,0 +1,3 @@
+ALTER TABLE devices
+    ADD COLUMN
+    websocket_at DATETIME;
\ No newline at end of file
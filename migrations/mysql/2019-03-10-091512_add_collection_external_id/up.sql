@@ -0,0 +1,3 @@
+ALTER TABLE collections
+    ADD COLUMN
+    external_id TEXT;
\ No newline at end of file
@@ -0,0 +1,7 @@
+ALTER TABLE attachments
+    ADD COLUMN
+    replaced_id TEXT;
+
+ALTER TABLE attachments
+    ADD COLUMN
+    uploaded_at DATETIME;
\ No newline at end of file
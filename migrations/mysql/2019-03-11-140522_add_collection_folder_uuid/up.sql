@@ -0,0 +1,4 @@
+-- The folder the collection was created for when sharing its items, so the next items of the folder go to it too
+ALTER TABLE collections
+    ADD COLUMN
+    folder_uuid TEXT;
\ No newline at end of file
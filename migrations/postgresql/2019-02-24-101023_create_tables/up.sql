@@ -0,0 +1,207 @@
+CREATE TABLE users (
+  uuid                 TEXT        NOT NULL PRIMARY KEY,
+  created_at           TIMESTAMP   NOT NULL,
+  updated_at           TIMESTAMP   NOT NULL,
+  email                TEXT        NOT NULL UNIQUE,
+  name                 TEXT        NOT NULL,
+  password_hash        BYTEA       NOT NULL,
+  salt                 BYTEA       NOT NULL,
+  password_iterations  INTEGER     NOT NULL,
+  password_hint        TEXT,
+  key                  TEXT        NOT NULL,
+  private_key          TEXT,
+  public_key           TEXT,
+  totp_secret          TEXT,
+  totp_recover         TEXT,
+  security_stamp       TEXT        NOT NULL,
+  equivalent_domains   TEXT        NOT NULL,
+  excluded_globals     TEXT        NOT NULL,
+  client_kdf_type      INTEGER     NOT NULL DEFAULT 0, -- PBKDF2
+  client_kdf_iter      INTEGER     NOT NULL DEFAULT 5000,
+  force_password_reset BOOLEAN     NOT NULL DEFAULT FALSE,
+  login_failures       INTEGER     NOT NULL DEFAULT 0,
+  locked_until         TIMESTAMP,
+  deleted_at           TIMESTAMP,
+  locale               TEXT,
+  disabled             BOOLEAN     NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE devices (
+  uuid                  TEXT        NOT NULL PRIMARY KEY,
+  created_at            TIMESTAMP   NOT NULL,
+  updated_at            TIMESTAMP   NOT NULL,
+  user_uuid             TEXT        NOT NULL REFERENCES users (uuid),
+  name                  TEXT        NOT NULL,
+  type                  INTEGER     NOT NULL,
+  push_token            TEXT,
+  refresh_token         TEXT        NOT NULL,
+  twofactor_remember    TEXT,
+  twofactor_remember_at TIMESTAMP,
+  last_ip               TEXT,
+  last_active_at        TIMESTAMP,
+  token_issued_at       TIMESTAMP
+);
+
+CREATE TABLE organizations (
+  uuid            TEXT        NOT NULL PRIMARY KEY,
+  name            TEXT        NOT NULL,
+  billing_email   TEXT        NOT NULL,
+  allowed_domains TEXT        NOT NULL DEFAULT '[]'
+);
+
+CREATE TABLE ciphers (
+  uuid              TEXT        NOT NULL PRIMARY KEY,
+  created_at        TIMESTAMP   NOT NULL,
+  updated_at        TIMESTAMP   NOT NULL,
+  user_uuid         TEXT        REFERENCES users (uuid),
+  organization_uuid TEXT        REFERENCES organizations (uuid),
+  type              INTEGER     NOT NULL,
+  name              TEXT        NOT NULL,
+  notes             TEXT,
+  fields            TEXT,
+  data              TEXT        NOT NULL,
+  favorite          BOOLEAN     NOT NULL,
+  password_history  TEXT
+);
+
+CREATE TABLE attachments (
+  id          TEXT        NOT NULL PRIMARY KEY,
+  cipher_uuid TEXT        NOT NULL REFERENCES ciphers (uuid),
+  file_name   TEXT        NOT NULL,
+  file_size   INTEGER     NOT NULL,
+  key         TEXT,
+  checksum    TEXT
+);
+
+CREATE TABLE attachment_uploads (
+  id          TEXT        NOT NULL PRIMARY KEY,
+  cipher_uuid TEXT        NOT NULL REFERENCES ciphers (uuid),
+  user_uuid   TEXT        NOT NULL REFERENCES users (uuid),
+  file_name   TEXT        NOT NULL,
+  file_size   INTEGER     NOT NULL,
+  key         TEXT,
+  created_at  TIMESTAMP   NOT NULL
+);
+
+CREATE TABLE folders (
+  uuid       TEXT        NOT NULL PRIMARY KEY,
+  created_at TIMESTAMP   NOT NULL,
+  updated_at TIMESTAMP   NOT NULL,
+  user_uuid  TEXT        NOT NULL REFERENCES users (uuid),
+  name       TEXT        NOT NULL
+);
+
+CREATE TABLE folders_ciphers (
+  cipher_uuid TEXT        NOT NULL REFERENCES ciphers (uuid),
+  folder_uuid TEXT        NOT NULL REFERENCES folders (uuid),
+
+  PRIMARY KEY (cipher_uuid, folder_uuid)
+);
+
+CREATE TABLE collections (
+  uuid     TEXT        NOT NULL PRIMARY KEY,
+  org_uuid TEXT        NOT NULL REFERENCES organizations (uuid),
+  name     TEXT        NOT NULL
+);
+
+CREATE TABLE users_collections (
+  user_uuid       TEXT        NOT NULL REFERENCES users (uuid),
+  collection_uuid TEXT        NOT NULL REFERENCES collections (uuid),
+  read_only       BOOLEAN     NOT NULL DEFAULT FALSE,
+
+  PRIMARY KEY (user_uuid, collection_uuid)
+);
+
+CREATE TABLE users_organizations (
+  uuid       TEXT        NOT NULL PRIMARY KEY,
+  user_uuid  TEXT        NOT NULL REFERENCES users (uuid),
+  org_uuid   TEXT        NOT NULL REFERENCES organizations (uuid),
+
+  access_all BOOLEAN     NOT NULL,
+  key        TEXT        NOT NULL,
+  status     INTEGER     NOT NULL,
+  type       INTEGER     NOT NULL,
+
+  UNIQUE (user_uuid, org_uuid)
+);
+
+CREATE TABLE ciphers_collections (
+  cipher_uuid     TEXT        NOT NULL REFERENCES ciphers (uuid),
+  collection_uuid TEXT        NOT NULL REFERENCES collections (uuid),
+
+  PRIMARY KEY (cipher_uuid, collection_uuid)
+);
+
+CREATE TABLE twofactor (
+  uuid      TEXT        NOT NULL PRIMARY KEY,
+  user_uuid TEXT        NOT NULL REFERENCES users (uuid),
+  type      INTEGER     NOT NULL,
+  enabled   BOOLEAN     NOT NULL,
+  data      TEXT        NOT NULL,
+  last_used INTEGER     NOT NULL DEFAULT 0,
+
+  UNIQUE (user_uuid, type)
+);
+
+CREATE TABLE invitations (
+  email TEXT NOT NULL PRIMARY KEY
+);
+
+CREATE TABLE org_domains (
+  uuid            TEXT        NOT NULL PRIMARY KEY,
+  org_uuid        TEXT        NOT NULL REFERENCES organizations (uuid),
+  domain_name     TEXT        NOT NULL,
+  txt_token       TEXT        NOT NULL,
+  created_at      TIMESTAMP   NOT NULL,
+  verified_at     TIMESTAMP,
+  last_checked_at TIMESTAMP,
+
+  UNIQUE (org_uuid, domain_name)
+);
+
+CREATE TABLE providers (
+  uuid          TEXT        NOT NULL PRIMARY KEY,
+  name          TEXT        NOT NULL,
+  business_name TEXT,
+  billing_email TEXT        NOT NULL,
+  status        INTEGER     NOT NULL,
+  enabled       BOOLEAN     NOT NULL DEFAULT TRUE,
+  created_at    TIMESTAMP   NOT NULL
+);
+
+CREATE TABLE provider_users (
+  uuid          TEXT        NOT NULL PRIMARY KEY,
+  provider_uuid TEXT        NOT NULL REFERENCES providers (uuid),
+  user_uuid     TEXT        NOT NULL REFERENCES users (uuid),
+  key           TEXT        NOT NULL,
+  status        INTEGER     NOT NULL,
+  type          INTEGER     NOT NULL,
+
+  UNIQUE (provider_uuid, user_uuid)
+);
+
+CREATE TABLE provider_organizations (
+  uuid          TEXT        NOT NULL PRIMARY KEY,
+  provider_uuid TEXT        NOT NULL REFERENCES providers (uuid),
+  org_uuid      TEXT        NOT NULL UNIQUE REFERENCES organizations (uuid),
+  key           TEXT        NOT NULL
+);
+
+CREATE TABLE client_certificates (
+  uuid        TEXT        NOT NULL PRIMARY KEY,
+  user_uuid   TEXT        NOT NULL REFERENCES users (uuid),
+  name        TEXT        NOT NULL,
+  fingerprint TEXT        NOT NULL UNIQUE,
+  created_at  TIMESTAMP   NOT NULL
+);
+
+CREATE TABLE events (
+  uuid        TEXT        NOT NULL PRIMARY KEY,
+  event_type  INTEGER     NOT NULL,
+  user_uuid   TEXT,
+  org_uuid    TEXT,
+  cipher_uuid TEXT,
+  device_type INTEGER,
+  ip_address  TEXT,
+  event_date  TIMESTAMP   NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE events
+    ADD COLUMN
+    org_user_uuid TEXT;
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE attachment_deletions;
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE TABLE queued_notifications (
+  uuid        TEXT        NOT NULL PRIMARY KEY,
+  device_uuid TEXT        NOT NULL REFERENCES devices (uuid),
+  seq         BIGINT      NOT NULL,
+  data        BYTEA       NOT NULL
+);
\ No newline at end of file
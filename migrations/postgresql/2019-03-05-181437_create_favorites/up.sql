@@ -0,0 +1,22 @@
+CREATE TABLE favorites (
+  user_uuid   TEXT        NOT NULL REFERENCES users (uuid),
+  cipher_uuid TEXT        NOT NULL REFERENCES ciphers (uuid),
+
+  PRIMARY KEY (user_uuid, cipher_uuid)
+);
+
+-- The favorites of the personal ciphers are kept for their owners. The ones of the organization ciphers
+-- were shared by all their members, so they are kept for the confirmed members that can see the cipher
+INSERT INTO favorites (user_uuid, cipher_uuid)
+SELECT user_uuid, uuid FROM ciphers WHERE favorite = TRUE AND user_uuid IS NOT NULL;
+
+INSERT INTO favorites (user_uuid, cipher_uuid)
+SELECT DISTINCT uo.user_uuid, c.uuid
+FROM ciphers c
+JOIN users_organizations uo ON uo.org_uuid = c.organization_uuid AND uo.status = 2
+LEFT JOIN ciphers_collections cc ON cc.cipher_uuid = c.uuid
+LEFT JOIN users_collections uc ON uc.collection_uuid = cc.collection_uuid AND uc.user_uuid = uo.user_uuid
+WHERE c.favorite = TRUE AND c.user_uuid IS NULL
+  AND (uo.access_all = TRUE OR uo.type <= 1 OR uc.user_uuid IS NOT NULL);
+
+UPDATE ciphers SET favorite = FALSE;
@@ -0,0 +1,6 @@
+ALTER TABLE users
+    ADD COLUMN
+    verified_at TIMESTAMP;
+
+-- The existing accounts were created before the verification was required
+UPDATE users SET verified_at = created_at;
\ No newline at end of file
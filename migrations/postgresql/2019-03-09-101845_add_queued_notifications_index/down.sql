@@ -0,0 +1 @@
+DROP INDEX queued_notifications_device_seq;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE devices
+    ADD COLUMN
+    websocket_at TIMESTAMP;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    force_password_reset BOOLEAN NOT NULL DEFAULT 0;
\ No newline at end of file
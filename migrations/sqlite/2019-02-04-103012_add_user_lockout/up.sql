@@ -0,0 +1,7 @@
+ALTER TABLE users
+    ADD COLUMN
+    login_failures INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE users
+    ADD COLUMN
+    locked_until DATETIME;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE organizations
+    ADD COLUMN
+    allowed_domains TEXT NOT NULL DEFAULT '[]';
\ No newline at end of file
@@ -0,0 +1 @@
+DROP TABLE org_domains;
\ No newline at end of file
@@ -0,0 +1,11 @@
+CREATE TABLE org_domains (
+  uuid            TEXT     NOT NULL PRIMARY KEY,
+  org_uuid        TEXT     NOT NULL REFERENCES organizations (uuid),
+  domain_name     TEXT     NOT NULL,
+  txt_token       TEXT     NOT NULL,
+  created_at      DATETIME NOT NULL,
+  verified_at     DATETIME,
+  last_checked_at DATETIME,
+
+  UNIQUE (org_uuid, domain_name)
+);
\ No newline at end of file
@@ -0,0 +1,9 @@
+CREATE TABLE attachment_uploads (
+  id          TEXT     NOT NULL PRIMARY KEY,
+  cipher_uuid TEXT     NOT NULL REFERENCES ciphers (uuid),
+  user_uuid   TEXT     NOT NULL REFERENCES users (uuid),
+  file_name   TEXT     NOT NULL,
+  file_size   INTEGER  NOT NULL,
+  key         TEXT,
+  created_at  DATETIME NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE attachments
+    ADD COLUMN
+    checksum TEXT;
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP TABLE provider_organizations;
+
+DROP TABLE provider_users;
+
+DROP TABLE providers;
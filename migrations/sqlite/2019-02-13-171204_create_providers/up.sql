@@ -0,0 +1,27 @@
+CREATE TABLE providers (
+  uuid          TEXT     NOT NULL PRIMARY KEY,
+  name          TEXT     NOT NULL,
+  business_name TEXT,
+  billing_email TEXT     NOT NULL,
+  status        INTEGER  NOT NULL,
+  enabled       BOOLEAN  NOT NULL DEFAULT 1,
+  created_at    DATETIME NOT NULL
+);
+
+CREATE TABLE provider_users (
+  uuid          TEXT     NOT NULL PRIMARY KEY,
+  provider_uuid TEXT     NOT NULL REFERENCES providers (uuid),
+  user_uuid     TEXT     NOT NULL REFERENCES users (uuid),
+  key           TEXT     NOT NULL,
+  status        INTEGER  NOT NULL,
+  type          INTEGER  NOT NULL,
+
+  UNIQUE (provider_uuid, user_uuid)
+);
+
+CREATE TABLE provider_organizations (
+  uuid          TEXT     NOT NULL PRIMARY KEY,
+  provider_uuid TEXT     NOT NULL REFERENCES providers (uuid),
+  org_uuid      TEXT     NOT NULL UNIQUE REFERENCES organizations (uuid),
+  key           TEXT     NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE twofactor
+    ADD COLUMN
+    last_used INTEGER NOT NULL DEFAULT 0;
\ No newline at end of file
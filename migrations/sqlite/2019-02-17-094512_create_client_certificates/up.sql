@@ -0,0 +1,7 @@
+CREATE TABLE client_certificates (
+  uuid        TEXT     NOT NULL PRIMARY KEY,
+  user_uuid   TEXT     NOT NULL REFERENCES users (uuid),
+  name        TEXT     NOT NULL,
+  fingerprint TEXT     NOT NULL UNIQUE,
+  created_at  DATETIME NOT NULL
+);
\ No newline at end of file
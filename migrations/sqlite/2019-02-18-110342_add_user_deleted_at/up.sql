@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    deleted_at DATETIME;
\ No newline at end of file
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    locale TEXT;
\ No newline at end of file
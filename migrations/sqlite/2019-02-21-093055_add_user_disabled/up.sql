@@ -0,0 +1,3 @@
+ALTER TABLE users
+    ADD COLUMN
+    disabled BOOLEAN NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,11 @@
+ALTER TABLE devices
+    ADD COLUMN
+    last_ip TEXT;
+
+ALTER TABLE devices
+    ADD COLUMN
+    last_active_at DATETIME;
+
+ALTER TABLE devices
+    ADD COLUMN
+    token_issued_at DATETIME;
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE events (
+  uuid        TEXT     NOT NULL PRIMARY KEY,
+  event_type  INTEGER  NOT NULL,
+  user_uuid   TEXT,
+  org_uuid    TEXT,
+  cipher_uuid TEXT,
+  device_type INTEGER,
+  ip_address  TEXT,
+  event_date  DATETIME NOT NULL
+);
\ No newline at end of file
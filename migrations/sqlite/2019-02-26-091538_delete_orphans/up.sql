@@ -0,0 +1,31 @@
+-- The foreign keys weren't enforced, remove the rows that reference deleted ones
+-- before turning them on. The parents are cleaned before their children
+DELETE FROM users_organizations WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM users_organizations WHERE org_uuid NOT IN (SELECT uuid FROM organizations);
+
+DELETE FROM devices WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM twofactor WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM client_certificates WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM folders WHERE user_uuid NOT IN (SELECT uuid FROM users);
+
+DELETE FROM provider_users WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM provider_users WHERE provider_uuid NOT IN (SELECT uuid FROM providers);
+DELETE FROM provider_organizations WHERE provider_uuid NOT IN (SELECT uuid FROM providers);
+DELETE FROM provider_organizations WHERE org_uuid NOT IN (SELECT uuid FROM organizations);
+
+DELETE FROM collections WHERE org_uuid NOT IN (SELECT uuid FROM organizations);
+DELETE FROM org_domains WHERE org_uuid NOT IN (SELECT uuid FROM organizations);
+
+DELETE FROM ciphers WHERE user_uuid IS NOT NULL AND user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM ciphers WHERE organization_uuid IS NOT NULL AND organization_uuid NOT IN (SELECT uuid FROM organizations);
+
+DELETE FROM attachments WHERE cipher_uuid NOT IN (SELECT uuid FROM ciphers);
+DELETE FROM attachment_uploads WHERE cipher_uuid NOT IN (SELECT uuid FROM ciphers);
+DELETE FROM attachment_uploads WHERE user_uuid NOT IN (SELECT uuid FROM users);
+
+DELETE FROM folders_ciphers WHERE cipher_uuid NOT IN (SELECT uuid FROM ciphers);
+DELETE FROM folders_ciphers WHERE folder_uuid NOT IN (SELECT uuid FROM folders);
+DELETE FROM ciphers_collections WHERE cipher_uuid NOT IN (SELECT uuid FROM ciphers);
+DELETE FROM ciphers_collections WHERE collection_uuid NOT IN (SELECT uuid FROM collections);
+DELETE FROM users_collections WHERE user_uuid NOT IN (SELECT uuid FROM users);
+DELETE FROM users_collections WHERE collection_uuid NOT IN (SELECT uuid FROM collections);
\ No newline at end of file
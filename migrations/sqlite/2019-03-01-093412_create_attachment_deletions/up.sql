@@ -0,0 +1,5 @@
+CREATE TABLE attachment_deletions (
+  uuid        TEXT     NOT NULL PRIMARY KEY,
+  file_path   TEXT     NOT NULL,
+  created_at  DATETIME NOT NULL
+);
\ No newline at end of file
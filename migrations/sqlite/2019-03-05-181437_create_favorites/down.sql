@@ -0,0 +1,3 @@
+UPDATE ciphers SET favorite = 1 WHERE uuid IN (SELECT cipher_uuid FROM favorites);
+
+DROP TABLE favorites;
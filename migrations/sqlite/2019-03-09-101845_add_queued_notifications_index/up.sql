@@ -0,0 +1 @@
+CREATE INDEX queued_notifications_device_seq ON queued_notifications (device_uuid, seq);
\ No newline at end of file
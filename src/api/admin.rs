@@ -6,10 +6,10 @@ use rocket::response::{content::Html, Flash, Redirect};
 use rocket::{Outcome, Route};
 use rocket_contrib::json::Json;
 
-use crate::api::{ApiResult, EmptyResult};
+use crate::api::{ApiResult, EmptyResult, JsonResult, Notify, UpdateType};
 use crate::auth::{decode_admin, encode_jwt, generate_admin_claims, ClientIp};
 use crate::config::ConfigBuilder;
-use crate::db::{models::*, DbConn};
+use crate::db::{models::*, DbConn, DbReadConn};
 use crate::error::Error;
 use crate::mail;
 use crate::CONFIG;
@@ -23,10 +23,26 @@ pub fn routes() -> Vec<Route> {
         admin_login,
         post_admin_login,
         admin_page,
+        get_users,
+        search,
+        get_user_devices,
         invite_user,
+        create_provider,
         delete_user,
+        restore_user,
         deauth_user,
+        disable_user,
+        enable_user,
+        purge_user_vault,
+        force_password_reset,
+        verify_attachments,
+        totp_drift,
+        notification_stats,
+        merge_duplicate_collections,
         post_config,
+        validate_config,
+        export_config,
+        import_config,
         delete_config,
     ]
 }
@@ -117,7 +133,7 @@ impl AdminTemplateData {
 }
 
 #[get("/", rank = 1)]
-fn admin_page(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
+fn admin_page(_token: AdminToken, conn: DbReadConn) -> ApiResult<Html<String>> {
     let users = User::get_all(&conn);
     let users_json: Vec<Value> = users.iter().map(|u| u.to_json(&conn)).collect();
 
@@ -125,6 +141,88 @@ fn admin_page(_token: AdminToken, conn: DbConn) -> ApiResult<Html<String>> {
     Ok(Html(text))
 }
 
+#[get("/users")]
+fn get_users(_token: AdminToken, conn: DbReadConn) -> Json<Value> {
+    let users = User::get_all(&conn);
+    let users_json: Vec<Value> = users.iter().map(|u| u.to_json(&conn)).collect();
+
+    Json(Value::Array(users_json))
+}
+
+#[derive(FromForm)]
+struct SearchQuery {
+    q: String,
+    page: Option<usize>,
+}
+
+const SEARCH_PAGE_SIZE: i64 = 50;
+
+/// Keeps the results of the page, and returns whether there are more after it.
+/// The queries ask for one more result than the page size to find that out
+fn search_page<T>(items: &mut Vec<T>) -> bool {
+    let more = items.len() as i64 > SEARCH_PAGE_SIZE;
+    items.truncate(SEARCH_PAGE_SIZE as usize);
+    more
+}
+
+/// Searches the users by email and name, the organizations by name and the devices by name and identifier.
+/// Every kind of result is paged separately, the next page is returned while any of them has more results
+#[get("/search?<query..>")]
+fn search(query: Form<SearchQuery>, _token: AdminToken, conn: DbReadConn) -> JsonResult {
+    let term = query.q.trim();
+    let page = query.page.unwrap_or(0);
+    let offset = match (page as i64).checked_mul(SEARCH_PAGE_SIZE) {
+        Some(offset) if page <= i64::max_value() as usize => offset,
+        _ => err!("Invalid page"),
+    };
+
+    let mut users = User::search(term, offset, SEARCH_PAGE_SIZE + 1, &conn);
+    let mut orgs = Organization::search(term, offset, SEARCH_PAGE_SIZE + 1, &conn);
+    let mut devices = Device::search(term, offset, SEARCH_PAGE_SIZE + 1, &conn);
+
+    let more_users = search_page(&mut users);
+    let more_orgs = search_page(&mut orgs);
+    let more_devices = search_page(&mut devices);
+
+    let users_json: Vec<Value> = users.iter().map(|u| u.to_json(&conn)).collect();
+    let orgs_json: Vec<Value> = orgs.iter().map(Organization::to_json).collect();
+    let devices_json: Vec<Value> = devices
+        .iter()
+        .map(|d| {
+            let mut json = d.to_json();
+            json["UserId"] = json!(d.user_uuid);
+            json
+        })
+        .collect();
+
+    let next_page = if more_users || more_orgs || more_devices {
+        Some(page + 1)
+    } else {
+        None
+    };
+
+    Ok(Json(json!({
+        "Users": users_json,
+        "Organizations": orgs_json,
+        "Devices": devices_json,
+        "Page": page,
+        "NextPage": next_page,
+    })))
+}
+
+#[get("/users/<uuid>/devices")]
+fn get_user_devices(uuid: String, _token: AdminToken, conn: DbReadConn) -> JsonResult {
+    let user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    let devices = Device::find_by_user(&user.uuid, &conn);
+    let devices_json: Vec<Value> = devices.iter().map(|d| d.to_json()).collect();
+
+    Ok(Json(Value::Array(devices_json)))
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct InviteData {
@@ -147,25 +245,71 @@ fn invite_user(data: Json<InviteData>, _token: AdminToken, conn: DbConn) -> Empt
         let mut user = User::new(email);
         user.save(&conn)?;
         let org_name = "bitwarden_rs";
-        mail::send_invite(&user.email, &user.uuid, None, None, &org_name, None)
+        mail::send_invite(&user.email, &user.uuid, None, None, &org_name, None, None)
     } else {
         let mut invitation = Invitation::new(data.email);
         invitation.save(&conn)
     }
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct ProviderData {
+    name: String,
+    email: String,
+}
+
+/// Creates a pending provider, the admin then needs to log in and set it up through the provider portal
+#[post("/providers", data = "<data>")]
+fn create_provider(data: Json<ProviderData>, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let data: ProviderData = data.into_inner();
+
+    let user = match User::find_by_mail(&data.email, &conn) {
+        Some(user) => user,
+        None => err!("The provider admin needs to have an account"),
+    };
+
+    let provider = Provider::new(data.name, data.email);
+    let mut provider_user = ProviderUser::new(provider.uuid.clone(), user.uuid);
+    provider_user.type_ = ProviderUserType::ProviderAdmin as i32;
+    provider_user.status = ProviderUserStatus::Accepted as i32;
+
+    provider.save(&conn)?;
+    provider_user.save(&conn)
+}
+
+/// Active accounts are only disabled during the retention period, deleting them again removes them right away
 #[post("/users/<uuid>/delete")]
 fn delete_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
-    let user = match User::find_by_uuid(&uuid, &conn) {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
         Some(user) => user,
         None => err!("User doesn't exist"),
     };
 
-    user.delete(&conn)
+    if user.is_deleted() || CONFIG.user_delete_grace_days() <= 0 {
+        user.delete(&conn)
+    } else {
+        user.soft_delete(&conn)
+    }
 }
 
+#[post("/users/<uuid>/restore")]
+fn restore_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    if !user.is_deleted() {
+        err!("User isn't deleted")
+    }
+
+    user.restore(&conn)
+}
+
+/// Logs out every session of the user, the connected clients are told to log out right away
 #[post("/users/<uuid>/deauth")]
-fn deauth_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+fn deauth_user(uuid: String, _token: AdminToken, conn: DbConn, nt: Notify) -> EmptyResult {
     let mut user = match User::find_by_uuid(&uuid, &conn) {
         Some(user) => user,
         None => err!("User doesn't exist"),
@@ -174,15 +318,170 @@ fn deauth_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
     Device::delete_all_by_user(&user.uuid, &conn)?;
     user.reset_security_stamp();
 
+    user.save(&conn)?;
+    nt.send_user_update(UpdateType::LogOut, &user);
+    Ok(())
+}
+
+/// The disabled users can't log in, and their current sessions are deauthorized
+#[post("/users/<uuid>/disable")]
+fn disable_user(uuid: String, _token: AdminToken, conn: DbConn, nt: Notify) -> EmptyResult {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    Device::delete_all_by_user(&user.uuid, &conn)?;
+    user.reset_security_stamp();
+    user.disabled = true;
+
+    user.save(&conn)?;
+    nt.send_user_update(UpdateType::LogOut, &user);
+    Ok(())
+}
+
+#[post("/users/<uuid>/enable")]
+fn enable_user(uuid: String, _token: AdminToken, conn: DbConn) -> EmptyResult {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    user.disabled = false;
     user.save(&conn)
 }
 
+#[post("/users/<uuid>/purge")]
+fn purge_user_vault(uuid: String, _token: AdminToken, conn: DbConn, nt: Notify) -> EmptyResult {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    crate::api::core::ciphers::purge_vault(&mut user, &conn, &nt)
+}
+
+#[post("/users/<uuid>/force-password-reset")]
+fn force_password_reset(uuid: String, _token: AdminToken, conn: DbConn, nt: Notify) -> EmptyResult {
+    let mut user = match User::find_by_uuid(&uuid, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    // Invalidate the existing sessions, so the flag is picked up on the next login
+    Device::delete_all_by_user(&user.uuid, &conn)?;
+    user.reset_security_stamp();
+    user.force_password_reset = true;
+
+    user.save(&conn)?;
+    nt.send_user_update(UpdateType::LogOut, &user);
+    Ok(())
+}
+
+#[get("/diagnostics/attachments")]
+fn verify_attachments(_token: AdminToken, conn: DbReadConn) -> Json<Value> {
+    let attachments = Attachment::get_all(&conn);
+    let total = attachments.len();
+
+    let errors: Vec<Value> = attachments
+        .iter()
+        .filter_map(|a| a.verify_checksum().err().map(|msg| (a, msg)))
+        .map(|(a, msg)| {
+            json!({
+                "Id": a.id,
+                "CipherId": a.cipher_uuid,
+                "FileName": a.file_name,
+                "Error": msg,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "Total": total,
+        "Errors": errors,
+    }))
+}
+
+/// Users whose last TOTP code was rejected but would have been valid with a small clock difference
+#[get("/diagnostics/totp-drift")]
+fn totp_drift(_token: AdminToken, conn: DbReadConn) -> Json<Value> {
+    use crate::api::core::two_factor::get_totp_drifts;
+    use crate::util::format_date;
+    use chrono::Utc;
+
+    let drifts: Vec<Value> = get_totp_drifts()
+        .into_iter()
+        .map(|(user_uuid, drift)| {
+            let email = User::find_by_uuid(&user_uuid, &conn).map(|u| u.email);
+            json!({
+                "UserId": user_uuid,
+                "Email": email,
+                "OffsetSeconds": drift.offset_seconds,
+                "DetectedDate": format_date(&drift.detected_at),
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "ServerTime": format_date(&Utc::now().naive_utc()),
+        "Drifts": drifts,
+    }))
+}
+
+/// Counters of the websocket notifications, with the time between the changes and their delivery
+#[get("/diagnostics/notifications")]
+fn notification_stats(_token: AdminToken) -> Json<Value> {
+    use crate::api::notifications::NOTIFICATION_STATS;
+
+    let mut stats = NOTIFICATION_STATS.to_json();
+    stats["WebsocketEnabled"] = json!(CONFIG.websocket_enabled());
+    Json(stats)
+}
+
+/// Merges the collections with the same external id in every organization, before enabling the unique external ids
+#[post("/collections/merge-duplicates")]
+fn merge_duplicate_collections(_token: AdminToken, conn: DbConn) -> JsonResult {
+    use crate::db::repo::Transactional;
+
+    let merged = conn.in_transaction(|| {
+        let mut merged = 0;
+        for org in Organization::get_all(&conn) {
+            merged += Collection::merge_duplicates(&org.uuid, &conn)?;
+        }
+        Ok(merged)
+    })?;
+
+    Ok(Json(json!({ "Merged": merged })))
+}
+
 #[post("/config", data = "<data>")]
 fn post_config(data: Json<ConfigBuilder>, _token: AdminToken) -> EmptyResult {
     let data: ConfigBuilder = data.into_inner();
     CONFIG.update_config(data)
 }
 
+#[post("/config/validate", data = "<data>")]
+fn validate_config(data: Json<ConfigBuilder>, _token: AdminToken) -> JsonResult {
+    let data: ConfigBuilder = data.into_inner();
+    let errors = CONFIG.check_config(&data);
+
+    Ok(Json(json!({
+        "Valid": errors.is_empty(),
+        "Errors": errors,
+    })))
+}
+
+#[get("/config/export")]
+fn export_config(_token: AdminToken) -> Json<ConfigBuilder> {
+    Json(CONFIG.export_user_config())
+}
+
+#[post("/config/import", data = "<data>")]
+fn import_config(data: Json<ConfigBuilder>, _token: AdminToken) -> EmptyResult {
+    let data: ConfigBuilder = data.into_inner();
+    CONFIG.import_user_config(data)
+}
+
 #[post("/config/delete")]
 fn delete_config(_token: AdminToken) -> EmptyResult {
     CONFIG.delete_user_config()
@@ -1,14 +1,25 @@
+use std::path::Path;
+
+use chrono::Utc;
+
 use rocket_contrib::json::Json;
+use serde_json::Value;
 
 use crate::db::models::*;
 use crate::db::DbConn;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, PasswordData, UpdateType};
-use crate::auth::{decode_invite, Headers};
+use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, NumberOrString, PasswordData, UpdateType};
+use crate::auth::{
+    decode_device_approval, decode_email_change_approval, decode_invite, decode_unlock, decode_verify_email, ldap,
+    ClientCert, ClientIp, Headers,
+};
 use crate::mail;
+use crate::ratelimit::{self, PASSWORD_HINT_LIMITER};
 
 use crate::CONFIG;
 
+use rocket::request::Form;
+use rocket::response::content::Html;
 use rocket::Route;
 
 pub fn routes() -> Vec<Route> {
@@ -23,13 +34,31 @@ pub fn routes() -> Vec<Route> {
         post_kdf,
         post_rotatekey,
         post_sstamp,
+        post_api_key,
+        post_rotate_api_key,
         post_email_token,
         post_email,
+        post_verify_email,
+        post_verify_email_token,
         delete_account,
         post_delete_account,
         revision_date,
         password_hint,
         prelogin,
+        unlock_account_page,
+        unlock_account,
+        directory_signup_page,
+        directory_signup,
+        approve_device_page,
+        approve_device,
+        approve_email_change_page,
+        approve_email_change,
+        get_client_certs,
+        post_client_cert,
+        delete_client_cert,
+        post_delete_client_cert,
+        get_vault_health,
+        post_vault_health_repair,
     ]
 }
 
@@ -68,6 +97,9 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
             if let Some(token) = data.Token {
                 let claims = decode_invite(&token)?;
                 if claims.email == data.Email {
+                    // The invite was sent to this address, which proves the user owns it
+                    let mut user = user;
+                    user.verified_at = Some(Utc::now().naive_utc());
                     user
                 } else {
                     err!("Registration email does not match invite email")
@@ -79,17 +111,19 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
                 }
 
                 user
-            } else if CONFIG.signups_allowed() {
+            } else if CONFIG.is_signup_allowed(&data.Email) {
                 err!("Account with this email already exists")
             } else {
                 err!("Registration not allowed")
             }
         }
         None => {
-            if CONFIG.signups_allowed() || Invitation::take(&data.Email, &conn) {
+            if CONFIG.is_signup_allowed(&data.Email) || Invitation::take(&data.Email, &conn) {
                 User::new(data.Email.clone())
-            } else {
+            } else if CONFIG.signups_domains_whitelist().trim().is_empty() {
                 err!("Registration not allowed")
+            } else {
+                err!("Registration is only allowed for some email domains, ask for an invitation")
             }
         }
     };
@@ -122,19 +156,59 @@ fn register(data: JsonUpcase<RegisterData>, conn: DbConn) -> EmptyResult {
         user.public_key = Some(keys.PublicKey);
     }
 
-    user.save(&conn)
+    user.save(&conn)?;
+
+    // Anyone can register with any address, so the domain only counts once the user proved to own it
+    if user.verified_at.is_some() {
+        _join_verified_domain_orgs(&user, &conn)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the user to the organizations that have verified the domain of their email,
+/// an admin still has to confirm them before they can access the shared items
+fn _join_verified_domain_orgs(user: &User, conn: &DbConn) -> EmptyResult {
+    let domain = match user.email.rfind('@') {
+        Some(pos) => &user.email[pos + 1..],
+        None => return Ok(()),
+    };
+
+    for org_domain in OrgDomain::find_verified_by_domain(domain, &conn) {
+        if UserOrganization::find_by_user_and_org(&user.uuid, &org_domain.org_uuid, &conn).is_some() {
+            continue;
+        }
+
+        let mut user_org = UserOrganization::new(user.uuid.clone(), org_domain.org_uuid.clone());
+        user_org.status = UserOrgStatus::Accepted as i32;
+        user_org.save(&conn)?;
+
+        info!(
+            "User {} joined organization {} by verified domain",
+            user.email, org_domain.org_uuid
+        );
+    }
+
+    Ok(())
 }
 
 #[get("/accounts/profile")]
 fn profile(headers: Headers, conn: DbConn) -> JsonResult {
-    Ok(Json(headers.user.to_json(&conn)))
+    let mut json = headers.user.to_json(&conn);
+
+    // The storage of the personal attachments, the organizations have their own
+    let storage = Attachment::size_by_user(&headers.user.uuid, &conn);
+    json["Storage"] = storage.into();
+    json["StorageName"] = crate::util::get_display_size(storage).into();
+    json["MaxStorage"] = json!(CONFIG.user_attachment_limit().map(|kb| kb * 1024));
+
+    Ok(Json(json))
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct ProfileData {
-    #[serde(rename = "Culture")]
-    _Culture: String, // Ignored, always use en-US
+    Culture: Option<String>,
     MasterPasswordHint: Option<String>,
     Name: String,
 }
@@ -155,6 +229,9 @@ fn post_profile(data: JsonUpcase<ProfileData>, headers: Headers, conn: DbConn) -
         Some(ref h) if h.is_empty() => None,
         _ => data.MasterPasswordHint,
     };
+    if let Some(ref culture) = data.Culture {
+        user.locale = crate::i18n::parse_locale(culture);
+    }
     user.save(&conn)?;
     Ok(Json(user.to_json(&conn)))
 }
@@ -313,8 +390,10 @@ fn post_rotatekey(data: JsonUpcase<KeyData>, headers: Headers, conn: DbConn, nt:
     user.save(&conn)
 }
 
+/// Logs out everywhere: the refresh tokens are removed with the devices, the access tokens
+/// stop working with the new security stamp, and the connected clients are told to log out
 #[post("/accounts/security-stamp", data = "<data>")]
-fn post_sstamp(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
+fn post_sstamp(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
     let data: PasswordData = data.into_inner().data;
     let mut user = headers.user;
 
@@ -324,7 +403,41 @@ fn post_sstamp(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -
 
     Device::delete_all_by_user(&user.uuid, &conn)?;
     user.reset_security_stamp();
-    user.save(&conn)
+    user.save(&conn)?;
+
+    nt.send_user_update(UpdateType::LogOut, &user);
+    Ok(())
+}
+
+/// Returns the personal API key, which is generated the first time it's requested
+#[post("/accounts/api-key", data = "<data>")]
+fn post_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, false, headers, conn)
+}
+
+/// Replaces the personal API key, the old one can't be used to log in anymore
+#[post("/accounts/rotate-api-key", data = "<data>")]
+fn post_rotate_api_key(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _api_key(data, true, headers, conn)
+}
+
+fn _api_key(data: JsonUpcase<PasswordData>, rotate: bool, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    if rotate || user.api_key.is_none() {
+        user.rotate_api_key();
+        user.save(&conn)?;
+    }
+
+    Ok(Json(json!({
+        "ApiKey": user.api_key,
+        "Object": "apiKey",
+    })))
 }
 
 #[derive(Deserialize)]
@@ -346,6 +459,21 @@ fn post_email_token(data: JsonUpcase<EmailTokenData>, headers: Headers, conn: Db
         err!("Email already in use");
     }
 
+    if CONFIG.mail_enabled() {
+        mail::send_verify_email_change(&data.NewEmail, &headers.user.uuid)?;
+
+        if CONFIG.email_change_require_old_address() {
+            // A new request has to be approved again, even if it's for the same address
+            let approval_type = TwoFactorType::EmailChangeApproval as i32;
+            if let Some(approval) = TwoFactor::find_by_user_and_type(&headers.user.uuid, approval_type, &conn) {
+                approval.delete(&conn)?;
+            }
+
+            let locale = headers.user.locale.as_ref().map(String::as_str);
+            mail::send_approve_email_change(&headers.user.email, &headers.user.uuid, &data.NewEmail, locale)?;
+        }
+    }
+
     Ok(())
 }
 
@@ -357,8 +485,7 @@ struct ChangeEmailData {
 
     Key: String,
     NewMasterPasswordHash: String,
-    #[serde(rename = "Token")]
-    _Token: NumberOrString,
+    Token: NumberOrString,
 }
 
 #[post("/accounts/email", data = "<data>")]
@@ -374,12 +501,98 @@ fn post_email(data: JsonUpcase<ChangeEmailData>, headers: Headers, conn: DbConn)
         err!("Email already in use");
     }
 
+    // The token is only sent when email is enabled, otherwise there's no way to verify the new address
+    if CONFIG.mail_enabled() {
+        let claims = match decode_verify_email(&data.Token.into_string()) {
+            Ok(claims) => claims,
+            Err(_) => err!("Invalid email token"),
+        };
+
+        if claims.sub != user.uuid || claims.email != data.NewEmail.to_lowercase() {
+            err!("Invalid email token")
+        }
+
+        if CONFIG.email_change_require_old_address() {
+            let approval_type = TwoFactorType::EmailChangeApproval as i32;
+            match TwoFactor::find_by_user_and_type(&user.uuid, approval_type, &conn) {
+                Some(ref approval) if approval.data == claims.email => (),
+                _ => err!("The email change hasn't been approved from the current address"),
+            }
+        }
+    }
+
     user.email = data.NewEmail;
 
+    // Without email there was no token, so nothing proves the new address belongs to the user
+    user.verified_at = if CONFIG.mail_enabled() {
+        Some(Utc::now().naive_utc())
+    } else {
+        None
+    };
+
     user.set_password(&data.NewMasterPasswordHash);
     user.key = data.Key;
 
-    user.save(&conn)
+    user.save(&conn)?;
+
+    let approval_type = TwoFactorType::EmailChangeApproval as i32;
+    if let Some(approval) = TwoFactor::find_by_user_and_type(&user.uuid, approval_type, &conn) {
+        approval.delete(&conn)?;
+    }
+
+    Ok(())
+}
+
+#[post("/accounts/verify-email")]
+fn post_verify_email(headers: Headers) -> EmptyResult {
+    let user = headers.user;
+
+    if !CONFIG.mail_enabled() {
+        err!("Cannot verify email address")
+    }
+
+    if user.verified_at.is_some() {
+        err!("The email address is already verified")
+    }
+
+    let locale = user.locale.as_ref().map(String::as_str);
+    mail::send_verify_email(&user.email, &user.uuid, locale)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct VerifyEmailTokenData {
+    UserId: String,
+    Token: String,
+}
+
+#[post("/accounts/verify-email-token", data = "<data>")]
+fn post_verify_email_token(data: JsonUpcase<VerifyEmailTokenData>, conn: DbConn) -> EmptyResult {
+    let data: VerifyEmailTokenData = data.into_inner().data;
+
+    let mut user = match User::find_by_uuid(&data.UserId, &conn) {
+        Some(user) => user,
+        None => err!("Invalid email token"),
+    };
+
+    let claims = match decode_verify_email(&data.Token) {
+        Ok(claims) => claims,
+        Err(_) => err!("Invalid email token"),
+    };
+
+    // The token is only valid for the address it was sent to, not one the user changed to later
+    if claims.sub != user.uuid || claims.email != user.email.to_lowercase() {
+        err!("Invalid email token")
+    }
+
+    if user.verified_at.is_some() {
+        return Ok(());
+    }
+
+    user.verified_at = Some(Utc::now().naive_utc());
+    user.save(&conn)?;
+
+    _join_verified_domain_orgs(&user, &conn)
 }
 
 #[post("/accounts/delete", data = "<data>")]
@@ -390,18 +603,22 @@ fn post_delete_account(data: JsonUpcase<PasswordData>, headers: Headers, conn: D
 #[delete("/accounts", data = "<data>")]
 fn delete_account(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
     let data: PasswordData = data.into_inner().data;
-    let user = headers.user;
+    let mut user = headers.user;
 
     if !user.check_valid_password(&data.MasterPasswordHash) {
         err!("Invalid password")
     }
 
-    user.delete(&conn)
+    if CONFIG.user_delete_grace_days() > 0 {
+        user.soft_delete(&conn)
+    } else {
+        user.delete(&conn)
+    }
 }
 
 #[get("/accounts/revision-date")]
 fn revision_date(headers: Headers) -> String {
-    let revision_date = headers.user.updated_at.timestamp_millis();
+    let revision_date = crate::util::to_utc(&headers.user.updated_at).timestamp_millis();
     revision_date.to_string()
 }
 
@@ -412,11 +629,25 @@ struct PasswordHintData {
 }
 
 #[post("/accounts/password-hint", data = "<data>")]
-fn password_hint(data: JsonUpcase<PasswordHintData>, conn: DbConn) -> EmptyResult {
+fn password_hint(data: JsonUpcase<PasswordHintData>, ip: ClientIp, conn: DbConn) -> EmptyResult {
     let data: PasswordHintData = data.into_inner().data;
 
+    // Both limits are checked before looking for the user, so the responses are the same for every email.
+    // The email is only counted when the IP address is allowed, so a refused IP address can't use up its limit
+    let max_hints = CONFIG.password_hints_per_hour();
+    let allowed = PASSWORD_HINT_LIMITER.check(&format!("ip:{}", ip.ip), max_hints)
+        && PASSWORD_HINT_LIMITER.check(&format!("email:{}", data.Email.to_lowercase()), max_hints);
+    if !allowed {
+        err_limit!(
+            "Too many password hint requests. Try again later",
+            format!("IP: {}. Email: {}.", ip.ip, data.Email)
+        )
+    }
+
     let hint = match User::find_by_mail(&data.Email, &conn) {
         Some(user) => user.password_hint,
+        // Without the emails the hint is shown directly, so the unknown accounts have to look like accounts without a hint
+        None if !CONFIG.mail_enabled() && CONFIG.show_password_hint() => None,
         None => return Ok(()),
     };
 
@@ -445,7 +676,8 @@ fn prelogin(data: JsonUpcase<PreloginData>, conn: DbConn) -> JsonResult {
 
     let (kdf_type, kdf_iter) = match User::find_by_mail(&data.Email, &conn) {
         Some(user) => (user.client_kdf_type, user.client_kdf_iter),
-        None => (User::CLIENT_KDF_TYPE_DEFAULT, User::CLIENT_KDF_ITER_DEFAULT),
+        // The unknown emails get the defaults of the new accounts, so they don't stand out
+        None => (User::CLIENT_KDF_TYPE_DEFAULT, CONFIG.client_kdf_iterations()),
     };
 
     Ok(Json(json!({
@@ -453,3 +685,380 @@ fn prelogin(data: JsonUpcase<PreloginData>, conn: DbConn) -> JsonResult {
         "KdfIterations": kdf_iter
     })))
 }
+
+/// The emailed link only shows the confirmation, so opening it (or a mail scanner following it) doesn't unlock the account
+#[get("/accounts/unlock?<token>")]
+fn unlock_account_page(token: String) -> ApiResult<Html<String>> {
+    // Only a valid token is put in the page, which only has URL safe characters
+    decode_unlock(&token)?;
+
+    Ok(Html(format!(
+        "<form method=\"post\" action=\"{}/api/accounts/unlock\">\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\
+         <button type=\"submit\">Unlock account</button>\
+         </form>",
+        CONFIG.domain(),
+        token
+    )))
+}
+
+#[derive(FromForm)]
+struct UnlockForm {
+    token: String,
+}
+
+/// The token can only unlock the lockout it was sent for, so it can't be used again after the account is unlocked
+#[post("/accounts/unlock", data = "<data>")]
+fn unlock_account(data: Form<UnlockForm>, conn: DbConn) -> ApiResult<&'static str> {
+    let claims = decode_unlock(&data.token)?;
+
+    let mut user = match User::find_by_uuid(&claims.sub, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    if user.locked_until.map(|until| until.timestamp()) != Some(claims.locked_until) {
+        err!("This link has already been used or has been replaced by a newer one")
+    }
+
+    user.login_failures = 0;
+    user.locked_until = None;
+    user.save_lockout(&conn)?;
+
+    info!("Account {} unlocked with the emailed link", user.email);
+    Ok("Your account has been unlocked, you can log in again")
+}
+
+fn _check_directory_signups() -> EmptyResult {
+    if !CONFIG.ldap_enabled() || !CONFIG.ldap_signups_allowed() {
+        err!("The directory signups are disabled")
+    }
+
+    if !CONFIG.mail_enabled() {
+        err!("The directory signups need the mail service, to send the invitation")
+    }
+    Ok(())
+}
+
+/// The users of the directory can sign up even when the signups are disabled. The clients never send
+/// the directory password, so it's entered in this page of the server instead
+#[get("/accounts/directory-signup")]
+fn directory_signup_page() -> ApiResult<Html<String>> {
+    _check_directory_signups()?;
+
+    Ok(Html(format!(
+        "<form method=\"post\" action=\"{}/api/accounts/directory-signup\">\
+         <input type=\"email\" name=\"email\" placeholder=\"Email\" required>\
+         <input type=\"password\" name=\"password\" placeholder=\"Directory password\" required>\
+         <button type=\"submit\">Sign up</button>\
+         </form>",
+        CONFIG.domain()
+    )))
+}
+
+#[derive(FromForm)]
+struct DirectorySignupForm {
+    email: String,
+    password: String,
+}
+
+/// Checks the password by binding as the user of the directory, and sends an invitation to the address,
+/// so the account is only created by someone that knows the directory password and can read the emails
+#[post("/accounts/directory-signup", data = "<data>")]
+fn directory_signup(data: Form<DirectorySignupForm>, ip: ClientIp, conn: DbConn) -> ApiResult<&'static str> {
+    _check_directory_signups()?;
+
+    let data = data.into_inner();
+    let email = data.email.trim().to_lowercase();
+    ratelimit::check_login(&email, &ip)?;
+
+    if !ldap::check_password(&email, &data.password)? {
+        ratelimit::register_login_failure(&ip);
+        err!(
+            "Username or password is incorrect. Try again",
+            format!("IP: {}. Username: {}.", ip.ip, email)
+        )
+    }
+
+    let user = match User::find_by_mail(&email, &conn) {
+        Some(user) if !user.password_hash.is_empty() => err!("Account with this email already exists"),
+        Some(user) => user,
+        None => {
+            let mut user = User::new(email);
+            user.save(&conn)?;
+            user
+        }
+    };
+
+    let org_name = "bitwarden_rs";
+    mail::send_invite(&user.email, &user.uuid, None, None, &org_name, None, None)?;
+    Ok("An invitation has been sent to your email, open it to create your account")
+}
+
+/// The page of an emailed approval link, which only sends the token back when the button is pressed, so a mail
+/// scanner following the link doesn't approve anything. Only a valid token is put in the page
+fn _approval_page(action: &str, token: &str, button: &str) -> Html<String> {
+    Html(format!(
+        "<form method=\"post\" action=\"{}/api/accounts/{}\">\
+         <input type=\"hidden\" name=\"token\" value=\"{}\">\
+         <button type=\"submit\">{}</button>\
+         </form>",
+        CONFIG.domain(),
+        action,
+        token,
+        button
+    ))
+}
+
+#[derive(FromForm)]
+struct ApprovalForm {
+    token: String,
+}
+
+#[get("/accounts/approve-device?<token>")]
+fn approve_device_page(token: String) -> ApiResult<Html<String>> {
+    decode_device_approval(&token)?;
+    Ok(_approval_page("approve-device", &token, "Approve the device"))
+}
+
+/// Registers the device from the emailed link, so the user can log in from it
+#[post("/accounts/approve-device", data = "<data>")]
+fn approve_device(data: Form<ApprovalForm>, conn: DbConn) -> ApiResult<&'static str> {
+    use num_traits::FromPrimitive;
+
+    let claims = decode_device_approval(&data.token)?;
+
+    let user = match User::find_by_uuid(&claims.sub, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    if let Some(device) = Device::find_by_uuid(&claims.device_id, &conn) {
+        if device.user_uuid == user.uuid {
+            return Ok("The device was already approved, you can log in from it");
+        }
+    }
+
+    let device_type = DeviceType::from_i32(claims.device_type).unwrap_or(DeviceType::UnknownBrowser);
+
+    // A device of another user with the same identifier is replaced, like on the login
+    let mut device = Device::new(claims.device_id, user.uuid.clone(), claims.device_name, device_type);
+    device.save(&conn)?;
+
+    info!(
+        "Device {} of {} approved with the emailed link",
+        device.uuid, user.email
+    );
+    Ok("The device has been approved, you can log in from it")
+}
+
+#[get("/accounts/approve-email-change?<token>")]
+fn approve_email_change_page(token: String) -> ApiResult<Html<String>> {
+    decode_email_change_approval(&token)?;
+    Ok(_approval_page(
+        "approve-email-change",
+        &token,
+        "Approve the email change",
+    ))
+}
+
+/// Approves the email change from the emailed link sent to the current address,
+/// the change itself is done by entering the token sent to the new one
+#[post("/accounts/approve-email-change", data = "<data>")]
+fn approve_email_change(data: Form<ApprovalForm>, conn: DbConn) -> ApiResult<&'static str> {
+    let claims = decode_email_change_approval(&data.token)?;
+
+    let user = match User::find_by_uuid(&claims.sub, &conn) {
+        Some(user) => user,
+        None => err!("User doesn't exist"),
+    };
+
+    if user.email.to_lowercase() == claims.email {
+        return Ok("The email address was already changed");
+    }
+
+    let approval = TwoFactor::new(user.uuid.clone(), TwoFactorType::EmailChangeApproval, claims.email);
+    approval.replace(&conn)?;
+
+    info!("Email change of {} approved with the emailed link", user.email);
+    Ok("The email change has been approved, enter the token sent to the new address to complete it")
+}
+
+#[get("/accounts/client-certificates")]
+fn get_client_certs(headers: Headers, conn: DbConn) -> JsonResult {
+    let certs = ClientCertificate::find_by_user(&headers.user.uuid, &conn);
+    let certs_json: Vec<Value> = certs.iter().map(ClientCertificate::to_json).collect();
+
+    Ok(Json(json!({
+        "Data": certs_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ClientCertData {
+    MasterPasswordHash: String,
+    Name: String,
+}
+
+#[post("/accounts/client-certificates", data = "<data>")]
+fn post_client_cert(data: JsonUpcase<ClientCertData>, headers: Headers, cert: ClientCert, conn: DbConn) -> JsonResult {
+    let data: ClientCertData = data.into_inner().data;
+    let user = headers.user;
+
+    if CONFIG.client_cert_header().is_none() {
+        err!("Client certificates are not enabled")
+    }
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    // Only the certificate used for this request can be registered, which proves the user has its key
+    let fingerprint = match cert.fingerprint {
+        Some(fingerprint) => fingerprint,
+        None => err!("No client certificate was used for this request"),
+    };
+
+    if ClientCertificate::find_by_fingerprint(&fingerprint, &conn).is_some() {
+        err!("This client certificate is already registered")
+    }
+
+    let client_cert = ClientCertificate::new(user.uuid, data.Name, fingerprint);
+    client_cert.save(&conn)?;
+
+    Ok(Json(client_cert.to_json()))
+}
+
+#[post("/accounts/client-certificates/<uuid>/delete", data = "<data>")]
+fn post_delete_client_cert(
+    uuid: String,
+    data: JsonUpcase<PasswordData>,
+    headers: Headers,
+    conn: DbConn,
+) -> EmptyResult {
+    delete_client_cert(uuid, data, headers, conn)
+}
+
+#[delete("/accounts/client-certificates/<uuid>", data = "<data>")]
+fn delete_client_cert(uuid: String, data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    match ClientCertificate::find_by_uuid_and_user(&uuid, &user.uuid, &conn) {
+        Some(client_cert) => client_cert.delete(&conn),
+        None => err!("Client certificate doesn't exist"),
+    }
+}
+
+/// The broken references in the data of the user, which can be left behind by a failed or interrupted delete.
+/// Besides the personal ciphers, the ciphers of the organizations the user administers are checked too
+struct VaultHealth {
+    folder_links: Vec<FolderCipher>,
+    user_collections: Vec<CollectionUser>,
+    collection_links: Vec<CollectionCipher>,
+    missing_files: Vec<Attachment>,
+}
+
+impl VaultHealth {
+    fn check(user_uuid: &str, conn: &DbConn) -> Self {
+        let mut cipher_uuids: Vec<String> = Cipher::find_owned_by_user(user_uuid, conn)
+            .into_iter()
+            .map(|c| c.uuid)
+            .collect();
+
+        for user_org in UserOrganization::find_by_user(user_uuid, conn) {
+            if user_org.type_ >= UserOrgType::Admin {
+                let org_ciphers = Cipher::find_by_org(&user_org.org_uuid, conn);
+                cipher_uuids.extend(org_ciphers.into_iter().map(|c| c.uuid));
+            }
+        }
+
+        let missing_files = Attachment::find_by_ciphers(cipher_uuids.clone(), conn)
+            .into_iter()
+            .filter(|a| !Path::new(&a.get_file_path()).exists())
+            .collect();
+
+        VaultHealth {
+            folder_links: FolderCipher::find_orphans_by_user(user_uuid, conn),
+            user_collections: CollectionUser::find_orphans_by_user(user_uuid, conn),
+            collection_links: CollectionCipher::find_orphans_by_ciphers(&cipher_uuids, conn),
+            missing_files,
+        }
+    }
+
+    /// The attachments whose file is missing are only removed when asked for, as the storage
+    /// may just be unavailable right now, and the files would come back with it
+    fn repair(self, delete_missing_files: bool, conn: &DbConn) -> EmptyResult {
+        for folder_cipher in self.folder_links {
+            folder_cipher.delete(conn)?;
+        }
+        for collection_user in self.user_collections {
+            collection_user.delete(conn)?;
+        }
+        for collection_cipher in self.collection_links {
+            CollectionCipher::delete(&collection_cipher.cipher_uuid, &collection_cipher.collection_uuid, conn)?;
+        }
+        if delete_missing_files {
+            for attachment in self.missing_files {
+                attachment.delete(conn)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "FolderLinks": self.folder_links.iter().map(|f| json!({
+                "CipherId": f.cipher_uuid,
+                "FolderId": f.folder_uuid,
+            })).collect::<Vec<Value>>(),
+            "CollectionUsers": self.user_collections.iter().map(|c| json!({
+                "CollectionId": c.collection_uuid,
+            })).collect::<Vec<Value>>(),
+            "CollectionLinks": self.collection_links.iter().map(|c| json!({
+                "CipherId": c.cipher_uuid,
+                "CollectionId": c.collection_uuid,
+            })).collect::<Vec<Value>>(),
+            "MissingAttachments": self.missing_files.iter().map(|a| json!({
+                "CipherId": a.cipher_uuid,
+                "AttachmentId": a.id,
+                "FileName": a.file_name,
+            })).collect::<Vec<Value>>(),
+            "Object": "vaultHealth",
+        })
+    }
+}
+
+#[get("/accounts/vault-health")]
+fn get_vault_health(headers: Headers, conn: DbConn) -> JsonResult {
+    Ok(Json(VaultHealth::check(&headers.user.uuid, &conn).to_json()))
+}
+
+/// Removes the broken references found by the health check, and returns what was found.
+/// The attachments with a missing file are only reported, unless `delete_missing_files` is set
+#[post("/accounts/vault-health/repair?<delete_missing_files>")]
+fn post_vault_health_repair(
+    delete_missing_files: Option<bool>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let delete_missing_files = delete_missing_files.unwrap_or(false);
+    let health = VaultHealth::check(&headers.user.uuid, &conn);
+    let mut result = health.to_json();
+    result["MissingAttachmentsDeleted"] = json!(delete_missing_files);
+    health.repair(delete_missing_files, &conn)?;
+
+    let mut user = headers.user;
+    user.update_revision(&conn)?;
+    nt.send_user_update(UpdateType::Vault, &user);
+
+    Ok(Json(result))
+}
@@ -1,24 +1,31 @@
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content;
+use rocket::Outcome;
 use rocket::{request::Form, Data, Route};
 
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
-use multipart::server::save::SavedData;
-use multipart::server::{Multipart, SaveResult};
+use multipart::server::Multipart;
 
 use data_encoding::HEXLOWER;
 
 use crate::db::models::*;
-use crate::db::DbConn;
+use crate::db::repo::Transactional;
+use crate::db::{DbConn, DbReadConn};
 
 use crate::crypto;
+use crate::service;
+use crate::util;
 
-use crate::api::{self, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType};
-use crate::auth::Headers;
+use crate::api::events::{self, events_page, EventRange};
+use crate::api::{self, ApiResult, EmptyResult, JsonResult, JsonUpcase, Notify, PasswordData, UpdateType, Uuid};
+use crate::auth::{ClientIp, Headers};
+use crate::cache::SYNC_CACHE;
 
 use crate::CONFIG;
 
@@ -37,10 +44,15 @@ pub fn routes() -> Vec<Route> {
         post_attachment,
         post_attachment_admin,
         post_attachment_share,
+        post_attachment_copy,
         delete_attachment_post,
         delete_attachment_post_admin,
         delete_attachment,
         delete_attachment_admin,
+        post_attachment_upload,
+        get_attachment_upload,
+        put_attachment_upload,
+        delete_attachment_upload,
         post_cipher_admin,
         post_cipher_share,
         put_cipher_share,
@@ -54,12 +66,16 @@ pub fn routes() -> Vec<Route> {
         delete_cipher_selected,
         delete_cipher_selected_post,
         delete_all,
+        post_export,
+        post_export_folder,
         move_cipher_selected,
         move_cipher_selected_put,
         put_collections_update,
         post_collections_update,
         post_collections_admin,
         put_collections_admin,
+        post_bulk_collections,
+        get_cipher_events,
     ]
 }
 
@@ -69,8 +85,34 @@ struct SyncData {
     exclude_domains: bool, // Default: 'false'
 }
 
+#[derive(Serialize)]
+#[allow(non_snake_case)]
+struct SyncResponse {
+    Profile: Value,
+    Folders: Vec<Value>,
+    Collections: Vec<Value>,
+    Ciphers: Vec<CipherResponse>,
+    Domains: Value,
+    Object: &'static str,
+}
+
 #[get("/sync?<data..>")]
-fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
+fn sync(data: Form<SyncData>, headers: Headers, conn: DbReadConn) -> ApiResult<content::Json<String>> {
+    let revision = headers.user.updated_at;
+    let user_uuid = headers.user.uuid.clone();
+    let host = headers.host.clone();
+
+    if let Some(cached) = SYNC_CACHE.get(&user_uuid, revision, &host, data.exclude_domains) {
+        return Ok(content::Json(cached));
+    }
+
+    // The revision the read connection sees, read before the rest of the data so the data is never older.
+    // A replica can lag behind, and the response has to be cached with the revision it was built from
+    let read_revision = match User::find_by_uuid(&user_uuid, &conn) {
+        Some(user) => user.updated_at,
+        None => err!("User doesn't exist"),
+    };
+
     let user_json = headers.user.to_json(&conn);
 
     let folders = Folder::find_by_user(&headers.user.uuid, &conn);
@@ -80,9 +122,9 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
     let collections_json: Vec<Value> = collections.iter().map(|c| c.to_json()).collect();
 
     let ciphers = Cipher::find_by_user(&headers.user.uuid, &conn);
-    let ciphers_json: Vec<Value> = ciphers
+    let ciphers_json: Vec<CipherResponse> = ciphers
         .iter()
-        .map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn))
+        .map(|c| c.to_response(&headers.host, &headers.user.uuid, &conn))
         .collect();
 
     let domains_json = if data.exclude_domains {
@@ -91,23 +133,29 @@ fn sync(data: Form<SyncData>, headers: Headers, conn: DbConn) -> JsonResult {
         api::core::get_eq_domains(headers).unwrap().into_inner()
     };
 
-    Ok(Json(json!({
-        "Profile": user_json,
-        "Folders": folders_json,
-        "Collections": collections_json,
-        "Ciphers": ciphers_json,
-        "Domains": domains_json,
-        "Object": "sync"
-    })))
+    let sync_json = serde_json::to_string(&SyncResponse {
+        Profile: user_json,
+        Folders: folders_json,
+        Collections: collections_json,
+        Ciphers: ciphers_json,
+        Domains: domains_json,
+        Object: "sync",
+    })?;
+
+    // When the replica is behind, the entry would never be requested again
+    if read_revision == revision {
+        SYNC_CACHE.insert(&user_uuid, revision, &host, data.exclude_domains, sync_json.clone());
+    }
+    Ok(content::Json(sync_json))
 }
 
 #[get("/ciphers")]
-fn get_ciphers(headers: Headers, conn: DbConn) -> JsonResult {
+fn get_ciphers(headers: Headers, conn: DbReadConn) -> JsonResult {
     let ciphers = Cipher::find_by_user(&headers.user.uuid, &conn);
 
-    let ciphers_json: Vec<Value> = ciphers
+    let ciphers_json: Vec<CipherResponse> = ciphers
         .iter()
-        .map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn))
+        .map(|c| c.to_response(&headers.host, &headers.user.uuid, &conn))
         .collect();
 
     Ok(Json(json!({
@@ -118,7 +166,7 @@ fn get_ciphers(headers: Headers, conn: DbConn) -> JsonResult {
 }
 
 #[get("/ciphers/<uuid>")]
-fn get_cipher(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+fn get_cipher(uuid: Uuid, headers: Headers, conn: DbConn) -> JsonResult {
     let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
         Some(cipher) => cipher,
         None => err!("Cipher doesn't exist"),
@@ -132,13 +180,21 @@ fn get_cipher(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
 }
 
 #[get("/ciphers/<uuid>/admin")]
-fn get_cipher_admin(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
-    // TODO: Implement this correctly
-    get_cipher(uuid, headers, conn)
+fn get_cipher_admin(uuid: Uuid, headers: Headers, conn: DbConn) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_org_admin(&headers.user.uuid, &conn) && !cipher.is_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not owned by user")
+    }
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
 #[get("/ciphers/<uuid>/details")]
-fn get_cipher_details(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
+fn get_cipher_details(uuid: Uuid, headers: Headers, conn: DbConn) -> JsonResult {
     get_cipher(uuid, headers, conn)
 }
 
@@ -152,13 +208,7 @@ pub struct CipherData {
     // TODO: Some of these might appear all the time, no need for Option
     OrganizationId: Option<String>,
 
-    /*
-    Login = 1,
-    SecureNote = 2,
-    Card = 3,
-    Identity = 4
-    */
-    pub Type: i32, // TODO: Change this to NumberOrString
+    pub Type: CipherType,
     pub Name: String,
     Notes: Option<String>,
     Fields: Option<Value>,
@@ -169,7 +219,7 @@ pub struct CipherData {
     Card: Option<Value>,
     Identity: Option<Value>,
 
-    Favorite: Option<bool>,
+    pub Favorite: Option<bool>,
 
     PasswordHistory: Option<Value>,
 
@@ -179,6 +229,46 @@ pub struct CipherData {
     Attachments2: Option<HashMap<String, Attachments2Data>>,
 }
 
+/// The same limit as the official server, for the encrypted value
+const NOTES_MAX_LENGTH: usize = 10_000;
+/// Max length of the encrypted password of each entry of the password history
+const PASSWORD_HISTORY_MAX_LENGTH: usize = 5_000;
+/// Max size of the stored password history, as JSON, with all the fields of the entries
+const PASSWORD_HISTORY_MAX_SIZE: usize = 64 * 1024;
+
+impl CipherData {
+    /// Checks the limits before the cipher is stored, the clients show the error next to the field
+    pub fn validate(&self) -> EmptyResult {
+        if let Some(ref notes) = self.Notes {
+            if notes.len() > NOTES_MAX_LENGTH {
+                let msg = format!(
+                    "The field Notes exceeds the maximum encrypted value length of {} characters.",
+                    NOTES_MAX_LENGTH
+                );
+                err_json!(_field_error("Notes", msg))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An error like the ones of `err!`, but with the message listed under the field in the validation errors
+fn _field_error(field: &str, msg: String) -> (String, Value) {
+    let json = json!({
+        "Message": msg,
+        "error": "",
+        "error_description": "",
+        "ValidationErrors": { field: [ msg ] },
+        "ErrorModel": {
+            "Message": msg,
+            "Object": "error"
+        },
+        "Object": "error"
+    });
+    (msg, json)
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 pub struct Attachments2Data {
@@ -189,6 +279,7 @@ pub struct Attachments2Data {
 #[post("/ciphers/admin", data = "<data>")]
 fn post_ciphers_admin(data: JsonUpcase<ShareCipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     let data: ShareCipherData = data.into_inner().data;
+    data.Cipher.validate()?;
 
     let mut cipher = Cipher::new(data.Cipher.Type, data.Cipher.Name.clone());
     cipher.user_uuid = Some(headers.user.uuid.clone());
@@ -208,19 +299,22 @@ fn post_ciphers(data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt
 
     let mut cipher = Cipher::new(data.Type, data.Name.clone());
     update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &nt, UpdateType::CipherCreate)?;
+    _log_cipher_event(EventType::CipherCreated, &cipher, &headers, &nt);
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
 pub fn update_cipher_from_data(
     cipher: &mut Cipher,
-    data: CipherData,
+    mut data: CipherData,
     headers: &Headers,
     shared_to_collection: bool,
     conn: &DbConn,
     nt: &Notify,
     ut: UpdateType,
 ) -> EmptyResult {
+    data.validate()?;
+
     if cipher.organization_uuid.is_some() && cipher.organization_uuid != data.OrganizationId {
         err!("Organization mismatch. Please resync the client before updating the cipher")
     }
@@ -255,9 +349,16 @@ pub fn update_cipher_from_data(
         }
     }
 
-    // Modify attachments name and keys when rotating
-    if let Some(attachments) = data.Attachments2 {
-        for (id, attachment) in attachments {
+    // The cipher is saved with its attachments and folder, or not at all,
+    // and the clients are only notified after all of them are stored
+    let attachments = data.Attachments2.take();
+    let folder_id = data.FolderId.clone();
+    let favorite = data.Favorite;
+    apply_cipher_data(cipher, data)?;
+
+    conn.in_transaction(|| {
+        // Modify attachments name and keys when rotating
+        for (id, attachment) in attachments.into_iter().flatten() {
             let mut saved_att = match Attachment::find_by_id(&id, &conn) {
                 Some(att) => att,
                 None => err!("Attachment doesn't exist"),
@@ -272,14 +373,27 @@ pub fn update_cipher_from_data(
 
             saved_att.save(&conn)?;
         }
+
+        cipher.save(&conn)?;
+        cipher.move_to_folder(folder_id, &headers.user.uuid, &conn)?;
+        Favorite::set_favorite(favorite, &cipher.uuid, &headers.user.uuid, &conn)
+    })?;
+
+    if ut != UpdateType::None {
+        _send_cipher_update(ut, &cipher, &conn, &nt);
     }
 
+    Ok(())
+}
+
+/// Copies the encrypted values of the data to the cipher, without checking the access or saving it.
+/// The favorite is kept for each user, so it's left to the caller
+pub fn apply_cipher_data(cipher: &mut Cipher, data: CipherData) -> EmptyResult {
     let type_data_opt = match data.Type {
-        1 => data.Login,
-        2 => data.SecureNote,
-        3 => data.Card,
-        4 => data.Identity,
-        _ => err!("Invalid type"),
+        CipherType::Login => data.Login,
+        CipherType::SecureNote => data.SecureNote,
+        CipherType::Card => data.Card,
+        CipherType::Identity => data.Identity,
     };
 
     let mut type_data = match type_data_opt {
@@ -287,30 +401,69 @@ pub fn update_cipher_from_data(
         None => err!("Data missing"),
     };
 
+    let password_history = _trim_password_history(data.PasswordHistory)?;
+
     // TODO: ******* Backwards compat start **********
     // To remove backwards compatibility, just delete this code,
     // and remove the compat code from cipher::to_json
     type_data["Name"] = Value::String(data.Name.clone());
     type_data["Notes"] = data.Notes.clone().map(Value::String).unwrap_or(Value::Null);
     type_data["Fields"] = data.Fields.clone().unwrap_or(Value::Null);
-    type_data["PasswordHistory"] = data.PasswordHistory.clone().unwrap_or(Value::Null);
+    type_data["PasswordHistory"] = password_history.clone().unwrap_or(Value::Null);
     // TODO: ******* Backwards compat end **********
 
-    cipher.favorite = data.Favorite.unwrap_or(false);
     cipher.name = data.Name;
     cipher.notes = data.Notes;
     cipher.fields = data.Fields.map(|f| f.to_string());
     cipher.data = type_data.to_string();
-    cipher.password_history = data.PasswordHistory.map(|f| f.to_string());
+    cipher.password_history = password_history.map(|f| f.to_string());
+    Ok(())
+}
 
-    cipher.save(&conn)?;
-    cipher.move_to_folder(data.FolderId, &headers.user.uuid, &conn)?;
+/// Keeps only the most recent entries of the password history, and rejects the ones with oversized passwords
+/// or that are too big in total. Some importers send hundreds of entries, which would be stored with the cipher
+/// and sent on every sync
+fn _trim_password_history(password_history: Option<Value>) -> ApiResult<Option<Value>> {
+    let mut entries = match password_history {
+        None | Some(Value::Null) => return Ok(None),
+        Some(Value::Array(entries)) => entries,
+        Some(_) => err!("The password history must be a list"),
+    };
 
-    if ut != UpdateType::None {
-        nt.send_cipher_update(ut, &cipher, &cipher.update_users_revision(&conn));
+    for entry in &mut entries {
+        let password_len = entry["Password"].as_str().map_or(0, str::len);
+        if password_len > PASSWORD_HISTORY_MAX_LENGTH {
+            err!(format!(
+                "The password history exceeds the maximum encrypted value length of {} characters.",
+                PASSWORD_HISTORY_MAX_LENGTH
+            ))
+        }
+
+        // The clients send the dates with their own offset, they're stored in UTC like the rest
+        let last_used = entry["LastUsedDate"].as_str().and_then(util::parse_date);
+        if let Some(date) = last_used {
+            entry["LastUsedDate"] = Value::String(util::format_date(&date));
+        }
     }
 
-    Ok(())
+    // Once they're all in UTC, the dates can be compared as strings
+    entries.sort_by(|a, b| b["LastUsedDate"].as_str().cmp(&a["LastUsedDate"].as_str()));
+    entries.truncate(CONFIG.password_history_limit());
+
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    // The other fields aren't checked one by one, so the size of what would be stored is limited as a whole
+    let history = Value::Array(entries);
+    if history.to_string().len() > PASSWORD_HISTORY_MAX_SIZE {
+        err!(format!(
+            "The password history exceeds the maximum size of {} bytes.",
+            PASSWORD_HISTORY_MAX_SIZE
+        ))
+    }
+
+    Ok(Some(history))
 }
 
 use super::folders::FolderData;
@@ -332,18 +485,21 @@ struct RelationsData {
     Value: usize,
 }
 
+/// The folders and ciphers are inserted in batches in a single transaction, and the clients
+/// are only notified once at the end, so they sync the whole vault
 #[post("/ciphers/import", data = "<data>")]
 fn post_ciphers_import(data: JsonUpcase<ImportData>, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
     let data: ImportData = data.into_inner().data;
+    let user_uuid = &headers.user.uuid;
 
-    // Read and create the folders
-    let mut folders: Vec<_> = Vec::new();
-    for folder in data.Folders.into_iter() {
-        let mut new_folder = Folder::new(headers.user.uuid.clone(), folder.Name);
-        new_folder.save(&conn)?;
+    // Nothing is imported if any of the ciphers is invalid
+    data.Ciphers.iter().try_for_each(CipherData::validate)?;
 
-        folders.push(new_folder);
-    }
+    let folders: Vec<Folder> = data
+        .Folders
+        .into_iter()
+        .map(|folder| Folder::new(user_uuid.clone(), folder.Name))
+        .collect();
 
     // Read the relations between folders and ciphers
     let mut relations_map = HashMap::new();
@@ -352,15 +508,33 @@ fn post_ciphers_import(data: JsonUpcase<ImportData>, headers: Headers, conn: DbC
         relations_map.insert(relation.Key, relation.Value);
     }
 
-    // Read and create the ciphers
-    for (index, mut cipher_data) in data.Ciphers.into_iter().enumerate() {
-        let folder_uuid = relations_map.get(&index).map(|i| folders[*i].uuid.clone());
-        cipher_data.FolderId = folder_uuid;
-
+    let mut ciphers = Vec::new();
+    let mut folder_ciphers = Vec::new();
+    let mut favorites = Vec::new();
+    for (index, cipher_data) in data.Ciphers.into_iter().enumerate() {
         let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-        update_cipher_from_data(&mut cipher, cipher_data, &headers, false, &conn, &nt, UpdateType::None)?;
+        cipher.user_uuid = Some(user_uuid.clone());
+        if cipher_data.Favorite == Some(true) {
+            favorites.push(Favorite::new(user_uuid, &cipher.uuid));
+        }
+        apply_cipher_data(&mut cipher, cipher_data)?;
+
+        if let Some(folder_index) = relations_map.get(&index) {
+            match folders.get(*folder_index) {
+                Some(folder) => folder_ciphers.push(FolderCipher::new(&folder.uuid, &cipher.uuid)),
+                None => err!("Failed to assign to folder"),
+            }
+        }
+        ciphers.push(cipher);
     }
 
+    conn.in_transaction(|| {
+        Folder::insert_all(&folders, &conn)?;
+        Cipher::insert_all(&ciphers, &conn)?;
+        FolderCipher::insert_all(&folder_ciphers, &conn)?;
+        Favorite::insert_all(&favorites, &conn)
+    })?;
+
     let mut user = headers.user;
     user.update_revision(&conn)?;
     nt.send_user_update(UpdateType::Vault, &user);
@@ -369,45 +543,50 @@ fn post_ciphers_import(data: JsonUpcase<ImportData>, headers: Headers, conn: DbC
 
 #[put("/ciphers/<uuid>/admin", data = "<data>")]
 fn put_cipher_admin(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CipherData>,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    put_cipher(uuid, data, headers, conn, nt)
+    _update_cipher(&uuid, data, &headers, true, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>/admin", data = "<data>")]
 fn post_cipher_admin(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CipherData>,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    post_cipher(uuid, data, headers, conn, nt)
+    _update_cipher(&uuid, data, &headers, true, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>", data = "<data>")]
-fn post_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+fn post_cipher(uuid: Uuid, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     put_cipher(uuid, data, headers, conn, nt)
 }
 
 #[put("/ciphers/<uuid>", data = "<data>")]
-fn put_cipher(uuid: String, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
-    let data: CipherData = data.into_inner().data;
+fn put_cipher(uuid: Uuid, data: JsonUpcase<CipherData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+    _update_cipher(&uuid, data, &headers, false, &conn, &nt)
+}
 
-    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => cipher,
-        None => err!("Cipher doesn't exist"),
-    };
+fn _update_cipher(
+    uuid: &str,
+    data: JsonUpcase<CipherData>,
+    headers: &Headers,
+    as_admin: bool,
+    conn: &DbConn,
+    nt: &Notify,
+) -> JsonResult {
+    let data: CipherData = data.into_inner().data;
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher is not write accessible")
-    }
+    let mut cipher = service::ciphers::find_writable(uuid, &headers.user.uuid, as_admin, conn)?;
 
     update_cipher_from_data(&mut cipher, data, &headers, false, &conn, &nt, UpdateType::CipherUpdate)?;
+    _log_cipher_event(EventType::CipherUpdated, &cipher, &headers, &nt);
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
@@ -420,75 +599,108 @@ struct CollectionsAdminData {
 
 #[put("/ciphers/<uuid>/collections", data = "<data>")]
 fn put_collections_update(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn)
+    _update_collections(&uuid, data, &headers, false, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>/collections", data = "<data>")]
 fn post_collections_update(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn)
+    _update_collections(&uuid, data, &headers, false, &conn, &nt)
 }
 
 #[put("/ciphers/<uuid>/collections-admin", data = "<data>")]
 fn put_collections_admin(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
-    post_collections_admin(uuid, data, headers, conn)
+    _update_collections(&uuid, data, &headers, true, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>/collections-admin", data = "<data>")]
 fn post_collections_admin(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<CollectionsAdminData>,
     headers: Headers,
     conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    _update_collections(&uuid, data, &headers, true, &conn, &nt)
+}
+
+fn _update_collections(
+    uuid: &str,
+    data: JsonUpcase<CollectionsAdminData>,
+    headers: &Headers,
+    as_admin: bool,
+    conn: &DbConn,
+    nt: &Notify,
 ) -> EmptyResult {
     let data: CollectionsAdminData = data.into_inner().data;
 
-    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => cipher,
-        None => err!("Cipher doesn't exist"),
-    };
+    let user_uuid = &headers.user.uuid;
+    let cipher = service::ciphers::update_collections(uuid, &data.CollectionIds, user_uuid, as_admin, conn)?;
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher is not write accessible")
+    _log_cipher_event(EventType::CipherUpdatedCollections, &cipher, &headers, &nt);
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct BulkCollectionsData {
+    CipherIds: Vec<String>,
+    AddCollectionIds: Option<Vec<String>>,
+    RemoveCollectionIds: Option<Vec<String>>,
+}
+
+/// Changes the collections of many ciphers at once, the users that can see them get a single sync
+/// notification, instead of an update for each cipher
+#[post("/ciphers/bulk-collections", data = "<data>")]
+fn post_bulk_collections(
+    data: JsonUpcase<BulkCollectionsData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    let data: BulkCollectionsData = data.into_inner().data;
+    let add_uuids = data.AddCollectionIds.unwrap_or_default();
+    let remove_uuids = data.RemoveCollectionIds.unwrap_or_default();
+
+    // The users that lose access to the ciphers need to sync too
+    let mut user_uuids: HashSet<String> = HashSet::new();
+    for uuid in &data.CipherIds {
+        if let Some(cipher) = Cipher::find_by_uuid(uuid, &conn) {
+            user_uuids.extend(cipher.find_user_uuids(&conn));
+        }
     }
 
-    let posted_collections: HashSet<String> = data.CollectionIds.iter().cloned().collect();
-    let current_collections: HashSet<String> = cipher
-        .get_collections(&headers.user.uuid, &conn)
-        .iter()
-        .cloned()
-        .collect();
+    let user_uuid = &headers.user.uuid;
+    let ciphers = conn.in_transaction(|| {
+        service::ciphers::update_collections_bulk(&data.CipherIds, &add_uuids, &remove_uuids, user_uuid, true, &conn)
+    })?;
 
-    for collection in posted_collections.symmetric_difference(&current_collections) {
-        match Collection::find_by_uuid(&collection, &conn) {
-            None => err!("Invalid collection ID provided"),
-            Some(collection) => {
-                if collection.is_writable_by_user(&headers.user.uuid, &conn) {
-                    if posted_collections.contains(&collection.uuid) {
-                        // Add to collection
-                        CollectionCipher::save(&cipher.uuid, &collection.uuid, &conn)?;
-                    } else {
-                        // Remove from collection
-                        CollectionCipher::delete(&cipher.uuid, &collection.uuid, &conn)?;
-                    }
-                } else {
-                    err!("No rights to modify the collection")
-                }
-            }
+    for cipher in &ciphers {
+        user_uuids.extend(cipher.find_user_uuids(&conn));
+        _log_cipher_event(EventType::CipherUpdatedCollections, cipher, &headers, &nt);
+    }
+
+    for uuid in user_uuids {
+        if let Some(mut user) = User::find_by_uuid(&uuid, &conn) {
+            user.update_revision(&conn)?;
+            nt.send_user_update(UpdateType::Vault, &user);
         }
     }
 
@@ -500,11 +712,21 @@ fn post_collections_admin(
 struct ShareCipherData {
     Cipher: CipherData,
     CollectionIds: Vec<String>,
+    #[serde(default)]
+    FolderCollections: Vec<FolderCollectionData>,
+}
+
+/// The name of the collection to create for a folder, encrypted with the key of the organization
+#[derive(Deserialize, Clone)]
+#[allow(non_snake_case)]
+struct FolderCollectionData {
+    FolderId: String,
+    Name: String,
 }
 
 #[post("/ciphers/<uuid>/share", data = "<data>")]
 fn post_cipher_share(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
     conn: DbConn,
@@ -517,7 +739,7 @@ fn post_cipher_share(
 
 #[put("/ciphers/<uuid>/share", data = "<data>")]
 fn put_cipher_share(
-    uuid: String,
+    uuid: Uuid,
     data: JsonUpcase<ShareCipherData>,
     headers: Headers,
     conn: DbConn,
@@ -533,6 +755,8 @@ fn put_cipher_share(
 struct ShareSelectedCipherData {
     Ciphers: Vec<CipherData>,
     CollectionIds: Vec<String>,
+    #[serde(default)]
+    FolderCollections: Vec<FolderCollectionData>,
 }
 
 #[put("/ciphers/share", data = "<data>")]
@@ -549,7 +773,7 @@ fn put_cipher_share_seleted(
         err!("You must select at least one cipher.")
     }
 
-    if data.CollectionIds.is_empty() {
+    if data.CollectionIds.is_empty() && data.FolderCollections.is_empty() {
         err!("You must select at least one collection.")
     }
 
@@ -570,6 +794,7 @@ fn put_cipher_share_seleted(
         let mut shared_cipher_data = ShareCipherData {
             Cipher: cipher,
             CollectionIds: data.CollectionIds.clone(),
+            FolderCollections: data.FolderCollections.clone(),
         };
 
         match shared_cipher_data.Cipher.Id.take() {
@@ -583,71 +808,135 @@ fn put_cipher_share_seleted(
 
 fn share_cipher_by_uuid(
     uuid: &str,
-    data: ShareCipherData,
+    mut data: ShareCipherData,
     headers: &Headers,
     conn: &DbConn,
     nt: &Notify,
 ) -> JsonResult {
-    let mut cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => {
-            if cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-                cipher
-            } else {
-                err!("Cipher is not write accessible")
-            }
-        }
-        None => err!("Cipher doesn't exist"),
-    };
+    let mut cipher = service::ciphers::find_writable(uuid, &headers.user.uuid, false, &conn)?;
+
+    // The folder of the user is kept when the client doesn't send it, as it's not a part of the organization.
+    // The favorite is also kept for the user
+    if data.Cipher.FolderId.is_none() {
+        data.Cipher.FolderId = cipher.get_folder_uuid(&headers.user.uuid, conn);
+    }
 
     match data.Cipher.OrganizationId.clone() {
         None => err!("Organization id not provided"),
         Some(organization_uuid) => {
-            let mut shared_to_collection = false;
-            for uuid in &data.CollectionIds {
-                match Collection::find_by_uuid_and_org(uuid, &organization_uuid, &conn) {
-                    None => err!("Invalid collection ID provided"),
-                    Some(collection) => {
-                        if collection.is_writable_by_user(&headers.user.uuid, &conn) {
-                            CollectionCipher::save(&cipher.uuid, &collection.uuid, &conn)?;
-                            shared_to_collection = true;
-                        } else {
-                            err!("No rights to modify the collection")
-                        }
-                    }
+            _check_shared_attachments_size(&cipher, &organization_uuid, conn)?;
+
+            let mut collection_ids = data.CollectionIds;
+            let folder_names = data.FolderCollections;
+
+            // The attachments uploaded again for the organization replace the old ones with the share,
+            // without it the cipher keeps the ones it can read. The collection of the folder is only
+            // created when the share is stored
+            let replaced = conn.in_transaction(|| {
+                if collection_ids.is_empty() && !folder_names.is_empty() {
+                    let folder_collection =
+                        _folder_collection(&cipher, &organization_uuid, &folder_names, headers, conn)?;
+                    collection_ids.push(folder_collection);
                 }
+
+                let shared_to_collection = service::ciphers::share_to_collections(
+                    &cipher,
+                    &organization_uuid,
+                    &collection_ids,
+                    &headers.user.uuid,
+                    &conn,
+                )?;
+                update_cipher_from_data(
+                    &mut cipher,
+                    data.Cipher,
+                    &headers,
+                    shared_to_collection,
+                    &conn,
+                    &nt,
+                    UpdateType::None,
+                )?;
+                Attachment::commit_replacements(&cipher.uuid, &conn)
+            })?;
+
+            _send_cipher_update(UpdateType::CipherUpdate, &cipher, &conn, &nt);
+            _log_cipher_event(EventType::CipherShared, &cipher, &headers, &nt);
+            for _ in 0..replaced {
+                _log_cipher_event(EventType::CipherAttachmentDeleted, &cipher, &headers, &nt);
             }
-            update_cipher_from_data(
-                &mut cipher,
-                data.Cipher,
-                &headers,
-                shared_to_collection,
-                &conn,
-                &nt,
-                UpdateType::CipherUpdate,
-            )?;
 
             Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
         }
     }
 }
 
+/// Without any collection selected, the cipher can go to a new collection named like its folder. The folder names
+/// are encrypted with the key of the user, so the client sends the names encrypted with the key of the organization.
+/// The collection keeps the folder it was created for, so the next items of the folder, shared one at a time
+/// by the clients, go to the same one
+fn _folder_collection(
+    cipher: &Cipher,
+    org_uuid: &str,
+    names: &[FolderCollectionData],
+    headers: &Headers,
+    conn: &DbConn,
+) -> ApiResult<String> {
+    let folder_uuid = match cipher.get_folder_uuid(&headers.user.uuid, conn) {
+        Some(folder_uuid) => folder_uuid,
+        None => err!("The item isn't in a folder, select a collection to share it"),
+    };
+
+    if let Some(collection) = Collection::find_by_folder_and_org(&folder_uuid, org_uuid, conn) {
+        return Ok(collection.uuid);
+    }
+
+    let name = match names.iter().find(|name| name.FolderId == folder_uuid) {
+        Some(name) => name.Name.clone(),
+        None => err!("The name of the collection of the folder is missing"),
+    };
+
+    // Like creating the collection from the organization page
+    let is_admin = match UserOrganization::find_by_user_and_org(&headers.user.uuid, org_uuid, conn) {
+        Some(member) => member.status == UserOrgStatus::Confirmed as i32 && member.type_ >= UserOrgType::Admin,
+        None => false,
+    };
+    if !is_admin {
+        err!("You need to be Admin or Owner to create the collections of the folders")
+    }
+
+    let mut collection = Collection::new(org_uuid.to_string(), name);
+    collection.folder_uuid = Some(folder_uuid);
+    collection.save(conn)?;
+
+    Ok(collection.uuid)
+}
+
 #[post("/ciphers/<uuid>/attachment", format = "multipart/form-data", data = "<data>")]
 fn post_attachment(
-    uuid: String,
+    uuid: Uuid,
     data: Data,
     content_type: &ContentType,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => cipher,
-        None => err!("Cipher doesn't exist"),
-    };
+    _post_attachment(&uuid, data, content_type, headers, false, None, conn, nt)
+}
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher is not write accessible")
-    }
+/// Saves the uploaded attachment. When it replaces another one, like when sharing, the new one stays pending
+/// and the old one is only deleted once the share is stored, so a failed share keeps the cipher readable.
+/// The size of the old one doesn't count for the limits
+#[allow(clippy::too_many_arguments)]
+fn _post_attachment(
+    uuid: &str,
+    data: Data,
+    content_type: &ContentType,
+    headers: Headers,
+    as_admin: bool,
+    replaced: Option<Attachment>,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let cipher = service::ciphers::find_writable(uuid, &headers.user.uuid, as_admin, &conn)?;
 
     let mut params = content_type.params();
     let boundary_pair = params.next().expect("No boundary provided");
@@ -656,6 +945,11 @@ fn post_attachment(
     let base_path = Path::new(&CONFIG.attachments_folder()).join(&cipher.uuid);
 
     let mut attachment_key = None;
+    let replaced_size = replaced.as_ref().map_or(0, |a| i64::from(a.file_size));
+    let mut max_size = _max_attachment_size(&cipher, replaced_size, &conn);
+    let mut size_exceeded = false;
+    let mut save_error = None;
+    let mut saved = None;
 
     Multipart::with_body(data.open(), boundary)
         .foreach_entry(|mut field| {
@@ -674,46 +968,133 @@ fn post_attachment(
                     let file_name = HEXLOWER.encode(&crypto::get_random(vec![0; 10]));
                     let path = base_path.join(&file_name);
 
-                    let size = match field.data.save().memory_threshold(0).size_limit(None).with_path(path) {
-                        SaveResult::Full(SavedData::File(_, size)) => size as i32,
-                        SaveResult::Full(other) => {
-                            error!("Attachment is not a file: {:?}", other);
-                            return;
-                        }
-                        SaveResult::Partial(_, reason) => {
-                            error!("Partial result: {:?}", reason);
+                    // The body is streamed to disk, hashed and counted as it arrives
+                    let limit = max_size.map(|max| std::cmp::max(max, 0) as u64);
+                    let (size, checksum) = match util::write_file_stream(&path, &mut field.data, limit) {
+                        Ok(Some(written)) => written,
+                        Ok(None) => {
+                            size_exceeded = true;
                             return;
                         }
-                        SaveResult::Error(e) => {
-                            error!("Error: {:?}", e);
+                        Err(e) => {
+                            error!("Error saving attachment: {:?}", e);
                             return;
                         }
                     };
 
+                    if let Some(ref mut max) = max_size {
+                        *max -= size as i64;
+                    }
+
+                    let size = size as i32;
                     let mut attachment = Attachment::new(file_name, cipher.uuid.clone(), name, size);
                     attachment.key = attachment_key.clone();
-                    attachment.save(&conn).expect("Error saving attachment");
+                    attachment.checksum = Some(checksum);
+                    if let Some(ref replaced) = replaced {
+                        attachment.set_replaced(replaced);
+                    }
+                    match attachment.save(&conn) {
+                        Ok(()) => saved = Some(attachment),
+                        Err(e) => {
+                            // The file is removed, as nothing points to it
+                            let path = attachment.get_file_path();
+                            if let Err(e) = util::run_file_job(move || util::delete_file(&path)) {
+                                error!("Error removing the attachment file that wasn't saved: {:?}", e);
+                            }
+                            save_error = Some(e);
+                        }
+                    }
                 }
                 _ => error!("Invalid multipart name"),
             }
         })
         .expect("Error processing multipart data");
 
-    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+    if size_exceeded {
+        err!("The attachment is bigger than the available storage")
+    }
+    if let Some(e) = save_error {
+        return Err(e);
+    }
+
+    if let Some(replaced) = replaced {
+        let attachment = match saved {
+            Some(attachment) => attachment,
+            None => err!("The new attachment wasn't received, the original one is kept"),
+        };
+
+        // The old one is only replaced when the share is stored, an earlier try is dropped
+        Attachment::delete_pending_replacements(&replaced.id, &attachment.id, &conn)?;
+    }
+
+    _log_cipher_event(EventType::CipherAttachmentCreated, &cipher, &headers, &nt);
+    _send_cipher_update(UpdateType::CipherUpdate, &cipher, &conn, &nt);
 
     Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
+/// The number of bytes that can still be stored in an attachment of the cipher, without limits when `None`.
+/// The personal ciphers count for the limit of the user, and the organization ones for the organization limit.
+/// The bytes of an attachment that is being replaced are freed
+fn _max_attachment_size(cipher: &Cipher, replaced_size: i64, conn: &DbConn) -> Option<i64> {
+    let available = match (&cipher.organization_uuid, &cipher.user_uuid) {
+        (Some(org_uuid), _) => CONFIG
+            .org_attachment_limit()
+            .map(|kb| _kb_to_bytes(kb) - Attachment::size_by_org(org_uuid, conn) + replaced_size),
+        (None, Some(user_uuid)) => CONFIG
+            .user_attachment_limit()
+            .map(|kb| _kb_to_bytes(kb) - Attachment::size_by_user(user_uuid, conn) + replaced_size),
+        (None, None) => None,
+    };
+
+    let max_size = match (CONFIG.attachment_size_limit().map(_kb_to_bytes), available) {
+        (Some(size), Some(available)) => Some(size.min(available)),
+        (size, available) => size.or(available),
+    };
+    max_size.map(|size| size.max(0))
+}
+
+/// The limits are configured in kilobytes, one too big to count in bytes is the same as no limit
+fn _kb_to_bytes(kb: i64) -> i64 {
+    kb.checked_mul(1024).unwrap_or(std::i64::MAX)
+}
+
+/// The attachments of a personal cipher count for the limit of the organization once it's shared.
+/// The ones replaced by the attachments uploaded again for the organization aren't moved
+fn _check_shared_attachments_size(cipher: &Cipher, org_uuid: &str, conn: &DbConn) -> EmptyResult {
+    let limit = match CONFIG.org_attachment_limit() {
+        Some(kb) if cipher.organization_uuid.is_none() => _kb_to_bytes(kb),
+        _ => return Ok(()),
+    };
+
+    let attachments = Attachment::find_by_cipher(&cipher.uuid, conn);
+    let replaced: HashSet<&str> = attachments
+        .iter()
+        .filter_map(|a| a.replaced_id.as_ref())
+        .map(String::as_str)
+        .collect();
+    let moved_size: i64 = attachments
+        .iter()
+        .filter(|a| !replaced.contains(a.id.as_str()))
+        .map(|a| i64::from(a.file_size))
+        .sum();
+
+    if moved_size > 0 && Attachment::size_by_org(org_uuid, conn) + moved_size > limit {
+        err!("The attachments are bigger than the available storage of the organization")
+    }
+    Ok(())
+}
+
 #[post("/ciphers/<uuid>/attachment-admin", format = "multipart/form-data", data = "<data>")]
 fn post_attachment_admin(
-    uuid: String,
+    uuid: Uuid,
     data: Data,
     content_type: &ContentType,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    post_attachment(uuid, data, content_type, headers, conn, nt)
+    _post_attachment(&uuid, data, content_type, headers, true, None, conn, nt)
 }
 
 #[post(
@@ -722,7 +1103,7 @@ fn post_attachment_admin(
     data = "<data>"
 )]
 fn post_attachment_share(
-    uuid: String,
+    uuid: Uuid,
     attachment_id: String,
     data: Data,
     content_type: &ContentType,
@@ -730,24 +1111,88 @@ fn post_attachment_share(
     conn: DbConn,
     nt: Notify,
 ) -> JsonResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)?;
-    post_attachment(uuid, data, content_type, headers, conn, nt)
+    // The attachment is uploaded again, encrypted with the key of the organization
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) => attachment,
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if attachment.cipher_uuid != *uuid {
+        err!("Attachment from other cipher")
+    }
+
+    if attachment.is_pending() {
+        err!("Attachment is already a replacement")
+    }
+
+    _post_attachment(&uuid, data, content_type, headers, false, Some(attachment), conn, nt)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CopyAttachmentData {
+    CipherId: String,
+}
+
+/// Copies the attachment to another cipher encrypted with the same key, like a cloned one,
+/// without the client downloading and uploading it again
+#[post("/ciphers/<uuid>/attachment/<attachment_id>/copy", data = "<data>")]
+fn post_attachment_copy(
+    uuid: Uuid,
+    attachment_id: String,
+    data: JsonUpcase<CopyAttachmentData>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: CopyAttachmentData = data.into_inner().data;
+
+    let attachment = match Attachment::find_by_id(&attachment_id, &conn) {
+        Some(attachment) => attachment,
+        None => err!("Attachment doesn't exist"),
+    };
+
+    if attachment.cipher_uuid != *uuid {
+        err!("Attachment from other cipher")
+    }
+
+    let source = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) if cipher.is_accessible_to_user(&headers.user.uuid, &conn) => cipher,
+        _ => err!("Cipher doesn't exist"),
+    };
+    let target = service::ciphers::find_writable(&data.CipherId, &headers.user.uuid, false, &conn)?;
+
+    // The personal ciphers are encrypted with the key of the user, and the rest with the key of their organization
+    if source.organization_uuid != target.organization_uuid || source.user_uuid != target.user_uuid {
+        err!("The attachment can only be copied to a cipher of the same owner")
+    }
+
+    if _max_attachment_size(&target, 0, &conn).map_or(false, |max| i64::from(attachment.file_size) > max) {
+        err!("The attachment is bigger than the available storage")
+    }
+
+    attachment.copy_to(&target.uuid, &conn)?;
+
+    _log_cipher_event(EventType::CipherAttachmentCreated, &target, &headers, &nt);
+    _send_cipher_update(UpdateType::CipherUpdate, &target, &conn, &nt);
+
+    Ok(Json(target.to_json(&headers.host, &headers.user.uuid, &conn)))
 }
 
 #[post("/ciphers/<uuid>/attachment/<attachment_id>/delete-admin")]
 fn delete_attachment_post_admin(
-    uuid: String,
+    uuid: Uuid,
     attachment_id: String,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> EmptyResult {
-    delete_attachment(uuid, attachment_id, headers, conn, nt)
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, true, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>/attachment/<attachment_id>/delete")]
 fn delete_attachment_post(
-    uuid: String,
+    uuid: Uuid,
     attachment_id: String,
     headers: Headers,
     conn: DbConn,
@@ -757,39 +1202,239 @@ fn delete_attachment_post(
 }
 
 #[delete("/ciphers/<uuid>/attachment/<attachment_id>")]
-fn delete_attachment(uuid: String, attachment_id: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
+fn delete_attachment(uuid: Uuid, attachment_id: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, false, &conn, &nt)
 }
 
 #[delete("/ciphers/<uuid>/attachment/<attachment_id>/admin")]
 fn delete_attachment_admin(
-    uuid: String,
+    uuid: Uuid,
     attachment_id: String,
     headers: Headers,
     conn: DbConn,
     nt: Notify,
 ) -> EmptyResult {
-    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, &conn, &nt)
+    _delete_cipher_attachment_by_id(&uuid, &attachment_id, &headers, true, &conn, &nt)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AttachmentUploadData {
+    FileName: String,
+    FileSize: i32,
+    Key: Option<String>,
+}
+
+/// Starts a chunked upload, the chunks are then sent with `PUT` to the returned upload
+#[post("/ciphers/<uuid>/attachment/upload", data = "<data>")]
+fn post_attachment_upload(
+    uuid: Uuid,
+    data: JsonUpcase<AttachmentUploadData>,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let data: AttachmentUploadData = data.into_inner().data;
+
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+        err!("Cipher is not write accessible")
+    }
+
+    if data.FileSize <= 0 {
+        err!("The file size is invalid")
+    }
+
+    if _max_attachment_size(&cipher, 0, &conn).map_or(false, |max| i64::from(data.FileSize) > max) {
+        err!("The attachment is bigger than the available storage")
+    }
+
+    let mut upload = AttachmentUpload::new(cipher.uuid, headers.user.uuid, data.FileName, data.FileSize);
+    upload.key = data.Key;
+    upload.save(&conn)?;
+
+    Ok(Json(upload.to_json()))
+}
+
+/// Returns the state of an upload, so an interrupted client knows where to resume from
+#[get("/ciphers/<uuid>/attachment/upload/<upload_id>")]
+fn get_attachment_upload(uuid: Uuid, upload_id: String, headers: Headers, conn: DbConn) -> JsonResult {
+    let upload = _get_attachment_upload(&uuid, &upload_id, &headers, &conn)?;
+    Ok(Json(upload.to_json()))
+}
+
+/// Each chunk of an upload is streamed to disk, but a limit keeps a single request from taking too long
+const MAX_UPLOAD_CHUNK_SIZE: i64 = 64 * 1024 * 1024;
+
+/// The byte range of a chunk, from the `Content-Range: bytes <start>-<end>/<total>` header
+struct ContentRange {
+    start: i32,
+    end: i32,
+    total: i32,
+}
+
+impl ContentRange {
+    fn parse(value: &str) -> Option<Self> {
+        let mut range = value.trim().trim_start_matches("bytes").trim().splitn(2, '/');
+        let (start_end, total) = (range.next()?, range.next()?);
+
+        let mut start_end = start_end.splitn(2, '-');
+        let range = ContentRange {
+            start: start_end.next()?.trim().parse().ok()?,
+            end: start_end.next()?.trim().parse().ok()?,
+            total: total.trim().parse().ok()?,
+        };
+
+        if range.start < 0 || range.end < range.start || range.end >= range.total {
+            return None;
+        }
+
+        Some(range)
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ContentRange {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Content-Range").and_then(ContentRange::parse) {
+            Some(range) => Outcome::Success(range),
+            None => Outcome::Failure((Status::BadRequest, "Invalid Content-Range header")),
+        }
+    }
+}
+
+/// Appends a chunk to the upload. When the last chunk is received, the attachment is created
+/// and the updated cipher is returned, otherwise the state of the upload is returned
+#[put("/ciphers/<uuid>/attachment/upload/<upload_id>", data = "<data>")]
+fn put_attachment_upload(
+    uuid: Uuid,
+    upload_id: String,
+    data: Data,
+    range: ContentRange,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let upload = _get_attachment_upload(&uuid, &upload_id, &headers, &conn)?;
+
+    // The length of the partial file is the offset, so two chunks of the same upload can't be saved at the same time
+    let path = PathBuf::from(upload.get_partial_path());
+    let _lock = match util::try_lock_path(&path) {
+        Some(lock) => lock,
+        None => err!("Another chunk of this upload is being saved"),
+    };
+
+    if range.total != upload.file_size {
+        err!("The total size doesn't match the size of the upload")
+    }
+
+    let offset = upload.get_offset();
+    if range.start != offset {
+        err!(
+            "The chunk doesn't start where the last one ended",
+            format!("Expected offset {}, got {}", offset, range.start)
+        )
+    }
+
+    let chunk_len = i64::from(range.end) - i64::from(range.start) + 1;
+    if chunk_len > MAX_UPLOAD_CHUNK_SIZE {
+        err!(
+            "The chunk is too big, send the upload in smaller chunks",
+            format!("{} bytes, the limit is {}", chunk_len, MAX_UPLOAD_CHUNK_SIZE)
+        )
+    }
+
+    if i64::from(offset) + chunk_len > i64::from(upload.file_size) {
+        err!("The chunk goes past the size of the upload")
+    }
+
+    // The body is streamed to the partial file, it's never kept in memory as a whole
+    match util::append_file_stream(&path, offset as u64, data.open(), chunk_len as u64) {
+        Ok(true) => (),
+        Ok(false) => err!("The chunk size doesn't match the Content-Range header"),
+        Err(e) => err!("Error saving the chunk", e.to_string()),
+    }
+
+    if !upload.is_complete() {
+        return Ok(Json(upload.to_json()));
+    }
+
+    // All the data has been received, move it to the attachment file
+    let mut attachment = upload.to_attachment();
+    let (partial_path, file_path) = (upload.get_partial_path(), attachment.get_file_path());
+    let checksum = util::run_file_job(move || {
+        std::fs::rename(&partial_path, &file_path)?;
+        crypto::sha256_hex_reader(std::fs::File::open(&file_path)?)
+    });
+
+    match checksum {
+        Ok(checksum) => attachment.checksum = Some(checksum),
+        Err(e) => err!("Error saving the attachment", e.to_string()),
+    }
+
+    attachment.save(&conn)?;
+    upload.delete(&conn)?;
+
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    _log_cipher_event(EventType::CipherAttachmentCreated, &cipher, &headers, &nt);
+    _send_cipher_update(UpdateType::CipherUpdate, &cipher, &conn, &nt);
+
+    Ok(Json(cipher.to_json(&headers.host, &headers.user.uuid, &conn)))
+}
+
+#[delete("/ciphers/<uuid>/attachment/upload/<upload_id>")]
+fn delete_attachment_upload(uuid: Uuid, upload_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let upload = _get_attachment_upload(&uuid, &upload_id, &headers, &conn)?;
+    upload.delete(&conn)
+}
+
+fn _get_attachment_upload(
+    uuid: &str,
+    upload_id: &str,
+    headers: &Headers,
+    conn: &DbConn,
+) -> ApiResult<AttachmentUpload> {
+    let upload = match AttachmentUpload::find_by_id_and_cipher(upload_id, uuid, conn) {
+        Some(upload) => upload,
+        None => err!("Upload doesn't exist"),
+    };
+
+    if upload.user_uuid != headers.user.uuid {
+        err!("Upload doesn't belong to user")
+    }
+
+    match Cipher::find_by_uuid(uuid, conn) {
+        Some(ref cipher) if cipher.is_write_accessible_to_user(&headers.user.uuid, conn) => Ok(upload),
+        _ => err!("Cipher is not write accessible"),
+    }
 }
 
 #[post("/ciphers/<uuid>/delete")]
-fn delete_cipher_post(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &nt)
+fn delete_cipher_post(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, false, &conn, &nt)
 }
 
 #[post("/ciphers/<uuid>/delete-admin")]
-fn delete_cipher_post_admin(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &nt)
+fn delete_cipher_post_admin(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, true, &conn, &nt)
 }
 
 #[delete("/ciphers/<uuid>")]
-fn delete_cipher(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &nt)
+fn delete_cipher(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, false, &conn, &nt)
 }
 
 #[delete("/ciphers/<uuid>/admin")]
-fn delete_cipher_admin(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    _delete_cipher_by_uuid(&uuid, &headers, &conn, &nt)
+fn delete_cipher_admin(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_cipher_by_uuid(&uuid, &headers, true, &conn, &nt)
 }
 
 #[delete("/ciphers", data = "<data>")]
@@ -805,7 +1450,7 @@ fn delete_cipher_selected(data: JsonUpcase<Value>, headers: Headers, conn: DbCon
     };
 
     for uuid in uuids {
-        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, &conn, &nt) {
+        if let error @ Err(_) = _delete_cipher_by_uuid(uuid, &headers, false, &conn, &nt) {
             return error;
         };
     }
@@ -847,8 +1492,8 @@ fn move_cipher_selected(data: JsonUpcase<MoveCipherData>, headers: Headers, conn
             None => err!("Cipher doesn't exist"),
         };
 
-        if !cipher.is_accessible_to_user(&user_uuid, &conn) {
-            err!("Cipher is not accessible by user")
+        if !cipher.is_write_accessible_to_user(&user_uuid, &conn) {
+            err!("Cipher is not write accessible")
         }
 
         // Move cipher
@@ -857,7 +1502,8 @@ fn move_cipher_selected(data: JsonUpcase<MoveCipherData>, headers: Headers, conn
         nt.send_cipher_update(
             UpdateType::CipherUpdate,
             &cipher,
-            &[user_uuid.clone()]
+            &[user_uuid.clone()],
+            &cipher.get_all_collections(&conn),
         );
     }
 
@@ -885,6 +1531,92 @@ fn delete_all(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn, nt
         err!("Invalid password")
     }
 
+    purge_vault(&mut user, &conn, &nt)
+}
+
+/// Exports the folders and the personal ciphers of the user in the encrypted JSON format,
+/// which can only be imported back into the same account. The organization ciphers aren't included
+#[post("/ciphers/export", data = "<data>")]
+fn post_export(data: JsonUpcase<PasswordData>, headers: Headers, ip: ClientIp, conn: DbConn, nt: Notify) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let folders: Vec<Value> = Folder::find_by_user(&user.uuid, &conn)
+        .iter()
+        .map(Folder::to_export_json)
+        .collect();
+
+    let items: Vec<Value> = Cipher::find_owned_by_user(&user.uuid, &conn)
+        .iter()
+        .map(|c| c.to_export_json(&user.uuid, &conn))
+        .collect();
+
+    let address = ip.ip.to_string();
+    events::log_user_event(
+        EventType::UserClientExportedVault,
+        &user.uuid,
+        headers.device.type_,
+        &address,
+        &nt,
+    );
+
+    Ok(Json(json!({
+        "encrypted": true,
+        "folders": folders,
+        "items": items,
+    })))
+}
+
+/// Exports a single folder of the user and the personal ciphers in it, in the same format as the full export
+#[post("/ciphers/export/folder/<folder_id>", data = "<data>")]
+fn post_export_folder(
+    folder_id: Uuid,
+    data: JsonUpcase<PasswordData>,
+    headers: Headers,
+    ip: ClientIp,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let folder = match Folder::find_by_uuid(&folder_id, &conn) {
+        Some(folder) if folder.user_uuid == user.uuid => folder,
+        _ => err!("Folder doesn't exist"),
+    };
+
+    let items: Vec<Value> = Cipher::find_by_folder(&folder.uuid, &conn)
+        .iter()
+        .filter(|c| c.user_uuid.as_ref() == Some(&user.uuid))
+        .map(|c| c.to_export_json(&user.uuid, &conn))
+        .collect();
+
+    let address = ip.ip.to_string();
+    events::log_user_event(
+        EventType::UserClientExportedVault,
+        &user.uuid,
+        headers.device.type_,
+        &address,
+        &nt,
+    );
+
+    Ok(Json(json!({
+        "encrypted": true,
+        "folders": [folder.to_export_json()],
+        "items": items,
+    })))
+}
+
+/// Removes all the ciphers owned by the user and their folders, the organization ciphers are kept
+pub fn purge_vault(user: &mut User, conn: &DbConn, nt: &Notify) -> EmptyResult {
     // Delete ciphers and their attachments
     for cipher in Cipher::find_owned_by_user(&user.uuid, &conn) {
         cipher.delete(&conn)?;
@@ -900,18 +1632,17 @@ fn delete_all(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn, nt
     Ok(())
 }
 
-fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, conn: &DbConn, nt: &Notify) -> EmptyResult {
-    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
-        Some(cipher) => cipher,
+fn _delete_cipher_by_uuid(uuid: &str, headers: &Headers, as_admin: bool, conn: &DbConn, nt: &Notify) -> EmptyResult {
+    // The collections of the cipher, and the users that can see it through them, are gone after deleting it
+    let (user_uuids, collection_uuids) = match Cipher::find_by_uuid(uuid, conn) {
+        Some(cipher) => (cipher.find_user_uuids(conn), cipher.get_all_collections(conn)),
         None => err!("Cipher doesn't exist"),
     };
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
-        err!("Cipher can't be deleted by user")
-    }
+    let cipher = service::ciphers::delete(uuid, &headers.user.uuid, as_admin, conn)?;
 
-    cipher.delete(&conn)?;
-    nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &cipher.update_users_revision(&conn));
+    _log_cipher_event(EventType::CipherDeleted, &cipher, &headers, &nt);
+    nt.send_cipher_update(UpdateType::CipherDelete, &cipher, &user_uuids, &collection_uuids);
     Ok(())
 }
 
@@ -919,6 +1650,7 @@ fn _delete_cipher_attachment_by_id(
     uuid: &str,
     attachment_id: &str,
     headers: &Headers,
+    as_admin: bool,
     conn: &DbConn,
     nt: &Notify,
 ) -> EmptyResult {
@@ -936,12 +1668,51 @@ fn _delete_cipher_attachment_by_id(
         None => err!("Cipher doesn't exist"),
     };
 
-    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn) {
+    if !cipher.is_write_accessible_to_user(&headers.user.uuid, &conn)
+        && !(as_admin && cipher.is_org_admin(&headers.user.uuid, &conn))
+    {
         err!("Cipher cannot be deleted by user")
     }
 
     // Delete attachment
     attachment.delete(&conn)?;
-    nt.send_cipher_update(UpdateType::CipherUpdate, &cipher, &cipher.update_users_revision(&conn));
+    _log_cipher_event(EventType::CipherAttachmentDeleted, &cipher, &headers, &nt);
+    _send_cipher_update(UpdateType::CipherUpdate, &cipher, &conn, &nt);
     Ok(())
 }
+
+/// Notifies all the users with access to the cipher, including the collections it belongs to
+fn _send_cipher_update(ut: UpdateType, cipher: &Cipher, conn: &DbConn, nt: &Notify) {
+    let user_uuids = cipher.update_users_revision(conn);
+    nt.send_cipher_update(ut, cipher, &user_uuids, &cipher.get_all_collections(conn));
+}
+
+fn _log_cipher_event(event_type: EventType, cipher: &Cipher, headers: &Headers, nt: &Notify) {
+    events::log_cipher_event(event_type, cipher, &headers.user.uuid, &headers.device, nt);
+}
+
+/// The activity of an organization cipher, only available to the organization admins
+#[get("/ciphers/<uuid>/events?<data..>")]
+fn get_cipher_events(uuid: Uuid, data: Form<EventRange>, headers: Headers, conn: DbReadConn) -> JsonResult {
+    let cipher = match Cipher::find_by_uuid(&uuid, &conn) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    let org_uuid = match cipher.organization_uuid {
+        Some(ref org_uuid) => org_uuid,
+        None => err!("Only the organization ciphers have events"),
+    };
+
+    match UserOrganization::find_by_user_and_org(&headers.user.uuid, org_uuid, &conn) {
+        Some(ref user_org)
+            if user_org.status == UserOrgStatus::Confirmed as i32 && user_org.type_ >= UserOrgType::Admin => {}
+        _ => err!("You need to be Admin or Owner of the organization to see the events"),
+    }
+
+    let cursor = data.cursor()?;
+    let last_uuid = cursor.last_uuid.as_ref().map(String::as_str);
+    let events = Event::find_by_cipher_and_date(&cipher.uuid, &cursor.start, &cursor.end, last_uuid, &conn);
+
+    Ok(Json(events_page(&events)))
+}
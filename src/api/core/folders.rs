@@ -2,9 +2,10 @@ use rocket_contrib::json::Json;
 use serde_json::Value;
 
 use crate::db::models::*;
-use crate::db::DbConn;
+use crate::db::{DbConn, DbReadConn};
+use crate::service;
 
-use crate::api::{EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType};
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, Notify, UpdateType, Uuid};
 use crate::auth::Headers;
 
 use rocket::Route;
@@ -22,7 +23,7 @@ pub fn routes() -> Vec<Route> {
 }
 
 #[get("/folders")]
-fn get_folders(headers: Headers, conn: DbConn) -> JsonResult {
+fn get_folders(headers: Headers, conn: DbReadConn) -> JsonResult {
     let folders = Folder::find_by_user(&headers.user.uuid, &conn);
 
     let folders_json: Vec<Value> = folders.iter().map(|c| c.to_json()).collect();
@@ -35,22 +36,14 @@ fn get_folders(headers: Headers, conn: DbConn) -> JsonResult {
 }
 
 #[get("/folders/<uuid>")]
-fn get_folder(uuid: String, headers: Headers, conn: DbConn) -> JsonResult {
-    let folder = match Folder::find_by_uuid(&uuid, &conn) {
-        Some(folder) => folder,
-        _ => err!("Invalid folder"),
-    };
-
-    if folder.user_uuid != headers.user.uuid {
-        err!("Folder belongs to another user")
-    }
+fn get_folder(uuid: Uuid, headers: Headers, conn: DbConn) -> JsonResult {
+    let folder = service::folders::find_owned(&uuid, &headers.user.uuid, &conn)?;
 
     Ok(Json(folder.to_json()))
 }
 
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
-
 pub struct FolderData {
     pub Name: String,
 }
@@ -61,56 +54,39 @@ fn post_folders(data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt
 
     let mut folder = Folder::new(headers.user.uuid.clone(), data.Name);
 
-    folder.save(&conn)?;
+    service::folders::save(&mut folder, &conn)?;
     nt.send_folder_update(UpdateType::FolderCreate, &folder);
 
     Ok(Json(folder.to_json()))
 }
 
 #[post("/folders/<uuid>", data = "<data>")]
-fn post_folder(uuid: String, data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+fn post_folder(uuid: Uuid, data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     put_folder(uuid, data, headers, conn, nt)
 }
 
 #[put("/folders/<uuid>", data = "<data>")]
-fn put_folder(uuid: String, data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
+fn put_folder(uuid: Uuid, data: JsonUpcase<FolderData>, headers: Headers, conn: DbConn, nt: Notify) -> JsonResult {
     let data: FolderData = data.into_inner().data;
 
-    let mut folder = match Folder::find_by_uuid(&uuid, &conn) {
-        Some(folder) => folder,
-        _ => err!("Invalid folder"),
-    };
-
-    if folder.user_uuid != headers.user.uuid {
-        err!("Folder belongs to another user")
-    }
+    let mut folder = service::folders::find_owned(&uuid, &headers.user.uuid, &conn)?;
 
     folder.name = data.Name;
 
-    folder.save(&conn)?;
+    service::folders::save(&mut folder, &conn)?;
     nt.send_folder_update(UpdateType::FolderUpdate, &folder);
 
     Ok(Json(folder.to_json()))
 }
 
 #[post("/folders/<uuid>/delete")]
-fn delete_folder_post(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+fn delete_folder_post(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
     delete_folder(uuid, headers, conn, nt)
 }
 
 #[delete("/folders/<uuid>")]
-fn delete_folder(uuid: String, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
-    let folder = match Folder::find_by_uuid(&uuid, &conn) {
-        Some(folder) => folder,
-        _ => err!("Invalid folder"),
-    };
-
-    if folder.user_uuid != headers.user.uuid {
-        err!("Folder belongs to another user")
-    }
-
-    // Delete the actual folder entry
-    folder.delete(&conn)?;
+fn delete_folder(uuid: Uuid, headers: Headers, conn: DbConn, nt: Notify) -> EmptyResult {
+    let folder = service::folders::delete(&uuid, &headers.user.uuid, &conn)?;
 
     nt.send_folder_update(UpdateType::FolderDelete, &folder);
     Ok(())
@@ -1,13 +1,17 @@
 mod accounts;
-mod ciphers;
+pub(crate) mod ciphers;
 mod folders;
 mod organizations;
+mod providers;
 pub(crate) mod two_factor;
 
 pub fn routes() -> Vec<Route> {
     let mut mod_routes = routes![
         clear_device_token,
         put_device_token,
+        get_devices,
+        delete_device,
+        post_delete_device,
         get_eq_domains,
         post_eq_domains,
         put_eq_domains,
@@ -19,6 +23,7 @@ pub fn routes() -> Vec<Route> {
     routes.append(&mut ciphers::routes());
     routes.append(&mut folders::routes());
     routes.append(&mut organizations::routes());
+    routes.append(&mut providers::routes());
     routes.append(&mut two_factor::routes());
     routes.append(&mut mod_routes);
 
@@ -33,6 +38,7 @@ use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use crate::db::models::Device;
 use crate::db::DbConn;
 
 use crate::api::{EmptyResult, JsonResult, JsonUpcase};
@@ -60,15 +66,46 @@ fn put_device_token(uuid: String, data: JsonUpcase<Value>, headers: Headers) ->
 
     // TODO: This should save the push token, but we don't have push functionality
 
+    Ok(Json(headers.device.to_json()))
+}
+
+/// The sessions of the user, one for each device that has logged in
+#[get("/devices")]
+fn get_devices(headers: Headers, conn: DbConn) -> JsonResult {
+    let devices = Device::find_by_user(&headers.user.uuid, &conn);
+    let devices_json: Vec<Value> = devices
+        .iter()
+        .map(|d| d.to_json_session(&headers.device.uuid))
+        .collect();
+
     Ok(Json(json!({
-        "Id": headers.device.uuid,
-        "Name": headers.device.name,
-        "Type": headers.device.type_,
-        "Identifier": headers.device.uuid,
-        "CreationDate": crate::util::format_date(&headers.device.created_at),
+        "Data": devices_json,
+        "Object": "list",
+        "ContinuationToken": null,
     })))
 }
 
+/// Removing the device invalidates its refresh token, and the access tokens stop working
+/// because the device is checked on every request
+#[delete("/devices/<uuid>")]
+fn delete_device(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let device = match Device::find_by_uuid(&uuid, &conn) {
+        Some(device) => device,
+        None => err!("Device doesn't exist"),
+    };
+
+    if device.user_uuid != headers.user.uuid {
+        err!("Device doesn't belong to this user")
+    }
+
+    device.delete(&conn)
+}
+
+#[post("/devices/<uuid>/deactivate")]
+fn post_delete_device(uuid: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    delete_device(uuid, headers, conn)
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct GlobalDomain {
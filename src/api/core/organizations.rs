@@ -1,15 +1,26 @@
+use std::collections::HashSet;
+
+use rocket::http::ContentType;
 use rocket::request::Form;
+use rocket::response::content::Content;
 use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use crate::api::events::{self, events_page, EventRange};
 use crate::api::{
-    EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, Notify, NumberOrString, PasswordData, UpdateType,
+    ApiResult, EmptyResult, JsonResult, JsonUpcase, JsonUpcaseVec, Notify, NumberOrString, PasswordData, UpdateType,
+    Uuid,
+};
+use crate::auth::{
+    decode_invite, AdminHeaders, ClientIp, Headers, ManagerHeaders, ManagerHeadersLoose, OrgMemberHeaders, OwnerHeaders,
 };
-use crate::auth::{decode_invite, AdminHeaders, Headers, OwnerHeaders};
 use crate::db::models::*;
-use crate::db::DbConn;
+use crate::db::repo::Transactional;
+use crate::db::{DbConn, DbReadConn};
 use crate::mail;
+use crate::service;
+use crate::util;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
@@ -24,6 +35,8 @@ pub fn routes() -> Vec<Route> {
         get_org_collection_detail,
         get_collection_users,
         put_collection_users,
+        put_collection_user,
+        post_collection_user,
         put_organization,
         post_organization,
         post_organization_collections,
@@ -33,18 +46,34 @@ pub fn routes() -> Vec<Route> {
         put_organization_collection_update,
         delete_organization_collection,
         post_organization_collection_delete,
+        post_organization_collection_export,
         get_org_details,
+        get_org_ciphers,
         get_org_users,
+        export_org_users,
         send_invite,
         reinvite_user,
+        bulk_reinvite_user,
         confirm_invite,
+        bulk_confirm_invite,
+        get_bulk_public_keys,
         accept_invite,
         get_user,
         edit_user,
         put_organization_user,
         delete_user,
         post_delete_user,
+        bulk_delete_user,
+        post_bulk_delete_user,
+        import_members,
         post_org_import,
+        get_org_domains,
+        post_org_domain,
+        verify_org_domain,
+        delete_org_domain,
+        post_delete_org_domain,
+        get_org_events,
+        get_org_stats,
     ]
 }
 
@@ -64,12 +93,15 @@ struct OrgData {
 struct OrganizationUpdateData {
     BillingEmail: String,
     Name: String,
+    AllowedDomains: Option<Vec<String>>,
+    UseEvents: Option<bool>,
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct NewCollectionData {
     Name: String,
+    ExternalId: Option<String>,
 }
 
 #[post("/organizations", data = "<data>")]
@@ -94,7 +126,7 @@ fn create_organization(headers: Headers, data: JsonUpcase<OrgData>, conn: DbConn
 
 #[delete("/organizations/<org_id>", data = "<data>")]
 fn delete_organization(
-    org_id: String,
+    org_id: Uuid,
     data: JsonUpcase<PasswordData>,
     headers: OwnerHeaders,
     conn: DbConn,
@@ -114,7 +146,7 @@ fn delete_organization(
 
 #[post("/organizations/<org_id>/delete", data = "<data>")]
 fn post_delete_organization(
-    org_id: String,
+    org_id: Uuid,
     data: JsonUpcase<PasswordData>,
     headers: OwnerHeaders,
     conn: DbConn,
@@ -123,26 +155,12 @@ fn post_delete_organization(
 }
 
 #[post("/organizations/<org_id>/leave")]
-fn leave_organization(org_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
-    match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
-        None => err!("User not part of organization"),
-        Some(user_org) => {
-            if user_org.type_ == UserOrgType::Owner {
-                let num_owners =
-                    UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
-
-                if num_owners <= 1 {
-                    err!("The last owner can't leave")
-                }
-            }
-
-            user_org.delete(&conn)
-        }
-    }
+fn leave_organization(org_id: Uuid, headers: Headers, conn: DbConn) -> EmptyResult {
+    service::organizations::leave(&org_id, &headers.user.uuid, &conn)
 }
 
 #[get("/organizations/<org_id>")]
-fn get_organization(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+fn get_organization(org_id: Uuid, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
     match Organization::find_by_uuid(&org_id, &conn) {
         Some(organization) => Ok(Json(organization.to_json())),
         None => err!("Can't find organization details"),
@@ -151,7 +169,7 @@ fn get_organization(org_id: String, _headers: OwnerHeaders, conn: DbConn) -> Jso
 
 #[put("/organizations/<org_id>", data = "<data>")]
 fn put_organization(
-    org_id: String,
+    org_id: Uuid,
     headers: OwnerHeaders,
     data: JsonUpcase<OrganizationUpdateData>,
     conn: DbConn,
@@ -161,7 +179,7 @@ fn put_organization(
 
 #[post("/organizations/<org_id>", data = "<data>")]
 fn post_organization(
-    org_id: String,
+    org_id: Uuid,
     _headers: OwnerHeaders,
     data: JsonUpcase<OrganizationUpdateData>,
     conn: DbConn,
@@ -176,13 +194,21 @@ fn post_organization(
     org.name = data.Name;
     org.billing_email = data.BillingEmail;
 
+    if let Some(domains) = data.AllowedDomains {
+        org.set_allowed_domains(&domains);
+    }
+
+    if let Some(use_events) = data.UseEvents {
+        org.use_events = use_events;
+    }
+
     org.save(&conn)?;
     Ok(Json(org.to_json()))
 }
 
 // GET /api/collections?writeOnly=false
 #[get("/collections")]
-fn get_user_collections(headers: Headers, conn: DbConn) -> JsonResult {
+fn get_user_collections(headers: Headers, conn: DbReadConn) -> JsonResult {
     Ok(Json(json!({
         "Data":
             Collection::find_by_user_uuid(&headers.user.uuid, &conn)
@@ -194,11 +220,18 @@ fn get_user_collections(headers: Headers, conn: DbConn) -> JsonResult {
     })))
 }
 
+/// The managers only get the collections they manage, the admins and owners get all of them
 #[get("/organizations/<org_id>/collections")]
-fn get_org_collections(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+fn get_org_collections(org_id: Uuid, headers: ManagerHeadersLoose, conn: DbReadConn) -> JsonResult {
+    let collections = if headers.org_user_type >= UserOrgType::Admin {
+        Collection::find_by_organization(&org_id, &conn)
+    } else {
+        Collection::find_by_organization_and_user_uuid(&org_id, &headers.user.uuid, &conn)
+    };
+
     Ok(Json(json!({
         "Data":
-            Collection::find_by_organization(&org_id, &conn)
+            collections
             .iter()
             .map(Collection::to_json)
             .collect::<Value>(),
@@ -209,7 +242,7 @@ fn get_org_collections(org_id: String, _headers: AdminHeaders, conn: DbConn) ->
 
 #[post("/organizations/<org_id>/collections", data = "<data>")]
 fn post_organization_collections(
-    org_id: String,
+    org_id: Uuid,
     _headers: AdminHeaders,
     data: JsonUpcase<NewCollectionData>,
     conn: DbConn,
@@ -222,16 +255,25 @@ fn post_organization_collections(
     };
 
     let mut collection = Collection::new(org.uuid.clone(), data.Name);
+    collection.set_external_id(data.ExternalId);
+    _check_collection_external_id(&collection, &conn)?;
     collection.save(&conn)?;
 
     Ok(Json(collection.to_json()))
 }
 
+fn _check_collection_external_id(collection: &Collection, conn: &DbConn) -> EmptyResult {
+    if CONFIG.unique_collection_external_ids() && collection.find_duplicate_external_id(conn).is_some() {
+        err!("A collection with this external id already exists")
+    }
+    Ok(())
+}
+
 #[put("/organizations/<org_id>/collections/<col_id>", data = "<data>")]
 fn put_organization_collection_update(
-    org_id: String,
-    col_id: String,
-    headers: AdminHeaders,
+    org_id: Uuid,
+    col_id: Uuid,
+    headers: ManagerHeaders,
     data: JsonUpcase<NewCollectionData>,
     conn: DbConn,
 ) -> JsonResult {
@@ -240,9 +282,9 @@ fn put_organization_collection_update(
 
 #[post("/organizations/<org_id>/collections/<col_id>", data = "<data>")]
 fn post_organization_collection_update(
-    org_id: String,
-    col_id: String,
-    _headers: AdminHeaders,
+    org_id: Uuid,
+    col_id: Uuid,
+    _headers: ManagerHeaders,
     data: JsonUpcase<NewCollectionData>,
     conn: DbConn,
 ) -> JsonResult {
@@ -263,17 +305,70 @@ fn post_organization_collection_update(
     }
 
     collection.name = data.Name.clone();
+    collection.set_external_id(data.ExternalId);
+    _check_collection_external_id(&collection, &conn)?;
     collection.save(&conn)?;
 
     Ok(Json(collection.to_json()))
 }
 
+/// Exports a single collection and its ciphers in the encrypted format of the organization exports,
+/// which can be imported into another organization. The collection ids of the ciphers only include this one
+#[post("/organizations/<org_id>/collections/<col_id>/export", data = "<data>")]
+fn post_organization_collection_export(
+    org_id: Uuid,
+    col_id: Uuid,
+    data: JsonUpcase<PasswordData>,
+    headers: ManagerHeaders,
+    ip: ClientIp,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password")
+    }
+
+    let collection = match Collection::find_by_uuid_and_org(&col_id, &org_id, &conn) {
+        Some(collection) => collection,
+        None => err!("Collection not found"),
+    };
+
+    let items: Vec<Value> = Cipher::find_by_collection(&collection.uuid, &conn)
+        .iter()
+        .map(|c| {
+            // The folders are personal, so they aren't part of the organization exports
+            let mut item = c.to_export_json(&headers.user.uuid, &conn);
+            item["folderId"] = Value::Null;
+            item["favorite"] = Value::Bool(false);
+            item["collectionIds"] = json!([collection.uuid]);
+            item
+        })
+        .collect();
+
+    let address = ip.ip.to_string();
+    events::log_user_event(
+        EventType::UserClientExportedVault,
+        &headers.user.uuid,
+        headers.device.type_,
+        &address,
+        &nt,
+    );
+
+    Ok(Json(json!({
+        "encrypted": true,
+        "collections": [collection.to_export_json()],
+        "items": items,
+    })))
+}
+
 #[delete("/organizations/<org_id>/collections/<col_id>/user/<org_user_id>")]
 fn delete_organization_collection_user(
-    org_id: String,
-    col_id: String,
-    org_user_id: String,
-    _headers: AdminHeaders,
+    org_id: Uuid,
+    col_id: Uuid,
+    org_user_id: Uuid,
+    _headers: ManagerHeaders,
     conn: DbConn,
 ) -> EmptyResult {
     let collection = match Collection::find_by_uuid(&col_id, &conn) {
@@ -300,17 +395,17 @@ fn delete_organization_collection_user(
 
 #[post("/organizations/<org_id>/collections/<col_id>/delete-user/<org_user_id>")]
 fn post_organization_collection_delete_user(
-    org_id: String,
-    col_id: String,
-    org_user_id: String,
-    headers: AdminHeaders,
+    org_id: Uuid,
+    col_id: Uuid,
+    org_user_id: Uuid,
+    headers: ManagerHeaders,
     conn: DbConn,
 ) -> EmptyResult {
     delete_organization_collection_user(org_id, col_id, org_user_id, headers, conn)
 }
 
 #[delete("/organizations/<org_id>/collections/<col_id>")]
-fn delete_organization_collection(org_id: String, col_id: String, _headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+fn delete_organization_collection(org_id: Uuid, col_id: Uuid, _headers: ManagerHeaders, conn: DbConn) -> EmptyResult {
     match Collection::find_by_uuid(&col_id, &conn) {
         None => err!("Collection not found"),
         Some(collection) => {
@@ -332,9 +427,9 @@ struct DeleteCollectionData {
 
 #[post("/organizations/<org_id>/collections/<col_id>/delete", data = "<_data>")]
 fn post_organization_collection_delete(
-    org_id: String,
-    col_id: String,
-    headers: AdminHeaders,
+    org_id: Uuid,
+    col_id: Uuid,
+    headers: ManagerHeaders,
     _data: JsonUpcase<DeleteCollectionData>,
     conn: DbConn,
 ) -> EmptyResult {
@@ -342,7 +437,7 @@ fn post_organization_collection_delete(
 }
 
 #[get("/organizations/<org_id>/collections/<coll_id>/details")]
-fn get_org_collection_detail(org_id: String, coll_id: String, headers: AdminHeaders, conn: DbConn) -> JsonResult {
+fn get_org_collection_detail(org_id: Uuid, coll_id: Uuid, headers: ManagerHeaders, conn: DbConn) -> JsonResult {
     match Collection::find_by_uuid_and_user(&coll_id, &headers.user.uuid, &conn) {
         None => err!("Collection not found"),
         Some(collection) => {
@@ -356,7 +451,7 @@ fn get_org_collection_detail(org_id: String, coll_id: String, headers: AdminHead
 }
 
 #[get("/organizations/<org_id>/collections/<coll_id>/users")]
-fn get_collection_users(org_id: String, coll_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+fn get_collection_users(org_id: Uuid, coll_id: Uuid, _headers: ManagerHeaders, conn: DbConn) -> JsonResult {
     // Get org and collection, check that collection is from org
     let collection = match Collection::find_by_uuid_and_org(&coll_id, &org_id, &conn) {
         None => err!("Collection not found in Organization"),
@@ -378,10 +473,10 @@ fn get_collection_users(org_id: String, coll_id: String, _headers: AdminHeaders,
 
 #[put("/organizations/<org_id>/collections/<coll_id>/users", data = "<data>")]
 fn put_collection_users(
-    org_id: String,
-    coll_id: String,
+    org_id: Uuid,
+    coll_id: Uuid,
     data: JsonUpcaseVec<CollectionData>,
-    _headers: AdminHeaders,
+    _headers: ManagerHeaders,
     conn: DbConn,
 ) -> EmptyResult {
     // Get org and collection, check that collection is from org
@@ -394,7 +489,7 @@ fn put_collection_users(
 
     // And then add all the received ones (except if the user has access_all)
     for d in data.iter().map(|d| &d.data) {
-        let user = match UserOrganization::find_by_uuid(&d.Id, &conn) {
+        let user = match UserOrganization::find_by_uuid_and_org(&d.Id, &org_id, &conn) {
             Some(u) => u,
             None => err!("User is not part of organization"),
         };
@@ -409,6 +504,52 @@ fn put_collection_users(
     Ok(())
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct CollectionUserData {
+    ReadOnly: bool,
+}
+
+/// Assigns a single member to the collection, or changes if they can only read its ciphers
+#[put("/organizations/<org_id>/collections/<coll_id>/users/<org_user_id>", data = "<data>")]
+fn put_collection_user(
+    org_id: Uuid,
+    coll_id: Uuid,
+    org_user_id: Uuid,
+    data: JsonUpcase<CollectionUserData>,
+    _headers: ManagerHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let collection = match Collection::find_by_uuid_and_org(&coll_id, &org_id, &conn) {
+        None => err!("Collection not found in Organization"),
+        Some(collection) => collection,
+    };
+
+    let user_org = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+        None => err!("User not found in organization"),
+        Some(user_org) => user_org,
+    };
+
+    if user_org.access_all {
+        err!("The user already has access to all the collections")
+    }
+
+    let data: CollectionUserData = data.into_inner().data;
+    CollectionUser::save(&user_org.user_uuid, &collection.uuid, data.ReadOnly, &conn)
+}
+
+#[post("/organizations/<org_id>/collections/<coll_id>/users/<org_user_id>", data = "<data>")]
+fn post_collection_user(
+    org_id: Uuid,
+    coll_id: Uuid,
+    org_user_id: Uuid,
+    data: JsonUpcase<CollectionUserData>,
+    headers: ManagerHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    put_collection_user(org_id, coll_id, org_user_id, data, headers, conn)
+}
+
 #[derive(FromForm)]
 struct OrgIdData {
     #[form(field = "organizationId")]
@@ -416,11 +557,40 @@ struct OrgIdData {
 }
 
 #[get("/ciphers/organization-details?<data..>")]
-fn get_org_details(data: Form<OrgIdData>, headers: Headers, conn: DbConn) -> JsonResult {
-    let ciphers = Cipher::find_by_org(&data.organization_id, &conn);
-    let ciphers_json: Vec<Value> = ciphers
+fn get_org_details(data: Form<OrgIdData>, headers: OrgMemberHeaders, conn: DbReadConn) -> JsonResult {
+    let (ciphers, full_access) = _get_org_ciphers(&data.organization_id, &headers, &conn);
+    _org_ciphers_response(&ciphers, full_access, &headers, &conn)
+}
+
+/// Admins and members with access to all the collections see every cipher of the organization,
+/// the rest only see the ciphers in their collections
+fn _get_org_ciphers(org_id: &str, headers: &OrgMemberHeaders, conn: &DbConn) -> (Vec<Cipher>, bool) {
+    let user_uuid = &headers.user.uuid;
+
+    let full_access = headers.org_user_type >= UserOrgType::Admin
+        || UserOrganization::find_by_user_and_org(user_uuid, org_id, conn).map_or(false, |u| u.access_all);
+
+    let ciphers = if full_access {
+        Cipher::find_by_org(org_id, conn)
+    } else {
+        Cipher::find_by_user(user_uuid, conn)
+            .into_iter()
+            .filter(|c| c.organization_uuid.as_ref().map(String::as_str) == Some(org_id))
+            .collect()
+    };
+
+    (ciphers, full_access)
+}
+
+fn _org_ciphers_response(
+    ciphers: &[Cipher],
+    full_access: bool,
+    headers: &OrgMemberHeaders,
+    conn: &DbConn,
+) -> JsonResult {
+    let ciphers_json: Vec<CipherResponse> = ciphers
         .iter()
-        .map(|c| c.to_json(&headers.host, &headers.user.uuid, &conn))
+        .map(|c| c.to_org_details_response(&headers.host, &headers.user.uuid, full_access, conn))
         .collect();
 
     Ok(Json(json!({
@@ -430,10 +600,79 @@ fn get_org_details(data: Form<OrgIdData>, headers: Headers, conn: DbConn) -> Jso
     })))
 }
 
-#[get("/organizations/<org_id>/users")]
-fn get_org_users(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
-    let users = UserOrganization::find_by_org(&org_id, &conn);
-    let users_json: Vec<Value> = users.iter().map(|c| c.to_json_user_details(&conn)).collect();
+#[derive(FromForm)]
+struct OrgCipherFilters {
+    #[form(field = "collectionId")]
+    collection_id: Option<String>,
+    #[form(field = "type")]
+    type_: Option<i32>,
+    deleted: Option<bool>,
+}
+
+/// The ciphers of the organization the member can see, with the same scoping as the organization details
+#[get("/organizations/<org_id>/ciphers?<filters..>")]
+fn get_org_ciphers(
+    org_id: String,
+    filters: Form<OrgCipherFilters>,
+    headers: OrgMemberHeaders,
+    conn: DbReadConn,
+) -> JsonResult {
+    let (mut ciphers, full_access) = _get_org_ciphers(&org_id, &headers, &conn);
+
+    if let Some(ref collection_id) = filters.collection_id {
+        if Collection::find_by_uuid_and_org(collection_id, &org_id, &conn).is_none() {
+            err!("Collection not found in Organization")
+        }
+
+        let cipher_uuids: Vec<String> = CollectionCipher::find_by_collection(collection_id, &conn)
+            .into_iter()
+            .map(|cc| cc.cipher_uuid)
+            .collect();
+        ciphers.retain(|c| cipher_uuids.contains(&c.uuid));
+    }
+
+    if let Some(type_) = filters.type_ {
+        ciphers.retain(|c| c.type_ == type_);
+    }
+
+    // The ciphers are deleted permanently, there are none in the trash
+    if filters.deleted == Some(true) {
+        ciphers.clear();
+    }
+
+    _org_ciphers_response(&ciphers, full_access, &headers, &conn)
+}
+
+#[derive(FromForm)]
+struct OrgUsersData {
+    #[form(field = "includeTwoFactor")]
+    include_twofactor: Option<bool>,
+}
+
+/// The managers can also list the members, but only the ones in the collections they manage
+#[get("/organizations/<org_id>/users?<data..>")]
+fn get_org_users(org_id: Uuid, data: Form<OrgUsersData>, headers: ManagerHeadersLoose, conn: DbReadConn) -> JsonResult {
+    let include_twofactor = data.include_twofactor.unwrap_or(false);
+
+    let mut users = UserOrganization::find_by_org(&org_id, &conn);
+    if headers.org_user_type < UserOrgType::Admin {
+        let mut visible: HashSet<String> = HashSet::new();
+        visible.insert(headers.user.uuid.clone());
+        for collection in Collection::find_by_organization_and_user_uuid(&org_id, &headers.user.uuid, &conn) {
+            visible.extend(
+                CollectionUser::find_by_collection(&collection.uuid, &conn)
+                    .into_iter()
+                    .map(|c| c.user_uuid),
+            );
+        }
+
+        users.retain(|u| visible.contains(&u.user_uuid));
+    }
+
+    let users_json: Vec<Value> = users
+        .iter()
+        .map(|c| c.to_json_user_details(include_twofactor, &conn))
+        .collect();
 
     Ok(Json(json!({
         "Data": users_json,
@@ -442,6 +681,48 @@ fn get_org_users(org_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonRe
     })))
 }
 
+/// Exports the members as CSV, to check which of them still need to enable two step login
+#[get("/organizations/<org_id>/users/export")]
+fn export_org_users(org_id: Uuid, _headers: AdminHeaders, conn: DbReadConn) -> ApiResult<Content<String>> {
+    use crate::util::csv_field;
+
+    let mut csv = String::from("Email,Name,Type,Status,TwoFactorEnabled\n");
+
+    for user_org in UserOrganization::find_by_org(&org_id, &conn) {
+        let user = match User::find_by_uuid(&user_org.user_uuid, &conn) {
+            Some(user) => user,
+            None => err!("User not found"),
+        };
+
+        let user_type = match UserOrgType::from_i32(user_org.type_) {
+            Some(UserOrgType::Owner) => "Owner",
+            Some(UserOrgType::Admin) => "Admin",
+            Some(UserOrgType::Manager) => "Manager",
+            Some(UserOrgType::User) => "User",
+            None => "Unknown",
+        };
+
+        let status = match user_org.status {
+            s if s == UserOrgStatus::Revoked as i32 => "Revoked",
+            s if s == UserOrgStatus::Invited as i32 => "Invited",
+            s if s == UserOrgStatus::Accepted as i32 => "Accepted",
+            s if s == UserOrgStatus::Confirmed as i32 => "Confirmed",
+            _ => "Unknown",
+        };
+
+        csv.push_str(&format!(
+            "{},{},{},{},{}\n",
+            csv_field(&user.email),
+            csv_field(&user.name),
+            user_type,
+            status,
+            user_org.has_twofactor(&conn)
+        ));
+    }
+
+    Ok(Content(ContentType::CSV, csv))
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct CollectionData {
@@ -453,97 +734,188 @@ struct CollectionData {
 #[allow(non_snake_case)]
 struct InviteData {
     Emails: Vec<String>,
-    Type: NumberOrString,
+    Type: UserOrgType,
     Collections: Option<Vec<CollectionData>>,
     AccessAll: Option<bool>,
 }
 
 #[post("/organizations/<org_id>/users/invite", data = "<data>")]
-fn send_invite(org_id: String, data: JsonUpcase<InviteData>, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+fn send_invite(
+    org_id: Uuid,
+    data: JsonUpcase<InviteData>,
+    headers: AdminHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
     let data: InviteData = data.into_inner().data;
 
-    let new_type = match UserOrgType::from_str(&data.Type.into_string()) {
-        Some(new_type) => new_type as i32,
-        None => err!("Invalid type"),
-    };
+    let new_type = data.Type as i32;
 
     if new_type != UserOrgType::User && headers.org_user_type != UserOrgType::Owner {
         err!("Only Owners can invite Managers, Admins or Owners")
     }
 
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Error looking up organization"),
+    };
+
+    let access_all = data.AccessAll.unwrap_or(false);
+    let collections = data.Collections.unwrap_or_default();
+
     for email in data.Emails.iter() {
-        let mut user_org_status = if CONFIG.mail_enabled() {
-            UserOrgStatus::Invited as i32
-        } else {
-            UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
-        };
-        let user = match User::find_by_mail(&email, &conn) {
-            None => {
-                if !CONFIG.invitations_allowed() {
-                    err!(format!("User email does not exist: {}", email))
-                }
+        let invite = _invite_member(email, new_type, access_all, &collections, &org, &conn)?;
+        _send_invite(invite, &org, &headers, &nt)?;
+    }
 
-                if !CONFIG.mail_enabled() {
-                    let mut invitation = Invitation::new(email.clone());
-                    invitation.save(&conn)?;
-                }
+    Ok(())
+}
 
-                let mut user = User::new(email.clone());
-                user.save(&conn)?;
-                user_org_status = UserOrgStatus::Invited as i32;
-                user
+/// A member that was just invited, its event and email are only sent once it's saved
+struct PendingInvite {
+    member: UserOrganization,
+    email: String,
+    locale: Option<String>,
+}
+
+/// Adds the email to the organization, creating its user first when it doesn't have an account yet.
+/// Without email, the members are marked as accepted right away
+fn _invite_member(
+    email: &str,
+    new_type: i32,
+    access_all: bool,
+    collections: &[CollectionData],
+    org: &Organization,
+    conn: &DbConn,
+) -> ApiResult<PendingInvite> {
+    let org_id = &org.uuid;
+
+    if !org.is_email_domain_allowed(email) {
+        err!(format!("Email domain not allowed in the organization: {}", email))
+    }
+
+    let mut user_org_status = if CONFIG.mail_enabled() {
+        UserOrgStatus::Invited as i32
+    } else {
+        UserOrgStatus::Accepted as i32 // Automatically mark user as accepted if no email invites
+    };
+    let user = match User::find_by_mail(&email, &conn) {
+        None => {
+            if !CONFIG.invitations_allowed() {
+                err!(format!("User email does not exist: {}", email))
             }
-            Some(user) => {
-                if UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn).is_some() {
-                    err!(format!("User already in organization: {}", email))
-                } else {
-                    user
-                }
+
+            if !CONFIG.mail_enabled() {
+                let mut invitation = Invitation::new(email.to_string());
+                invitation.save(&conn)?;
             }
-        };
 
-        let mut new_user = UserOrganization::new(user.uuid.clone(), org_id.clone());
-        let access_all = data.AccessAll.unwrap_or(false);
-        new_user.access_all = access_all;
-        new_user.type_ = new_type;
-        new_user.status = user_org_status;
-
-        // If no accessAll, add the collections received
-        if !access_all {
-            for col in data.Collections.iter().flatten() {
-                match Collection::find_by_uuid_and_org(&col.Id, &org_id, &conn) {
-                    None => err!("Collection not found in Organization"),
-                    Some(collection) => {
-                        CollectionUser::save(&user.uuid, &collection.uuid, col.ReadOnly, &conn)?;
-                    }
+            let mut user = User::new(email.to_string());
+            user.save(&conn)?;
+            user_org_status = UserOrgStatus::Invited as i32;
+            user
+        }
+        Some(user) => {
+            if UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn).is_some() {
+                err!(format!("User already in organization: {}", email))
+            } else {
+                user
+            }
+        }
+    };
+
+    let mut new_user = UserOrganization::new(user.uuid.clone(), org_id.clone());
+    new_user.access_all = access_all;
+    new_user.type_ = new_type;
+    new_user.status = user_org_status;
+
+    // If no accessAll, add the collections received
+    if !access_all {
+        for col in collections {
+            match Collection::find_by_uuid_and_org(&col.Id, &org_id, &conn) {
+                None => err!("Collection not found in Organization"),
+                Some(collection) => {
+                    CollectionUser::save(&user.uuid, &collection.uuid, col.ReadOnly, &conn)?;
                 }
             }
         }
+    }
 
-        new_user.save(&conn)?;
+    new_user.save(&conn)?;
 
-        if CONFIG.mail_enabled() {
-            let org_name = match Organization::find_by_uuid(&org_id, &conn) {
-                Some(org) => org.name,
-                None => err!("Error looking up organization"),
-            };
+    Ok(PendingInvite {
+        member: new_user,
+        email: email.to_string(),
+        locale: user.locale,
+    })
+}
 
-            mail::send_invite(
-                &email,
-                &user.uuid,
-                Some(org_id.clone()),
-                Some(new_user.uuid),
-                &org_name,
-                Some(headers.user.email.clone()),
-            )?;
-        }
+fn _send_invite(invite: PendingInvite, org: &Organization, headers: &AdminHeaders, nt: &Notify) -> EmptyResult {
+    _log_member_event(EventType::OrganizationUserInvited, &invite.member, &headers, &nt);
+
+    if CONFIG.mail_enabled() {
+        mail::send_invite(
+            &invite.email,
+            &invite.member.user_uuid,
+            Some(org.uuid.clone()),
+            Some(invite.member.uuid.clone()),
+            &org.name,
+            Some(headers.user.email.clone()),
+            invite.locale.as_ref().map(String::as_str),
+        )?;
     }
 
     Ok(())
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgBulkIds {
+    Ids: Vec<String>,
+}
+
+/// The response of the bulk member endpoints, with the error of each member that failed, or an empty one
+fn _bulk_response(object: &str, results: Vec<(String, EmptyResult)>) -> Value {
+    let data: Vec<Value> = results
+        .into_iter()
+        .map(|(id, result)| {
+            json!({
+                "Object": object,
+                "Id": id,
+                "Error": result.err().map(|e| e.message().to_string()).unwrap_or_default(),
+            })
+        })
+        .collect();
+
+    json!({
+        "Data": data,
+        "Object": "list",
+        "ContinuationToken": null,
+    })
+}
+
 #[post("/organizations/<org_id>/users/<user_org>/reinvite")]
-fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+fn reinvite_user(org_id: Uuid, user_org: Uuid, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
+    _reinvite_member(&org_id, &user_org, &headers, &conn)
+}
+
+#[post("/organizations/<org_id>/users/reinvite", data = "<data>")]
+fn bulk_reinvite_user(org_id: Uuid, data: JsonUpcase<OrgBulkIds>, headers: AdminHeaders, conn: DbConn) -> JsonResult {
+    let data: OrgBulkIds = data.into_inner().data;
+
+    let results = data
+        .Ids
+        .into_iter()
+        .map(|id| {
+            let result = _reinvite_member(&org_id, &id, &headers, &conn);
+            (id, result)
+        })
+        .collect();
+
+    Ok(Json(_bulk_response("OrganizationUserBulkResponseModel", results)))
+}
+
+fn _reinvite_member(org_id: &str, member_id: &str, headers: &AdminHeaders, conn: &DbConn) -> EmptyResult {
     if !CONFIG.invitations_allowed() {
         err!("Invitations are not allowed.")
     }
@@ -552,7 +924,7 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
         err!("SMTP is not configured.")
     }
 
-    let user_org = match UserOrganization::find_by_uuid(&user_org, &conn) {
+    let user_org = match UserOrganization::find_by_uuid_and_org(member_id, org_id, conn) {
         Some(user_org) => user_org,
         None => err!("The user hasn't been invited to the organization."),
     };
@@ -561,12 +933,12 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
         err!("The user is already accepted or confirmed to the organization")
     }
 
-    let user = match User::find_by_uuid(&user_org.user_uuid, &conn) {
+    let user = match User::find_by_uuid(&user_org.user_uuid, conn) {
         Some(user) => user,
         None => err!("User not found."),
     };
 
-    let org_name = match Organization::find_by_uuid(&org_id, &conn) {
+    let org_name = match Organization::find_by_uuid(org_id, conn) {
         Some(org) => org.name,
         None => err!("Error looking up organization."),
     };
@@ -575,14 +947,15 @@ fn reinvite_user(org_id: String, user_org: String, headers: AdminHeaders, conn:
         mail::send_invite(
             &user.email,
             &user.uuid,
-            Some(org_id),
+            Some(org_id.to_string()),
             Some(user_org.uuid),
             &org_name,
-            Some(headers.user.email),
+            Some(headers.user.email.clone()),
+            user.locale.as_ref().map(String::as_str),
         )?;
     } else {
         let mut invitation = Invitation::new(user.email.clone());
-        invitation.save(&conn)?;
+        invitation.save(conn)?;
     }
 
     Ok(())
@@ -595,14 +968,14 @@ struct AcceptData {
 }
 
 #[post("/organizations/<_org_id>/users/<_org_user_id>/accept", data = "<data>")]
-fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptData>, conn: DbConn) -> EmptyResult {
+fn accept_invite(_org_id: Uuid, _org_user_id: Uuid, data: JsonUpcase<AcceptData>, conn: DbConn) -> EmptyResult {
     // The web-vault passes org_id and org_user_id in the URL, but we are just reading them from the JWT instead
     let data: AcceptData = data.into_inner().data;
     let token = &data.Token;
     let claims = decode_invite(&token)?;
 
-    match User::find_by_mail(&claims.email, &conn) {
-        Some(_) => {
+    let user_locale = match User::find_by_mail(&claims.email, &conn) {
+        Some(user) => {
             Invitation::take(&claims.email, &conn);
 
             if let (Some(user_org), Some(org)) = (&claims.user_org_id, &claims.org_id) {
@@ -615,12 +988,22 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
                     err!("User already accepted the invitation")
                 }
 
+                match Organization::find_by_uuid(org, &conn) {
+                    Some(org) if !org.is_email_domain_allowed(&claims.email) => {
+                        err!("Your email domain is not allowed in this organization")
+                    }
+                    Some(_) => (),
+                    None => err!("Organization not found."),
+                }
+
                 user_org.status = UserOrgStatus::Accepted as i32;
                 user_org.save(&conn)?;
             }
+
+            user.locale
         }
         None => err!("Invited user not found"),
-    }
+    };
 
     if CONFIG.mail_enabled() {
         let mut org_name = String::from("bitwarden_rs");
@@ -632,10 +1015,16 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
         };
         if let Some(invited_by_email) = &claims.invited_by_email {
             // User was invited to an organization, so they must be confirmed manually after acceptance
-            mail::send_invite_accepted(&claims.email, invited_by_email, &org_name)?;
+            let inviter_locale = User::find_by_mail(invited_by_email, &conn).and_then(|u| u.locale);
+            mail::send_invite_accepted(
+                &claims.email,
+                invited_by_email,
+                &org_name,
+                inviter_locale.as_ref().map(String::as_str),
+            )?;
         } else {
             // User was invited from /admin, so they are automatically confirmed
-            mail::send_invite_confirmed(&claims.email, &org_name)?;
+            mail::send_invite_confirmed(&claims.email, &org_name, user_locale.as_ref().map(String::as_str))?;
         }
     }
 
@@ -644,15 +1033,100 @@ fn accept_invite(_org_id: String, _org_user_id: String, data: JsonUpcase<AcceptD
 
 #[post("/organizations/<org_id>/users/<org_user_id>/confirm", data = "<data>")]
 fn confirm_invite(
-    org_id: String,
-    org_user_id: String,
+    org_id: Uuid,
+    org_user_id: Uuid,
     data: JsonUpcase<Value>,
     headers: AdminHeaders,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
     let data = data.into_inner().data;
+    let key = match data["Key"].as_str() {
+        Some(key) => key.to_string(),
+        None => err!("Invalid key provided"),
+    };
+
+    _confirm_member(&org_id, &org_user_id, key, &headers, &conn, &nt)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgBulkConfirmKey {
+    Id: String,
+    Key: String,
+}
 
-    let mut user_to_confirm = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgBulkConfirmData {
+    Keys: Vec<OrgBulkConfirmKey>,
+}
+
+#[post("/organizations/<org_id>/users/confirm", data = "<data>")]
+fn bulk_confirm_invite(
+    org_id: Uuid,
+    data: JsonUpcase<OrgBulkConfirmData>,
+    headers: AdminHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: OrgBulkConfirmData = data.into_inner().data;
+
+    let results = data
+        .Keys
+        .into_iter()
+        .map(|k| {
+            let result = _confirm_member(&org_id, &k.Id, k.Key, &headers, &conn, &nt);
+            (k.Id, result)
+        })
+        .collect();
+
+    Ok(Json(_bulk_response("OrganizationBulkConfirmResponseModel", results)))
+}
+
+/// The public keys of the members, so the org key can be encrypted for each of them before a bulk confirm.
+/// The members that don't have keys yet are left out
+#[post("/organizations/<org_id>/users/public-keys", data = "<data>")]
+fn get_bulk_public_keys(
+    org_id: Uuid,
+    data: JsonUpcase<OrgBulkIds>,
+    _headers: AdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: OrgBulkIds = data.into_inner().data;
+
+    let keys: Vec<Value> = data
+        .Ids
+        .iter()
+        .filter_map(|id| UserOrganization::find_by_uuid_and_org(id, &org_id, &conn))
+        .filter_map(|member| {
+            let user = User::find_by_uuid(&member.user_uuid, &conn)?;
+            let key = user.public_key?;
+            Some(json!({
+                "Object": "OrganizationUserPublicKeyResponseModel",
+                "Id": member.uuid,
+                "UserId": user.uuid,
+                "Key": key,
+            }))
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "Data": keys,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+fn _confirm_member(
+    org_id: &str,
+    member_id: &str,
+    key: String,
+    headers: &AdminHeaders,
+    conn: &DbConn,
+    nt: &Notify,
+) -> EmptyResult {
+    let mut user_to_confirm = match UserOrganization::find_by_uuid_and_org(member_id, org_id, conn) {
         Some(user) => user,
         None => err!("The specified user isn't a member of the organization"),
     };
@@ -666,28 +1140,27 @@ fn confirm_invite(
     }
 
     user_to_confirm.status = UserOrgStatus::Confirmed as i32;
-    user_to_confirm.key = match data["Key"].as_str() {
-        Some(key) => key.to_string(),
-        None => err!("Invalid key provided"),
-    };
+    user_to_confirm.key = key;
 
     if CONFIG.mail_enabled() {
-        let org_name = match Organization::find_by_uuid(&org_id, &conn) {
+        let org_name = match Organization::find_by_uuid(org_id, conn) {
             Some(org) => org.name,
             None => err!("Error looking up organization."),
         };
-        let address = match User::find_by_uuid(&user_to_confirm.user_uuid, &conn) {
-            Some(user) => user.email,
+        let user = match User::find_by_uuid(&user_to_confirm.user_uuid, conn) {
+            Some(user) => user,
             None => err!("Error looking up user."),
         };
-        mail::send_invite_confirmed(&address, &org_name)?;
+        mail::send_invite_confirmed(&user.email, &org_name, user.locale.as_ref().map(String::as_str))?;
     }
 
-    user_to_confirm.save(&conn)
+    user_to_confirm.save(conn)?;
+    _log_member_event(EventType::OrganizationUserConfirmed, &user_to_confirm, headers, nt);
+    Ok(())
 }
 
-#[get("/organizations/<org_id>/users/<org_user_id>")]
-fn get_user(org_id: String, org_user_id: String, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
+#[get("/organizations/<org_id>/users/<org_user_id>", rank = 1)]
+fn get_user(org_id: Uuid, org_user_id: Uuid, _headers: AdminHeaders, conn: DbConn) -> JsonResult {
     let user = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
         Some(user) => user,
         None => err!("The specified user isn't a member of the organization"),
@@ -699,61 +1172,42 @@ fn get_user(org_id: String, org_user_id: String, _headers: AdminHeaders, conn: D
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct EditUserData {
-    Type: NumberOrString,
+    Type: UserOrgType,
     Collections: Option<Vec<CollectionData>>,
     AccessAll: bool,
 }
 
 #[put("/organizations/<org_id>/users/<org_user_id>", data = "<data>", rank = 1)]
 fn put_organization_user(
-    org_id: String,
-    org_user_id: String,
+    org_id: Uuid,
+    org_user_id: Uuid,
     data: JsonUpcase<EditUserData>,
     headers: AdminHeaders,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
-    edit_user(org_id, org_user_id, data, headers, conn)
+    edit_user(org_id, org_user_id, data, headers, conn, nt)
 }
 
 #[post("/organizations/<org_id>/users/<org_user_id>", data = "<data>", rank = 1)]
 fn edit_user(
-    org_id: String,
-    org_user_id: String,
+    org_id: Uuid,
+    org_user_id: Uuid,
     data: JsonUpcase<EditUserData>,
     headers: AdminHeaders,
     conn: DbConn,
+    nt: Notify,
 ) -> EmptyResult {
     let data: EditUserData = data.into_inner().data;
 
-    let new_type = match UserOrgType::from_str(&data.Type.into_string()) {
-        Some(new_type) => new_type,
-        None => err!("Invalid type"),
-    };
+    let new_type = data.Type;
 
     let mut user_to_edit = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
         Some(user) => user,
         None => err!("The specified user isn't member of the organization"),
     };
 
-    if new_type != user_to_edit.type_
-        && (user_to_edit.type_ >= UserOrgType::Admin || new_type >= UserOrgType::Admin)
-        && headers.org_user_type != UserOrgType::Owner
-    {
-        err!("Only Owners can grant and remove Admin or Owner privileges")
-    }
-
-    if user_to_edit.type_ == UserOrgType::Owner && headers.org_user_type != UserOrgType::Owner {
-        err!("Only Owners can edit Owner users")
-    }
-
-    if user_to_edit.type_ == UserOrgType::Owner && new_type != UserOrgType::Owner {
-        // Removing owner permmission, check that there are at least another owner
-        let num_owners = UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
-
-        if num_owners <= 1 {
-            err!("Can't delete the last owner")
-        }
-    }
+    service::organizations::check_type_change(&user_to_edit, new_type, headers.org_user_type, &conn)?;
 
     user_to_edit.access_all = data.AccessAll;
     user_to_edit.type_ = new_type as i32;
@@ -775,38 +1229,195 @@ fn edit_user(
         }
     }
 
-    user_to_edit.save(&conn)
+    user_to_edit.save(&conn)?;
+    _log_member_event(EventType::OrganizationUserUpdated, &user_to_edit, &headers, &nt);
+    Ok(())
 }
 
 #[delete("/organizations/<org_id>/users/<org_user_id>")]
-fn delete_user(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
-    let user_to_delete = match UserOrganization::find_by_uuid_and_org(&org_user_id, &org_id, &conn) {
-        Some(user) => user,
+fn delete_user(org_id: Uuid, org_user_id: Uuid, headers: AdminHeaders, conn: DbConn, nt: Notify) -> EmptyResult {
+    _delete_member(&org_id, &org_user_id, &headers, &conn, &nt)
+}
+
+#[delete("/organizations/<org_id>/users", data = "<data>")]
+fn bulk_delete_user(
+    org_id: Uuid,
+    data: JsonUpcase<OrgBulkIds>,
+    headers: AdminHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    let data: OrgBulkIds = data.into_inner().data;
+
+    let results = data
+        .Ids
+        .into_iter()
+        .map(|id| {
+            let result = _delete_member(&org_id, &id, &headers, &conn, &nt);
+            (id, result)
+        })
+        .collect();
+
+    Ok(Json(_bulk_response("OrganizationUserBulkResponseModel", results)))
+}
+
+#[post("/organizations/<org_id>/users/delete", data = "<data>")]
+fn post_bulk_delete_user(
+    org_id: Uuid,
+    data: JsonUpcase<OrgBulkIds>,
+    headers: AdminHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> JsonResult {
+    bulk_delete_user(org_id, data, headers, conn, nt)
+}
+
+fn _delete_member(org_id: &str, member_id: &str, headers: &AdminHeaders, conn: &DbConn, nt: &Notify) -> EmptyResult {
+    let member = match UserOrganization::find_by_uuid_and_org(member_id, org_id, conn) {
+        Some(member) => member,
         None => err!("User to delete isn't member of the organization"),
     };
 
-    if user_to_delete.type_ != UserOrgType::User && headers.org_user_type != UserOrgType::Owner {
-        err!("Only Owners can delete Admins or Owners")
-    }
+    service::organizations::remove_member(org_id, member_id, headers.org_user_type, conn)?;
+    _log_member_event(EventType::OrganizationUserRemoved, &member, headers, nt);
+    Ok(())
+}
 
-    if user_to_delete.type_ == UserOrgType::Owner {
-        // Removing owner, check that there are at least another owner
-        let num_owners = UserOrganization::find_by_org_and_type(&org_id, UserOrgType::Owner as i32, &conn).len();
+fn _log_member_event(event_type: EventType, member: &UserOrganization, headers: &AdminHeaders, nt: &Notify) {
+    events::log_member_event(event_type, member, &headers.user.uuid, &headers.device, nt);
+}
 
-        if num_owners <= 1 {
-            err!("Can't delete the last owner")
+#[post("/organizations/<org_id>/users/<org_user_id>/delete")]
+fn post_delete_user(org_id: Uuid, org_user_id: Uuid, headers: AdminHeaders, conn: DbConn, nt: Notify) -> EmptyResult {
+    delete_user(org_id, org_user_id, headers, conn, nt)
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgImportUserData {
+    Email: String,
+    #[serde(rename = "ExternalId")]
+    _ExternalId: Option<String>,
+    Deleted: bool,
+    #[serde(default)]
+    Disabled: bool,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgImportData {
+    #[serde(rename = "Groups")]
+    _Groups: Option<Value>, // Ignored, groups aren't supported
+    // The older versions of the Directory Connector send them as `Users`
+    #[serde(alias = "Users")]
+    Members: Vec<OrgImportUserData>,
+    OverwriteExisting: bool,
+}
+
+/// Syncs the members with a directory, used by the Directory Connector. The new emails are invited as users,
+/// the disabled ones lose their access and the deleted ones are removed. With `OverwriteExisting`, the members
+/// that aren't in the directory are removed too. The admins and owners are never changed by the sync,
+/// and the whole sync is undone when a member fails. The events and the invites are only sent once it's saved
+#[post("/organizations/<org_id>/import", data = "<data>")]
+fn import_members(
+    org_id: Uuid,
+    data: JsonUpcase<OrgImportData>,
+    headers: AdminHeaders,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    let data: OrgImportData = data.into_inner().data;
+
+    let org = match Organization::find_by_uuid(&org_id, &conn) {
+        Some(org) => org,
+        None => err!("Error looking up organization"),
+    };
+
+    let (invites, removed) = conn.in_transaction(|| {
+        let mut invites = Vec::new();
+        let mut removed = Vec::new();
+
+        let new_type = UserOrgType::User as i32;
+        for member in &data.Members {
+            let user_org = User::find_by_mail(&member.Email, &conn)
+                .and_then(|user| UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn));
+
+            match user_org {
+                Some(user_org) if member.Deleted => _remove_imported_member(user_org, &mut removed, &conn)?,
+                Some(user_org) => _set_imported_member_disabled(user_org, member.Disabled, &conn)?,
+                None if !member.Deleted && !member.Disabled => {
+                    invites.push(_invite_member(&member.Email, new_type, false, &[], &org, &conn)?)
+                }
+                None => (),
+            }
+        }
+
+        if data.OverwriteExisting {
+            let emails: Vec<String> = data
+                .Members
+                .iter()
+                .filter(|m| !m.Deleted)
+                .map(|m| m.Email.to_lowercase())
+                .collect();
+
+            for user_org in UserOrganization::find_by_org(&org_id, &conn) {
+                let in_directory = match User::find_by_uuid(&user_org.user_uuid, &conn) {
+                    Some(user) => emails.contains(&user.email),
+                    None => false,
+                };
+
+                if !in_directory {
+                    _remove_imported_member(user_org, &mut removed, &conn)?;
+                }
+            }
         }
+
+        Ok((invites, removed))
+    })?;
+
+    for member in &removed {
+        _log_member_event(EventType::OrganizationUserRemoved, member, &headers, &nt);
+    }
+    for invite in invites {
+        _send_invite(invite, &org, &headers, &nt)?;
     }
 
-    user_to_delete.delete(&conn)
+    Ok(())
 }
 
-#[post("/organizations/<org_id>/users/<org_user_id>/delete")]
-fn post_delete_user(org_id: String, org_user_id: String, headers: AdminHeaders, conn: DbConn) -> EmptyResult {
-    delete_user(org_id, org_user_id, headers, conn)
+fn _remove_imported_member(
+    member: UserOrganization,
+    removed: &mut Vec<UserOrganization>,
+    conn: &DbConn,
+) -> EmptyResult {
+    if member.type_ >= UserOrgType::Admin {
+        return Ok(());
+    }
+
+    removed.push(member.clone());
+    member.delete(&conn)
 }
 
-use super::ciphers::update_cipher_from_data;
+/// The invited members don't have access yet, so they are left as they are. The enabled members go back
+/// to accepted, as their key may have changed, so an admin needs to confirm them again
+fn _set_imported_member_disabled(mut member: UserOrganization, disabled: bool, conn: &DbConn) -> EmptyResult {
+    if member.type_ >= UserOrgType::Admin {
+        return Ok(());
+    }
+
+    let status = if disabled && member.status >= UserOrgStatus::Accepted as i32 {
+        UserOrgStatus::Revoked
+    } else if !disabled && member.status == UserOrgStatus::Revoked as i32 {
+        UserOrgStatus::Accepted
+    } else {
+        return Ok(());
+    };
+
+    member.status = status as i32;
+    member.save(conn)
+}
+
+use super::ciphers::apply_cipher_data;
 use super::ciphers::CipherData;
 
 #[derive(Deserialize)]
@@ -830,74 +1441,251 @@ struct RelationsData {
 fn post_org_import(
     query: Form<OrgIdData>,
     data: JsonUpcase<ImportData>,
-    headers: Headers,
+    headers: AdminHeaders,
     conn: DbConn,
     nt: Notify,
 ) -> EmptyResult {
     let data: ImportData = data.into_inner().data;
     let org_id = query.into_inner().organization_id;
 
-    let org_user = match UserOrganization::find_by_user_and_org(&headers.user.uuid, &org_id, &conn) {
-        Some(user) => user,
-        None => err!("User is not part of the organization"),
+    // Nothing is imported if any of the ciphers is invalid
+    data.Ciphers.iter().try_for_each(CipherData::validate)?;
+
+    // Read the collections, they are created with the rest of the import. When the external ids are unique,
+    // the collections that already exist with the same external id are reused instead
+    let mut known = if CONFIG.unique_collection_external_ids() {
+        Collection::find_by_organization(&org_id, &conn)
+    } else {
+        Vec::new()
     };
+    let mut collections = Vec::new();
+    let mut collection_uuids = Vec::new();
+    for coll in data.Collections {
+        let mut collection = Collection::new(org_id.clone(), coll.Name);
+        collection.set_external_id(coll.ExternalId);
+
+        if let Some(uuid) = collection.find_duplicate_external_id_in(&known) {
+            collection_uuids.push(uuid);
+            continue;
+        }
 
-    if org_user.type_ < UserOrgType::Admin {
-        err!("Only admins or owners can import into an organization")
+        collection_uuids.push(collection.uuid.clone());
+        if CONFIG.unique_collection_external_ids() {
+            known.push(collection.clone());
+        }
+        collections.push(collection);
     }
 
-    // Read and create the collections
-    let collections: Vec<_> = data
-        .Collections
-        .into_iter()
-        .map(|coll| {
-            let mut collection = Collection::new(org_id.clone(), coll.Name);
-            if collection.save(&conn).is_err() {
-                err!("Failed to create Collection");
-            }
-
-            Ok(collection)
-        })
-        .collect();
+    // The favorites are kept for the user that imports them
+    let mut ciphers = Vec::new();
+    let mut favorites = Vec::new();
+    for cipher_data in data.Ciphers {
+        let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
+        cipher.organization_uuid = Some(org_id.clone());
+        if cipher_data.Favorite == Some(true) {
+            favorites.push(Favorite::new(&headers.user.uuid, &cipher.uuid));
+        }
+        apply_cipher_data(&mut cipher, cipher_data)?;
+        ciphers.push(cipher);
+    }
 
-    // Read the relations between collections and ciphers
-    let mut relations = Vec::new();
+    let mut collection_ciphers = Vec::new();
     for relation in data.CollectionRelationships {
-        relations.push((relation.Key, relation.Value));
+        match (ciphers.get(relation.Key), collection_uuids.get(relation.Value)) {
+            (Some(cipher), Some(coll_uuid)) => collection_ciphers.push(CollectionCipher {
+                cipher_uuid: cipher.uuid.clone(),
+                collection_uuid: coll_uuid.clone(),
+            }),
+            _ => err!("Failed to assign to collection"),
+        }
     }
 
-    // Read and create the ciphers
-    let ciphers: Vec<_> = data
-        .Ciphers
-        .into_iter()
-        .map(|cipher_data| {
-            let mut cipher = Cipher::new(cipher_data.Type, cipher_data.Name.clone());
-            update_cipher_from_data(
-                &mut cipher,
-                cipher_data,
-                &headers,
-                false,
-                &conn,
-                &nt,
-                UpdateType::CipherCreate,
-            )
-            .ok();
-            cipher
-        })
+    conn.in_transaction(|| {
+        for collection in collections.iter_mut() {
+            collection.save(&conn)?;
+        }
+        Cipher::insert_all(&ciphers, &conn)?;
+        CollectionCipher::insert_all(&collection_ciphers, &conn)?;
+        Favorite::insert_all(&favorites, &conn)
+    })?;
+
+    // A single update for each member, instead of one for each cipher
+    for member in UserOrganization::find_by_org(&org_id, &conn) {
+        if member.status != UserOrgStatus::Confirmed as i32 {
+            continue;
+        }
+
+        if let Some(mut user) = User::find_by_uuid(&member.user_uuid, &conn) {
+            user.update_revision(&conn)?;
+            nt.send_user_update(UpdateType::Vault, &user);
+        }
+    }
+    Ok(())
+}
+
+#[get("/organizations/<org_id>/domain")]
+fn get_org_domains(org_id: Uuid, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let domains_json: Vec<Value> = OrgDomain::find_by_org(&org_id, &conn)
+        .iter()
+        .map(OrgDomain::to_json)
         .collect();
 
-    // Assign the collections
-    for (cipher_index, coll_index) in relations {
-        let cipher_id = &ciphers[cipher_index].uuid;
-        let coll = &collections[coll_index];
-        let coll_id = match coll {
-            Ok(coll) => coll.uuid.as_str(),
-            Err(_) => err!("Failed to assign to collection"),
-        };
+    Ok(Json(json!({
+        "Data": domains_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct OrgDomainData {
+    DomainName: String,
+}
+
+#[post("/organizations/<org_id>/domain", data = "<data>")]
+fn post_org_domain(org_id: Uuid, data: JsonUpcase<OrgDomainData>, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let data: OrgDomainData = data.into_inner().data;
+    let domain_name = data.DomainName.trim().to_lowercase();
+
+    let valid_chars = domain_name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-');
+    if !valid_chars || !domain_name.contains('.') || domain_name.starts_with('.') || domain_name.ends_with('.') {
+        err!("Invalid domain name")
+    }
+
+    if OrgDomain::find_by_org_and_domain(&org_id, &domain_name, &conn).is_some() {
+        err!("The domain has already been added to the organization")
+    }
+
+    let domain = OrgDomain::new(org_id.into_string(), domain_name);
+    domain.save(&conn)?;
+
+    Ok(Json(domain.to_json()))
+}
 
-        CollectionCipher::save(cipher_id, coll_id, &conn)?;
+#[post("/organizations/<org_id>/domain/<domain_id>/verify")]
+fn verify_org_domain(org_id: Uuid, domain_id: Uuid, _headers: OwnerHeaders, conn: DbConn) -> JsonResult {
+    let mut domain = match OrgDomain::find_by_uuid_and_org(&domain_id, &org_id, &conn) {
+        Some(domain) => domain,
+        None => err!("Domain not found in the organization"),
+    };
+
+    if domain.is_verified() {
+        return Ok(Json(domain.to_json()));
     }
 
-    let mut user = headers.user;
-    user.update_revision(&conn)
+    let claimed_elsewhere = OrgDomain::find_verified_by_domain(&domain.domain_name, &conn)
+        .iter()
+        .any(|d| d.org_uuid != org_id);
+    if claimed_elsewhere {
+        err!("The domain has already been verified by another organization")
+    }
+
+    let now = chrono::Utc::now().naive_utc();
+    domain.last_checked_at = Some(now);
+
+    if _domain_has_txt_record(&domain.domain_name, &domain.txt_record())? {
+        info!("Domain {} verified for organization {}", domain.domain_name, org_id);
+        domain.verified_at = Some(now);
+    }
+
+    domain.save(&conn)?;
+    Ok(Json(domain.to_json()))
+}
+
+fn _domain_has_txt_record(domain_name: &str, expected: &str) -> Result<bool, crate::error::Error> {
+    use std::time::Duration;
+    use trust_dns_resolver::error::ResolveErrorKind;
+    use trust_dns_resolver::{system_conf, Resolver};
+
+    // The lookup runs inside the request, so a slow or unreachable server
+    // can't hold the worker for longer than a few seconds
+    let (config, mut opts) = system_conf::read_system_conf()?;
+    opts.timeout = Duration::from_secs(5);
+    opts.attempts = 1;
+
+    let resolver = Resolver::new(config, opts)?;
+
+    let lookup = match resolver.txt_lookup(domain_name) {
+        Ok(lookup) => lookup,
+        Err(e) => match e.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => return Ok(false),
+            _ => return Err(e.into()),
+        },
+    };
+
+    // A TXT record can be split in multiple strings, which need to be joined back
+    Ok(lookup.iter().any(|txt| {
+        let value: Vec<u8> = txt.txt_data().iter().flat_map(|part| part.iter().cloned()).collect();
+        crate::crypto::ct_eq(value, expected)
+    }))
+}
+
+#[delete("/organizations/<org_id>/domain/<domain_id>")]
+fn delete_org_domain(org_id: Uuid, domain_id: Uuid, _headers: OwnerHeaders, conn: DbConn) -> EmptyResult {
+    match OrgDomain::find_by_uuid_and_org(&domain_id, &org_id, &conn) {
+        Some(domain) => domain.delete(&conn),
+        None => err!("Domain not found in the organization"),
+    }
+}
+
+#[post("/organizations/<org_id>/domain/<domain_id>/delete")]
+fn post_delete_org_domain(org_id: Uuid, domain_id: Uuid, headers: OwnerHeaders, conn: DbConn) -> EmptyResult {
+    delete_org_domain(org_id, domain_id, headers, conn)
+}
+
+/// The activity of the organization, only available to its admins
+#[get("/organizations/<org_id>/events?<data..>")]
+fn get_org_events(org_id: Uuid, data: Form<EventRange>, _headers: AdminHeaders, conn: DbReadConn) -> JsonResult {
+    let cursor = data.cursor()?;
+    let last_uuid = cursor.last_uuid.as_ref().map(String::as_str);
+    let events = Event::find_by_org_and_date(&org_id, &cursor.start, &cursor.end, last_uuid, &conn);
+
+    Ok(Json(events_page(&events)))
+}
+
+/// Simple reporting for the owners, counted from the current data instead of the events
+#[get("/organizations/<org_id>/stats")]
+fn get_org_stats(org_id: Uuid, _headers: OwnerHeaders, conn: DbReadConn) -> JsonResult {
+    let count_ciphers = |type_: CipherType| Cipher::count_by_org_and_type(&org_id, type_ as i32, &conn);
+    let ciphers = [
+        count_ciphers(CipherType::Login),
+        count_ciphers(CipherType::SecureNote),
+        count_ciphers(CipherType::Card),
+        count_ciphers(CipherType::Identity),
+    ];
+
+    let count_members =
+        |status: UserOrgStatus| UserOrganization::count_by_org_and_status(&org_id, status as i32, &conn);
+    let members = [
+        count_members(UserOrgStatus::Invited),
+        count_members(UserOrgStatus::Accepted),
+        count_members(UserOrgStatus::Confirmed),
+        count_members(UserOrgStatus::Revoked),
+    ];
+
+    let storage = Attachment::size_by_org(&org_id, &conn);
+
+    Ok(Json(json!({
+        "Ciphers": {
+            "Total": ciphers.iter().sum::<i64>(),
+            "Login": ciphers[0],
+            "SecureNote": ciphers[1],
+            "Card": ciphers[2],
+            "Identity": ciphers[3],
+        },
+        "Collections": Collection::count_by_organization(&org_id, &conn),
+        "Members": {
+            "Total": members.iter().sum::<i64>(),
+            "Invited": members[0],
+            "Accepted": members[1],
+            "Confirmed": members[2],
+            "Revoked": members[3],
+        },
+        "Storage": storage,
+        "StorageName": util::get_display_size(storage),
+        "Object": "organizationStats",
+    })))
 }
@@ -0,0 +1,376 @@
+use rocket::Route;
+use rocket_contrib::json::Json;
+use serde_json::Value;
+
+use crate::api::{EmptyResult, JsonResult, JsonUpcase, NumberOrString};
+use crate::auth::{Headers, ProviderAdminHeaders, ProviderHeaders};
+use crate::db::models::*;
+use crate::db::DbConn;
+
+pub fn routes() -> Vec<Route> {
+    routes![
+        get_provider,
+        put_provider,
+        post_provider,
+        setup_provider,
+        get_provider_users,
+        invite_provider_users,
+        accept_provider_user,
+        confirm_provider_user,
+        delete_provider_user,
+        post_delete_provider_user,
+        get_provider_organizations,
+        create_provider_organization,
+        add_provider_organization,
+        delete_provider_organization,
+        post_delete_provider_organization,
+    ]
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderUpdateData {
+    Name: String,
+    BusinessName: Option<String>,
+    BillingEmail: String,
+}
+
+#[get("/providers/<provider_id>")]
+fn get_provider(provider_id: String, _headers: ProviderHeaders, conn: DbConn) -> JsonResult {
+    match Provider::find_by_uuid(&provider_id, &conn) {
+        Some(provider) => Ok(Json(provider.to_json())),
+        None => err!("Can't find provider details"),
+    }
+}
+
+#[put("/providers/<provider_id>", data = "<data>")]
+fn put_provider(
+    provider_id: String,
+    data: JsonUpcase<ProviderUpdateData>,
+    headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    post_provider(provider_id, data, headers, conn)
+}
+
+#[post("/providers/<provider_id>", data = "<data>")]
+fn post_provider(
+    provider_id: String,
+    data: JsonUpcase<ProviderUpdateData>,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: ProviderUpdateData = data.into_inner().data;
+
+    let mut provider = match Provider::find_by_uuid(&provider_id, &conn) {
+        Some(provider) => provider,
+        None => err!("Can't find provider details"),
+    };
+
+    provider.name = data.Name;
+    provider.business_name = data.BusinessName;
+    provider.billing_email = data.BillingEmail;
+
+    provider.save(&conn)?;
+    Ok(Json(provider.to_json()))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderSetupData {
+    Name: String,
+    BusinessName: Option<String>,
+    BillingEmail: String,
+    Key: String,
+}
+
+/// Completes the creation of a provider started in the admin panel.
+/// The key is the new provider key, encrypted with the user key of the provider admin.
+#[post("/providers/<provider_id>/setup", data = "<data>")]
+fn setup_provider(
+    provider_id: String,
+    data: JsonUpcase<ProviderSetupData>,
+    headers: Headers,
+    conn: DbConn,
+) -> JsonResult {
+    let data: ProviderSetupData = data.into_inner().data;
+
+    let mut provider = match Provider::find_by_uuid(&provider_id, &conn) {
+        Some(provider) => provider,
+        None => err!("Can't find provider details"),
+    };
+
+    if provider.status != ProviderStatus::Pending as i32 {
+        err!("Provider is already set up")
+    }
+
+    // Without it, the provider admin couldn't decrypt the keys of the client organizations
+    if data.Key.trim().is_empty() {
+        err!("The provider key is required")
+    }
+
+    let mut provider_user = match ProviderUser::find_by_user_and_provider(&headers.user.uuid, &provider.uuid, &conn) {
+        Some(provider_user) if provider_user.is_admin() => provider_user,
+        _ => err!("Only the provider admin can set up the provider"),
+    };
+
+    provider.name = data.Name;
+    provider.business_name = data.BusinessName;
+    provider.billing_email = data.BillingEmail;
+    provider.status = ProviderStatus::Created as i32;
+
+    provider_user.key = data.Key;
+    provider_user.status = ProviderUserStatus::Confirmed as i32;
+
+    provider.save(&conn)?;
+    provider_user.save(&conn)?;
+
+    Ok(Json(provider.to_json()))
+}
+
+#[get("/providers/<provider_id>/users")]
+fn get_provider_users(provider_id: String, _headers: ProviderAdminHeaders, conn: DbConn) -> JsonResult {
+    let users = ProviderUser::find_by_provider(&provider_id, &conn);
+    let users_json: Vec<Value> = users.iter().map(|u| u.to_json(&conn)).collect();
+
+    Ok(Json(json!({
+        "Data": users_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderInviteData {
+    Emails: Vec<String>,
+    Type: NumberOrString,
+}
+
+/// Only registered users can be invited, they accept the invitation themselves and
+/// then an admin confirms them by sending the provider key encrypted with their public key
+#[post("/providers/<provider_id>/users/invite", data = "<data>")]
+fn invite_provider_users(
+    provider_id: String,
+    data: JsonUpcase<ProviderInviteData>,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let data: ProviderInviteData = data.into_inner().data;
+
+    let new_type = match ProviderUserType::from_str(&data.Type.into_string()) {
+        Some(new_type) => new_type as i32,
+        None => err!("Invalid type"),
+    };
+
+    for email in data.Emails.iter() {
+        let user = match User::find_by_mail(&email, &conn) {
+            Some(user) => user,
+            None => err!(format!("User email does not exist: {}", email)),
+        };
+
+        if ProviderUser::find_by_user_and_provider(&user.uuid, &provider_id, &conn).is_some() {
+            err!(format!("User already in provider: {}", email))
+        }
+
+        let mut provider_user = ProviderUser::new(provider_id.clone(), user.uuid);
+        provider_user.type_ = new_type;
+        provider_user.save(&conn)?;
+    }
+
+    Ok(())
+}
+
+#[post("/providers/<provider_id>/users/<provider_user_id>/accept")]
+fn accept_provider_user(provider_id: String, provider_user_id: String, headers: Headers, conn: DbConn) -> EmptyResult {
+    let mut provider_user = match ProviderUser::find_by_uuid_and_provider(&provider_user_id, &provider_id, &conn) {
+        Some(provider_user) => provider_user,
+        None => err!("The specified user isn't a member of the provider"),
+    };
+
+    if provider_user.user_uuid != headers.user.uuid {
+        err!("The invitation is for a different user")
+    }
+
+    if provider_user.status != ProviderUserStatus::Invited as i32 {
+        err!("User already accepted the invitation")
+    }
+
+    provider_user.status = ProviderUserStatus::Accepted as i32;
+    provider_user.save(&conn)
+}
+
+#[post("/providers/<provider_id>/users/<provider_user_id>/confirm", data = "<data>")]
+fn confirm_provider_user(
+    provider_id: String,
+    provider_user_id: String,
+    data: JsonUpcase<Value>,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let data = data.into_inner().data;
+
+    let mut provider_user = match ProviderUser::find_by_uuid_and_provider(&provider_user_id, &provider_id, &conn) {
+        Some(provider_user) => provider_user,
+        None => err!("The specified user isn't a member of the provider"),
+    };
+
+    if provider_user.status != ProviderUserStatus::Accepted as i32 {
+        err!("User in invalid state")
+    }
+
+    provider_user.status = ProviderUserStatus::Confirmed as i32;
+    provider_user.key = match data["Key"].as_str() {
+        Some(key) => key.to_string(),
+        None => err!("Invalid key provided"),
+    };
+
+    provider_user.save(&conn)
+}
+
+#[delete("/providers/<provider_id>/users/<provider_user_id>")]
+fn delete_provider_user(
+    provider_id: String,
+    provider_user_id: String,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    let provider_user = match ProviderUser::find_by_uuid_and_provider(&provider_user_id, &provider_id, &conn) {
+        Some(provider_user) => provider_user,
+        None => err!("User to delete isn't member of the provider"),
+    };
+
+    if provider_user.is_admin() && ProviderUser::find_admins_by_provider(&provider_id, &conn).len() <= 1 {
+        err!("Can't delete the last provider admin")
+    }
+
+    provider_user.delete(&conn)
+}
+
+#[post("/providers/<provider_id>/users/<provider_user_id>/delete")]
+fn post_delete_provider_user(
+    provider_id: String,
+    provider_user_id: String,
+    headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    delete_provider_user(provider_id, provider_user_id, headers, conn)
+}
+
+#[get("/providers/<provider_id>/organizations")]
+fn get_provider_organizations(provider_id: String, _headers: ProviderHeaders, conn: DbConn) -> JsonResult {
+    let provider_orgs = ProviderOrganization::find_by_provider(&provider_id, &conn);
+    let provider_orgs_json: Vec<Value> = provider_orgs.iter().map(|o| o.to_json(&conn)).collect();
+
+    Ok(Json(json!({
+        "Data": provider_orgs_json,
+        "Object": "list",
+        "ContinuationToken": null,
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderOrgCreateData {
+    ClientOwnerEmail: String,
+    OrganizationCreateRequest: ProviderOrgData,
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderOrgData {
+    Name: String,
+    BillingEmail: String,
+    CollectionName: String,
+    Key: String,
+}
+
+/// Creates a client organization managed by the provider.
+/// The key is the organization key encrypted with the provider key, and the client owner
+/// is added as an accepted user, so a provider admin can confirm them with the organization key.
+#[post("/providers/<provider_id>/organizations", data = "<data>")]
+fn create_provider_organization(
+    provider_id: String,
+    data: JsonUpcase<ProviderOrgCreateData>,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: ProviderOrgCreateData = data.into_inner().data;
+    let org_data = data.OrganizationCreateRequest;
+
+    let owner = match User::find_by_mail(&data.ClientOwnerEmail, &conn) {
+        Some(owner) => owner,
+        None => err!("The client owner needs to have an account"),
+    };
+
+    let mut org = Organization::new(org_data.Name, org_data.BillingEmail);
+    let mut owner_org = UserOrganization::new(owner.uuid, org.uuid.clone());
+    let mut collection = Collection::new(org.uuid.clone(), org_data.CollectionName);
+    let provider_org = ProviderOrganization::new(provider_id, org.uuid.clone(), org_data.Key);
+
+    owner_org.access_all = true;
+    owner_org.type_ = UserOrgType::Owner as i32;
+
+    org.save(&conn)?;
+    owner_org.save(&conn)?;
+    collection.save(&conn)?;
+    provider_org.save(&conn)?;
+
+    Ok(Json(provider_org.to_json(&conn)))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct ProviderOrgAddData {
+    OrganizationId: String,
+    Key: String,
+}
+
+/// Adds an existing organization to the provider, the current user needs to be an owner of it
+#[post("/providers/<provider_id>/organizations/add", data = "<data>")]
+fn add_provider_organization(
+    provider_id: String,
+    data: JsonUpcase<ProviderOrgAddData>,
+    headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> JsonResult {
+    let data: ProviderOrgAddData = data.into_inner().data;
+
+    match UserOrganization::find_by_user_and_org(&headers.user.uuid, &data.OrganizationId, &conn) {
+        Some(ref user_org) if user_org.type_ == UserOrgType::Owner => (),
+        _ => err!("You need to be Owner of the organization to add it to the provider"),
+    }
+
+    if ProviderOrganization::find_by_org(&data.OrganizationId, &conn).is_some() {
+        err!("The organization is already managed by a provider")
+    }
+
+    let provider_org = ProviderOrganization::new(provider_id, data.OrganizationId, data.Key);
+    provider_org.save(&conn)?;
+
+    Ok(Json(provider_org.to_json(&conn)))
+}
+
+/// Stops managing the organization, which isn't deleted
+#[delete("/providers/<provider_id>/organizations/<provider_org_id>")]
+fn delete_provider_organization(
+    provider_id: String,
+    provider_org_id: String,
+    _headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    match ProviderOrganization::find_by_uuid_and_provider(&provider_org_id, &provider_id, &conn) {
+        Some(provider_org) => provider_org.delete(&conn),
+        None => err!("Organization isn't managed by the provider"),
+    }
+}
+
+#[post("/providers/<provider_id>/organizations/<provider_org_id>/delete")]
+fn post_delete_provider_organization(
+    provider_id: String,
+    provider_org_id: String,
+    headers: ProviderAdminHeaders,
+    conn: DbConn,
+) -> EmptyResult {
+    delete_provider_organization(provider_id, provider_org_id, headers, conn)
+}
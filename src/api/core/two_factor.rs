@@ -1,16 +1,21 @@
-use data_encoding::BASE32;
+use data_encoding::{BASE32, BASE64};
+use rocket::http::ContentType;
+use rocket::response::content::Content;
 use rocket_contrib::json::Json;
 use serde_json;
 use serde_json::Value;
 
+use crate::api::identity::register_failed_login;
 use crate::api::{ApiResult, EmptyResult, JsonResult, JsonUpcase, NumberOrString, PasswordData};
-use crate::auth::Headers;
+use crate::auth::{ClientIp, Headers};
 use crate::crypto;
 use crate::db::{
-    models::{TwoFactor, TwoFactorType, User},
+    models::{Device, TwoFactor, TwoFactorType, User},
     DbConn,
 };
 use crate::error::{Error, MapResult};
+use crate::mail;
+use crate::ratelimit::{self, EMAIL_TOKEN_LIMITER};
 
 use rocket::Route;
 
@@ -19,18 +24,27 @@ pub fn routes() -> Vec<Route> {
         get_twofactor,
         get_recover,
         recover,
+        revoke_twofactor_remember,
         disable_twofactor,
         disable_twofactor_put,
         generate_authenticator,
+        authenticator_qr,
         activate_authenticator,
         activate_authenticator_put,
         generate_u2f,
         generate_u2f_challenge,
+        generate_u2f_verify,
         activate_u2f,
         activate_u2f_put,
+        delete_u2f,
         generate_yubikey,
         activate_yubikey,
         activate_yubikey_put,
+        get_email,
+        send_email_login,
+        send_email,
+        activate_email,
+        activate_email_put,
     ]
 }
 
@@ -70,24 +84,30 @@ struct RecoverTwoFactor {
 }
 
 #[post("/two-factor/recover", data = "<data>")]
-fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn) -> JsonResult {
+fn recover(data: JsonUpcase<RecoverTwoFactor>, ip: ClientIp, conn: DbConn) -> JsonResult {
     let data: RecoverTwoFactor = data.into_inner().data;
+    ratelimit::check_login(&data.Email, &ip)?;
 
     use crate::db::models::User;
 
     // Get the user
     let mut user = match User::find_by_mail(&data.Email, &conn) {
         Some(user) => user,
-        None => err!("Username or password is incorrect. Try again."),
+        None => {
+            ratelimit::register_login_failure(&ip);
+            err!("Username or password is incorrect. Try again.")
+        }
     };
 
     // Check password
     if !user.check_valid_password(&data.MasterPasswordHash) {
+        ratelimit::register_login_failure(&ip);
         err!("Username or password is incorrect. Try again.")
     }
 
     // Check if recovery code is correct
     if !user.check_valid_recovery_code(&data.RecoveryCode) {
+        ratelimit::register_login_failure(&ip);
         err!("Recovery code is incorrect. Try again.")
     }
 
@@ -102,10 +122,23 @@ fn recover(data: JsonUpcase<RecoverTwoFactor>, conn: DbConn) -> JsonResult {
     Ok(Json(json!({})))
 }
 
+/// Forgets all the remembered devices, so the next login on any of them asks for the two factor again
+#[post("/two-factor/remember/revoke", data = "<data>")]
+fn revoke_twofactor_remember(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    Device::delete_twofactor_remember_by_user(&headers.user.uuid, &conn)
+}
+
 #[derive(Deserialize)]
 #[allow(non_snake_case)]
 struct DisableTwoFactorData {
     MasterPasswordHash: String,
+    TwoFactorToken: Option<String>,
     Type: NumberOrString,
 }
 
@@ -119,6 +152,8 @@ fn disable_twofactor(data: JsonUpcase<DisableTwoFactorData>, headers: Headers, c
         err!("Invalid password");
     }
 
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
     let type_ = data.Type.into_i32()?;
 
     if let Some(twofactor) = TwoFactor::find_by_user_and_type(&user.uuid, type_, &conn) {
@@ -154,17 +189,76 @@ fn generate_authenticator(data: JsonUpcase<PasswordData>, headers: Headers, conn
         _ => (false, BASE32.encode(&crypto::get_random(vec![0u8; 20]))),
     };
 
+    let uri = _authenticator_uri(&user.email, &key);
+    let qr_code = format!("data:image/png;base64,{}", BASE64.encode(&_generate_qr_code(&uri)?));
+
     Ok(Json(json!({
         "Enabled": enabled,
         "Key": key,
+        "Uri": uri,
+        "QrCode": qr_code,
         "Object": "twoFactorAuthenticator"
     })))
 }
 
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct AuthenticatorQrData {
+    MasterPasswordHash: String,
+    Key: String,
+}
+
+/// Returns the QR code of the key as a PNG image, for clients that don't generate it themselves
+#[post("/two-factor/authenticator/qr", data = "<data>")]
+fn authenticator_qr(data: JsonUpcase<AuthenticatorQrData>, headers: Headers) -> ApiResult<Content<Vec<u8>>> {
+    let data: AuthenticatorQrData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let uri = _authenticator_uri(&headers.user.email, &data.Key);
+    Ok(Content(ContentType::PNG, _generate_qr_code(&uri)?))
+}
+
+/// The otpauth URI understood by the authenticator apps, the issuer is configurable
+fn _authenticator_uri(email: &str, key: &str) -> String {
+    use rocket::http::uri::Uri;
+
+    let issuer = CONFIG.authenticator_issuer();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}",
+        Uri::percent_encode(&issuer),
+        Uri::percent_encode(email),
+        key.to_uppercase(),
+        Uri::percent_encode(&issuer)
+    )
+}
+
+fn _generate_qr_code(uri: &str) -> ApiResult<Vec<u8>> {
+    use image::{DynamicImage, ImageOutputFormat, Luma};
+    use qrcode::QrCode;
+
+    let code = match QrCode::new(uri.as_bytes()) {
+        Ok(code) => code,
+        Err(e) => err!("Error generating QR code", e.to_string()),
+    };
+
+    let image = code.render::<Luma<u8>>().min_dimensions(200, 200).build();
+
+    let mut png = Vec::new();
+    if let Err(e) = DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::PNG) {
+        err!("Error generating QR code", e.to_string())
+    }
+
+    Ok(png)
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct EnableAuthenticatorData {
     MasterPasswordHash: String,
+    TwoFactorToken: Option<String>,
     Key: String,
     Token: NumberOrString,
 }
@@ -182,6 +276,8 @@ fn activate_authenticator(data: JsonUpcase<EnableAuthenticatorData>, headers: He
         err!("Invalid password");
     }
 
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
     // Validate key as base32 and 20 bytes length
     let decoded_key: Vec<u8> = match BASE32.decode(key.as_bytes()) {
         Ok(decoded) => decoded,
@@ -193,15 +289,15 @@ fn activate_authenticator(data: JsonUpcase<EnableAuthenticatorData>, headers: He
     }
 
     let type_ = TwoFactorType::Authenticator;
-    let twofactor = TwoFactor::new(user.uuid.clone(), type_, key.to_uppercase());
+    let mut twofactor = TwoFactor::new(user.uuid.clone(), type_, key.to_uppercase());
 
     // Validate the token provided with the key
     if !twofactor.check_totp_code(token) {
         err!("Invalid totp code")
     }
 
-    _generate_recover_code(&mut user, &conn);
-    twofactor.save(&conn)?;
+    _generate_recover_code(&mut user, &conn)?;
+    twofactor.replace(&conn)?;
 
     Ok(Json(json!({
         "Enabled": true,
@@ -215,12 +311,93 @@ fn activate_authenticator_put(data: JsonUpcase<EnableAuthenticatorData>, headers
     activate_authenticator(data, headers, conn)
 }
 
-fn _generate_recover_code(user: &mut User, conn: &DbConn) {
+/// Before adding or removing a two factor method, the user needs to provide a code from one
+/// of the methods they already have enabled, unless that's disabled in the config.
+/// For U2F, the token is the response to the challenge from `/two-factor/get-u2f-verify`.
+fn _check_current_twofactor(user_uuid: &str, token: &Option<String>, conn: &DbConn) -> EmptyResult {
+    use num_traits::FromPrimitive;
+
+    if !CONFIG.twofactor_change_require_current() {
+        return Ok(());
+    }
+
+    let twofactors: Vec<TwoFactor> = TwoFactor::find_by_user(user_uuid, conn)
+        .into_iter()
+        .filter(|tf| tf.enabled)
+        .collect();
+
+    if twofactors.is_empty() {
+        return Ok(());
+    }
+
+    let token = match token {
+        Some(token) => token.trim(),
+        None => err!("A code from your current two factor method is required to change the two factor settings"),
+    };
+
+    for mut twofactor in twofactors {
+        let valid = match TwoFactorType::from_i32(twofactor.type_) {
+            Some(TwoFactorType::Authenticator) => match token.parse() {
+                Ok(code) => twofactor.check_totp_code(code) && twofactor.save(conn).is_ok(),
+                Err(_) => false,
+            },
+            Some(TwoFactorType::YubiKey) => validate_yubikey_login(user_uuid, token, conn).is_ok(),
+            Some(TwoFactorType::Email) => validate_email_code(user_uuid, token, conn).is_ok(),
+            Some(TwoFactorType::U2f) if token.starts_with('{') => validate_u2f_login(user_uuid, token, conn).is_ok(),
+            _ => false,
+        };
+
+        if valid {
+            return Ok(());
+        }
+    }
+
+    err!("Invalid two factor code")
+}
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{NaiveDateTime, Utc};
+
+/// The last clock drift detected for each user, only kept in memory for the admin diagnostics
+#[derive(Clone)]
+pub struct TotpDrift {
+    pub offset_seconds: i64,
+    pub detected_at: NaiveDateTime,
+}
+
+lazy_static! {
+    static ref TOTP_DRIFTS: Mutex<HashMap<String, TotpDrift>> = Mutex::new(HashMap::new());
+}
+
+/// Called when a TOTP code was rejected, but it would have been valid with a small clock difference
+pub fn register_totp_drift(user_uuid: &str, offset_seconds: i64) {
+    warn!(
+        "TOTP clock drift detected. User: {}, Offset: {} seconds",
+        user_uuid, offset_seconds
+    );
+
+    let drift = TotpDrift {
+        offset_seconds,
+        detected_at: Utc::now().naive_utc(),
+    };
+    TOTP_DRIFTS.lock().unwrap().insert(user_uuid.to_string(), drift);
+}
+
+/// Returns the detected drifts, with the uuid of the user
+pub fn get_totp_drifts() -> Vec<(String, TotpDrift)> {
+    let drifts = TOTP_DRIFTS.lock().unwrap();
+    drifts.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+}
+
+fn _generate_recover_code(user: &mut User, conn: &DbConn) -> EmptyResult {
     if user.totp_recover.is_none() {
         let totp_recover = BASE32.encode(&crypto::get_random(vec![0u8; 20]));
         user.totp_recover = Some(totp_recover);
-        user.save(conn).ok();
+        user.save(conn)?;
     }
+    Ok(())
 }
 
 use u2f::messages::{RegisterResponse, SignResponse, U2fSignRequest};
@@ -236,11 +413,16 @@ lazy_static! {
     static ref U2F: U2f = U2f::new(APP_ID.clone());
 }
 
-#[post("/two-factor/get-u2f", data = "<data>")]
-fn generate_u2f(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+fn _check_u2f_enabled() -> EmptyResult {
     if !CONFIG.domain_set() {
         err!("`DOMAIN` environment variable is not set. U2F disabled")
     }
+    Ok(())
+}
+
+#[post("/two-factor/get-u2f", data = "<data>")]
+fn generate_u2f(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _check_u2f_enabled()?;
     let data: PasswordData = data.into_inner().data;
 
     if !headers.user.check_valid_password(&data.MasterPasswordHash) {
@@ -259,6 +441,7 @@ fn generate_u2f(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn)
 
 #[post("/two-factor/get-u2f-challenge", data = "<data>")]
 fn generate_u2f_challenge(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    _check_u2f_enabled()?;
     let data: PasswordData = data.into_inner().data;
 
     if !headers.user.check_valid_password(&data.MasterPasswordHash) {
@@ -276,12 +459,41 @@ fn generate_u2f_challenge(data: JsonUpcase<PasswordData>, headers: Headers, conn
     })))
 }
 
+/// Generates a U2F challenge for the already registered keys, used to confirm changes to the two factor settings
+#[post("/two-factor/get-u2f-verify", data = "<data>")]
+fn generate_u2f_verify(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+
+    if !headers.user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let request = generate_u2f_login(&headers.user.uuid, &conn)?;
+    let keys: Vec<Value> = request
+        .registered_keys
+        .into_iter()
+        .map(|key| {
+            json!({
+                "Version": key.version,
+                "KeyHandle": key.key_handle,
+            })
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "AppId": request.app_id,
+        "Challenge": request.challenge,
+        "Keys": keys,
+    })))
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct EnableU2FData {
     Id: NumberOrString, // 1..5
     Name: String,
     MasterPasswordHash: String,
+    TwoFactorToken: Option<String>,
     DeviceResponse: String,
 }
 
@@ -347,6 +559,8 @@ fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn)
         err!("Invalid password");
     }
 
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
     let tf_type = TwoFactorType::U2fRegisterChallenge as i32;
     let tf_challenge = match TwoFactor::find_by_user_and_type(&user.uuid, tf_type, &conn) {
         Some(c) => c,
@@ -367,9 +581,14 @@ fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn)
         err!("Error registering U2F token")
     }
 
+    let id = data.Id.into_i32()?;
+    if id < 1 || id > 5 {
+        err!("Invalid U2F key id")
+    }
+
     let registration = U2F.register_response(challenge.clone(), response.into())?;
     let full_registration = U2FRegistration {
-        id: data.Id.into_i32()?,
+        id,
         name: data.Name,
         reg: registration,
         compromised: false,
@@ -378,11 +597,13 @@ fn activate_u2f(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbConn)
 
     let mut regs = get_u2f_registrations(&user.uuid, &conn)?.1;
 
-    // TODO: Check that there is no repeat Id
+    // Registering a key with the Id of an existing one replaces it
+    regs.retain(|r| r.id != id);
     regs.push(full_registration);
+    regs.sort_by_key(|r| r.id);
     save_u2f_registrations(&user.uuid, &regs, &conn)?;
 
-    _generate_recover_code(&mut user, &conn);
+    _generate_recover_code(&mut user, &conn)?;
 
     let keys_json: Vec<Value> = regs.iter().map(|r| r.to_json()).collect();
     Ok(Json(json!({
@@ -397,18 +618,62 @@ fn activate_u2f_put(data: JsonUpcase<EnableU2FData>, headers: Headers, conn: DbC
     activate_u2f(data, headers, conn)
 }
 
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct DeleteU2FData {
+    Id: NumberOrString,
+    MasterPasswordHash: String,
+    TwoFactorToken: Option<String>,
+}
+
+/// Removes a single key, U2F is disabled when the last one is removed
+#[delete("/two-factor/u2f", data = "<data>")]
+fn delete_u2f(data: JsonUpcase<DeleteU2FData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: DeleteU2FData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
+    let id = data.Id.into_i32()?;
+    let mut regs = get_u2f_registrations(&user.uuid, &conn)?.1;
+
+    if !regs.iter().any(|r| r.id == id) {
+        err!("U2F key not found")
+    }
+    regs.retain(|r| r.id != id);
+
+    if regs.is_empty() {
+        if let Some(twofactor) = TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::U2f as i32, &conn) {
+            twofactor.delete(&conn)?;
+        }
+    } else {
+        save_u2f_registrations(&user.uuid, &regs, &conn)?;
+    }
+
+    let keys_json: Vec<Value> = regs.iter().map(|r| r.to_json()).collect();
+    Ok(Json(json!({
+        "Enabled": !regs.is_empty(),
+        "Keys": keys_json,
+        "Object": "twoFactorU2f"
+    })))
+}
+
 fn _create_u2f_challenge(user_uuid: &str, type_: TwoFactorType, conn: &DbConn) -> Challenge {
     let challenge = U2F.generate_challenge().unwrap();
 
     TwoFactor::new(user_uuid.into(), type_, serde_json::to_string(&challenge).unwrap())
-        .save(conn)
+        .replace(conn)
         .expect("Error saving challenge");
 
     challenge
 }
 
 fn save_u2f_registrations(user_uuid: &str, regs: &[U2FRegistration], conn: &DbConn) -> EmptyResult {
-    TwoFactor::new(user_uuid.into(), TwoFactorType::U2f, serde_json::to_string(regs)?).save(&conn)
+    TwoFactor::new(user_uuid.into(), TwoFactorType::U2f, serde_json::to_string(regs)?).replace(&conn)
 }
 
 fn get_u2f_registrations(user_uuid: &str, conn: &DbConn) -> Result<(bool, Vec<U2FRegistration>), Error> {
@@ -521,6 +786,7 @@ pub fn validate_u2f_login(user_uuid: &str, response: &str, conn: &DbConn) -> Emp
 #[allow(non_snake_case)]
 struct EnableYubikeyData {
     MasterPasswordHash: String,
+    TwoFactorToken: Option<String>,
     Key1: Option<String>,
     Key2: Option<String>,
     Key3: Option<String>,
@@ -556,6 +822,10 @@ fn jsonify_yubikeys(yubikeys: Vec<String>) -> serde_json::Value {
 }
 
 fn get_yubico_credentials() -> Result<(String, String), Error> {
+    if !CONFIG.yubico_enabled() {
+        err!("Yubikey OTP is disabled in this server")
+    }
+
     match (CONFIG.yubico_client_id(), CONFIG.yubico_secret_key()) {
         (Some(id), Some(secret)) => Ok((id, secret)),
         _ => err!("`YUBICO_CLIENT_ID` or `YUBICO_SECRET_KEY` environment variable is not set. Yubikey OTP Disabled"),
@@ -600,13 +870,13 @@ fn generate_yubikey(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbCo
 
         result["Enabled"] = Value::Bool(true);
         result["Nfc"] = Value::Bool(yubikey_metadata.Nfc);
-        result["Object"] = Value::String("twoFactorU2f".to_owned());
+        result["Object"] = Value::String("twoFactorYubiKey".to_owned());
 
         Ok(Json(result))
     } else {
         Ok(Json(json!({
             "Enabled": false,
-            "Object": "twoFactorU2f",
+            "Object": "twoFactorYubiKey",
         })))
     }
 }
@@ -620,6 +890,8 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
         err!("Invalid password");
     }
 
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
     // Check if we already have some data
     let mut yubikey_data = match TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::YubiKey as i32, &conn) {
         Some(data) => data,
@@ -631,12 +903,16 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
     if yubikeys.is_empty() {
         return Ok(Json(json!({
             "Enabled": false,
-            "Object": "twoFactorU2f",
+            "Object": "twoFactorYubiKey",
         })));
     }
 
     // Ensure they are valid OTPs
     for yubikey in &yubikeys {
+        if !yubikey.chars().all(|c| c.is_ascii_alphanumeric()) || (yubikey.len() != 12 && yubikey.len() != 44) {
+            err!("Invalid Yubikey OTP provided");
+        }
+
         if yubikey.len() == 12 {
             // YubiKey ID
             continue;
@@ -653,15 +929,15 @@ fn activate_yubikey(data: JsonUpcase<EnableYubikeyData>, headers: Headers, conn:
     };
 
     yubikey_data.data = serde_json::to_string(&yubikey_metadata).unwrap();
-    yubikey_data.save(&conn)?;
+    yubikey_data.replace(&conn)?;
 
-    _generate_recover_code(&mut user, &conn);
+    _generate_recover_code(&mut user, &conn)?;
 
     let mut result = jsonify_yubikeys(yubikey_metadata.Keys);
 
     result["Enabled"] = Value::Bool(true);
     result["Nfc"] = Value::Bool(yubikey_metadata.Nfc);
-    result["Object"] = Value::String("twoFactorU2f".to_owned());
+    result["Object"] = Value::String("twoFactorYubiKey".to_owned());
 
     Ok(Json(result))
 }
@@ -683,11 +959,11 @@ pub fn validate_yubikey_login(user_uuid: &str, response: &str, conn: &DbConn) ->
         None => err!("No YubiKey devices registered"),
     };
 
-    let yubikey_metadata: YubikeyMetadata =
-        serde_json::from_str(&twofactor.data).expect("Can't parse Yubikey Metadata");
-    let response_id = &response[..12];
+    let yubikey_metadata: YubikeyMetadata = serde_json::from_str(&twofactor.data)?;
+    // The length is in bytes, so the id is taken by chars to not split a multibyte char
+    let response_id: String = response.chars().take(12).collect();
 
-    if !yubikey_metadata.Keys.contains(&response_id.to_owned()) {
+    if !yubikey_metadata.Keys.contains(&response_id) {
         err!("Given Yubikey is not registered");
     }
 
@@ -698,3 +974,266 @@ pub fn validate_yubikey_login(user_uuid: &str, response: &str, conn: &DbConn) ->
         Err(_e) => err!("Failed to verify Yubikey against OTP server"),
     }
 }
+
+/// The address and the last code sent, stored in the data of the email two factor
+#[derive(Serialize, Deserialize)]
+pub struct EmailTokenData {
+    pub email: String,
+    last_token: Option<String>,
+    token_sent: i64,
+    attempts: u32,
+}
+
+impl EmailTokenData {
+    fn new(email: String) -> Self {
+        Self {
+            email,
+            last_token: None,
+            token_sent: 0,
+            attempts: 0,
+        }
+    }
+
+    /// Generates a new code, replacing the previous one
+    fn new_token(&mut self) -> String {
+        let token = crypto::get_random_digits(CONFIG.email_token_size() as usize);
+
+        self.last_token = Some(token.clone());
+        self.token_sent = Utc::now().timestamp();
+        self.attempts = 0;
+        token
+    }
+
+    /// Each code can only be used once, and it's also discarded when it expires or after too many wrong attempts
+    fn check_token(&mut self, token: &str) -> EmptyResult {
+        let valid = match &self.last_token {
+            Some(last_token) => crypto::ct_eq(last_token, token.trim()),
+            None => err!("No code has been sent, request a new one"),
+        };
+
+        if Utc::now().timestamp() - self.token_sent > CONFIG.email_expiration_time() {
+            self.last_token = None;
+            err!("The code has expired, request a new one")
+        }
+
+        if !valid {
+            self.attempts += 1;
+            if self.attempts >= CONFIG.email_attempts_limit() {
+                self.last_token = None;
+                err!("Too many wrong codes, request a new one")
+            }
+            err!("Invalid code")
+        }
+
+        self.last_token = None;
+        Ok(())
+    }
+
+    /// Hides most of the address, it's shown in the login page before the user is authenticated
+    pub fn obscured_email(&self) -> String {
+        let mut parts = self.email.splitn(2, '@');
+        let name = parts.next().unwrap_or_default();
+        let domain = parts.next().unwrap_or_default();
+
+        let shown: String = name.chars().take(2).collect();
+        let hidden = "*".repeat(name.chars().count().saturating_sub(2));
+        format!("{}{}@{}", shown, hidden, domain)
+    }
+}
+
+fn _check_email_2fa_enabled() -> EmptyResult {
+    if !CONFIG.mail_enabled() {
+        err!("The mail service is disabled. Email two factor is not available")
+    }
+    Ok(())
+}
+
+#[post("/two-factor/get-email", data = "<data>")]
+fn get_email(data: JsonUpcase<PasswordData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: PasswordData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    let email = match TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::Email as i32, &conn) {
+        Some(twofactor) => Some(serde_json::from_str::<EmailTokenData>(&twofactor.data)?.email),
+        None => None,
+    };
+    let enabled = email.is_some();
+
+    Ok(Json(json!({
+        "Email": email,
+        "Enabled": enabled,
+        "Object": "twoFactorEmail"
+    })))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SendEmailLoginData {
+    Email: String,
+    MasterPasswordHash: String,
+}
+
+/// Requested by the login page when the user chooses the email two factor, before having a token.
+/// It checks the password like the login, so it has the same limits, and every failure gives the same error,
+/// to not tell which accounts exist, are locked or have the email two factor
+#[post("/two-factor/send-email-login", data = "<data>")]
+fn send_email_login(data: JsonUpcase<SendEmailLoginData>, ip: ClientIp, conn: DbConn) -> EmptyResult {
+    let data: SendEmailLoginData = data.into_inner().data;
+
+    _check_email_2fa_enabled()?;
+    ratelimit::check_login(&data.Email, &ip)?;
+
+    let log_value = format!("IP: {}. Username: {}.", ip.ip, data.Email);
+    let mut user = match User::find_by_mail(&data.Email, &conn) {
+        Some(user) => user,
+        None => {
+            ratelimit::register_login_failure(&ip);
+            err!("Username or password is incorrect. Try again.", log_value)
+        }
+    };
+
+    if user.is_locked() {
+        err!("Username or password is incorrect. Try again.", log_value)
+    }
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        register_failed_login(&mut user, &ip, &conn);
+        ratelimit::register_login_failure(&ip);
+        err!("Username or password is incorrect. Try again.", log_value)
+    }
+
+    if TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::Email as i32, &conn).is_none() {
+        err!("Username or password is incorrect. Try again.", log_value)
+    }
+
+    send_email_login_code(&user, &conn)
+}
+
+/// Sends a new login code to the address of the email two factor of the user
+pub fn send_email_login_code(user: &User, conn: &DbConn) -> EmptyResult {
+    let mut twofactor = match TwoFactor::find_by_user_and_type(&user.uuid, TwoFactorType::Email as i32, conn) {
+        Some(twofactor) => twofactor,
+        None => err!("Email two factor is not enabled"),
+    };
+
+    let mut token_data: EmailTokenData = serde_json::from_str(&twofactor.data)?;
+    let token = token_data.new_token();
+
+    twofactor.data = serde_json::to_string(&token_data)?;
+    twofactor.save(conn)?;
+
+    mail::send_token(&token_data.email, &token, user.locale.as_ref().map(String::as_str))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct SendEmailData {
+    Email: String,
+    MasterPasswordHash: String,
+}
+
+/// Sends a code to the address being set up, the two factor is enabled when the code is confirmed
+#[post("/two-factor/send-email", data = "<data>")]
+fn send_email(data: JsonUpcase<SendEmailData>, headers: Headers, conn: DbConn) -> EmptyResult {
+    let data: SendEmailData = data.into_inner().data;
+    let user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    _check_email_2fa_enabled()?;
+
+    // Each code is sent to the address chosen by the user, so they are limited to not be used to send spam
+    if !EMAIL_TOKEN_LIMITER.check(&user.uuid, CONFIG.email_tokens_per_hour()) {
+        err_limit!(
+            "Too many codes sent. Try again later",
+            format!("User: {}. Email: {}.", user.uuid, data.Email)
+        )
+    }
+
+    let mut token_data = EmailTokenData::new(data.Email.trim().to_lowercase());
+    let token = token_data.new_token();
+
+    let challenge_type = TwoFactorType::EmailVerificationChallenge;
+    TwoFactor::new(user.uuid.clone(), challenge_type, serde_json::to_string(&token_data)?).replace(&conn)?;
+
+    mail::send_token(&token_data.email, &token, user.locale.as_ref().map(String::as_str))
+}
+
+#[derive(Deserialize)]
+#[allow(non_snake_case)]
+struct EmailData {
+    Email: String,
+    MasterPasswordHash: String,
+    Token: String,
+    TwoFactorToken: Option<String>,
+}
+
+#[post("/two-factor/email", data = "<data>")]
+fn activate_email(data: JsonUpcase<EmailData>, headers: Headers, conn: DbConn) -> JsonResult {
+    let data: EmailData = data.into_inner().data;
+    let mut user = headers.user;
+
+    if !user.check_valid_password(&data.MasterPasswordHash) {
+        err!("Invalid password");
+    }
+
+    _check_current_twofactor(&user.uuid, &data.TwoFactorToken, &conn)?;
+
+    let challenge_type = TwoFactorType::EmailVerificationChallenge as i32;
+    let mut challenge = match TwoFactor::find_by_user_and_type(&user.uuid, challenge_type, &conn) {
+        Some(challenge) => challenge,
+        None => err!("No code has been sent, request a new one"),
+    };
+
+    let mut token_data: EmailTokenData = serde_json::from_str(&challenge.data)?;
+
+    if token_data.email != data.Email.trim().to_lowercase() {
+        err!("The code was sent to a different address")
+    }
+
+    if let Err(e) = token_data.check_token(&data.Token) {
+        challenge.data = serde_json::to_string(&token_data)?;
+        challenge.save(&conn)?;
+        return Err(e);
+    }
+    challenge.delete(&conn)?;
+
+    let email = token_data.email.clone();
+    let data = serde_json::to_string(&token_data)?;
+    TwoFactor::new(user.uuid.clone(), TwoFactorType::Email, data).replace(&conn)?;
+
+    _generate_recover_code(&mut user, &conn)?;
+
+    Ok(Json(json!({
+        "Email": email,
+        "Enabled": true,
+        "Object": "twoFactorEmail"
+    })))
+}
+
+#[put("/two-factor/email", data = "<data>")]
+fn activate_email_put(data: JsonUpcase<EmailData>, headers: Headers, conn: DbConn) -> JsonResult {
+    activate_email(data, headers, conn)
+}
+
+pub fn validate_email_code(user_uuid: &str, token: &str, conn: &DbConn) -> EmptyResult {
+    let mut twofactor = match TwoFactor::find_by_user_and_type(user_uuid, TwoFactorType::Email as i32, conn) {
+        Some(twofactor) => twofactor,
+        None => err!("Email two factor is not enabled"),
+    };
+
+    let mut token_data: EmailTokenData = serde_json::from_str(&twofactor.data)?;
+    let result = token_data.check_token(token);
+
+    // The attempts and the used code are stored in both cases
+    twofactor.data = serde_json::to_string(&token_data)?;
+    twofactor.save(conn)?;
+
+    result
+}
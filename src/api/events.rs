@@ -0,0 +1,261 @@
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+use rocket::Route;
+use serde_json::Value;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::api::{ApiResult, EmptyResult, JsonUpcaseVec, Notify};
+use crate::auth::Headers;
+use crate::bus::{DomainEvent, Subscriber};
+use crate::db::models::*;
+use crate::db::{DbConn, Pool};
+use crate::util;
+
+pub fn routes() -> Vec<Route> {
+    routes![post_events_collect]
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct EventCollection {
+    Type: i32,
+    CipherId: Option<String>,
+    Date: Option<String>,
+}
+
+/// The clients report here the events that happen only on their side, like viewing a password.
+/// They are sent in batches and the invalid ones are ignored, so a single event doesn't make the clients retry the rest.
+/// The events are only stored for the ciphers of the organizations that have them enabled
+#[post("/collect", format = "application/json", data = "<data>")]
+fn post_events_collect(
+    data: JsonUpcaseVec<EventCollection>,
+    headers: Headers,
+    conn: DbConn,
+    nt: Notify,
+) -> EmptyResult {
+    for event in data.into_inner().into_iter().map(|d| d.data) {
+        let event_type = match EventType::from_client(event.Type) {
+            Some(event_type) => event_type,
+            None => continue,
+        };
+
+        let cipher = match event.CipherId.and_then(|id| Cipher::find_by_uuid(&id, &conn)) {
+            Some(cipher) => cipher,
+            None => continue,
+        };
+
+        let org = match cipher.organization_uuid {
+            Some(ref org_uuid) => Organization::find_by_uuid(org_uuid, &conn),
+            None => None,
+        };
+
+        match org {
+            Some(ref org) if org.use_events => (),
+            _ => continue,
+        }
+
+        if !cipher.is_accessible_to_user(&headers.user.uuid, &conn) {
+            continue;
+        }
+
+        let mut new_event = Event::new(event_type, Some(headers.user.uuid.clone()));
+        new_event.org_uuid = cipher.organization_uuid.clone();
+        new_event.cipher_uuid = Some(cipher.uuid);
+        new_event.set_device(&headers.device);
+
+        // The date can't be in the future, to keep the order of the events
+        let now = Utc::now().naive_utc();
+        new_event.event_date = match event.Date.as_ref().and_then(|d| util::parse_date(d)) {
+            Some(date) if date < now => date,
+            _ => now,
+        };
+
+        nt.log_event(new_event);
+    }
+
+    Ok(())
+}
+
+/// The events waiting to be saved, the newer ones are dropped when the database can't keep up
+const MAX_QUEUED_EVENTS: usize = 10_000;
+
+/// Saves the events of the activity log in its own thread, so the requests don't wait for them,
+/// and a slow database doesn't hold back the other subscribers of the bus, like the websocket notifications
+pub struct EventLog {
+    // The SyncSender can't be shared between the threads, so it's behind a lock
+    sender: Mutex<mpsc::SyncSender<Event>>,
+}
+
+impl EventLog {
+    pub fn new(pool: Pool) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_EVENTS);
+
+        thread::Builder::new()
+            .name("event-log".into())
+            .spawn(move || {
+                for event in receiver {
+                    _save_event(event, &pool);
+                }
+            })
+            .expect("Error starting the event log");
+
+        Self {
+            sender: Mutex::new(sender),
+        }
+    }
+}
+
+impl Subscriber for EventLog {
+    fn handle(&self, event: &DomainEvent, _published_at: NaiveDateTime) {
+        let event = match event {
+            DomainEvent::Log(event) => event,
+            _ => return,
+        };
+
+        if self.sender.lock().unwrap().try_send(event.clone()).is_err() {
+            error!("The event log queue is full, an event was dropped");
+        }
+    }
+}
+
+fn _save_event(event: Event, pool: &Pool) {
+    let conn = match pool.get() {
+        Ok(conn) => DbConn(conn),
+        Err(e) => {
+            error!("The event log can't get a database connection: {:#?}", e);
+            return;
+        }
+    };
+
+    match (&event.org_uuid, &event.user_uuid) {
+        (Some(org_uuid), _) => _save_org_event(event.clone(), org_uuid, &conn),
+        (None, Some(user_uuid)) => {
+            for member in UserOrganization::find_by_user(user_uuid, &conn) {
+                let member_event = Event {
+                    uuid: util::get_uuid(),
+                    org_user_uuid: Some(member.uuid.clone()),
+                    ..event.clone()
+                };
+                _save_org_event(member_event, &member.org_uuid, &conn);
+            }
+        }
+        (None, None) => (),
+    }
+}
+
+/// Saves the event in the organization, only if it has the events enabled.
+/// The request has already succeeded, so an event that can't be saved is only logged
+fn _save_org_event(mut event: Event, org_uuid: &str, conn: &DbConn) {
+    match Organization::find_by_uuid(org_uuid, conn) {
+        Some(ref org) if org.use_events => (),
+        _ => return,
+    }
+
+    event.org_uuid = Some(org_uuid.to_string());
+    if let Err(e) = event.save(conn) {
+        error!("Error saving event: {:#?}", e);
+    }
+}
+
+/// Only the changes to organization ciphers are logged, for the organization admins
+pub fn log_cipher_event(event_type: EventType, cipher: &Cipher, user_uuid: &str, device: &Device, nt: &Notify) {
+    if let Some(ref org_uuid) = cipher.organization_uuid {
+        let mut event = Event::new(event_type, Some(user_uuid.to_string()));
+        event.org_uuid = Some(org_uuid.clone());
+        event.cipher_uuid = Some(cipher.uuid.clone());
+        event.set_device(device);
+        nt.log_event(event);
+    }
+}
+
+/// The changes to a member of an organization, made by the acting user
+pub fn log_member_event(
+    event_type: EventType,
+    member: &UserOrganization,
+    acting_user_uuid: &str,
+    device: &Device,
+    nt: &Notify,
+) {
+    let mut event = Event::new(event_type, Some(acting_user_uuid.to_string()));
+    event.org_uuid = Some(member.org_uuid.clone());
+    event.org_user_uuid = Some(member.uuid.clone());
+    event.set_device(device);
+    nt.log_event(event);
+}
+
+/// The events of the account, like the logins, are logged in every organization the user is confirmed in
+pub fn log_user_event(event_type: EventType, user_uuid: &str, device_type: i32, ip: &str, nt: &Notify) {
+    let mut event = Event::new(event_type, Some(user_uuid.to_string()));
+    event.device_type = Some(device_type);
+    event.ip_address = Some(ip.to_string());
+    nt.log_event(event);
+}
+
+#[derive(FromForm)]
+pub struct EventRange {
+    start: Option<String>,
+    end: Option<String>,
+    #[form(field = "continuationToken")]
+    continuation_token: Option<String>,
+}
+
+/// Where the requested page starts and ends. The events are sorted by date and id, newest first,
+/// and a page continues after the event with the date `end` and the id `last_uuid`
+pub struct EventCursor {
+    pub start: NaiveDateTime,
+    pub end: NaiveDateTime,
+    pub last_uuid: Option<String>,
+}
+
+impl EventRange {
+    /// The range of the requested page, by default the last 30 days
+    pub fn cursor(&self) -> ApiResult<EventCursor> {
+        let (end, last_uuid) = match self.continuation_token {
+            Some(ref token) => {
+                let mut parts = token.splitn(2, '|');
+                match (parts.next().and_then(util::parse_date), parts.next()) {
+                    (Some(date), Some(uuid)) => (date, Some(uuid.to_string())),
+                    _ => err!("Invalid continuation token"),
+                }
+            }
+            None => match self.end {
+                Some(ref date) => match util::parse_date(date) {
+                    Some(date) => (date, None),
+                    None => err!("Invalid end date"),
+                },
+                None => (Utc::now().naive_utc(), None),
+            },
+        };
+        let start = match self.start {
+            Some(ref date) => match util::parse_date(date) {
+                Some(date) => date,
+                None => err!("Invalid start date"),
+            },
+            None => end - Duration::days(30),
+        };
+
+        Ok(EventCursor { start, end, last_uuid })
+    }
+}
+
+/// The events are returned in pages, the continuation token is the date and the id of the last event returned,
+/// so the events with the same date aren't skipped or repeated
+pub fn events_page(events: &[Event]) -> Value {
+    let continuation_token = if events.len() as i64 == Event::PAGE_SIZE {
+        events
+            .last()
+            .map(|e| format!("{}|{}", util::format_date(&e.event_date), e.uuid))
+    } else {
+        None
+    };
+    let events_json: Vec<Value> = events.iter().map(Event::to_json).collect();
+
+    json!({
+        "Data": events_json,
+        "Object": "list",
+        "ContinuationToken": continuation_token,
+    })
+}
@@ -1,19 +1,26 @@
-use std::fs::{create_dir_all, remove_file, symlink_metadata, File};
-use std::io::prelude::*;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, remove_file, rename, symlink_metadata, File};
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 use rocket::http::ContentType;
 use rocket::response::Content;
 use rocket::Route;
 
-use reqwest::{header::HeaderMap, Client, Response};
+use reqwest::{header::HeaderMap, Client, Proxy, RedirectPolicy, Response, Url};
 
 use rocket::http::{Cookie};
 
 use regex::Regex;
 use soup::prelude::*;
 
+use crate::crypto;
 use crate::error::Error;
+use crate::listener;
+use crate::telemetry;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
@@ -22,14 +29,47 @@ pub fn routes() -> Vec<Route> {
 
 const FALLBACK_ICON: &[u8; 344] = include_bytes!("../static/fallback-icon.png");
 
+// The pages and the icons are only read up to these sizes, bigger icons are skipped
+const MAX_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_ICON_SIZE: u64 = 512 * 1024;
+
+// Maximum number of icons refreshed at the same time in the background
+const MAX_ICON_REFRESHES: usize = 8;
+
 lazy_static! {
     // Reuse the client between requests
     static ref CLIENT: Client = Client::builder()
         .gzip(true)
         .timeout(Duration::from_secs(CONFIG.icon_download_timeout()))
         .default_headers(_header_map())
+        // The addresses of every host, including the redirects, are checked by the proxy
+        .proxy(
+            Proxy::all(format!("http://{}", ICON_PROXY.addr).as_str())
+                .unwrap()
+                .basic_auth(ICON_PROXY_USER, &ICON_PROXY.secret),
+        )
+        .redirect(RedirectPolicy::custom(|attempt| {
+            // Check the redirects too, as an allowed domain could redirect to a blacklisted one
+            let blacklisted = attempt.url().host_str().map_or(true, is_domain_blacklisted);
+
+            if blacklisted {
+                attempt.stop()
+            } else if attempt.previous().len() > 10 {
+                attempt.too_many_redirects()
+            } else {
+                attempt.follow()
+            }
+        }))
         .build()
         .unwrap();
+    static ref ICON_REL_REGEX: Regex = Regex::new(r"(?i)icon$|apple.*icon").unwrap();
+    static ref ICON_HREF_REGEX: Regex = Regex::new(r"(?i)\w+\.(jpg|jpeg|png|ico)(\?.*)?$").unwrap();
+    static ref ICON_SIZE_REGEX: Regex = Regex::new(r"(?x)(\d+)\D*(\d+)").unwrap();
+    // The domains whose icons are being refreshed
+    static ref REFRESHING_ICONS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // The blacklist is only compiled again when it's changed in the config
+    static ref ICON_BLACKLIST: Mutex<Option<(String, Regex)>> = Mutex::new(None);
+    static ref ICON_PROXY: IconProxy = IconProxy::start();
 }
 
 #[get("/<domain>/icon.png")]
@@ -48,19 +88,27 @@ fn icon(domain: String) -> Content<Vec<u8>> {
 
 fn get_icon(domain: &str) -> Vec<u8> {
     let path = format!("{}/{}.png", CONFIG.icon_cache_folder(), domain);
+    let negcached = icon_is_negcached(&path);
 
     if let Some(icon) = get_cached_icon(&path) {
+        // The expired icons are still served while they are downloaded again in the background,
+        // unless the last download failed, so the request is never blocked by a slow domain
+        if icon_is_expired(&path) && !negcached && !CONFIG.disable_icon_download() {
+            refresh_icon(domain, &path);
+        }
         return icon;
     }
 
-    if CONFIG.disable_icon_download() {
+    if negcached || CONFIG.disable_icon_download() {
         return FALLBACK_ICON.to_vec();
     }
 
     // Get the icon, or fallback in case of error
     match download_icon(&domain) {
         Ok(icon) => {
-            save_icon(&path, &icon);
+            if let Err(e) = save_icon(&path, &icon) {
+                error!("Error saving icon {:?}: {:?}", path, e);
+            }
             icon
         }
         Err(e) => {
@@ -72,16 +120,6 @@ fn get_icon(domain: &str) -> Vec<u8> {
 }
 
 fn get_cached_icon(path: &str) -> Option<Vec<u8>> {
-    // Check for expiration of negatively cached copy
-    if icon_is_negcached(path) {
-        return Some(FALLBACK_ICON.to_vec());
-    }
-
-    // Check for expiration of successfully cached copy
-    if icon_is_expired(path) {
-        return None;
-    }
-
     // Try to read the cached icon, and return it if it exists
     if let Ok(mut f) = File::open(path) {
         let mut buffer = Vec::new();
@@ -94,6 +132,49 @@ fn get_cached_icon(path: &str) -> Option<Vec<u8>> {
     None
 }
 
+/// Downloads the icon again in a background thread. The domains already being refreshed are skipped,
+/// and so are all of them when there are too many refreshes running, they are retried on the next request
+fn refresh_icon(domain: &str, path: &str) {
+    {
+        let mut refreshing = REFRESHING_ICONS.lock().unwrap();
+        if refreshing.len() >= MAX_ICON_REFRESHES || !refreshing.insert(domain.to_string()) {
+            return;
+        }
+    }
+
+    // The domain is removed when the guard is dropped, even if the refresh panics or can't be started
+    let refreshing = RefreshingIcon(domain.to_string());
+    let path = path.to_string();
+
+    let spawned = thread::Builder::new().name("icon-refresh".into()).spawn(move || {
+        let domain = &refreshing.0;
+        let result = download_icon(domain).and_then(|icon| save_icon(&path, &icon));
+        if let Err(e) = result {
+            // Keep serving the old icon, and don't try again until the negative TTL expires
+            warn!("Error refreshing icon of {}: {:?}", domain, e);
+            mark_negcache(&path);
+        }
+    });
+
+    if let Err(e) = spawned {
+        error!("Error starting the icon refresh: {:?}", e);
+    }
+}
+
+/// A domain in `REFRESHING_ICONS`, removed from it when this is dropped
+struct RefreshingIcon(String);
+
+impl Drop for RefreshingIcon {
+    fn drop(&mut self) {
+        // A refresh that panics poisons the lock, the set is still usable
+        let mut refreshing = match REFRESHING_ICONS.lock() {
+            Ok(refreshing) => refreshing,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        refreshing.remove(&self.0);
+    }
+}
+
 fn file_is_expired(path: &str, ttl: u64) -> Result<bool, Error> {
     let meta = symlink_metadata(path)?;
     let modified = meta.modified()?;
@@ -123,7 +204,10 @@ fn icon_is_negcached(path: &str) -> bool {
 
 fn mark_negcache(path: &str) {
     let miss_indicator = path.to_owned() + ".miss";
-    File::create(&miss_indicator).expect("Error creating negative cache marker");
+
+    if let Err(e) = create_dir_all(&CONFIG.icon_cache_folder()).and_then(|_| File::create(&miss_indicator)) {
+        error!("Could not create negative cache indicator for icon {:?}: {:?}", path, e);
+    }
 }
 
 fn icon_is_expired(path: &str) -> bool {
@@ -167,26 +251,34 @@ fn get_icon_url(domain: &str) -> Result<(Vec<IconList>, String), Error> {
         // Extract the URL from the respose in case redirects occured (like @ gitlab.com)
         let url = content.url().clone();
         let raw_cookies = content.headers().get_all("set-cookie");
-        cookie_str = raw_cookies.iter().map(|raw_cookie| {
-            let cookie = Cookie::parse(raw_cookie.to_str().unwrap_or_default()).unwrap();
-            format!("{}={}; ", cookie.name(), cookie.value())
-        }).collect::<String>();
+        // The cookies that can't be parsed are skipped
+        cookie_str = raw_cookies
+            .iter()
+            .filter_map(|raw_cookie| Cookie::parse(raw_cookie.to_str().ok()?).ok())
+            .map(|cookie| format!("{}={}; ", cookie.name(), cookie.value()))
+            .collect::<String>();
 
         // Add the default favicon.ico to the list with the domain the content responded from.
-        iconlist.push(IconList { priority: 35, href: url.join("/favicon.ico").unwrap().into_string() });
+        if let Ok(href) = url.join("/favicon.ico") {
+            iconlist.push(IconList { priority: 35, href: href.into_string() });
+        }
 
-        let soup = Soup::from_reader(content)?;
+        // The page is parsed from the start, the icons are in the head
+        let soup = Soup::from_reader(content.take(MAX_PAGE_SIZE))?;
         // Search for and filter
         let favicons = soup
             .tag("link")
-            .attr("rel", Regex::new(r"icon$|apple.*icon")?) // Only use icon rels
-            .attr("href", Regex::new(r"(?i)\w+\.(jpg|jpeg|png|ico)(\?.*)?$")?) // Only allow specific extensions
+            .attr("rel", ICON_REL_REGEX.clone()) // Only use icon rels
+            .attr("href", ICON_HREF_REGEX.clone()) // Only allow specific extensions
             .find_all();
 
-        // Loop through all the found icons and determine it's priority
+        // Loop through all the found icons and determine it's priority, the invalid links are skipped
         for favicon in favicons {
             let sizes = favicon.get("sizes").unwrap_or_default();
-            let href = url.join(&favicon.get("href").unwrap_or_default()).unwrap().into_string();
+            let href = match url.join(&favicon.get("href").unwrap_or_default()) {
+                Ok(href) => href.into_string(),
+                Err(_) => continue,
+            };
             let priority = get_icon_priority(&href, &sizes);
 
             iconlist.push(IconList { priority, href })
@@ -209,7 +301,17 @@ fn get_page(url: &str) -> Result<Response, Error> {
 }
 
 fn get_page_with_cookies(url: &str, cookie_str: &str) -> Result<Response, Error> {
-    CLIENT.get(url).header("cookie", cookie_str).send()?.error_for_status().map_err(Into::into)
+    let span = telemetry::client_span("GET");
+    if let Some(host) = Url::parse(url).ok().as_ref().and_then(Url::host_str) {
+        span.set_attribute("net.peer.name", host);
+    }
+
+    let result = CLIENT
+        .get(url)
+        .header("cookie", cookie_str)
+        .send()
+        .and_then(Response::error_for_status);
+    span.record(result).map_err(Into::into)
 }
 
 /// Returns a Integer with the priority of the type of the icon which to prefer.
@@ -277,7 +379,7 @@ fn parse_sizes(sizes: &str) -> (u16, u16) {
     let mut height: u16 = 0;
 
     if !sizes.is_empty() {
-        match Regex::new(r"(?x)(\d+)\D*(\d+)").unwrap().captures(sizes.trim()) {
+        match ICON_SIZE_REGEX.captures(sizes.trim()) {
             None => {},
             Some(dimensions) => {
                 if dimensions.len() >= 3 {
@@ -292,6 +394,16 @@ fn parse_sizes(sizes: &str) -> (u16, u16) {
 }
 
 fn download_icon(domain: &str) -> Result<Vec<u8>, Error> {
+    let span = telemetry::span("icon.download");
+    span.set_attribute("icon.domain", domain);
+    span.record(_download_icon(domain))
+}
+
+fn _download_icon(domain: &str) -> Result<Vec<u8>, Error> {
+    if is_domain_blacklisted(domain) {
+        err!("Domain is blacklisted", domain)
+    }
+
     let (mut iconlist, cookie_str) = get_icon_url(&domain)?;
 
     let mut buffer = Vec::new();
@@ -299,12 +411,30 @@ fn download_icon(domain: &str) -> Result<Vec<u8>, Error> {
     iconlist.truncate(5);
     for icon in iconlist {
         let url = icon.href;
+
+        // The links of the page can point to any host
+        let host = Url::parse(&url).ok().and_then(|u| u.host_str().map(str::to_string));
+        if host.as_ref().map_or(true, |h| is_domain_blacklisted(h)) {
+            info!("Blacklisted icon URL, skipping {}", url);
+            continue;
+        }
+
         info!("Downloading icon for {} via {}...", domain, url);
         match get_page_with_cookies(&url, &cookie_str) {
-            Ok(mut res) => {
-                info!("Download finished for {}", url);
-                res.copy_to(&mut buffer)?;
-                break;
+            Ok(res) => {
+                // Reading one byte more than the limit is enough to know that the icon is too big
+                buffer.clear();
+                res.take(MAX_ICON_SIZE + 1).read_to_end(&mut buffer)?;
+
+                if buffer.len() as u64 > MAX_ICON_SIZE {
+                    info!("Icon too big, skipping {}", url);
+                } else if !is_image(&buffer) {
+                    info!("Not an image, skipping {}", url);
+                } else {
+                    info!("Download finished for {}", url);
+                    break;
+                }
+                buffer.clear();
             },
             Err(_) => info!("Download failed for {}", url),
         };
@@ -317,12 +447,253 @@ fn download_icon(domain: &str) -> Result<Vec<u8>, Error> {
     Ok(buffer)
 }
 
-fn save_icon(path: &str, icon: &[u8]) {
-    create_dir_all(&CONFIG.icon_cache_folder()).expect("Error creating icon cache");
+/// Checks if the domain matches the configured blacklist. Its addresses are checked by the proxy when it connects
+fn is_domain_blacklisted(domain: &str) -> bool {
+    let blacklist = match CONFIG.icon_blacklist_regex() {
+        Some(blacklist) => blacklist,
+        None => return false,
+    };
+
+    let mut cached = ICON_BLACKLIST.lock().unwrap();
+    if cached.as_ref().map_or(true, |(pattern, _)| *pattern != blacklist) {
+        // The regex is validated with the config
+        match Regex::new(&blacklist) {
+            Ok(re) => *cached = Some((blacklist, re)),
+            Err(_) => return true,
+        }
+    }
 
-    if let Ok(mut f) = File::create(path) {
-        f.write_all(icon).expect("Error writing icon file");
+    let is_match = cached.as_ref().map_or(true, |(_, re)| re.is_match(domain));
+    if is_match {
+        info!("Domain {} matches the icon blacklist", domain);
+    }
+    is_match
+}
+
+/// The user name of the proxy, only its secret is checked
+const ICON_PROXY_USER: &str = "icons";
+
+/// The icons are downloaded through this proxy on the loopback interface. It resolves the hosts itself and connects
+/// to the addresses it has checked, as otherwise a domain could resolve to a global address for the check, and to
+/// an internal one for the download. The other processes of the host can't use it without its secret
+struct IconProxy {
+    addr: SocketAddr,
+    secret: String,
+}
+
+impl IconProxy {
+    fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Error starting the icon proxy");
+        let addr = listener.local_addr().expect("Error starting the icon proxy");
+        let secret = data_encoding::HEXLOWER.encode(&crypto::get_random(vec![0u8; 32]));
+
+        let credentials = format!("{}:{}", ICON_PROXY_USER, secret);
+        let auth = format!("Basic {}", data_encoding::BASE64.encode(credentials.as_bytes()));
+
+        thread::Builder::new()
+            .name("icon-proxy".into())
+            .spawn(move || {
+                for client in listener.incoming() {
+                    let client = match client {
+                        Ok(client) => client,
+                        Err(e) => {
+                            warn!("Error accepting an icon proxy connection: {:?}", e);
+                            continue;
+                        }
+                    };
+
+                    let auth = auth.clone();
+                    let spawned = thread::Builder::new().spawn(move || {
+                        if let Err(e) = proxy_icon_connection(client, &auth) {
+                            info!("Icon proxy connection closed: {:?}", e);
+                        }
+                    });
+                    if let Err(e) = spawned {
+                        warn!("Error starting the thread of an icon proxy connection: {:?}", e);
+                    }
+                }
+            })
+            .expect("Error starting the icon proxy");
+
+        IconProxy { addr, secret }
+    }
+}
+
+/// The HTTPS downloads open a tunnel with CONNECT, and the HTTP ones send the whole URL in the request line,
+/// which is sent to the server with only the path
+fn proxy_icon_connection(mut client: TcpStream, auth: &str) -> io::Result<()> {
+    let timeout = Duration::from_secs(CONFIG.icon_download_timeout());
+    client.set_read_timeout(Some(timeout))?;
+    let head = listener::read_head(&mut client)?;
+
+    let head_str = String::from_utf8_lossy(&head).into_owned();
+    let mut request_line = head_str.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let version = request_line.next().unwrap_or_default();
+
+    let authorized = head_str.lines().any(|line| {
+        let mut parts = line.splitn(2, ':');
+        let is_auth = parts
+            .next()
+            .map_or(false, |name| name.eq_ignore_ascii_case("proxy-authorization"));
+        is_auth && parts.next().map_or(false, |value| crypto::ct_eq(value.trim(), auth))
+    });
+    if !authorized {
+        client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Missing the secret of the icon proxy",
+        ));
+    }
+
+    let (authority, path, default_port) = if method == "CONNECT" {
+        (target, None, 443)
+    } else if target.starts_with("http://") {
+        let rest = &target["http://".len()..];
+        match rest.find('/') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos..]), 80),
+            None => (rest, Some("/"), 80),
+        }
+    } else {
+        client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported icon proxy request",
+        ));
     };
+
+    let (host, port) = split_authority(authority, default_port);
+    let mut backend = match connect_icon_host(&host, port, timeout) {
+        Ok(backend) => backend,
+        Err(e) => {
+            client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n")?;
+            return Err(e);
+        }
+    };
+
+    match path {
+        None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?,
+        Some(path) => {
+            // The connection only carries this request, so the next ones to the host go through the checks again,
+            // and the secret isn't sent to the server
+            let head = listener::remove_header(&head, "Proxy-Authorization");
+            let head = listener::remove_header(&head, "Connection");
+            let head = listener::add_header(&head, "Connection", "close");
+            let line_end = head.windows(2).position(|w| w == b"\r\n").unwrap_or_default();
+            backend.write_all(format!("{} {} {}", method, path, version).as_bytes())?;
+            backend.write_all(&head[line_end..])?;
+        }
+    }
+
+    listener::copy_both(client, &mut backend, timeout)
+}
+
+/// Splits the host and the port, the IPv6 literals are enclosed in brackets
+fn split_authority(authority: &str, default_port: u16) -> (String, u16) {
+    let (host, port) = match authority.rfind(':') {
+        Some(pos) if !authority[pos..].contains(']') => {
+            (&authority[..pos], authority[pos + 1..].parse().unwrap_or(default_port))
+        }
+        _ => (authority, default_port),
+    };
+
+    (host.trim_start_matches('[').trim_end_matches(']').to_string(), port)
+}
+
+/// Connects to the host when it isn't blacklisted, and none of its addresses is a non global one.
+/// The hosts that can't be resolved can't be checked, so they aren't connected to either
+fn connect_icon_host(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    if is_domain_blacklisted(host) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The domain is blacklisted",
+        ));
+    }
+
+    let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+    if CONFIG.icon_blacklist_non_global_ips() && addrs.iter().any(|addr| !is_global_ip(addr.ip())) {
+        info!("Domain {} resolves to a non global IP", host);
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The domain resolves to a non global IP",
+        ));
+    }
+
+    let mut error = io::Error::new(io::ErrorKind::NotFound, "The domain doesn't have any address");
+    for addr in &addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// The standard library version is still unstable
+fn is_global_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || octets[0] == 0 // This network
+                || (octets[0] == 100 && octets[1] & 0xc0 == 64) // Shared address space
+                || octets[0] >= 240) // Reserved
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+                false
+            } else if segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80 {
+                // Unique local and link local
+                false
+            } else if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                // NAT64, the IPv4 address is in the last 32 bits
+                is_global_ip(ipv4_from_segments(segments[6], segments[7]))
+            } else if segments[0] == 0x2002 {
+                // 6to4, the IPv4 address is right after the prefix
+                is_global_ip(ipv4_from_segments(segments[1], segments[2]))
+            } else if let Some(ip) = ip.to_ipv4() {
+                // IPv4 mapped and compatible
+                is_global_ip(IpAddr::V4(ip))
+            } else {
+                true
+            }
+        }
+    }
+}
+
+fn ipv4_from_segments(high: u16, low: u16) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from(u32::from(high) << 16 | u32::from(low)))
+}
+
+/// Checks the magic bytes of the formats that are accepted, as some sites answer the missing icons with an HTML page
+fn is_image(data: &[u8]) -> bool {
+    const SIGNATURES: [&[u8]; 5] = [
+        b"\x89PNG\r\n\x1a\n", // PNG
+        b"\xff\xd8\xff",      // JPEG
+        b"\x00\x00\x01\x00",  // ICO
+        b"GIF8",              // GIF
+        b"RIFF",              // WEBP
+    ];
+
+    SIGNATURES.iter().any(|sig| data.starts_with(sig))
+}
+
+fn save_icon(path: &str, icon: &[u8]) -> Result<(), Error> {
+    create_dir_all(&CONFIG.icon_cache_folder())?;
+
+    // Write to a temporary file first, so the icon isn't read half written while it's refreshed
+    let tmp_path = path.to_owned() + ".tmp";
+
+    File::create(&tmp_path)?.write_all(icon)?;
+    rename(&tmp_path, path)?;
+    Ok(())
 }
 
 fn _header_map() -> HeaderMap {
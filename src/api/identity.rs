@@ -4,16 +4,20 @@ use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use chrono::{NaiveDateTime, Utc};
 use num_traits::FromPrimitive;
 
 use crate::db::models::*;
 use crate::db::DbConn;
 
-use crate::util::{self, JsonMap};
+use crate::util::JsonMap;
 
-use crate::api::{ApiResult, EmptyResult, JsonResult};
+use crate::api::events::log_user_event;
+use crate::api::{ApiResult, EmptyResult, JsonResult, Notify};
 
-use crate::auth::ClientIp;
+use crate::auth::{ldap, ClientCert, ClientIp, TrustedHeaderUser};
+use crate::mail;
+use crate::ratelimit::{self, TWOFACTOR_LIMITER};
 
 use crate::CONFIG;
 
@@ -22,13 +26,20 @@ pub fn routes() -> Vec<Route> {
 }
 
 #[post("/connect/token", data = "<data>")]
-fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
+fn login(
+    data: Form<ConnectData>,
+    conn: DbConn,
+    ip: ClientIp,
+    cert: ClientCert,
+    sso: TrustedHeaderUser,
+    nt: Notify,
+) -> JsonResult {
     let data: ConnectData = data.into_inner();
 
     match data.grant_type.as_ref() {
         "refresh_token" => {
             _check_is_some(&data.refresh_token, "refresh_token cannot be blank")?;
-            _refresh_login(data, conn)
+            _refresh_login(data, conn, ip, cert)
         }
         "password" => {
             _check_is_some(&data.client_id, "client_id cannot be blank")?;
@@ -38,29 +49,80 @@ fn login(data: Form<ConnectData>, conn: DbConn, ip: ClientIp) -> JsonResult {
 
             _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
             _check_is_some(&data.device_name, "device_name cannot be blank")?;
-            _check_is_some(&data.device_type, "device_type cannot be blank")?;
 
-            _password_login(data, conn, ip)
+            _password_login(data, conn, ip, cert, sso, &nt)
+        }
+        "client_credentials" => {
+            _check_is_some(&data.client_id, "client_id cannot be blank")?;
+            _check_is_some(&data.client_secret, "client_secret cannot be blank")?;
+            _check_is_some(&data.scope, "scope cannot be blank")?;
+
+            _check_is_some(&data.device_identifier, "device_identifier cannot be blank")?;
+            _check_is_some(&data.device_name, "device_name cannot be blank")?;
+
+            _api_key_login(data, conn, ip, cert, &nt)
         }
         t => err!("Invalid type", t),
     }
 }
 
-fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
+fn _refresh_login(data: ConnectData, conn: DbConn, ip: ClientIp, cert: ClientCert) -> JsonResult {
     // Extract token
     let token = data.refresh_token.unwrap();
 
-    // Get device by refresh token
+    // Get device by refresh token, the approved devices that haven't logged in yet don't have one
     let mut device = match Device::find_by_refresh_token(&token, &conn) {
+        Some(ref device) if device.refresh_token.is_empty() => err!("Invalid refresh token"),
         Some(device) => device,
         None => err!("Invalid refresh token"),
     };
 
     // COMMON
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
+
+    // The refresh tokens don't expire, so the account is checked again like on the password login
+    if user.is_locked() {
+        err!(
+            "Your account has been temporarily locked due to too many failed login attempts. Try again later",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    if user.is_deleted() {
+        err!(
+            "This account has been deleted. Contact the administrator to restore it",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    if user.disabled {
+        err!(
+            "This account has been disabled. Contact the administrator to enable it",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    // The result is cached for a while, so an outage of the directory doesn't end every session
+    if CONFIG.ldap_enabled() && !ldap::is_cached_directory_user(&user.email)? {
+        err!(
+            "This account isn't active in the directory. Contact the administrator",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    // The sessions last longer than the certificates, so they're checked again on every refresh
+    let valid_cert = _check_client_cert(&user, &cert, &conn);
+    if _client_cert_missing(&user, &cert, valid_cert, &conn) {
+        err!(
+            "A registered client certificate is required to log in",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    device.last_ip = Some(ip.ip.to_string());
 
     device.save(&conn)?;
     Ok(Json(json!({
@@ -70,63 +132,204 @@ fn _refresh_login(data: ConnectData, conn: DbConn) -> JsonResult {
         "refresh_token": device.refresh_token,
         "Key": user.key,
         "PrivateKey": user.private_key,
+        "ForcePasswordReset": user.force_password_reset,
     })))
 }
 
-fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult {
+/// The new clients can have types that aren't known yet, they log in like an unknown browser
+fn _device_type(data: &ConnectData) -> DeviceType {
+    data.device_type
+        .as_ref()
+        .and_then(|t| DeviceType::from_client(t))
+        .unwrap_or(DeviceType::UnknownBrowser)
+}
+
+fn _password_login(
+    data: ConnectData,
+    conn: DbConn,
+    ip: ClientIp,
+    cert: ClientCert,
+    sso: TrustedHeaderUser,
+    nt: &Notify,
+) -> JsonResult {
     // Validate scope
     let scope = data.scope.as_ref().unwrap();
     if scope != "api offline_access" {
         err!("Scope not supported")
     }
 
-    // Get the user
+    let device_type = _device_type(&data);
+
+    // The limit is checked before looking for the user, so the responses are the same for every email
     let username = data.username.as_ref().unwrap();
-    let user = match User::find_by_mail(username, &conn) {
+    ratelimit::check_login(username, &ip)?;
+
+    // Get the user
+    let mut user = match User::find_by_mail(username, &conn) {
         Some(user) => user,
-        None => err!(
-            "Username or password is incorrect. Try again",
-            format!("IP: {}. Username: {}.", ip.ip, username)
-        ),
+        None => {
+            ratelimit::register_login_failure(&ip);
+            err!(
+                "Username or password is incorrect. Try again",
+                format!("IP: {}. Username: {}.", ip.ip, username)
+            )
+        }
     };
 
+    // Check if the account is locked
+    if user.is_locked() {
+        err!(
+            "Your account has been temporarily locked due to too many failed login attempts. Try again later",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
     // Check password
     let password = data.password.as_ref().unwrap();
     if !user.check_valid_password(password) {
+        register_failed_login(&mut user, &ip, &conn);
+        ratelimit::register_login_failure(&ip);
+        let address = ip.ip.to_string();
+        let event_device = device_type as i32;
+        log_user_event(EventType::UserFailedLogIn, &user.uuid, event_device, &address, nt);
         err!(
             "Username or password is incorrect. Try again",
             format!("IP: {}. Username: {}.", ip.ip, username)
         )
     }
 
-    // On iOS, device_type sends "iOS", on others it sends a number
-    let device_type = util::try_parse_string(data.device_type.as_ref()).unwrap_or(0);
+    if user.is_deleted() {
+        err!(
+            "This account has been deleted. Contact the administrator to restore it",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
+    if user.disabled {
+        err!(
+            "This account has been disabled. Contact the administrator to enable it",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
+    // The directory decides which accounts are still active
+    if CONFIG.ldap_enabled() && !ldap::is_directory_user(&user.email)? {
+        err!(
+            "This account isn't active in the directory. Contact the administrator",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
+    // The authenticating proxy decides the account, only the master password is checked here
+    let sso_login = sso.email.as_ref() == Some(&user.email.to_lowercase());
+    if sso.email.is_some() && !sso_login {
+        err!(
+            "This isn't the account you signed in with. Sign out of the single sign-on to use another one",
+            format!("IP: {}. Username: {}. Signed in as: {:?}.", ip.ip, username, sso.email)
+        )
+    }
+
+    let from_web_vault = data.client_id.as_ref().map_or(false, |client_id| client_id == "web");
+    if CONFIG.trusted_header().is_some() && CONFIG.trusted_header_required() && from_web_vault && !sso_login {
+        err!(
+            "Sign in through the single sign-on to use the web vault",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
+    // Check the client certificate, if the proxy sends them
+    let valid_cert = _check_client_cert(&user, &cert, &conn);
+    if _client_cert_missing(&user, &cert, valid_cert, &conn) {
+        err!(
+            "A registered client certificate is required to log in",
+            format!("IP: {}. Username: {}.", ip.ip, username)
+        )
+    }
+
+    // Clear any failed attempts left over from before
+    if user.login_failures != 0 || user.locked_until.is_some() {
+        user.login_failures = 0;
+        user.locked_until = None;
+        user.save_lockout(&conn)?;
+    }
+
     let device_id = data.device_identifier.clone().expect("No device id provided");
     let device_name = data.device_name.clone().expect("No device name provided");
 
-    // Find device or create new
-    let mut device = match Device::find_by_uuid(&device_id, &conn) {
+    // The device is only looked up here, it's not created or modified until the login is complete
+    let known_device = match Device::find_by_uuid(&device_id, &conn) {
         Some(device) => {
-            // Check if owned device, and recreate if not
+            // Check if owned device, and recreate it later if not
             if device.user_uuid != user.uuid {
                 info!("Device exists but is owned by another user. The old device will be discarded");
-                Device::new(device_id, user.uuid.clone(), device_name, device_type)
+                None
             } else {
-                device
+                Some(device)
             }
         }
+        None => None,
+    };
+
+    let skip_twofactor =
+        (valid_cert && CONFIG.client_cert_skip_twofactor()) || (sso_login && CONFIG.trusted_header_skip_twofactor());
+    let remember = if skip_twofactor {
+        RememberAction::Keep
+    } else {
+        twofactor_auth(&user.uuid, &data, known_device.as_ref(), &conn)?
+    };
+
+    // The user is fully authenticated, so the device can be registered now
+    let new_device = known_device.is_none();
+    let mut device = match known_device {
+        Some(device) => device,
         None => Device::new(device_id, user.uuid.clone(), device_name, device_type),
     };
 
-    let twofactor_token = twofactor_auth(&user.uuid, &data, &mut device, &conn)?;
+    // The new devices are only registered once the user approves them from the email
+    if new_device && CONFIG.mail_enabled() && CONFIG.new_device_approval() {
+        let locale = user.locale.as_ref().map(String::as_str);
+        let address = ip.ip.to_string();
+        mail::send_device_approval(&user.email, &user.uuid, &address, &device, locale)?;
+        err!(
+            "This device needs to be approved. Check your email, and log in again after approving it",
+            format!("IP: {}. Username: {}. Device: {}.", ip.ip, username, device.uuid)
+        )
+    }
+
+    let twofactor_token = match remember {
+        RememberAction::Keep => None,
+        RememberAction::Refresh => Some(device.refresh_twofactor_remember()),
+        RememberAction::Clear => {
+            device.delete_twofactor_remember();
+            None
+        }
+    };
+
+    // The password is known to be correct here, so take the chance to upgrade the stored hash
+    if user.password_rehash_required() {
+        info!("Rehashing password for {}", user.email);
+        let force_password_reset = user.force_password_reset;
+        user.set_password(password);
+        user.force_password_reset = force_password_reset;
+        user.save(&conn)?;
+    }
 
     // Common
     let user = User::find_by_uuid(&device.user_uuid, &conn).unwrap();
     let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
 
     let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    device.last_ip = Some(ip.ip.to_string());
     device.save(&conn)?;
 
+    if new_device && CONFIG.mail_enabled() && CONFIG.new_device_email() {
+        let locale = user.locale.as_ref().map(String::as_str);
+        let ip = ip.ip.to_string();
+        if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip, &device.created_at, &device.name, locale) {
+            error!("Error sending new device email: {:#?}", e);
+        }
+    }
+
     let mut result = json!({
         "access_token": access_token,
         "expires_in": expires_in,
@@ -134,6 +337,7 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
         "refresh_token": device.refresh_token,
         "Key": user.key,
         "PrivateKey": user.private_key,
+        "ForcePasswordReset": user.force_password_reset,
         //"TwoFactorToken": "11122233333444555666777888999"
     });
 
@@ -141,22 +345,227 @@ fn _password_login(data: ConnectData, conn: DbConn, ip: ClientIp) -> JsonResult
         result["TwoFactorToken"] = Value::String(token);
     }
 
+    let address = ip.ip.to_string();
+    log_user_event(EventType::UserLoggedIn, &user.uuid, device.type_, &address, nt);
     info!("User {} logged in successfully. IP: {}", username, ip.ip);
     Ok(Json(result))
 }
 
+/// The login with the personal API key of the user, where the client id is "user.<uuid>" and the key is the secret.
+/// The key replaces both the master password and the two factor, like in the official server
+fn _api_key_login(data: ConnectData, conn: DbConn, ip: ClientIp, cert: ClientCert, nt: &Notify) -> JsonResult {
+    // Validate scope
+    let scope = data.scope.as_ref().unwrap();
+    if scope != "api" {
+        err!("Scope not supported")
+    }
+
+    let device_type = _device_type(&data);
+
+    let client_id = data.client_id.as_ref().unwrap();
+    ratelimit::check_login(client_id, &ip)?;
+
+    let user_uuid = if client_id.starts_with("user.") {
+        &client_id["user.".len()..]
+    } else {
+        ratelimit::register_login_failure(&ip);
+        err!(
+            "Client ID or client secret is incorrect. Try again",
+            format!("IP: {}. Client ID: {}.", ip.ip, client_id)
+        )
+    };
+
+    let user = match User::find_by_uuid(user_uuid, &conn) {
+        Some(user) => user,
+        None => {
+            ratelimit::register_login_failure(&ip);
+            err!(
+                "Client ID or client secret is incorrect. Try again",
+                format!("IP: {}. Client ID: {}.", ip.ip, client_id)
+            )
+        }
+    };
+
+    let client_secret = data.client_secret.as_ref().unwrap();
+    if !user.check_valid_api_key(client_secret) {
+        ratelimit::register_login_failure(&ip);
+        let address = ip.ip.to_string();
+        let event_device = device_type as i32;
+        log_user_event(EventType::UserFailedLogIn, &user.uuid, event_device, &address, nt);
+        err!(
+            "Client ID or client secret is incorrect. Try again",
+            format!("IP: {}. Client ID: {}.", ip.ip, client_id)
+        )
+    }
+
+    if user.is_locked() || user.is_deleted() || user.disabled {
+        err!(
+            "This account is locked, deleted or disabled",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    if CONFIG.ldap_enabled() && !ldap::is_directory_user(&user.email)? {
+        err!(
+            "This account isn't active in the directory. Contact the administrator",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    let valid_cert = _check_client_cert(&user, &cert, &conn);
+    if _client_cert_missing(&user, &cert, valid_cert, &conn) {
+        err!(
+            "A registered client certificate is required to log in",
+            format!("IP: {}. Username: {}.", ip.ip, user.email)
+        )
+    }
+
+    let device_id = data.device_identifier.clone().expect("No device id provided");
+    let device_name = data.device_name.clone().expect("No device name provided");
+
+    let known_device = match Device::find_by_uuid(&device_id, &conn) {
+        Some(device) if device.user_uuid == user.uuid => Some(device),
+        Some(_) => {
+            info!("Device exists but is owned by another user. The old device will be discarded");
+            None
+        }
+        None => None,
+    };
+
+    let new_device = known_device.is_none();
+    let mut device = match known_device {
+        Some(device) => device,
+        None => Device::new(device_id, user.uuid.clone(), device_name, device_type),
+    };
+
+    // The new devices are only registered once the user approves them from the email
+    if new_device && CONFIG.mail_enabled() && CONFIG.new_device_approval() {
+        let locale = user.locale.as_ref().map(String::as_str);
+        let address = ip.ip.to_string();
+        mail::send_device_approval(&user.email, &user.uuid, &address, &device, locale)?;
+        err!(
+            "This device needs to be approved. Check your email, and log in again after approving it",
+            format!("IP: {}. Username: {}. Device: {}.", ip.ip, user.email, device.uuid)
+        )
+    }
+
+    // The tokens are the same as the ones of a password login
+    let orgs = UserOrganization::find_by_user(&user.uuid, &conn);
+    let (access_token, expires_in) = device.refresh_tokens(&user, orgs);
+    device.last_ip = Some(ip.ip.to_string());
+    device.save(&conn)?;
+
+    if new_device && CONFIG.mail_enabled() && CONFIG.new_device_email() {
+        let locale = user.locale.as_ref().map(String::as_str);
+        let ip = ip.ip.to_string();
+        if let Err(e) = mail::send_new_device_logged_in(&user.email, &ip, &device.created_at, &device.name, locale) {
+            error!("Error sending new device email: {:#?}", e);
+        }
+    }
+
+    let address = ip.ip.to_string();
+    log_user_event(EventType::UserLoggedIn, &user.uuid, device.type_, &address, nt);
+    info!("User {} logged in with the API key. IP: {}", user.email, ip.ip);
+
+    Ok(Json(json!({
+        "access_token": access_token,
+        "expires_in": expires_in,
+        "token_type": "Bearer",
+        "refresh_token": device.refresh_token,
+        "Key": user.key,
+        "PrivateKey": user.private_key,
+        "Kdf": user.client_kdf_type,
+        "KdfIterations": user.client_kdf_iter,
+        "ForcePasswordReset": user.force_password_reset,
+    })))
+}
+
+/// Checks if the certificate sent by the proxy is one of the certificates registered by the user
+fn _check_client_cert(user: &User, cert: &ClientCert, conn: &DbConn) -> bool {
+    match cert.fingerprint {
+        Some(ref fingerprint) => {
+            ClientCertificate::find_by_fingerprint(fingerprint, conn).map_or(false, |c| c.user_uuid == user.uuid)
+        }
+        None => false,
+    }
+}
+
+/// The users that registered a certificate need one of them to log in, and so do all the users when they're required.
+/// A user without registered certificates can still log in with any certificate accepted by the proxy
+/// that nobody registered, to be able to register it
+fn _client_cert_missing(user: &User, cert: &ClientCert, valid_cert: bool, conn: &DbConn) -> bool {
+    if CONFIG.client_cert_header().is_none() || valid_cert {
+        return false;
+    }
+
+    if !ClientCertificate::find_by_user(&user.uuid, conn).is_empty() {
+        return true;
+    }
+
+    if !CONFIG.client_cert_required() {
+        return false;
+    }
+
+    match cert.fingerprint {
+        Some(ref fingerprint) => ClientCertificate::find_by_fingerprint(fingerprint, conn).is_some(),
+        None => true,
+    }
+}
+
+pub fn register_failed_login(user: &mut User, ip: &ClientIp, conn: &DbConn) {
+    let max_attempts = CONFIG.login_lockout_attempts();
+    if max_attempts == 0 {
+        return;
+    }
+
+    user.login_failures += 1;
+
+    if user.login_failures >= max_attempts as i32 {
+        user.login_failures = 0;
+        // In whole seconds like the unlock token, as MySQL rounds the fractional ones when storing them
+        let locked_until = NaiveDateTime::from_timestamp(Utc::now().timestamp() + CONFIG.login_lockout_seconds(), 0);
+        user.locked_until = Some(locked_until);
+
+        warn!(
+            "Account {} locked after {} failed login attempts. IP: {}",
+            user.email, max_attempts, ip.ip
+        );
+
+        if CONFIG.mail_enabled() {
+            let locale = user.locale.as_ref().map(String::as_str);
+            let address = ip.ip.to_string();
+            if let Err(e) = mail::send_account_locked(&user.email, &user.uuid, &locked_until, &address, locale) {
+                error!("Error sending account locked email: {:#?}", e);
+            }
+        }
+    }
+
+    if let Err(e) = user.save_lockout(conn) {
+        error!("Error saving failed login attempt: {:#?}", e);
+    }
+}
+
+/// What to do with the two factor remember token of the device after the login
+enum RememberAction {
+    Keep,
+    Refresh,
+    Clear,
+}
+
+/// Checks the two factor code, if the user has two factor enabled.
+/// The device is only provided when it's already registered, and it's never modified here
 fn twofactor_auth(
     user_uuid: &str,
     data: &ConnectData,
-    device: &mut Device,
+    device: Option<&Device>,
     conn: &DbConn,
-) -> ApiResult<Option<String>> {
+) -> ApiResult<RememberAction> {
     let twofactors = TwoFactor::find_by_user(user_uuid, conn);
     let providers: Vec<_> = twofactors.iter().map(|tf| tf.type_).collect();
 
     // No twofactor token if twofactor is disabled
     if twofactors.is_empty() {
-        return Ok(None);
+        return Ok(RememberAction::Keep);
     }
 
     let provider = data.two_factor_provider.unwrap_or(providers[0]); // If we aren't given a two factor provider, asume the first one
@@ -166,19 +575,26 @@ fn twofactor_auth(
         None => err_json!(_json_err_twofactor(&providers, user_uuid, conn)?),
     };
 
-    let twofactor = twofactors.iter().filter(|tf| tf.type_ == provider).nth(0);
+    if !TWOFACTOR_LIMITER.check(user_uuid, CONFIG.twofactor_ratelimit_attempts()) {
+        err_limit!(
+            "Too many two factor attempts. Try again later",
+            format!("User: {}.", user_uuid)
+        )
+    }
+
+    let twofactor = twofactors.into_iter().filter(|tf| tf.type_ == provider).nth(0);
 
     match TwoFactorType::from_i32(provider) {
         Some(TwoFactorType::Remember) => {
-            use crate::crypto::ct_eq;
-            match device.twofactor_remember {
-                Some(ref remember) if ct_eq(remember, twofactor_code) => return Ok(None), // No twofactor token needed here
-                _ => err_json!(_json_err_twofactor(&providers, user_uuid, conn)?),
+            if device.map_or(false, |d| d.check_twofactor_remember(twofactor_code)) {
+                return Ok(RememberAction::Keep); // No twofactor token needed here
+            } else {
+                err_json!(_json_err_twofactor(&providers, user_uuid, conn)?)
             }
         }
 
         Some(TwoFactorType::Authenticator) => {
-            let twofactor = match twofactor {
+            let mut twofactor = match twofactor {
                 Some(tf) => tf,
                 None => err!("TOTP not enabled"),
             };
@@ -189,8 +605,15 @@ fn twofactor_auth(
             };
 
             if !twofactor.check_totp_code(totp_code) {
+                if let Some(offset) = twofactor.find_totp_drift(totp_code) {
+                    use crate::api::core::two_factor;
+                    two_factor::register_totp_drift(user_uuid, offset);
+                }
                 err_json!(_json_err_twofactor(&providers, user_uuid, conn)?)
             }
+
+            // Store the used time step, so the same code can't be used again
+            twofactor.save(conn)?;
         }
 
         Some(TwoFactorType::U2f) => {
@@ -205,14 +628,19 @@ fn twofactor_auth(
             two_factor::validate_yubikey_login(user_uuid, twofactor_code, conn)?;
         }
 
+        Some(TwoFactorType::Email) => {
+            use crate::api::core::two_factor;
+
+            two_factor::validate_email_code(user_uuid, twofactor_code, conn)?;
+        }
+
         _ => err!("Invalid two factor provider"),
     }
 
-    if data.two_factor_remember.unwrap_or(0) == 1 {
-        Ok(Some(device.refresh_twofactor_remember()))
+    if data.two_factor_remember.unwrap_or(0) == 1 && CONFIG.twofactor_remember_days() > 0 {
+        Ok(RememberAction::Refresh)
     } else {
-        device.delete_twofactor_remember();
-        Ok(None)
+        Ok(RememberAction::Clear)
     }
 }
 
@@ -261,14 +689,34 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
                     None => err!("No YubiKey devices registered"),
                 };
 
-                let yubikey_metadata: two_factor::YubikeyMetadata =
-                    serde_json::from_str(&twofactor.data).expect("Can't parse Yubikey Metadata");
+                let yubikey_metadata: two_factor::YubikeyMetadata = serde_json::from_str(&twofactor.data)?;
 
                 let mut map = JsonMap::new();
                 map.insert("Nfc".into(), Value::Bool(yubikey_metadata.Nfc));
                 result["TwoFactorProviders2"][provider.to_string()] = Value::Object(map);
             }
 
+            Some(tf_type @ TwoFactorType::Email) => {
+                let twofactor = match TwoFactor::find_by_user_and_type(user_uuid, tf_type as i32, &conn) {
+                    Some(tf) => tf,
+                    None => err!("Email two factor is not enabled"),
+                };
+
+                // The clients only ask for the code when the user chooses the provider, so when it's the only one,
+                // the code is sent right away
+                if providers.len() == 1 && CONFIG.mail_enabled() {
+                    if let Some(user) = User::find_by_uuid(user_uuid, conn) {
+                        two_factor::send_email_login_code(&user, conn)?;
+                    }
+                }
+
+                let token_data: two_factor::EmailTokenData = serde_json::from_str(&twofactor.data)?;
+
+                let mut map = JsonMap::new();
+                map.insert("Email".into(), Value::String(token_data.obscured_email()));
+                result["TwoFactorProviders2"][provider.to_string()] = Value::Object(map);
+            }
+
             _ => {}
         }
     }
@@ -279,7 +727,7 @@ fn _json_err_twofactor(providers: &[i32], user_uuid: &str, conn: &DbConn) -> Api
 #[derive(Debug, Clone, Default)]
 #[allow(non_snake_case)]
 struct ConnectData {
-    grant_type: String, // refresh_token, password
+    grant_type: String, // refresh_token, password, client_credentials
 
     // Needed for grant_type="refresh_token"
     refresh_token: Option<String>,
@@ -290,6 +738,9 @@ struct ConnectData {
     scope: Option<String>,
     username: Option<String>,
 
+    // Needed for grant_type="client_credentials"
+    client_secret: Option<String>,
+
     device_identifier: Option<String>,
     device_name: Option<String>,
     device_type: Option<String>,
@@ -314,6 +765,7 @@ impl<'f> FromForm<'f> for ConnectData {
                 "granttype" => form.grant_type = value,
                 "refreshtoken" => form.refresh_token = Some(value),
                 "clientid" => form.client_id = Some(value),
+                "clientsecret" => form.client_secret = Some(value),
                 "password" => form.password = Some(value),
                 "scope" => form.scope = Some(value),
                 "username" => form.username = Some(value),
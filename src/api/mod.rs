@@ -1,5 +1,6 @@
 mod admin;
 pub(crate) mod core;
+mod events;
 mod icons;
 mod identity;
 mod notifications;
@@ -7,14 +8,25 @@ mod web;
 
 pub use self::admin::routes as admin_routes;
 pub use self::core::routes as core_routes;
+pub use self::events::routes as events_routes;
+pub use self::events::EventLog;
 pub use self::icons::routes as icons_routes;
 pub use self::identity::routes as identity_routes;
 pub use self::notifications::routes as notifications_routes;
-pub use self::notifications::{start_notification_server, Notify, UpdateType};
+pub use self::notifications::{limit_long_polls, start_notification_server, UpdateType};
 pub use self::web::routes as web_routes;
+pub use crate::bus::Notify;
 
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::RawStr;
+use rocket::request::FromParam;
+use rocket::response::status;
+use rocket::{Catcher, Data, Request};
 use rocket_contrib::json::Json;
 use serde_json::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
 
 // Type aliases for API methods results
 type ApiResult<T> = Result<T, crate::error::Error>;
@@ -57,3 +69,90 @@ impl NumberOrString {
         }
     }
 }
+
+/// An id from the path, which needs to be a valid UUID. It's converted to lowercase,
+/// and the malformed ids are rejected before they reach the database
+pub struct Uuid(String);
+
+thread_local! {
+    /// The malformed id rejected while routing the request handled by this thread. Rocket skips the route,
+    /// so the catcher uses it to answer with a bad request instead of a missing page
+    static REJECTED_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+impl<'r> FromParam<'r> for Uuid {
+    type Error = &'r RawStr;
+
+    fn from_param(param: &'r RawStr) -> Result<Self, Self::Error> {
+        let uuid = param.as_str().to_lowercase();
+
+        let valid = uuid.len() == 36
+            && uuid.char_indices().all(|(i, c)| match i {
+                8 | 13 | 18 | 23 => c == '-',
+                _ => c.is_ascii_hexdigit(),
+            });
+
+        if valid {
+            Ok(Uuid(uuid))
+        } else {
+            warn!("Rejected malformed id: {}", param);
+            REJECTED_ID.with(|id| *id.borrow_mut() = Some(param.to_string()));
+            Err(param)
+        }
+    }
+}
+
+impl Uuid {
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+/// Forgets the id rejected in the previous request of the thread
+pub struct RejectedIds();
+
+impl Fairing for RejectedIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rejected Ids",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, _req: &mut Request, _data: &Data) {
+        REJECTED_ID.with(|id| id.borrow_mut().take());
+    }
+}
+
+/// The requests that only missed a route because of a malformed id get an error body, like the other bad requests
+#[catch(404)]
+fn not_found(_req: &Request) -> Result<status::NotFound<&'static str>, crate::Error> {
+    match REJECTED_ID.with(|id| id.borrow_mut().take()) {
+        Some(id) => Err(crate::Error::new("Invalid id", format!("Malformed id: {}", id))),
+        None => Ok(status::NotFound("Not found")),
+    }
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![not_found]
+}
+
+impl Deref for Uuid {
+    type Target = String;
+
+    fn deref(&self) -> &String {
+        &self.0
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl PartialEq<Uuid> for String {
+    fn eq(&self, other: &Uuid) -> bool {
+        *self == other.0
+    }
+}
@@ -1,20 +1,24 @@
-use rocket::Route;
+use std::io::{self, Read};
+
+use rocket::http::ContentType;
+use rocket::response::{status::NoContent, Content};
+use rocket::{Data, Route};
 use rocket_contrib::json::Json;
 use serde_json::Value as JsonValue;
 
-use crate::api::JsonResult;
+use crate::api::{ApiResult, EmptyResult, JsonResult};
 use crate::auth::Headers;
 use crate::db::DbConn;
 
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
-    routes![negotiate, websockets_err]
+    routes![negotiate, websockets_err, poll, poll_send, poll_close]
 }
 
-#[get("/hub")]
+#[get("/hub", rank = 2)]
 fn websockets_err() -> JsonResult {
-    err!("'/notifications/hub' should be proxied to the websocket server or notifications won't work. Go to the README for more info.")
+    err!("'/notifications/hub' should be proxied to the websocket server, or WEBSOCKET_SINGLE_PORT enabled, or notifications won't work. Go to the README for more info.")
 }
 
 #[post("/hub/negotiate")]
@@ -29,30 +33,129 @@ fn negotiate(_headers: Headers, _conn: DbConn) -> JsonResult {
         available_transports.push(json!({"transport":"WebSockets", "transferFormats":["Text","Binary"]}));
     }
 
+    // The long polling connection is only created on the first poll, most clients open a websocket instead
+    if CONFIG.long_polling_enabled() {
+        available_transports.push(json!({"transport":"LongPolling", "transferFormats":["Text","Binary"]}));
+    }
+
     // TODO: Implement transports
-    // Rocket WS support: https://github.com/SergioBenitez/Rocket/issues/90
     // Rocket SSE support: https://github.com/SergioBenitez/Rocket/issues/33
     // {"transport":"ServerSentEvents", "transferFormats":["Text"]},
-    // {"transport":"LongPolling", "transferFormats":["Text","Binary"]}
     Ok(Json(json!({
         "connectionId": conn_id,
         "availableTransports": available_transports
     })))
 }
 
+fn find_poll_connection(id: &str, headers: &Headers) -> ApiResult<Arc<PollConnection>> {
+    match LONG_POLLING.get(id, &headers.user.uuid) {
+        Some(connection) => Ok(connection),
+        None => err!("Invalid connection id"),
+    }
+}
+
+/// Number of polls that can wait at the same time, set from the number of Rocket workers on launch
+static MAX_WAITING_POLLS: AtomicUsize = AtomicUsize::new(1);
+static WAITING_POLLS: AtomicUsize = AtomicUsize::new(0);
+
+/// The waiting polls can use at most a quarter of the workers, the rest are kept for the API
+pub fn limit_long_polls(workers: usize) {
+    MAX_WAITING_POLLS.store((workers / 4).max(1), Ordering::Relaxed);
+}
+
+/// A place of one of the waiting polls, given back when it's dropped
+struct WaitingPoll;
+
+impl WaitingPoll {
+    fn take() -> Option<Self> {
+        let max = MAX_WAITING_POLLS.load(Ordering::Relaxed);
+        if WAITING_POLLS.fetch_add(1, Ordering::SeqCst) >= max {
+            WAITING_POLLS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(WaitingPoll)
+    }
+}
+
+impl Drop for WaitingPoll {
+    fn drop(&mut self) {
+        WAITING_POLLS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Waits until there are messages for the client, or the ping interval ends. The first poll creates the connection.
+/// When too many polls are already waiting, the pending messages are returned right away, even if there are none,
+/// and the client polls again. There's no content once the connection has been closed, which tells the client to stop
+#[get("/hub?<id>")]
+fn poll(id: String, headers: Headers) -> ApiResult<Result<Content<Vec<u8>>, NoContent>> {
+    let connection = match LONG_POLLING.get(&id, &headers.user.uuid) {
+        Some(connection) => connection,
+        None if CONFIG.long_polling_enabled() => {
+            match LONG_POLLING.add(id, headers.user.uuid.clone(), headers.device.uuid.clone()) {
+                Some(connection) => connection,
+                None => err!("Invalid connection id"),
+            }
+        }
+        None => err!("Invalid connection id"),
+    };
+
+    let data = match WaitingPoll::take() {
+        Some(_waiting) => connection.poll(),
+        None => connection.take_pending(),
+    };
+
+    Ok(match data {
+        Some(data) => Ok(Content(ContentType::Binary, data)),
+        None => Err(NoContent),
+    })
+}
+
+#[post("/hub?<id>", data = "<data>")]
+fn poll_send(id: String, data: Data, headers: Headers, conn: DbConn) -> EmptyResult {
+    let connection = find_poll_connection(&id, &headers)?;
+
+    // Reading one byte more than the limit is enough to know that the message is too big
+    let mut buffer = Vec::new();
+    data.open().take(MAX_BUFFER_SIZE as u64 + 1).read_to_end(&mut buffer)?;
+
+    match connection.receive(&buffer) {
+        Ok(true) => replay_queued(&connection.device_uuid, &conn, |data| {
+            connection.push(&data);
+            Ok(())
+        }),
+        Ok(false) => Ok(()),
+        Err(e) => {
+            LONG_POLLING.remove(&id);
+            err!(e)
+        }
+    }
+}
+
+#[delete("/hub?<id>")]
+fn poll_close(id: String, headers: Headers) -> EmptyResult {
+    find_poll_connection(&id, &headers)?;
+    LONG_POLLING.remove(&id);
+    Ok(())
+}
+
 //
 // Websockets server
 //
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 
-use ws::{self, util::Token, Factory, Handler, Handshake, Message, Sender, WebSocket};
+use ws::{self, util::Token, Factory, Handler, Handshake, Message, Sender};
 
 use chashmap::CHashMap;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use serde_json::from_str;
 
-use crate::db::models::{Cipher, Folder, User};
+use crate::bus::{DomainEvent, Subscriber};
+use crate::db::models::{Device, QueuedNotification};
+use crate::db::Pool;
+use crate::listener;
 
 use rmpv::Value;
 
@@ -62,8 +165,12 @@ fn serialize(val: Value) -> Vec<u8> {
     let mut buf = Vec::new();
     write_value(&mut buf, &val).expect("Error encoding MsgPack");
 
-    // Add size bytes at the start
-    // Extracted from BinaryMessageFormat.js
+    frame(buf)
+}
+
+/// Adds the size bytes at the start of a message, a VarInt of up to 5 bytes
+/// Extracted from BinaryMessageFormat.js
+fn frame(mut buf: Vec<u8>) -> Vec<u8> {
     let mut size: usize = buf.len();
     let mut len_buf: Vec<u8> = Vec::new();
 
@@ -86,7 +193,48 @@ fn serialize(val: Value) -> Vec<u8> {
     len_buf
 }
 
+/// Reads the VarInt at the start of the data, returning the size and the number of bytes it uses,
+/// or `None` when the data ends before the VarInt does
+fn read_size(data: &[u8]) -> Result<Option<(usize, usize)>, &'static str> {
+    let mut size: usize = 0;
+
+    for (i, byte) in data.iter().enumerate().take(5) {
+        size |= ((byte & 0x7f) as usize) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            return Ok(Some((size, i + 1)));
+        }
+    }
+
+    if data.len() >= 5 {
+        Err("Invalid message size")
+    } else {
+        Ok(None)
+    }
+}
+
+/// Takes the complete messages from the start of the buffer. A websocket message can contain more than one of them,
+/// and an incomplete one is left in the buffer until the rest arrives in the next websocket message
+fn parse_messages(buf: &mut Vec<u8>) -> Result<Vec<Vec<u8>>, &'static str> {
+    let mut messages = Vec::new();
+    let mut start = 0;
+
+    while let Some((size, size_len)) = read_size(&buf[start..])? {
+        let end = start + size_len + size;
+        if end > buf.len() {
+            break;
+        }
+
+        messages.push(buf[start + size_len..end].to_vec());
+        start = end;
+    }
+
+    buf.drain(..start);
+    Ok(messages)
+}
+
 fn serialize_date(date: NaiveDateTime) -> Value {
+    let date = crate::util::to_utc(&date);
     let seconds: i64 = date.timestamp();
     let nanos: i64 = date.timestamp_subsec_nanos().into();
     let timestamp = nanos << 34 | seconds;
@@ -109,7 +257,10 @@ fn convert_option<T: Into<Value>>(option: Option<T>) -> Value {
 pub struct WSHandler {
     out: Sender,
     user_uuid: Option<String>,
+    device_uuid: Option<String>,
     users: WebSocketUsers,
+    /// The received binary data that doesn't form a complete message yet
+    buffer: Vec<u8>,
 }
 
 const RECORD_SEPARATOR: u8 = 0x1e;
@@ -121,9 +272,158 @@ struct InitialMessage {
     version: i32,
 }
 
-const PING_MS: u64 = 15_000;
+/// The clients only send small messages, anything bigger than this is not a valid client
+const MAX_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Outgoing messages bigger than this, like the updates of ciphers with a lot of data,
+/// are split into continuation frames that the clients assemble back into one message
+const MAX_FRAGMENT_SIZE: usize = 16 * 1024;
+
 const PING: Token = Token(1);
 
+/// The message type of the SignalR pings, the first element of the message
+const PING_TYPE: u64 = 6;
+
+fn ping_ms() -> u64 {
+    CONFIG.websocket_ping_interval() * 1000
+}
+
+/// The clients send pings to keep their side of the connection alive, they aren't echoed like the rest
+fn is_ping(message: &[u8]) -> bool {
+    match rmpv::decode::read_value(&mut &message[..]) {
+        Ok(Value::Array(ref values)) => values.get(0).and_then(Value::as_u64) == Some(PING_TYPE),
+        _ => false,
+    }
+}
+
+/// Sends the notifications that were queued while the device was disconnected, once the client
+/// has finished the handshake. They are only removed when all of them have been sent
+fn replay_queued<E, F>(device_uuid: &str, conn: &DbConn, mut send: F) -> Result<(), E>
+where
+    F: FnMut(Vec<u8>) -> Result<(), E>,
+{
+    if CONFIG.websocket_replay_hours() == 0 {
+        return Ok(());
+    }
+
+    // From now on the notifications are queued for this device while it's disconnected
+    if let Err(e) = Device::save_websocket_opened(device_uuid, conn) {
+        error!("Error saving the websocket of the device: {:#?}", e);
+    }
+
+    let queued = QueuedNotification::find_by_device(device_uuid, conn);
+    let last_seq = match queued.last() {
+        Some(notification) => notification.seq,
+        None => return Ok(()),
+    };
+
+    debug!("Replaying {} notifications to device {}", queued.len(), device_uuid);
+    for notification in queued {
+        send(notification.data)?;
+    }
+
+    if let Err(e) = QueuedNotification::delete_by_device_until(device_uuid, last_seq, conn) {
+        error!("Error deleting the replayed notifications: {:#?}", e);
+    }
+    Ok(())
+}
+
+impl WSHandler {
+    /// The replay runs in the queue worker, the database isn't used in the event loop of the websockets
+    fn replay_queued(&self) {
+        if let Some(device_uuid) = &self.device_uuid {
+            if CONFIG.websocket_replay_hours() > 0 {
+                self.users.queue.push(QueueJob::Replay {
+                    device_uuid: device_uuid.clone(),
+                    sender: self.out.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// The database work of the notifications, which runs in the queue worker
+enum QueueJob {
+    /// Queues the update for the devices of the user that didn't get it
+    Store {
+        user_uuid: String,
+        data: Vec<u8>,
+        reached: Vec<String>,
+    },
+    /// Sends the queued notifications to a device that has just connected
+    Replay { device_uuid: String, sender: Sender },
+}
+
+/// The jobs over this limit are dropped. The devices then miss those updates, like when the replay is disabled
+const MAX_QUEUE_JOBS: usize = 10_000;
+
+/// Runs the queue jobs in its own thread, so a slow database doesn't stall the websockets or the event bus
+#[derive(Clone)]
+struct QueueWorker {
+    // The SyncSender can't be shared between the threads, so it's behind a lock
+    sender: Arc<Mutex<mpsc::SyncSender<QueueJob>>>,
+}
+
+impl QueueWorker {
+    fn start(pool: Pool) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUE_JOBS);
+
+        thread::Builder::new()
+            .name("notification-queue".into())
+            .spawn(move || run_queue_jobs(&pool, &receiver))
+            .expect("Error starting the notification queue");
+
+        QueueWorker {
+            sender: Arc::new(Mutex::new(sender)),
+        }
+    }
+
+    fn push(&self, job: QueueJob) {
+        if self.sender.lock().unwrap().try_send(job).is_err() {
+            error!("The notification queue is full, a job was dropped");
+        }
+    }
+}
+
+/// Takes all the waiting jobs at once, so the updates queued together are inserted in bulk
+fn run_queue_jobs(pool: &Pool, receiver: &mpsc::Receiver<QueueJob>) {
+    for job in receiver.iter() {
+        let jobs: Vec<QueueJob> = Some(job).into_iter().chain(receiver.try_iter()).collect();
+
+        let conn = match pool.get() {
+            Ok(conn) => DbConn(conn),
+            Err(e) => {
+                error!("The notification queue can't get a database connection: {:#?}", e);
+                continue;
+            }
+        };
+
+        let mut pending = Vec::new();
+        for job in jobs {
+            match job {
+                QueueJob::Store {
+                    user_uuid,
+                    data,
+                    reached,
+                } => pending.extend(undelivered_updates(&user_uuid, &data, &reached, &conn)),
+                QueueJob::Replay { device_uuid, sender } => {
+                    // The updates queued before the device connected are saved first, so they're replayed too
+                    save_queued(&mut pending, &conn);
+                    replay_queued(&device_uuid, &conn, |data| sender.send(data)).ok();
+                }
+            }
+        }
+        save_queued(&mut pending, &conn);
+    }
+}
+
+fn save_queued(pending: &mut Vec<QueuedNotification>, conn: &DbConn) {
+    if let Err(e) = QueuedNotification::insert_all(pending, conn) {
+        error!("Error queueing the notifications: {:#?}", e);
+    }
+    pending.clear();
+}
+
 impl Handler for WSHandler {
     fn on_open(&mut self, hs: Handshake) -> ws::Result<()> {
         // TODO: Improve this split
@@ -140,20 +440,16 @@ impl Handler for WSHandler {
             Err(_) => return Err(ws::Error::new(ws::ErrorKind::Internal, "Invalid access token provided")),
         };
 
-        // Assign the user to the handler
+        // Assign the user and device to the handler
         let user_uuid = claims.sub;
         self.user_uuid = Some(user_uuid.clone());
+        self.device_uuid = Some(claims.device.clone());
 
         // Add the current Sender to the user list
-        let handler_insert = self.out.clone();
-        let handler_update = self.out.clone();
-
-        self.users
-            .map
-            .upsert(user_uuid, || vec![handler_insert], |ref mut v| v.push(handler_update));
+        self.users.add_sender(user_uuid, claims.device, self.out.clone());
 
         // Schedule a ping to keep the connection alive
-        self.out.timeout(PING_MS, PING)
+        self.out.timeout(ping_ms(), PING)
     }
 
     fn on_message(&mut self, msg: Message) -> ws::Result<()> {
@@ -164,11 +460,36 @@ impl Handler for WSHandler {
 
             if let Ok(InitialMessage { protocol, version }) = from_str::<InitialMessage>(json) {
                 if &protocol == "messagepack" && version == 1 {
-                    return self.out.send(&INITIAL_RESPONSE[..]); // Respond to initial message
+                    self.out.send(&INITIAL_RESPONSE[..])?; // Respond to initial message
+                    self.replay_queued();
+                    return Ok(());
                 }
             }
         }
 
+        if let Message::Binary(data) = msg {
+            self.buffer.extend_from_slice(&data);
+
+            if self.buffer.len() > MAX_BUFFER_SIZE {
+                return Err(ws::Error::new(ws::ErrorKind::Capacity, "Message too big"));
+            }
+
+            let messages = match parse_messages(&mut self.buffer) {
+                Ok(messages) => messages,
+                Err(e) => return Err(ws::Error::new(ws::ErrorKind::Protocol, e)),
+            };
+
+            // Answer the pings, and echo every other complete message, each one framed on its own
+            for message in messages {
+                if is_ping(&message) {
+                    self.out.send(create_ping())?;
+                } else {
+                    self.out.send(frame(message))?;
+                }
+            }
+            return Ok(());
+        }
+
         // If it's not the initial message, just echo the message
         self.out.send(msg)
     }
@@ -179,7 +500,7 @@ impl Handler for WSHandler {
             self.out.send(create_ping())?;
 
             // reschedule the timeout
-            self.out.timeout(PING_MS, PING)
+            self.out.timeout(ping_ms(), PING)
         } else {
             Err(ws::Error::new(
                 ws::ErrorKind::Internal,
@@ -194,10 +515,11 @@ struct WSFactory {
 }
 
 impl WSFactory {
-    pub fn init() -> Self {
+    pub fn init(pool: Pool) -> Self {
         WSFactory {
             users: WebSocketUsers {
                 map: Arc::new(CHashMap::new()),
+                queue: QueueWorker::start(pool),
             },
         }
     }
@@ -210,78 +532,247 @@ impl Factory for WSFactory {
         WSHandler {
             out,
             user_uuid: None,
+            device_uuid: None,
             users: self.users.clone(),
+            buffer: Vec::new(),
         }
     }
 
     fn connection_lost(&mut self, handler: Self::Handler) {
         // Remove handler
         if let Some(user_uuid) = &handler.user_uuid {
-            if let Some(mut user_conn) = self.users.map.get_mut(user_uuid) {
-                user_conn.remove_item(&handler.out);
-            }
+            self.users.remove_senders(user_uuid, |c| c.sender == handler.out);
         }
     }
 }
 
+#[derive(Clone)]
+struct WSConnection {
+    device_uuid: String,
+    sender: Sender,
+}
+
 #[derive(Clone)]
 pub struct WebSocketUsers {
-    map: Arc<CHashMap<String, Vec<Sender>>>,
+    map: Arc<CHashMap<String, Vec<WSConnection>>>,
+    /// Queues the updates of the devices that aren't connected, and replays them
+    queue: QueueWorker,
 }
 
 impl WebSocketUsers {
-    fn send_update(&self, user_uuid: &String, data: &[u8]) -> ws::Result<()> {
-        if let Some(user) = self.map.get(user_uuid) {
-            for sender in user.iter() {
-                sender.send(data)?;
+    /// When the user goes over the limit, the oldest connections are closed to make room for the new one
+    fn add_sender(&self, user_uuid: String, device_uuid: String, sender: Sender) {
+        let max_connections = CONFIG.websocket_max_user_connections().max(1);
+
+        self.map.alter(user_uuid, |connections| {
+            let mut connections = connections.unwrap_or_default();
+
+            while connections.len() >= max_connections {
+                let oldest = connections.remove(0);
+                oldest.sender.close(ws::CloseCode::Away).ok();
             }
-        }
-        Ok(())
+
+            connections.push(WSConnection { device_uuid, sender });
+            Some(connections)
+        });
     }
 
-    // NOTE: The last modified date needs to be updated before calling these methods
-    pub fn send_user_update(&self, ut: UpdateType, user: &User) {
-        let data = create_update(
-            vec![
-                ("UserId".into(), user.uuid.clone().into()),
-                ("Date".into(), serialize_date(user.updated_at)),
-            ],
-            ut,
-        );
+    /// Removes the matching connections, and the user when it has none left, so the map doesn't keep growing
+    fn remove_senders<F: Fn(&WSConnection) -> bool>(&self, user_uuid: &str, matches: F) {
+        self.map.alter(user_uuid.to_string(), |connections| {
+            let mut connections = connections?;
+            connections.retain(|c| !matches(c));
 
-        self.send_update(&user.uuid, &data).ok();
+            if connections.is_empty() {
+                None
+            } else {
+                Some(connections)
+            }
+        });
     }
 
-    pub fn send_folder_update(&self, ut: UpdateType, folder: &Folder) {
-        let data = create_update(
-            vec![
-                ("Id".into(), folder.uuid.clone().into()),
-                ("UserId".into(), folder.user_uuid.clone().into()),
-                ("RevisionDate".into(), serialize_date(folder.updated_at)),
-            ],
-            ut,
-        );
+    /// The senders that fail are dropped right away, instead of waiting for their connection to be closed.
+    /// The devices of the user that didn't get the update have it queued, when the replay is enabled.
+    /// Returns whether the update was delivered to at least one connection
+    fn send_update(&self, user_uuid: &str, data: &[u8]) -> bool {
+        let mut reached = LONG_POLLING.send_update(user_uuid, data);
+        let polled = reached.len();
 
-        self.send_update(&folder.user_uuid, &data).ok();
-    }
+        let (sent, failed): (Vec<WSConnection>, Vec<WSConnection>) = match self.map.get(user_uuid) {
+            Some(connections) => connections.iter().cloned().partition(|c| c.sender.send(data).is_ok()),
+            None => (Vec::new(), Vec::new()),
+        };
 
-    pub fn send_cipher_update(&self, ut: UpdateType, cipher: &Cipher, user_uuids: &[String]) {
-        let user_uuid = convert_option(cipher.user_uuid.clone());
-        let org_uuid = convert_option(cipher.organization_uuid.clone());
+        let delivered = sent.len() + polled;
+        reached.extend(sent.into_iter().map(|c| c.device_uuid));
 
-        let data = create_update(
-            vec![
-                ("Id".into(), cipher.uuid.clone().into()),
-                ("UserId".into(), user_uuid),
-                ("OrganizationId".into(), org_uuid),
-                ("CollectionIds".into(), Value::Nil),
-                ("RevisionDate".into(), serialize_date(cipher.updated_at)),
-            ],
-            ut,
-        );
+        if CONFIG.websocket_replay_hours() > 0 {
+            self.queue.push(QueueJob::Store {
+                user_uuid: user_uuid.to_string(),
+                data: data.to_vec(),
+                reached,
+            });
+        }
+
+        if delivered == 0 && failed.is_empty() {
+            NOTIFICATION_STATS.no_connection.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        NOTIFICATION_STATS.delivered.fetch_add(delivered, Ordering::Relaxed);
+        NOTIFICATION_STATS.failed.fetch_add(failed.len(), Ordering::Relaxed);
+
+        if !failed.is_empty() {
+            debug!("Dropping {} failed websocket connections", failed.len());
+            self.remove_senders(user_uuid, |c| failed.iter().any(|f| f.sender == c.sender));
+        }
+        delivered > 0
+    }
+
+    /// Sends the update to the users, and records the lag since it was published when it reached any of them
+    fn send_updates<'a, I: IntoIterator<Item = &'a String>>(
+        &self,
+        user_uuids: I,
+        data: &[u8],
+        published_at: NaiveDateTime,
+    ) {
+        NOTIFICATION_STATS.emitted.fetch_add(1, Ordering::Relaxed);
 
+        let mut delivered = false;
         for uuid in user_uuids {
-            self.send_update(&uuid, &data).ok();
+            delivered |= self.send_update(uuid, data);
+        }
+
+        if delivered {
+            NOTIFICATION_STATS.record_lag(published_at);
+        }
+    }
+}
+
+/// The update for each device of the user that didn't get it, to replay it when they connect.
+/// The devices that never opened a websocket, like most of the browser extensions, are skipped
+fn undelivered_updates(user_uuid: &str, data: &[u8], reached: &[String], conn: &DbConn) -> Vec<QueuedNotification> {
+    Device::find_by_user(user_uuid, conn)
+        .into_iter()
+        .filter(|device| device.websocket_at.is_some() && !reached.contains(&device.uuid))
+        .map(|device| QueuedNotification::new(device.uuid, data.to_vec()))
+        .collect()
+}
+
+lazy_static! {
+    pub static ref NOTIFICATION_STATS: NotificationStats = NotificationStats::default();
+}
+
+/// Upper bounds in milliseconds of the buckets of the lag histogram, the last bucket has the slower ones
+const LAG_BUCKETS_MS: [i64; 5] = [10, 100, 1000, 10_000, 60_000];
+
+/// Counters of the websocket notifications since the server started, to find out why a device didn't get an update.
+/// The lag is measured from the moment the change was published on the event bus, after its commit
+#[derive(Default)]
+pub struct NotificationStats {
+    /// Updates created, once for each change
+    emitted: AtomicUsize,
+    /// Messages sent to a connection, one update can be sent to many connections
+    delivered: AtomicUsize,
+    /// Messages that couldn't be sent because the connection was lost
+    failed: AtomicUsize,
+    /// Users that should have received an update but had no device connected
+    no_connection: AtomicUsize,
+    lag_buckets: [AtomicUsize; 6],
+    lag_total_ms: AtomicUsize,
+}
+
+impl NotificationStats {
+    fn record_lag(&self, date: NaiveDateTime) {
+        let lag_ms = (Utc::now().naive_utc() - date).num_milliseconds().max(0);
+        let bucket = LAG_BUCKETS_MS.iter().position(|max| lag_ms <= *max);
+
+        self.lag_buckets[bucket.unwrap_or(LAG_BUCKETS_MS.len())].fetch_add(1, Ordering::Relaxed);
+        self.lag_total_ms.fetch_add(lag_ms as usize, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let lags: Vec<usize> = self.lag_buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        let lag_count: usize = lags.iter().sum();
+        let lag_average = self.lag_total_ms.load(Ordering::Relaxed).checked_div(lag_count);
+
+        let buckets: Vec<JsonValue> = lags
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                json!({
+                    "MaxMilliseconds": LAG_BUCKETS_MS.get(i),
+                    "Count": count,
+                })
+            })
+            .collect();
+
+        json!({
+            "Emitted": self.emitted.load(Ordering::Relaxed),
+            "Delivered": self.delivered.load(Ordering::Relaxed),
+            "Failed": self.failed.load(Ordering::Relaxed),
+            "NoConnection": self.no_connection.load(Ordering::Relaxed),
+            "LagAverageMilliseconds": lag_average,
+            "LagBuckets": buckets,
+        })
+    }
+}
+
+impl Subscriber for WebSocketUsers {
+    fn handle(&self, event: &DomainEvent, published_at: NaiveDateTime) {
+        match event {
+            DomainEvent::User { ut, user_uuid, date } => {
+                let data = create_update(
+                    vec![
+                        ("UserId".into(), user_uuid.clone().into()),
+                        ("Date".into(), serialize_date(*date)),
+                    ],
+                    *ut,
+                );
+
+                self.send_updates(Some(user_uuid), &data, published_at);
+            }
+            DomainEvent::Folder {
+                ut,
+                uuid,
+                user_uuid,
+                date,
+            } => {
+                let data = create_update(
+                    vec![
+                        ("Id".into(), uuid.clone().into()),
+                        ("UserId".into(), user_uuid.clone().into()),
+                        ("RevisionDate".into(), serialize_date(*date)),
+                    ],
+                    *ut,
+                );
+
+                self.send_updates(Some(user_uuid), &data, published_at);
+            }
+            DomainEvent::Cipher {
+                ut,
+                uuid,
+                user_uuid,
+                org_uuid,
+                date,
+                user_uuids,
+                collection_uuids,
+            } => {
+                let collection_ids = collection_uuids.iter().map(|uuid| uuid.clone().into()).collect();
+
+                let data = create_update(
+                    vec![
+                        ("Id".into(), uuid.clone().into()),
+                        ("UserId".into(), convert_option(user_uuid.clone())),
+                        ("OrganizationId".into(), convert_option(org_uuid.clone())),
+                        ("CollectionIds".into(), Value::Array(collection_ids)),
+                        ("RevisionDate".into(), serialize_date(*date)),
+                    ],
+                    *ut,
+                );
+
+                self.send_updates(user_uuids, &data, published_at);
+            }
         }
     }
 }
@@ -324,7 +815,7 @@ fn create_ping() -> Vec<u8> {
 }
 
 #[allow(dead_code)]
-#[derive(PartialEq)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum UpdateType {
     CipherUpdate = 0,
     CipherCreate = 1,
@@ -344,21 +835,284 @@ pub enum UpdateType {
     None = 100,
 }
 
-use rocket::State;
-pub type Notify<'a> = State<'a, WebSocketUsers>;
-
-pub fn start_notification_server() -> WebSocketUsers {
-    let factory = WSFactory::init();
+pub fn start_notification_server(pool: Pool) -> WebSocketUsers {
+    let factory = WSFactory::init(pool);
     let users = factory.users.clone();
 
     if CONFIG.websocket_enabled() {
-        thread::spawn(move || {
-            WebSocket::new(factory)
-                .unwrap()
-                .listen((CONFIG.websocket_address().as_str(), CONFIG.websocket_port()))
-                .unwrap();
-        });
+        // In single port mode, or with the Unix sockets, the connections come from the listener in front of it
+        let proxied = CONFIG.websocket_single_port()
+            || CONFIG.unix_socket_path().is_some()
+            || CONFIG.websocket_unix_socket_path().is_some();
+        let addresses = if proxied {
+            vec!["127.0.0.1".to_string()]
+        } else if CONFIG.bind_addresses().is_some() {
+            listener::bind_addresses().iter().map(IpAddr::to_string).collect()
+        } else {
+            vec![CONFIG.websocket_address()]
+        };
+
+        for address in addresses {
+            let factory = WSFactory {
+                users: factory.users.clone(),
+            };
+            start_websocket_server(factory, address);
+        }
     }
 
     users
 }
+
+/// Each address has its own server, they are bound one after the other, so the IPv6 ones cover the IPv4 ones
+fn start_websocket_server(factory: WSFactory, address: String) {
+    let port = CONFIG.websocket_port();
+    let (bound_tx, bound_rx) = mpsc::channel();
+
+    let thread_address = address.clone();
+    thread::spawn(move || {
+        let settings = ws::Settings {
+            fragment_size: MAX_FRAGMENT_SIZE,
+            ..Default::default()
+        };
+        let server = ws::Builder::new()
+            .with_settings(settings)
+            .build(factory)
+            .and_then(|ws| ws.bind((thread_address.as_str(), port)));
+        match server {
+            Ok(server) => {
+                bound_tx.send(Ok(())).ok();
+                if let Err(e) = server.run() {
+                    error!("Websocket server error: {:?}", e);
+                }
+            }
+            Err(ws::Error {
+                kind: ws::ErrorKind::Io(e),
+                ..
+            }) => {
+                bound_tx.send(Err(e)).ok();
+            }
+            Err(e) => {
+                let e = io::Error::new(io::ErrorKind::Other, e.to_string());
+                bound_tx.send(Err(e)).ok();
+            }
+        }
+    });
+
+    match bound_rx.recv() {
+        Ok(Ok(())) => (),
+        Ok(Err(ref e)) if address.parse().map_or(false, |ip| is_covered_by_dual_stack(ip, e)) => {
+            info!("{}:{} is already served by the IPv6 websocket server", address, port)
+        }
+        Ok(Err(e)) => panic!("Error starting the websocket server on {}:{}: {:?}", address, port, e),
+        Err(_) => panic!("The websocket server on {}:{} stopped before listening", address, port),
+    }
+}
+
+fn is_covered_by_dual_stack(ip: IpAddr, e: &io::Error) -> bool {
+    listener::is_covered_by_dual_stack(ip, &listener::bind_addresses(), e)
+}
+
+//
+// Long polling transport, for the clients that can't open a websocket. The messages are queued
+// in the connection until the client polls for them. Each waiting poll holds a Rocket worker
+//
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    static ref LONG_POLLING: LongPollingConnections = LongPollingConnections::default();
+}
+
+struct PollState {
+    /// The framed messages waiting for the next poll
+    pending: Vec<u8>,
+    /// The received data that doesn't form a complete message yet
+    buffer: Vec<u8>,
+    handshake_done: bool,
+    closed: bool,
+    last_seen: Instant,
+}
+
+struct PollConnection {
+    user_uuid: String,
+    device_uuid: String,
+    state: Mutex<PollState>,
+    ready: Condvar,
+}
+
+impl PollConnection {
+    fn new(user_uuid: String, device_uuid: String) -> Self {
+        PollConnection {
+            user_uuid,
+            device_uuid,
+            state: Mutex::new(PollState {
+                pending: Vec::new(),
+                buffer: Vec::new(),
+                handshake_done: false,
+                closed: false,
+                last_seen: Instant::now(),
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    /// Returns the pending messages, or a ping when there are none before the interval ends,
+    /// so the client knows the connection is still alive. `None` means the connection was closed
+    fn poll(&self) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + Duration::from_millis(ping_ms());
+        let mut state = self.state.lock().unwrap();
+
+        while state.pending.is_empty() && !state.closed {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.ready.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        state.last_seen = Instant::now();
+
+        if state.closed {
+            None
+        } else if state.pending.is_empty() {
+            Some(create_ping())
+        } else {
+            Some(std::mem::replace(&mut state.pending, Vec::new()))
+        }
+    }
+
+    /// Returns the pending messages without waiting, which can be none. `None` means the connection was closed
+    fn take_pending(&self) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.last_seen = Instant::now();
+
+        if state.closed {
+            None
+        } else {
+            Some(std::mem::replace(&mut state.pending, Vec::new()))
+        }
+    }
+
+    /// Handles the messages from the client, like the websocket handler does.
+    /// Returns whether this message completed the handshake
+    fn receive(&self, data: &[u8]) -> Result<bool, &'static str> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.last_seen = Instant::now();
+
+        if !state.handshake_done {
+            let text = String::from_utf8_lossy(data);
+            let json = text.trim_end_matches(RECORD_SEPARATOR as char);
+
+            match from_str::<InitialMessage>(json) {
+                Ok(InitialMessage { ref protocol, version }) if protocol == "messagepack" && version == 1 => {
+                    state.handshake_done = true;
+                    state.pending.extend_from_slice(&INITIAL_RESPONSE);
+                    self.ready.notify_all();
+                    return Ok(true);
+                }
+                _ => return Err("Invalid initial message"),
+            }
+        } else {
+            state.buffer.extend_from_slice(data);
+
+            if state.buffer.len() > MAX_BUFFER_SIZE {
+                return Err("Message too big");
+            }
+
+            for message in parse_messages(&mut state.buffer)? {
+                if is_ping(&message) {
+                    state.pending.extend(create_ping());
+                } else {
+                    state.pending.extend(frame(message));
+                }
+            }
+        }
+
+        self.ready.notify_all();
+        Ok(false)
+    }
+
+    fn push(&self, data: &[u8]) {
+        self.state.lock().unwrap().pending.extend_from_slice(data);
+        self.ready.notify_all();
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// The connections that the client stopped polling
+    fn is_abandoned(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.last_seen.elapsed() > Duration::from_millis(3 * ping_ms())
+    }
+}
+
+#[derive(Default)]
+struct LongPollingConnections {
+    connections: Mutex<HashMap<String, Arc<PollConnection>>>,
+}
+
+impl LongPollingConnections {
+    /// Like with the websockets, the oldest connections of the user are closed when it goes over the limit.
+    /// Returns None when the id is already used by the connection of another user
+    fn add(&self, id: String, user_uuid: String, device_uuid: String) -> Option<Arc<PollConnection>> {
+        let max_connections = CONFIG.websocket_max_user_connections().max(1);
+        let mut connections = self.connections.lock().unwrap();
+
+        if connections.contains_key(&id) {
+            return None;
+        }
+
+        connections.retain(|_, c| {
+            let abandoned = c.is_abandoned();
+            if abandoned {
+                c.close();
+            }
+            !abandoned
+        });
+
+        let mut user_connections: Vec<(String, Instant)> = connections
+            .iter()
+            .filter(|(_, c)| c.user_uuid == user_uuid)
+            .map(|(id, c)| (id.clone(), c.state.lock().unwrap().last_seen))
+            .collect();
+        user_connections.sort_by_key(|(_, last_seen)| *last_seen);
+
+        let excess = (user_connections.len() + 1).saturating_sub(max_connections);
+        for (old_id, _) in user_connections.into_iter().take(excess) {
+            if let Some(old) = connections.remove(&old_id) {
+                old.close();
+            }
+        }
+
+        let connection = Arc::new(PollConnection::new(user_uuid, device_uuid));
+        connections.insert(id, Arc::clone(&connection));
+        Some(connection)
+    }
+
+    fn get(&self, id: &str, user_uuid: &str) -> Option<Arc<PollConnection>> {
+        let connections = self.connections.lock().unwrap();
+        connections.get(id).filter(|c| c.user_uuid == user_uuid).cloned()
+    }
+
+    fn remove(&self, id: &str) {
+        if let Some(connection) = self.connections.lock().unwrap().remove(id) {
+            connection.close();
+        }
+    }
+
+    /// Queues the update in all the connections of the user, returning the device of each one
+    fn send_update(&self, user_uuid: &str, data: &[u8]) -> Vec<String> {
+        let connections = self.connections.lock().unwrap();
+        let user_connections: Vec<_> = connections.values().filter(|c| c.user_uuid == user_uuid).collect();
+
+        for connection in &user_connections {
+            connection.push(data);
+        }
+        user_connections.iter().map(|c| c.device_uuid.clone()).collect()
+    }
+}
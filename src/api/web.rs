@@ -1,21 +1,33 @@
+use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
 
-use rocket::http::ContentType;
+use rocket::http::{ContentType, Status};
 use rocket::response::content::Content;
 use rocket::response::NamedFile;
 use rocket::Route;
 use rocket_contrib::json::Json;
 use serde_json::Value;
 
+use crate::auth::decode_file_download;
 use crate::util::Cached;
 use crate::CONFIG;
 
 pub fn routes() -> Vec<Route> {
     if CONFIG.web_vault_enabled() {
-        routes![web_index, app_id, web_files, attachments, alive]
+        routes![
+            web_index,
+            app_id,
+            duo_connector,
+            u2f_connector,
+            webauthn_connector,
+            webauthn_fallback_connector,
+            web_files,
+            attachments,
+            alive
+        ]
     } else {
-        routes![attachments, alive]
+        routes![app_id, attachments, alive]
     }
 }
 
@@ -30,29 +42,69 @@ fn web_index() -> Cached<io::Result<NamedFile>> {
 fn app_id() -> Cached<Content<Json<Value>>> {
     let content_type = ContentType::new("application", "fido.trusted-apps+json");
 
+    let mut ids = vec![
+        CONFIG.domain(),
+        "ios:bundle-id:com.8bit.bitwarden".to_string(),
+        "android:apk-key-hash:dUGFzUzf3lmHSLBDBIv+WaFyZMI".to_string(),
+    ];
+    // The pages that embed the vault also run the U2F requests
+    ids.extend(CONFIG.allowed_iframe_ancestors().split_whitespace().map(String::from));
+
     Cached::long(Content(
         content_type,
         Json(json!({
         "trustedFacets": [
             {
             "version": { "major": 1, "minor": 0 },
-            "ids": [
-                &CONFIG.domain(),
-                "ios:bundle-id:com.8bit.bitwarden",
-                "android:apk-key-hash:dUGFzUzf3lmHSLBDBIv+WaFyZMI" ]
+            "ids": ids
             }]
         })),
     ))
 }
 
+#[get("/duo-connector.html")]
+fn duo_connector() -> Option<Cached<Content<String>>> {
+    connector("duo-connector.html")
+}
+
+#[get("/u2f-connector.html")]
+fn u2f_connector() -> Option<Cached<Content<String>>> {
+    connector("u2f-connector.html")
+}
+
+#[get("/webauthn-connector.html")]
+fn webauthn_connector() -> Option<Cached<Content<String>>> {
+    connector("webauthn-connector.html")
+}
+
+#[get("/webauthn-fallback-connector.html")]
+fn webauthn_fallback_connector() -> Option<Cached<Content<String>>> {
+    connector("webauthn-fallback-connector.html")
+}
+
+/// The clients load the connectors in a frame to complete the 2FA ceremonies, and the web vault build
+/// points them to the official vault, so that address is replaced with the domain of this server
+fn connector(name: &str) -> Option<Cached<Content<String>>> {
+    let page = fs::read_to_string(Path::new(&CONFIG.web_vault_folder()).join(name)).ok()?;
+    let page = page.replace("https://vault.bitwarden.com", &CONFIG.domain());
+
+    Some(Cached::short(Content(ContentType::HTML, page)))
+}
+
 #[get("/<p..>", rank = 10)] // Only match this if the other routes don't match
 fn web_files(p: PathBuf) -> Cached<io::Result<NamedFile>> {
     Cached::long(NamedFile::open(Path::new(&CONFIG.web_vault_folder()).join(p)))
 }
 
-#[get("/attachments/<uuid>/<file..>")]
-fn attachments(uuid: String, file: PathBuf) -> io::Result<NamedFile> {
-    NamedFile::open(Path::new(&CONFIG.attachments_folder()).join(uuid).join(file))
+/// The files are only served with the token of the url returned with the cipher, which is valid for that file only
+#[get("/attachments/<uuid>/<file_id>?<token>")]
+fn attachments(uuid: String, file_id: String, token: Option<String>) -> Result<NamedFile, Status> {
+    match token.map(|t| decode_file_download(&t)) {
+        Some(Ok(ref claims)) if claims.sub == uuid && claims.file_id == file_id => (),
+        _ => return Err(Status::Unauthorized),
+    }
+
+    NamedFile::open(Path::new(&CONFIG.attachments_folder()).join(uuid).join(file_id)).map_err(|_| Status::NotFound)
 }
 
 #[get("/alive")]
@@ -2,7 +2,7 @@
 // JWT Handling
 //
 use crate::util::read_file;
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 
 use jsonwebtoken::{self, Algorithm, Header};
 use serde::de::DeserializeOwned;
@@ -11,14 +11,18 @@ use serde::ser::Serialize;
 use crate::error::{Error, MapResult};
 use crate::CONFIG;
 
-const JWT_ALGORITHM: Algorithm = Algorithm::RS256;
+pub mod ldap;
 
 lazy_static! {
     pub static ref DEFAULT_VALIDITY: Duration = Duration::hours(2);
-    static ref JWT_HEADER: Header = Header::new(JWT_ALGORITHM);
     pub static ref JWT_LOGIN_ISSUER: String = format!("{}|login", CONFIG.domain());
     pub static ref JWT_INVITE_ISSUER: String = format!("{}|invite", CONFIG.domain());
     pub static ref JWT_ADMIN_ISSUER: String = format!("{}|admin", CONFIG.domain());
+    pub static ref JWT_UNLOCK_ISSUER: String = format!("{}|unlock", CONFIG.domain());
+    pub static ref JWT_VERIFY_EMAIL_ISSUER: String = format!("{}|verifyemail", CONFIG.domain());
+    pub static ref JWT_FILE_DOWNLOAD_ISSUER: String = format!("{}|filedownload", CONFIG.domain());
+    pub static ref JWT_DEVICE_APPROVAL_ISSUER: String = format!("{}|deviceapproval", CONFIG.domain());
+    pub static ref JWT_EMAIL_CHANGE_APPROVAL_ISSUER: String = format!("{}|emailchangeapproval", CONFIG.domain());
     static ref PRIVATE_RSA_KEY: Vec<u8> = match read_file(&CONFIG.private_rsa_key()) {
         Ok(key) => key,
         Err(e) => panic!(
@@ -33,44 +37,233 @@ lazy_static! {
             CONFIG.public_rsa_key(), e
         ),
     };
+    static ref INVITE_SECRET: Vec<u8> = derive_secret(JwtClass::Invite);
+    static ref ADMIN_SECRET: Vec<u8> = derive_secret(JwtClass::Admin);
+    static ref UNLOCK_SECRET: Vec<u8> = derive_secret(JwtClass::Unlock);
+    static ref VERIFY_EMAIL_SECRET: Vec<u8> = derive_secret(JwtClass::VerifyEmail);
+    static ref FILE_DOWNLOAD_SECRET: Vec<u8> = derive_secret(JwtClass::FileDownload);
+    static ref DEVICE_APPROVAL_SECRET: Vec<u8> = derive_secret(JwtClass::DeviceApproval);
+    static ref EMAIL_CHANGE_APPROVAL_SECRET: Vec<u8> = derive_secret(JwtClass::EmailChangeApproval);
 }
 
-pub fn encode_jwt<T: Serialize>(claims: &T) -> String {
-    match jsonwebtoken::encode(&JWT_HEADER, claims, &PRIVATE_RSA_KEY) {
+/// The different kinds of tokens. Each one has its own issuer and signing key,
+/// so a token issued for one purpose is never accepted for another, and leaking
+/// the key of one kind doesn't allow forging the others
+#[derive(Clone, Copy)]
+pub enum JwtClass {
+    Login,
+    Invite,
+    Admin,
+    Unlock,
+    VerifyEmail,
+    FileDownload,
+    DeviceApproval,
+    EmailChangeApproval,
+}
+
+impl JwtClass {
+    fn name(self) -> &'static str {
+        match self {
+            JwtClass::Login => "login",
+            JwtClass::Invite => "invite",
+            JwtClass::Admin => "admin",
+            JwtClass::Unlock => "unlock",
+            JwtClass::VerifyEmail => "verifyemail",
+            JwtClass::FileDownload => "filedownload",
+            JwtClass::DeviceApproval => "deviceapproval",
+            JwtClass::EmailChangeApproval => "emailchangeapproval",
+        }
+    }
+
+    fn issuer(self) -> String {
+        match self {
+            JwtClass::Login => JWT_LOGIN_ISSUER.to_string(),
+            JwtClass::Invite => JWT_INVITE_ISSUER.to_string(),
+            JwtClass::Admin => JWT_ADMIN_ISSUER.to_string(),
+            JwtClass::Unlock => JWT_UNLOCK_ISSUER.to_string(),
+            JwtClass::VerifyEmail => JWT_VERIFY_EMAIL_ISSUER.to_string(),
+            JwtClass::FileDownload => JWT_FILE_DOWNLOAD_ISSUER.to_string(),
+            JwtClass::DeviceApproval => JWT_DEVICE_APPROVAL_ISSUER.to_string(),
+            JwtClass::EmailChangeApproval => JWT_EMAIL_CHANGE_APPROVAL_ISSUER.to_string(),
+        }
+    }
+
+    // The login tokens keep using the RSA key, so other services can validate them with the public key.
+    // The rest are only validated by this server, so they use a secret derived for each kind
+    fn algorithm(self) -> Algorithm {
+        match self {
+            JwtClass::Login => Algorithm::RS256,
+            _ => Algorithm::HS256,
+        }
+    }
+
+    fn encoding_key(self) -> &'static [u8] {
+        match self {
+            JwtClass::Login => &PRIVATE_RSA_KEY,
+            _ => self.decoding_key(),
+        }
+    }
+
+    fn decoding_key(self) -> &'static [u8] {
+        match self {
+            JwtClass::Login => &PUBLIC_RSA_KEY,
+            JwtClass::Invite => &INVITE_SECRET,
+            JwtClass::Admin => &ADMIN_SECRET,
+            JwtClass::Unlock => &UNLOCK_SECRET,
+            JwtClass::VerifyEmail => &VERIFY_EMAIL_SECRET,
+            JwtClass::FileDownload => &FILE_DOWNLOAD_SECRET,
+            JwtClass::DeviceApproval => &DEVICE_APPROVAL_SECRET,
+            JwtClass::EmailChangeApproval => &EMAIL_CHANGE_APPROVAL_SECRET,
+        }
+    }
+}
+
+fn derive_secret(class: JwtClass) -> Vec<u8> {
+    use ring::{digest, hmac};
+
+    let key = hmac::SigningKey::new(&digest::SHA256, &PRIVATE_RSA_KEY);
+    hmac::sign(&key, class.name().as_bytes()).as_ref().to_vec()
+}
+
+/// Implemented by all the claims, to know which kind of token they belong to
+pub trait JwtClaims {
+    const CLASS: JwtClass;
+}
+
+pub fn encode_jwt<T: Serialize + JwtClaims>(claims: &T) -> String {
+    let header = Header::new(T::CLASS.algorithm());
+
+    match jsonwebtoken::encode(&header, claims, T::CLASS.encoding_key()) {
         Ok(token) => token,
         Err(e) => panic!("Error encoding jwt {}", e),
     }
 }
 
-fn decode_jwt<T: DeserializeOwned>(token: &str, issuer: String) -> Result<T, Error> {
+fn decode_jwt<T: DeserializeOwned + JwtClaims>(token: &str) -> Result<T, Error> {
+    decode_jwt_class(token, T::CLASS).map_res("Error decoding JWT")
+}
+
+fn decode_jwt_class<T: DeserializeOwned>(token: &str, class: JwtClass) -> Result<T, jsonwebtoken::errors::Error> {
+    decode_jwt_with(token, class, class.algorithm(), class.decoding_key())
+}
+
+fn decode_jwt_with<T: DeserializeOwned>(
+    token: &str,
+    class: JwtClass,
+    algorithm: Algorithm,
+    key: &[u8],
+) -> Result<T, jsonwebtoken::errors::Error> {
     let validation = jsonwebtoken::Validation {
         leeway: 30, // 30 seconds
         validate_exp: true,
         validate_iat: false, // IssuedAt is the same as NotBefore
         validate_nbf: true,
         aud: None,
-        iss: Some(issuer),
+        iss: Some(class.issuer()),
         sub: None,
-        algorithms: vec![JWT_ALGORITHM],
+        algorithms: vec![algorithm],
     };
 
     let token = token.replace(char::is_whitespace, "");
 
-    jsonwebtoken::decode(&token, &PUBLIC_RSA_KEY, &validation)
-        .map(|d| d.claims)
-        .map_res("Error decoding JWT")
+    jsonwebtoken::decode(&token, key, &validation).map(|d| d.claims)
+}
+
+#[derive(Serialize, Deserialize)]
+struct SelfTestJWTClaims {
+    nbf: i64,
+    exp: i64,
+    iss: String,
+    sub: String,
+}
+
+/// Signs and verifies a token with the RSA keys and another one with a derived secret.
+/// The keys are read here first, so a missing or corrupted file is reported with its path
+pub fn self_test() -> Result<(), String> {
+    for path in &[CONFIG.private_rsa_key(), CONFIG.public_rsa_key()] {
+        if let Err(e) = read_file(path) {
+            return Err(format!("Can't read the RSA key {}: {}", path, e));
+        }
+    }
+
+    for class in &[JwtClass::Login, JwtClass::Unlock] {
+        let time_now = Utc::now().naive_utc();
+        let claims = SelfTestJWTClaims {
+            nbf: time_now.timestamp(),
+            exp: (time_now + Duration::minutes(1)).timestamp(),
+            iss: class.issuer(),
+            sub: "self-test".to_string(),
+        };
+
+        let header = Header::new(class.algorithm());
+        let token = jsonwebtoken::encode(&header, &claims, class.encoding_key())
+            .map_err(|e| format!("Can't sign a {} token: {}", class.name(), e))?;
+
+        match decode_jwt_class::<SelfTestJWTClaims>(&token, *class) {
+            Ok(ref decoded) if decoded.sub == claims.sub => (),
+            _ => {
+                return Err(format!(
+                    "Can't verify a {} token, the public RSA key may not match the private one",
+                    class.name()
+                ));
+            }
+        }
+    }
+
+    Ok(())
 }
 
 pub fn decode_login(token: &str) -> Result<LoginJWTClaims, Error> {
-    decode_jwt(token, JWT_LOGIN_ISSUER.to_string())
+    decode_jwt(token)
 }
 
+/// The expired invitations get their own error, so the users know they need a new one
 pub fn decode_invite(token: &str) -> Result<InviteJWTClaims, Error> {
-    decode_jwt(token, JWT_INVITE_ISSUER.to_string())
+    use jsonwebtoken::errors::ErrorKind;
+
+    // The invitations sent before each kind of token had its own secret are signed with the RSA key,
+    // they are still accepted until they expire
+    let is_legacy = match jsonwebtoken::decode_header(&token.replace(char::is_whitespace, "")) {
+        Ok(header) => header.alg == Algorithm::RS256,
+        Err(_) => false,
+    };
+    let result = if is_legacy {
+        decode_jwt_with(token, JwtClass::Invite, Algorithm::RS256, &PUBLIC_RSA_KEY)
+    } else {
+        decode_jwt_class(token, JwtClass::Invite)
+    };
+
+    match result {
+        Ok(claims) => Ok(claims),
+        Err(e) => match e.kind() {
+            ErrorKind::ExpiredSignature => err!("The invitation has expired, ask for a new one"),
+            _ => Err(Error::from(e).with_msg("Error decoding JWT")),
+        },
+    }
 }
 
 pub fn decode_admin(token: &str) -> Result<AdminJWTClaims, Error> {
-    decode_jwt(token, JWT_ADMIN_ISSUER.to_string())
+    decode_jwt(token)
+}
+
+pub fn decode_unlock(token: &str) -> Result<UnlockJWTClaims, Error> {
+    decode_jwt(token)
+}
+
+pub fn decode_verify_email(token: &str) -> Result<VerifyEmailJWTClaims, Error> {
+    decode_jwt(token)
+}
+
+pub fn decode_file_download(token: &str) -> Result<FileDownloadJWTClaims, Error> {
+    decode_jwt(token)
+}
+
+pub fn decode_device_approval(token: &str) -> Result<DeviceApprovalJWTClaims, Error> {
+    decode_jwt(token)
+}
+
+pub fn decode_email_change_approval(token: &str) -> Result<EmailChangeApprovalJWTClaims, Error> {
+    decode_jwt(token)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -104,6 +297,10 @@ pub struct LoginJWTClaims {
     pub amr: Vec<String>,
 }
 
+impl JwtClaims for LoginJWTClaims {
+    const CLASS: JwtClass = JwtClass::Login;
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InviteJWTClaims {
     // Not before
@@ -121,6 +318,10 @@ pub struct InviteJWTClaims {
     pub invited_by_email: Option<String>,
 }
 
+impl JwtClaims for InviteJWTClaims {
+    const CLASS: JwtClass = JwtClass::Invite;
+}
+
 pub fn generate_invite_claims(
     uuid: String,
     email: String,
@@ -131,7 +332,7 @@ pub fn generate_invite_claims(
     let time_now = Utc::now().naive_utc();
     InviteJWTClaims {
         nbf: time_now.timestamp(),
-        exp: (time_now + Duration::days(5)).timestamp(),
+        exp: (time_now + Duration::hours(CONFIG.invitation_expiration_hours())).timestamp(),
         iss: JWT_INVITE_ISSUER.to_string(),
         sub: uuid.clone(),
         email: email.clone(),
@@ -153,6 +354,10 @@ pub struct AdminJWTClaims {
     pub sub: String,
 }
 
+impl JwtClaims for AdminJWTClaims {
+    const CLASS: JwtClass = JwtClass::Admin;
+}
+
 pub fn generate_admin_claims() -> AdminJWTClaims {
     let time_now = Utc::now().naive_utc();
     AdminJWTClaims {
@@ -163,13 +368,175 @@ pub fn generate_admin_claims() -> AdminJWTClaims {
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnlockJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    // The lockout the token was sent for, so it can only unlock that one
+    pub locked_until: i64,
+}
+
+impl JwtClaims for UnlockJWTClaims {
+    const CLASS: JwtClass = JwtClass::Unlock;
+}
+
+pub fn generate_unlock_claims(uuid: String, locked_until: &NaiveDateTime) -> UnlockJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    UnlockJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::days(1)).timestamp(),
+        iss: JWT_UNLOCK_ISSUER.to_string(),
+        sub: uuid,
+        locked_until: locked_until.timestamp(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    pub email: String,
+}
+
+impl JwtClaims for VerifyEmailJWTClaims {
+    const CLASS: JwtClass = JwtClass::VerifyEmail;
+}
+
+pub fn generate_verify_email_claims(uuid: String, email: String) -> VerifyEmailJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    VerifyEmailJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::hours(1)).timestamp(),
+        iss: JWT_VERIFY_EMAIL_ISSUER.to_string(),
+        sub: uuid,
+        email,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceApprovalJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    pub device_id: String,
+    pub device_name: String,
+    pub device_type: i32,
+}
+
+impl JwtClaims for DeviceApprovalJWTClaims {
+    const CLASS: JwtClass = JwtClass::DeviceApproval;
+}
+
+pub fn generate_device_approval_claims(
+    uuid: String,
+    device_id: String,
+    device_name: String,
+    device_type: i32,
+) -> DeviceApprovalJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    DeviceApprovalJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::hours(1)).timestamp(),
+        iss: JWT_DEVICE_APPROVAL_ISSUER.to_string(),
+        sub: uuid,
+        device_id,
+        device_name,
+        device_type,
+    }
+}
+
+/// Sent to the current address of the user, when the email change has to be approved from it too
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailChangeApprovalJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject
+    pub sub: String,
+
+    // The new address
+    pub email: String,
+}
+
+impl JwtClaims for EmailChangeApprovalJWTClaims {
+    const CLASS: JwtClass = JwtClass::EmailChangeApproval;
+}
+
+pub fn generate_email_change_approval_claims(uuid: String, email: String) -> EmailChangeApprovalJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    EmailChangeApprovalJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + Duration::hours(1)).timestamp(),
+        iss: JWT_EMAIL_CHANGE_APPROVAL_ISSUER.to_string(),
+        sub: uuid,
+        email,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileDownloadJWTClaims {
+    // Not before
+    pub nbf: i64,
+    // Expiration time
+    pub exp: i64,
+    // Issuer
+    pub iss: String,
+    // Subject, the cipher of the file
+    pub sub: String,
+
+    pub file_id: String,
+}
+
+impl JwtClaims for FileDownloadJWTClaims {
+    const CLASS: JwtClass = JwtClass::FileDownload;
+}
+
+/// The download links are only given to the users that can see the cipher, and they last as long as a login
+pub fn generate_file_download_claims(cipher_uuid: String, file_id: String) -> FileDownloadJWTClaims {
+    let time_now = Utc::now().naive_utc();
+    FileDownloadJWTClaims {
+        nbf: time_now.timestamp(),
+        exp: (time_now + *DEFAULT_VALIDITY).timestamp(),
+        iss: JWT_FILE_DOWNLOAD_ISSUER.to_string(),
+        sub: cipher_uuid,
+        file_id,
+    }
+}
+
 //
 // Bearer token authentication
 //
+use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
 use rocket::Outcome;
 
-use crate::db::models::{Device, User, UserOrgStatus, UserOrgType, UserOrganization};
+use crate::db::models::{
+    ClientCertificate, Collection, Device, Provider, ProviderOrganization, ProviderUser, User, UserOrgStatus,
+    UserOrgType, UserOrganization,
+};
 use crate::db::DbConn;
 
 pub struct Headers {
@@ -235,7 +602,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             _ => err_handler!("Error getting DB"),
         };
 
-        let device = match Device::find_by_uuid(&device_uuid, &conn) {
+        let mut device = match Device::find_by_uuid(&device_uuid, &conn) {
             Some(device) => device,
             None => err_handler!("Invalid device id"),
         };
@@ -249,66 +616,247 @@ impl<'a, 'r> FromRequest<'a, 'r> for Headers {
             err_handler!("Invalid security stamp")
         }
 
+        if user.is_deleted() {
+            err_handler!("This account has been deleted")
+        }
+
+        if user.disabled {
+            err_handler!("This account has been disabled")
+        }
+
+        // The clients are sent to the password change with the ForcePasswordReset of the login response,
+        // until then the rest of the API is refused. It's not a 401, so the clients don't log out
+        if user.force_password_reset && !is_password_change_route(request) {
+            let msg = "Your master password must be changed before using the vault";
+            error!("Forbidden Error: {}. User: {}", msg, user.email);
+            return Outcome::Failure((Status::Forbidden, msg));
+        }
+
+        crate::i18n::set_request_locale(request, user.locale.clone());
+
+        let ip = match request.guard::<ClientIp>() {
+            Outcome::Success(ip) => ip.ip.to_string(),
+            _ => err_handler!("Error getting Client IP"),
+        };
+        if device.update_activity(&ip) {
+            if let Err(e) = device.save_activity(&conn) {
+                error!("Error saving device activity: {:#?}", e);
+            }
+        }
+
         Outcome::Success(Headers { host, device, user })
     }
 }
 
-pub struct OrgHeaders {
+/// The routes used to change the master password, which can still be used while a change is required
+fn is_password_change_route(request: &Request) -> bool {
+    const ROUTES: &[&str] = &[
+        "/api/accounts/profile",
+        "/api/accounts/revision-date",
+        "/api/accounts/password",
+        "/api/accounts/kdf",
+        "/api/accounts/key",
+    ];
+    ROUTES.contains(&request.uri().path())
+}
+
+/// The organization id is taken from the path ("/organizations/<org_id>/..."),
+/// or from the query for the routes that don't have it there ("?organizationId=<org_id>")
+fn get_org_id(request: &Request) -> Option<String> {
+    if let Some(Ok(segment)) = request.get_param::<String>(0) {
+        if segment == "organizations" {
+            return match request.get_param::<String>(1) {
+                Some(Ok(org_id)) => Some(org_id),
+                _ => None,
+            };
+        }
+    }
+
+    match request.get_query_value::<String>("organizationId") {
+        Some(Ok(org_id)) => Some(org_id),
+        _ => None,
+    }
+}
+
+/// The collection id, for the routes under "/organizations/<org_id>/collections/<col_id>"
+fn get_col_id(request: &Request) -> Option<String> {
+    match request.get_param::<String>(2) {
+        Some(Ok(ref segment)) if segment == "collections" => match request.get_param::<String>(3) {
+            Some(Ok(col_id)) => Some(col_id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Any confirmed member of the organization, with the type they have in it
+pub struct OrgMemberHeaders {
     pub host: String,
     pub device: Device,
     pub user: User,
+    pub org_id: String,
     pub org_user_type: UserOrgType,
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for OrgHeaders {
+impl<'a, 'r> FromRequest<'a, 'r> for OrgMemberHeaders {
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
         match request.guard::<Headers>() {
             Outcome::Forward(_) => Outcome::Forward(()),
             Outcome::Failure(f) => Outcome::Failure(f),
-            Outcome::Success(headers) => {
-                // org_id is expected to be the second param ("/organizations/<org_id>")
-                match request.get_param::<String>(1) {
-                    Some(Ok(org_id)) => {
-                        let conn = match request.guard::<DbConn>() {
-                            Outcome::Success(conn) => conn,
-                            _ => err_handler!("Error getting DB"),
-                        };
+            Outcome::Success(headers) => match get_org_id(request) {
+                Some(org_id) => {
+                    let conn = match request.guard::<DbConn>() {
+                        Outcome::Success(conn) => conn,
+                        _ => err_handler!("Error getting DB"),
+                    };
+
+                    let user = headers.user;
+                    let org_user_type = match UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn) {
+                        Some(org_user) => {
+                            if org_user.status != UserOrgStatus::Confirmed as i32 {
+                                err_handler!("The current user isn't confirmed member of the organization")
+                            }
 
-                        let user = headers.user;
-                        let org_user = match UserOrganization::find_by_user_and_org(&user.uuid, &org_id, &conn) {
-                            Some(user) => {
-                                if user.status == UserOrgStatus::Confirmed as i32 {
-                                    user
-                                } else {
-                                    err_handler!("The current user isn't confirmed member of the organization")
-                                }
+                            match UserOrgType::from_i32(org_user.type_) {
+                                Some(org_user_type) => org_user_type,
+                                // This should only happen if the DB is corrupted
+                                None => err_handler!("Unknown user type in the database"),
                             }
+                        }
+                        None => match get_provider_org_user_type(&user.uuid, &org_id, &conn) {
+                            Some(org_user_type) => org_user_type,
                             None => err_handler!("The current user isn't member of the organization"),
-                        };
+                        },
+                    };
 
-                        Outcome::Success(Self {
-                            host: headers.host,
-                            device: headers.device,
-                            user,
-                            org_user_type: {
-                                if let Some(org_usr_type) = UserOrgType::from_i32(org_user.type_) {
-                                    org_usr_type
-                                } else {
-                                    // This should only happen if the DB is corrupted
-                                    err_handler!("Unknown user type in the database")
-                                }
-                            },
-                        })
-                    }
-                    _ => err_handler!("Error getting the organization id"),
+                    Outcome::Success(Self {
+                        host: headers.host,
+                        device: headers.device,
+                        user,
+                        org_id,
+                        org_user_type,
+                    })
+                }
+                None => err_handler!("Error getting the organization id"),
+            },
+        }
+    }
+}
+
+impl OrgMemberHeaders {
+    /// Gets the member headers, failing if their type in the organization is lower than `min_type`
+    fn with_min_type(
+        request: &Request,
+        min_type: UserOrgType,
+        error: &'static str,
+    ) -> request::Outcome<Self, &'static str> {
+        match request.guard::<OrgMemberHeaders>() {
+            Outcome::Forward(_) => Outcome::Forward(()),
+            Outcome::Failure(f) => Outcome::Failure(f),
+            Outcome::Success(headers) => {
+                if headers.org_user_type >= min_type {
+                    Outcome::Success(headers)
+                } else {
+                    err_handler!(error)
                 }
             }
         }
     }
 }
 
+/// The users of the provider that manages an organization can access it without being members of it.
+/// Provider admins act as owners of the organization, and service users as admins.
+fn get_provider_org_user_type(user_uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<UserOrgType> {
+    let provider_org = ProviderOrganization::find_by_org(org_uuid, conn)?;
+    let provider_user = ProviderUser::find_by_user_and_provider(user_uuid, &provider_org.provider_uuid, conn)?;
+
+    if !provider_user.is_confirmed() {
+        return None;
+    }
+
+    match Provider::find_by_uuid(&provider_org.provider_uuid, conn) {
+        Some(ref provider) if provider.enabled => (),
+        _ => return None,
+    }
+
+    if provider_user.is_admin() {
+        Some(UserOrgType::Owner)
+    } else {
+        Some(UserOrgType::Admin)
+    }
+}
+
+/// Managers, admins and owners. In the collection routes, managers also need to be assigned to the collection
+pub struct ManagerHeaders {
+    pub host: String,
+    pub device: Device,
+    pub user: User,
+    pub org_user_type: UserOrgType,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ManagerHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let error = "You need to be Manager, Admin or Owner to call this endpoint";
+        let headers = match OrgMemberHeaders::with_min_type(request, UserOrgType::Manager, error) {
+            Outcome::Success(headers) => headers,
+            Outcome::Forward(f) => return Outcome::Forward(f),
+            Outcome::Failure(f) => return Outcome::Failure(f),
+        };
+
+        // The managers only manage their collections, so the routes without one are only for the admins
+        if headers.org_user_type < UserOrgType::Admin {
+            let col_id = match get_col_id(request) {
+                Some(col_id) => col_id,
+                None => err_handler!("You need to be Admin or Owner to call this endpoint"),
+            };
+
+            let conn = match request.guard::<DbConn>() {
+                Outcome::Success(conn) => conn,
+                _ => err_handler!("Error getting DB"),
+            };
+
+            match Collection::find_by_uuid_and_user(&col_id, &headers.user.uuid, &conn) {
+                Some(ref collection) if collection.org_uuid == headers.org_id => (),
+                _ => err_handler!("The current user isn't a manager for this collection"),
+            }
+        }
+
+        Outcome::Success(Self {
+            host: headers.host,
+            device: headers.device,
+            user: headers.user,
+            org_user_type: headers.org_user_type,
+        })
+    }
+}
+
+/// A manager, admin or owner of the organization, without checking any collection.
+/// Only for the listings, which have to limit the results of the managers to their own collections
+pub struct ManagerHeadersLoose {
+    pub host: String,
+    pub device: Device,
+    pub user: User,
+    pub org_user_type: UserOrgType,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ManagerHeadersLoose {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let error = "You need to be Manager, Admin or Owner to call this endpoint";
+        OrgMemberHeaders::with_min_type(request, UserOrgType::Manager, error).map(|headers| Self {
+            host: headers.host,
+            device: headers.device,
+            user: headers.user,
+            org_user_type: headers.org_user_type,
+        })
+    }
+}
+
 pub struct AdminHeaders {
     pub host: String,
     pub device: Device,
@@ -320,47 +868,121 @@ impl<'a, 'r> FromRequest<'a, 'r> for AdminHeaders {
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        match request.guard::<OrgHeaders>() {
+        let error = "You need to be Admin or Owner to call this endpoint";
+        OrgMemberHeaders::with_min_type(request, UserOrgType::Admin, error).map(|headers| Self {
+            host: headers.host,
+            device: headers.device,
+            user: headers.user,
+            org_user_type: headers.org_user_type,
+        })
+    }
+}
+
+impl From<AdminHeaders> for Headers {
+    fn from(h: AdminHeaders) -> Headers {
+        Headers {
+            host: h.host,
+            device: h.device,
+            user: h.user,
+        }
+    }
+}
+
+pub struct OwnerHeaders {
+    pub host: String,
+    pub device: Device,
+    pub user: User,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let error = "You need to be Owner to call this endpoint";
+        OrgMemberHeaders::with_min_type(request, UserOrgType::Owner, error).map(|headers| Self {
+            host: headers.host,
+            device: headers.device,
+            user: headers.user,
+        })
+    }
+}
+
+pub struct ProviderHeaders {
+    pub host: String,
+    pub device: Device,
+    pub user: User,
+    pub provider_user: ProviderUser,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ProviderHeaders {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.guard::<Headers>() {
             Outcome::Forward(_) => Outcome::Forward(()),
             Outcome::Failure(f) => Outcome::Failure(f),
             Outcome::Success(headers) => {
-                if headers.org_user_type >= UserOrgType::Admin {
-                    Outcome::Success(Self {
-                        host: headers.host,
-                        device: headers.device,
-                        user: headers.user,
-                        org_user_type: headers.org_user_type,
-                    })
-                } else {
-                    err_handler!("You need to be Admin or Owner to call this endpoint")
+                // provider_id is expected to be the second param ("/providers/<provider_id>")
+                match request.get_param::<String>(1) {
+                    Some(Ok(provider_id)) => {
+                        let conn = match request.guard::<DbConn>() {
+                            Outcome::Success(conn) => conn,
+                            _ => err_handler!("Error getting DB"),
+                        };
+
+                        let provider_user =
+                            match ProviderUser::find_by_user_and_provider(&headers.user.uuid, &provider_id, &conn) {
+                                Some(provider_user) => provider_user,
+                                None => err_handler!("The current user isn't member of the provider"),
+                            };
+
+                        if !provider_user.is_confirmed() {
+                            err_handler!("The current user isn't confirmed member of the provider")
+                        }
+
+                        match Provider::find_by_uuid(&provider_id, &conn) {
+                            Some(ref provider) if provider.enabled => (),
+                            _ => err_handler!("The provider is disabled"),
+                        }
+
+                        Outcome::Success(Self {
+                            host: headers.host,
+                            device: headers.device,
+                            user: headers.user,
+                            provider_user,
+                        })
+                    }
+                    _ => err_handler!("Error getting the provider id"),
                 }
             }
         }
     }
 }
 
-pub struct OwnerHeaders {
+pub struct ProviderAdminHeaders {
     pub host: String,
     pub device: Device,
     pub user: User,
+    pub provider_user: ProviderUser,
 }
 
-impl<'a, 'r> FromRequest<'a, 'r> for OwnerHeaders {
+impl<'a, 'r> FromRequest<'a, 'r> for ProviderAdminHeaders {
     type Error = &'static str;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        match request.guard::<OrgHeaders>() {
+        match request.guard::<ProviderHeaders>() {
             Outcome::Forward(_) => Outcome::Forward(()),
             Outcome::Failure(f) => Outcome::Failure(f),
             Outcome::Success(headers) => {
-                if headers.org_user_type == UserOrgType::Owner {
+                if headers.provider_user.is_admin() {
                     Outcome::Success(Self {
                         host: headers.host,
                         device: headers.device,
                         user: headers.user,
+                        provider_user: headers.provider_user,
                     })
                 } else {
-                    err_handler!("You need to be Owner to call this endpoint")
+                    err_handler!("You need to be Provider Admin to call this endpoint")
                 }
             }
         }
@@ -388,3 +1010,64 @@ impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
         Outcome::Success(ClientIp { ip })
     }
 }
+
+/// The email of the user that signed in to the authenticating proxy. It's always None unless the header
+/// was configured and the request comes from one of the trusted proxies, as it would be trivial to fake otherwise
+pub struct TrustedHeaderUser {
+    pub email: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for TrustedHeaderUser {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let email = CONFIG
+            .trusted_header()
+            .filter(|_| is_from_trusted_proxy(request))
+            .and_then(|header| request.headers().get_one(&header).map(str::trim).map(str::to_lowercase))
+            .filter(|email| !email.is_empty());
+
+        Outcome::Success(TrustedHeaderUser { email })
+    }
+}
+
+/// In single port mode the requests come from the listener, which already removed the header of the other clients.
+/// The local processes can also connect to Rocket directly, so only the requests with the secret of the listener count
+fn is_from_trusted_proxy(request: &Request) -> bool {
+    use crate::listener;
+
+    if listener::is_active() {
+        return request
+            .headers()
+            .get_one(listener::SECRET_HEADER)
+            .map_or(false, listener::is_secret);
+    }
+
+    match request.remote() {
+        Some(addr) => is_trusted_proxy(addr.ip()),
+        None => false,
+    }
+}
+
+pub fn is_trusted_proxy(ip: IpAddr) -> bool {
+    crate::util::parse_ip_list(&CONFIG.trusted_header_proxies()).map_or(false, |proxies| proxies.contains(&ip))
+}
+
+/// The fingerprint of the client certificate, as sent by the reverse proxy.
+/// It's always None unless the header was configured, as it would be trivial to fake otherwise
+pub struct ClientCert {
+    pub fingerprint: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientCert {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let fingerprint = CONFIG
+            .client_cert_header()
+            .and_then(|header| request.headers().get_one(&header).map(String::from))
+            .and_then(|fingerprint| ClientCertificate::normalize_fingerprint(&fingerprint));
+
+        Outcome::Success(ClientCert { fingerprint })
+    }
+}
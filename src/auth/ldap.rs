@@ -0,0 +1,128 @@
+//
+// LDAP directory checks. The clients only send a hash of the master password, so the login can't check
+// the directory password, the directory decides which accounts can log in. The directory password is only
+// checked on the signup page of the server, before the invitation is sent to the address
+//
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::error::Error;
+use crate::CONFIG;
+
+lazy_static! {
+    // The emails last found in the directory, with the time of the check
+    static ref DIRECTORY_USERS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Like `is_directory_user`, but a user found in the directory is only checked again after `LDAP_CACHE_SECONDS`.
+/// The token refreshes use it, so that a slow or unreachable directory doesn't end the sessions right away
+pub fn is_cached_directory_user(email: &str) -> Result<bool, Error> {
+    let ttl = Duration::from_secs(CONFIG.ldap_cache_seconds());
+    let now = Instant::now();
+
+    if let Some(checked_at) = DIRECTORY_USERS.lock().unwrap().get(email) {
+        if now.duration_since(*checked_at) < ttl {
+            return Ok(true);
+        }
+    }
+
+    let found = is_directory_user(email)?;
+
+    let mut users = DIRECTORY_USERS.lock().unwrap();
+    users.retain(|_, checked_at| now.duration_since(*checked_at) < ttl);
+    if found && ttl > Duration::from_secs(0) {
+        users.insert(email.to_string(), now);
+    } else {
+        users.remove(email);
+    }
+    Ok(found)
+}
+
+/// Connects to the directory and finds the entries of the users that match the user filter and the email.
+/// The connection and every operation give up after `LDAP_TIMEOUT`
+#[cfg(feature = "enable_ldap")]
+fn find_directory_user(email: &str) -> Result<(ldap3::LdapConn, Vec<ldap3::ResultEntry>), Error> {
+    use crate::error::MapResult;
+    use ldap3::{ldap_escape, LdapConn, LdapConnSettings, Scope};
+
+    let timeout = Duration::from_secs(CONFIG.ldap_timeout());
+    let url = CONFIG.ldap_url().unwrap_or_default();
+    let settings = LdapConnSettings::new().set_conn_timeout(timeout);
+    let ldap = LdapConn::with_settings(settings, &url).map_res("Can't connect to the directory")?;
+
+    if let (Some(dn), Some(password)) = (CONFIG.ldap_bind_dn(), CONFIG.ldap_bind_password()) {
+        ldap.with_timeout(timeout)
+            .simple_bind(&dn, &password)
+            .and_then(|r| r.success())
+            .map_res("Can't bind to the directory")?;
+    }
+
+    let filter = format!(
+        "(&{}({}={}))",
+        CONFIG.ldap_user_filter(),
+        CONFIG.ldap_mail_attribute(),
+        ldap_escape(email)
+    );
+
+    // "1.1" requests no attributes, only the existence of the entry and its DN matter
+    let (entries, _) = ldap
+        .with_timeout(timeout)
+        .search(&CONFIG.ldap_search_base(), Scope::Subtree, &filter, vec!["1.1"])
+        .and_then(|r| r.success())
+        .map_res("Can't search the directory")?;
+
+    Ok((ldap, entries))
+}
+
+/// Whether the email belongs to one of the users of the directory that match the user filter
+#[cfg(feature = "enable_ldap")]
+pub fn is_directory_user(email: &str) -> Result<bool, Error> {
+    let (ldap, entries) = find_directory_user(email)?;
+
+    ldap.unbind().ok();
+    Ok(!entries.is_empty())
+}
+
+/// Whether the password is the directory password of the user with the email, by binding as that user
+#[cfg(feature = "enable_ldap")]
+pub fn check_password(email: &str, password: &str) -> Result<bool, Error> {
+    use crate::error::MapResult;
+    use ldap3::SearchEntry;
+
+    // An empty password would be an unauthenticated bind, which most servers accept
+    if password.is_empty() {
+        return Ok(false);
+    }
+
+    let (ldap, entries) = find_directory_user(email)?;
+
+    // The emails have to be unique in the directory, otherwise it's not clear which user is signing up
+    let dn = match entries.len() {
+        1 => SearchEntry::construct(entries.into_iter().next().unwrap()).dn,
+        _ => {
+            ldap.unbind().ok();
+            return Ok(false);
+        }
+    };
+
+    // An invalid password is a result code, only the connection errors fail the bind itself
+    let timeout = Duration::from_secs(CONFIG.ldap_timeout());
+    let result = ldap
+        .with_timeout(timeout)
+        .simple_bind(&dn, password)
+        .map_res("Can't bind to the directory")?;
+
+    ldap.unbind().ok();
+    Ok(result.rc == 0)
+}
+
+#[cfg(not(feature = "enable_ldap"))]
+pub fn is_directory_user(_email: &str) -> Result<bool, Error> {
+    err!("The server was built without LDAP support")
+}
+
+#[cfg(not(feature = "enable_ldap"))]
+pub fn check_password(_email: &str, _password: &str) -> Result<bool, Error> {
+    err!("The server was built without LDAP support")
+}
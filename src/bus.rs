@@ -0,0 +1,129 @@
+//
+// Internal event bus: the route handlers publish what changed, and the subscribers,
+// like the websocket notifications, handle it in their own thread
+//
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use chrono::{NaiveDateTime, Utc};
+use rocket::State;
+
+use crate::api::UpdateType;
+use crate::db::models::{Cipher, Event, Folder, User};
+
+/// A change made by a request. The events own their data, as they are handled after the request,
+/// in the worker thread
+pub enum DomainEvent {
+    User {
+        ut: UpdateType,
+        user_uuid: String,
+        date: NaiveDateTime,
+    },
+    Folder {
+        ut: UpdateType,
+        uuid: String,
+        user_uuid: String,
+        date: NaiveDateTime,
+    },
+    Cipher {
+        ut: UpdateType,
+        uuid: String,
+        user_uuid: Option<String>,
+        org_uuid: Option<String>,
+        date: NaiveDateTime,
+        /// The users that have access to the cipher
+        user_uuids: Vec<String>,
+        collection_uuids: Vec<String>,
+    },
+    /// An entry of the activity log. The events without an organization are logged
+    /// in every organization the user is a member of
+    Log(Event),
+}
+
+/// Receives all the published events, in the order they were published, with the time they were published at
+pub trait Subscriber: Send {
+    fn handle(&self, event: &DomainEvent, published_at: NaiveDateTime);
+}
+
+pub struct EventBus {
+    // The Sender can't be shared between the request threads, so it's behind a lock
+    sender: Mutex<Sender<(DomainEvent, NaiveDateTime)>>,
+}
+
+impl EventBus {
+    /// The handlers publish the events after the commit, so the time is stamped here and not taken from the models
+    pub fn publish(&self, event: DomainEvent) {
+        let published_at = Utc::now().naive_utc();
+        if self.sender.lock().unwrap().send((event, published_at)).is_err() {
+            error!("The event bus has stopped, an event was dropped");
+        }
+    }
+
+    // NOTE: The last modified date needs to be updated before calling these methods
+    pub fn send_user_update(&self, ut: UpdateType, user: &User) {
+        self.publish(DomainEvent::User {
+            ut,
+            user_uuid: user.uuid.clone(),
+            date: user.updated_at,
+        });
+    }
+
+    pub fn send_folder_update(&self, ut: UpdateType, folder: &Folder) {
+        self.publish(DomainEvent::Folder {
+            ut,
+            uuid: folder.uuid.clone(),
+            user_uuid: folder.user_uuid.clone(),
+            date: folder.updated_at,
+        });
+    }
+
+    pub fn send_cipher_update(
+        &self,
+        ut: UpdateType,
+        cipher: &Cipher,
+        user_uuids: &[String],
+        collection_uuids: &[String],
+    ) {
+        self.publish(DomainEvent::Cipher {
+            ut,
+            uuid: cipher.uuid.clone(),
+            user_uuid: cipher.user_uuid.clone(),
+            org_uuid: cipher.organization_uuid.clone(),
+            date: cipher.updated_at,
+            user_uuids: user_uuids.to_vec(),
+            collection_uuids: collection_uuids.to_vec(),
+        });
+    }
+
+    pub fn log_event(&self, event: Event) {
+        self.publish(DomainEvent::Log(event));
+    }
+}
+
+/// The request guard used by the handlers to publish the events
+pub type Notify<'a> = State<'a, EventBus>;
+
+pub fn start(subscribers: Vec<Box<dyn Subscriber>>) -> EventBus {
+    let (sender, receiver) = channel();
+
+    thread::Builder::new()
+        .name("event-bus".into())
+        .spawn(move || {
+            for (event, published_at) in receiver {
+                for subscriber in &subscribers {
+                    // A subscriber that panics only loses this event, the rest of the subscribers and events are still handled
+                    let handle = || subscriber.handle(&event, published_at);
+                    if panic::catch_unwind(AssertUnwindSafe(handle)).is_err() {
+                        error!("An event bus subscriber panicked, the event was dropped");
+                    }
+                }
+            }
+        })
+        .expect("Error starting the event bus");
+
+    EventBus {
+        sender: Mutex::new(sender),
+    }
+}
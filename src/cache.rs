@@ -0,0 +1,150 @@
+//
+// In-memory cache of serialized sync responses
+//
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::CONFIG;
+
+lazy_static! {
+    pub static ref SYNC_CACHE: SyncCache = SyncCache::new();
+}
+
+thread_local! {
+    /// The users whose revision was bumped with a connection of this thread, their entries are removed
+    /// once the connection is dropped, after the writes that caused the bump are committed
+    static PENDING_INVALIDATIONS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Entries are also dropped after this time, in case a write wasn't followed by an invalidation
+const MAX_ENTRY_AGE_SECS: i64 = 300;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct SyncCacheKey {
+    user_uuid: String,
+    revision: NaiveDateTime,
+    host: String,
+    exclude_domains: bool,
+}
+
+struct SyncCacheEntry {
+    value: String,
+    created_at: NaiveDateTime,
+    last_used: u64,
+}
+
+struct SyncCacheInner {
+    entries: HashMap<SyncCacheKey, SyncCacheEntry>,
+    counter: u64,
+}
+
+/// A least recently used cache of sync responses, keyed by the user and their revision date.
+/// The size is read from the config on every call, and a size of 0 disables the cache.
+pub struct SyncCache {
+    inner: Mutex<SyncCacheInner>,
+}
+
+impl SyncCache {
+    fn new() -> Self {
+        Self {
+            inner: Mutex::new(SyncCacheInner {
+                entries: HashMap::new(),
+                counter: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, user_uuid: &str, revision: NaiveDateTime, host: &str, exclude_domains: bool) -> Option<String> {
+        if CONFIG.sync_cache_size() == 0 {
+            return None;
+        }
+
+        let key = SyncCacheKey {
+            user_uuid: user_uuid.to_string(),
+            revision,
+            host: host.to_string(),
+            exclude_domains,
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.counter += 1;
+        let counter = inner.counter;
+
+        let max_age = Duration::seconds(MAX_ENTRY_AGE_SECS);
+
+        match inner.entries.get_mut(&key) {
+            Some(entry) if entry.created_at + max_age > Utc::now().naive_utc() => {
+                entry.last_used = counter;
+                return Some(entry.value.clone());
+            }
+            Some(_) => (),
+            None => return None,
+        }
+
+        // The entry is too old
+        inner.entries.remove(&key);
+        None
+    }
+
+    pub fn insert(&self, user_uuid: &str, revision: NaiveDateTime, host: &str, exclude_domains: bool, value: String) {
+        let max_size = CONFIG.sync_cache_size();
+        if max_size == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        // Evict the least recently used entries to make space for the new one
+        while inner.entries.len() >= max_size {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(key) => inner.entries.remove(&key),
+                None => break,
+            };
+        }
+
+        inner.counter += 1;
+        let entry = SyncCacheEntry {
+            value,
+            created_at: Utc::now().naive_utc(),
+            last_used: inner.counter,
+        };
+
+        let key = SyncCacheKey {
+            user_uuid: user_uuid.to_string(),
+            revision,
+            host: host.to_string(),
+            exclude_domains,
+        };
+        inner.entries.insert(key, entry);
+    }
+
+    /// Removes all the entries of a user right away
+    pub fn invalidate_user(&self, user_uuid: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.retain(|key, _| key.user_uuid != user_uuid);
+    }
+
+    /// Removes the entries of a user once the database connection of the thread is dropped, should be called
+    /// every time their revision changes. The revision is bumped before the writes are committed, so a sync
+    /// in between could cache the old vault with the new revision
+    pub fn invalidate_user_after_writes(&self, user_uuid: &str) {
+        PENDING_INVALIDATIONS.with(|pending| pending.borrow_mut().insert(user_uuid.to_string()));
+    }
+
+    /// Runs the invalidations of the users bumped with the connections of this thread
+    pub fn run_pending_invalidations(&self) {
+        let users = PENDING_INVALIDATIONS.with(|pending| std::mem::replace(&mut *pending.borrow_mut(), HashSet::new()));
+        for user_uuid in users {
+            self.invalidate_user(&user_uuid);
+        }
+    }
+}
@@ -19,6 +19,57 @@ pub fn verify_password_hash(secret: &[u8], salt: &[u8], previous: &[u8], iterati
     pbkdf2::verify(DIGEST_ALG, iterations, salt, secret, previous).is_ok()
 }
 
+//
+// SHA-256 digests
+//
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    use data_encoding::HEXLOWER;
+
+    HEXLOWER.encode(digest::digest(DIGEST_ALG, data).as_ref())
+}
+
+/// Computes the digest of everything in the reader, without loading it all in memory
+pub fn sha256_hex_reader<R: std::io::Read>(mut reader: R) -> std::io::Result<String> {
+    let mut context = Sha256Context::new();
+    let mut buffer = [0u8; 8192];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        context.update(&buffer[..read]);
+    }
+
+    Ok(context.finish_hex())
+}
+
+/// A digest computed as the data arrives, for data that isn't available all at once
+pub struct Sha256Context(digest::Context);
+
+impl Sha256Context {
+    pub fn new() -> Self {
+        Sha256Context(digest::Context::new(DIGEST_ALG))
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data)
+    }
+
+    pub fn finish_hex(self) -> String {
+        use data_encoding::HEXLOWER;
+
+        HEXLOWER.encode(self.0.finish().as_ref())
+    }
+}
+
+impl Default for Sha256Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 //
 // Random values
 //
@@ -37,6 +88,39 @@ pub fn get_random(mut array: Vec<u8>) -> Vec<u8> {
     array
 }
 
+/// A string of random decimal digits, like the codes sent by email
+pub fn get_random_digits(len: usize) -> String {
+    let mut digits = String::with_capacity(len);
+
+    while digits.len() < len {
+        for byte in get_random(vec![0u8; len]) {
+            // The values over 249 are skipped, otherwise the lower digits would be more likely
+            if byte < 250 && digits.len() < len {
+                digits.push(char::from(b'0' + byte % 10));
+            }
+        }
+    }
+
+    digits
+}
+
+/// A string of random letters and digits, like the API keys
+pub fn get_random_alphanum(len: usize) -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    let mut chars = String::with_capacity(len);
+
+    while chars.len() < len {
+        for byte in get_random(vec![0u8; len]) {
+            // The values over 247 are skipped, otherwise the first characters would be more likely
+            if byte < 248 && chars.len() < len {
+                chars.push(char::from(CHARSET[byte as usize % CHARSET.len()]));
+            }
+        }
+    }
+
+    chars
+}
+
 //
 // Constant time compare
 //
@@ -45,3 +129,32 @@ pub fn ct_eq<T: AsRef<[u8]>, U: AsRef<[u8]>>(a: T, b: U) -> bool {
 
     verify_slices_are_equal(a.as_ref(), b.as_ref()).is_ok()
 }
+
+//
+// Startup self-test
+//
+
+/// Checks that the primitives work on this system, so a broken build or platform fails at startup
+pub fn self_test() -> Result<(), &'static str> {
+    let salt = get_random(vec![0u8; 16]);
+    let hash = hash_password(b"self-test", &salt, 1000);
+
+    if !verify_password_hash(b"self-test", &salt, &hash, 1000) {
+        return Err("PBKDF2 can't verify its own hash");
+    }
+    if verify_password_hash(b"self-tesT", &salt, &hash, 1000) {
+        return Err("PBKDF2 accepted a wrong password");
+    }
+
+    let first = get_random(vec![0u8; 32]);
+    let second = get_random(vec![0u8; 32]);
+    if first == second || first.iter().all(|b| *b == 0) {
+        return Err("The random generator returned repeated values");
+    }
+
+    if sha256_hex(b"abc") != "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad" {
+        return Err("SHA-256 returned a wrong digest");
+    }
+
+    Ok(())
+}
@@ -2,32 +2,173 @@ use std::ops::Deref;
 
 use diesel::r2d2;
 use diesel::r2d2::ConnectionManager;
-use diesel::sqlite::SqliteConnection;
 use diesel::{Connection as DieselConnection, ConnectionError};
 
 use rocket::http::Status;
 use rocket::request::{self, FromRequest};
 use rocket::{Outcome, Request, State};
 
+use crate::telemetry;
 use crate::CONFIG;
 
-/// An alias to the database connection used
-type Connection = SqliteConnection;
+/// An alias to the database connection used, the backend is selected with the features
+#[cfg(feature = "sqlite")]
+type Connection = diesel::sqlite::SqliteConnection;
+#[cfg(feature = "postgresql")]
+type Connection = diesel::pg::PgConnection;
+#[cfg(feature = "mysql")]
+type Connection = diesel::mysql::MysqlConnection;
 
-/// An alias to the type for a pool of Diesel SQLite connections.
-type Pool = r2d2::Pool<ConnectionManager<Connection>>;
+/// An alias to the type for a pool of Diesel connections.
+pub type Pool = r2d2::Pool<ConnectionManager<Connection>>;
+
+/// Rows inserted by each statement of the batch inserts, to stay below the limit of bound parameters of SQLite.
+/// SQLite still runs one statement for each row, which is fast enough inside a transaction
+pub const INSERT_BATCH_SIZE: usize = 50;
+
+/// The escape character of the `LIKE` patterns. A backslash would need to be escaped again in MySQL
+pub const LIKE_ESCAPE: char = '!';
+
+/// The lowercase `LIKE` pattern that finds the text anywhere in a column, with its wildcards escaped
+pub fn like_pattern(text: &str) -> String {
+    let mut pattern = String::from("%");
+    for c in text.to_lowercase().chars() {
+        if c == LIKE_ESCAPE || c == '%' || c == '_' {
+            pattern.push(LIKE_ESCAPE);
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+sql_function! {
+    /// Lowercases the text in the query, so the searches ignore the case on every backend
+    fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text;
+}
 
 /// Connection request guard type: a wrapper around an r2d2 pooled connection.
 pub struct DbConn(pub r2d2::PooledConnection<ConnectionManager<Connection>>);
 
+/// Inserts a row, replacing the existing one with the same primary key, and returns the result of the query.
+/// The values need to implement `AsChangeset` and `Identifiable`, as some backends update the existing row instead.
+/// When all the columns are part of the key, there is nothing to update and the `nothing` variant has to be used
+#[cfg(feature = "sqlite")]
+macro_rules! replace_into {
+    ($table:expr, $key:expr, $values:expr, $conn:expr) => {
+        diesel::replace_into($table).values($values).execute($conn)
+    };
+    ($table:expr, $key:expr, $values:expr, $conn:expr, nothing) => {
+        diesel::replace_into($table).values($values).execute($conn)
+    };
+}
+
+/// PostgreSQL doesn't have `REPLACE INTO`, the existing row is updated in an `ON CONFLICT` clause
+#[cfg(feature = "postgresql")]
+macro_rules! replace_into {
+    ($table:expr, $key:expr, $values:expr, $conn:expr) => {
+        diesel::insert_into($table)
+            .values($values)
+            .on_conflict($key)
+            .do_update()
+            .set($values)
+            .execute($conn)
+    };
+    ($table:expr, $key:expr, $values:expr, $conn:expr, nothing) => {
+        diesel::insert_into($table)
+            .values($values)
+            .on_conflict($key)
+            .do_nothing()
+            .execute($conn)
+    };
+}
+
+/// The `REPLACE INTO` of MySQL deletes the existing row first, which fails when other rows reference it.
+/// Instead, the row is updated when the insert finds one with the same key. If the conflict was caused
+/// by a different unique column the update doesn't find the row, and the original error is returned
+#[cfg(feature = "mysql")]
+macro_rules! replace_into {
+    ($table:expr, $key:expr, $values:expr, $conn:expr) => {{
+        use diesel::result::{DatabaseErrorKind, Error as DieselError};
+
+        match diesel::insert_into($table).values($values).execute($conn) {
+            Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)) => {
+                match diesel::update($values).set($values).execute($conn) {
+                    Ok(0) => Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info)),
+                    result => result,
+                }
+            }
+            result => result,
+        }
+    }};
+    ($table:expr, $key:expr, $values:expr, $conn:expr, nothing) => {
+        diesel::insert_or_ignore_into($table).values($values).execute($conn)
+    };
+}
+
 pub mod models;
+pub mod repo;
 pub mod schema;
 
+/// SQLite only enforces the foreign keys when enabled in each connection, this is done when the pool creates them.
+/// The other backends always enforce them
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct ForeignKeys;
+
+#[cfg(feature = "sqlite")]
+impl r2d2::CustomizeConnection<Connection, r2d2::Error> for ForeignKeys {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), r2d2::Error> {
+        use diesel::connection::SimpleConnection;
+        conn.batch_execute("PRAGMA foreign_keys = ON")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+fn pool_builder() -> r2d2::Builder<ConnectionManager<Connection>> {
+    let builder = r2d2::Pool::builder();
+
+    #[cfg(feature = "sqlite")]
+    let builder = builder.connection_customizer(Box::new(ForeignKeys));
+
+    builder
+}
+
 /// Initializes a database pool.
 pub fn init_pool() -> Pool {
     let manager = ConnectionManager::new(CONFIG.database_url());
 
-    r2d2::Pool::builder().build(manager).expect("Failed to create pool")
+    pool_builder().build(manager).expect("Failed to create pool")
+}
+
+/// A pool used only by endpoints that don't write, which can point to a read replica of the database.
+pub struct ReadPool(Pool);
+
+/// Initializes the read pool. When no replica is configured, it shares the connections of the main pool.
+pub fn init_read_pool(pool: &Pool) -> ReadPool {
+    match CONFIG.database_read_url() {
+        Some(url) => {
+            let manager = ConnectionManager::new(url);
+            let pool = pool_builder().build(manager).expect("Failed to create read pool");
+            ReadPool(pool)
+        }
+        None => ReadPool(pool.clone()),
+    }
+}
+
+/// A connection to a new in-memory database with the migrations applied, for the tests of the queries.
+/// Each connection to `:memory:` opens a different database, so the pool only keeps this one
+#[cfg(all(test, feature = "sqlite"))]
+pub fn test_conn() -> DbConn {
+    let manager = ConnectionManager::new(":memory:");
+    let pool = pool_builder()
+        .max_size(1)
+        .build(manager)
+        .expect("Failed to create the test pool");
+
+    let conn = DbConn(pool.get().expect("Failed to get the test connection"));
+    crate::migrations::run_test_migrations(&conn);
+    conn
 }
 
 pub fn get_connection() -> Result<Connection, ConnectionError> {
@@ -42,6 +183,7 @@ impl<'a, 'r> FromRequest<'a, 'r> for DbConn {
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<DbConn, ()> {
         let pool = request.guard::<State<Pool>>()?;
+        let _span = telemetry::span("db.pool.get");
         match pool.get() {
             Ok(conn) => Outcome::Success(DbConn(conn)),
             Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
@@ -49,6 +191,30 @@ impl<'a, 'r> FromRequest<'a, 'r> for DbConn {
     }
 }
 
+/// Read only connection request guard type, it derefs to a `DbConn` so it can be used with the models.
+/// Note that a replica can lag behind the main database, so this should only be used where that is acceptable.
+pub struct DbReadConn(pub DbConn);
+
+impl<'a, 'r> FromRequest<'a, 'r> for DbReadConn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<DbReadConn, ()> {
+        let pool = request.guard::<State<ReadPool>>()?;
+        let _span = telemetry::span("db.read_pool.get");
+        match pool.0.get() {
+            Ok(conn) => Outcome::Success(DbReadConn(DbConn(conn))),
+            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
+        }
+    }
+}
+
+impl Deref for DbReadConn {
+    type Target = DbConn;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 // For the convenience of using an &DbConn as a &Database.
 impl Deref for DbConn {
     type Target = Connection;
@@ -56,3 +222,11 @@ impl Deref for DbConn {
         &self.0
     }
 }
+
+/// The writes done with the connection are committed by now, so the cached syncs of the users whose
+/// revision was bumped can be removed
+impl Drop for DbConn {
+    fn drop(&mut self) {
+        crate::cache::SYNC_CACHE.run_pending_invalidations();
+    }
+}
@@ -1,9 +1,13 @@
+use std::path::Path;
+
+use chrono::{NaiveDateTime, Utc};
 use serde_json::Value;
 
-use super::Cipher;
+use super::{Cipher, User};
 use crate::CONFIG;
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "attachments"]
 #[belongs_to(Cipher, foreign_key = "cipher_uuid")]
 #[primary_key(id)]
@@ -13,6 +17,9 @@ pub struct Attachment {
     pub file_name: String,
     pub file_size: i32,
     pub key: Option<String>,
+    pub checksum: Option<String>,
+    pub replaced_id: Option<String>,
+    pub uploaded_at: Option<NaiveDateTime>,
 }
 
 /// Local methods
@@ -24,67 +31,293 @@ impl Attachment {
             file_name,
             file_size,
             key: None,
+            checksum: None,
+            replaced_id: None,
+            uploaded_at: None,
         }
     }
 
+    /// Marks the attachment as the replacement of another one, uploaded before its cipher is shared.
+    /// It stays hidden, and the other one is kept, until the share is stored
+    pub fn set_replaced(&mut self, replaced: &Attachment) {
+        self.replaced_id = Some(replaced.id.clone());
+        self.uploaded_at = Some(Utc::now().naive_utc());
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.replaced_id.is_some()
+    }
+
     pub fn get_file_path(&self) -> String {
         format!("{}/{}/{}", CONFIG.attachments_folder(), self.cipher_uuid, self.id)
     }
 
-    pub fn to_json(&self, host: &str) -> Value {
+    /// Checks the file on disk against the stored SHA-256 digest.
+    /// Attachments uploaded before the digests were stored are only checked for existence.
+    pub fn verify_checksum(&self) -> Result<(), &'static str> {
+        let file = match std::fs::File::open(self.get_file_path()) {
+            Ok(file) => file,
+            Err(_) => return Err("File is missing"),
+        };
+
+        let checksum = match self.checksum {
+            Some(ref checksum) => checksum,
+            None => return Ok(()),
+        };
+
+        match crate::crypto::sha256_hex_reader(file) {
+            Ok(ref actual) if actual == checksum => Ok(()),
+            Ok(_) => Err("Checksum mismatch"),
+            Err(_) => Err("File can't be read"),
+        }
+    }
+
+    /// The url includes a token to download the file, so it must only be sent to the users that can see the cipher
+    pub fn to_response(&self, host: &str) -> AttachmentResponse {
+        use crate::auth::{encode_jwt, generate_file_download_claims};
         use crate::util::get_display_size;
 
-        let web_path = format!("{}/attachments/{}/{}", host, self.cipher_uuid, self.id);
-        let display_size = get_display_size(self.file_size);
+        let claims = generate_file_download_claims(self.cipher_uuid.clone(), self.id.clone());
+        let token = encode_jwt(&claims);
+
+        AttachmentResponse {
+            Id: self.id.clone(),
+            Url: format!("{}/attachments/{}/{}?token={}", host, self.cipher_uuid, self.id, token),
+            FileName: self.file_name.clone(),
+            Size: self.file_size.to_string(),
+            SizeName: get_display_size(self.file_size.into()),
+            Key: self.key.clone(),
+            Object: "attachment",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct AttachmentResponse {
+    pub Id: String,
+    pub Url: String,
+    pub FileName: String,
+    pub Size: String,
+    pub SizeName: String,
+    pub Key: Option<String>,
+    pub Object: &'static str,
+}
+
+/// An attachment that is being uploaded in multiple chunks.
+/// The data is appended to a partial file, which becomes the attachment file once it's complete.
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "attachment_uploads"]
+#[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(id)]
+pub struct AttachmentUpload {
+    pub id: String,
+    pub cipher_uuid: String,
+    pub user_uuid: String,
+    pub file_name: String,
+    pub file_size: i32,
+    pub key: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl AttachmentUpload {
+    pub fn new(cipher_uuid: String, user_uuid: String, file_name: String, file_size: i32) -> Self {
+        use crate::crypto;
+        use data_encoding::HEXLOWER;
+
+        Self {
+            id: HEXLOWER.encode(&crypto::get_random(vec![0; 10])),
+            cipher_uuid,
+            user_uuid,
+            file_name,
+            file_size,
+            key: None,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn get_partial_path(&self) -> String {
+        format!("{}/{}/{}.part", CONFIG.attachments_folder(), self.cipher_uuid, self.id)
+    }
+
+    /// The number of bytes already received, which is where the next chunk has to start
+    pub fn get_offset(&self) -> i32 {
+        match std::fs::metadata(self.get_partial_path()) {
+            Ok(metadata) => metadata.len() as i32,
+            Err(_) => 0,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.get_offset() == self.file_size
+    }
+
+    /// Creates the attachment with the same id, so the partial file only needs to be renamed
+    pub fn to_attachment(&self) -> Attachment {
+        let mut attachment = Attachment::new(
+            self.id.clone(),
+            self.cipher_uuid.clone(),
+            self.file_name.clone(),
+            self.file_size,
+        );
+        attachment.key = self.key.clone();
+        attachment
+    }
 
+    pub fn to_json(&self) -> Value {
         json!({
             "Id": self.id,
-            "Url": web_path,
+            "CipherId": self.cipher_uuid,
             "FileName": self.file_name,
-            "Size": self.file_size.to_string(),
-            "SizeName": display_size,
-            "Key": self.key,
-            "Object": "attachment"
+            "FileSize": self.file_size,
+            "Offset": self.get_offset(),
+            "Object": "attachmentUpload",
         })
     }
 }
 
-use crate::db::schema::attachments;
+/// A file of a removed attachment, which is deleted by the scheduler. The row is saved in the same
+/// transaction that removes the attachment, so if it's rolled back the file is kept too
+#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[table_name = "attachment_deletions"]
+#[primary_key(uuid)]
+pub struct AttachmentDeletion {
+    pub uuid: String,
+    pub file_path: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl AttachmentDeletion {
+    pub fn new(file_path: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            file_path,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn delete_file(&self) -> std::io::Result<()> {
+        let file_path = self.file_path.clone();
+        match crate::util::run_file_job(move || crate::util::delete_file(&file_path)) {
+            // The file can already be missing, there is nothing else to delete then
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+use crate::db::repo::Transactional;
+use crate::db::schema::{attachment_deletions, attachment_uploads, attachments, ciphers};
 use crate::db::DbConn;
 use diesel;
 use diesel::prelude::*;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Attachment {
     pub fn save(&self, conn: &DbConn) -> EmptyResult {
-        diesel::replace_into(attachments::table)
-            .values(self)
-            .execute(&**conn)
-            .map_res("Error saving attachment")
+        let _span = telemetry::span("db.attachment.save");
+        replace_into!(attachments::table, attachments::id, self, &**conn).map_res("Error saving attachment")
     }
 
+    /// The file is deleted later by the scheduler, after the deletion has been committed
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        crate::util::retry(
-            || diesel::delete(attachments::table.filter(attachments::id.eq(&self.id))).execute(&**conn),
-            10,
-        )
-        .map_res("Error deleting attachment")?;
+        let _span = telemetry::span("db.attachment.delete");
+        conn.in_transaction(|| {
+            crate::util::retry(
+                || diesel::delete(attachments::table.filter(attachments::id.eq(&self.id))).execute(&**conn),
+                10,
+            )
+            .map_res("Error deleting attachment")?;
 
-        crate::util::delete_file(&self.get_file_path())?;
-        Ok(())
+            AttachmentDeletion::new(self.get_file_path()).save(conn)
+        })
+    }
+
+    /// Copies the file and the attachment to another cipher, encrypted with the same key.
+    /// The copied file is removed again when the attachment can't be saved
+    pub fn copy_to(&self, cipher_uuid: &str, conn: &DbConn) -> ApiResult<Self> {
+        use crate::util::{copy_file, run_file_job};
+        use data_encoding::HEXLOWER;
+
+        let _span = telemetry::span("db.attachment.copy_to");
+        let id = HEXLOWER.encode(&crate::crypto::get_random(vec![0; 10]));
+        let mut copy = Self::new(id, cipher_uuid.to_string(), self.file_name.clone(), self.file_size);
+        copy.key = self.key.clone();
+        copy.checksum = self.checksum.clone();
+
+        let (from, to) = (self.get_file_path(), copy.get_file_path());
+        if let Err(e) = run_file_job(move || copy_file(&from, Path::new(&to))) {
+            err!("Error copying the attachment", e.to_string())
+        }
+
+        if let Err(e) = copy.save(conn) {
+            let path = copy.get_file_path();
+            if let Err(e) = run_file_job(move || crate::util::delete_file(&path)) {
+                error!("Error removing the copied attachment file: {:?}", e);
+            }
+            return Err(e);
+        }
+
+        Ok(copy)
     }
 
     pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete_all_by_cipher");
         for attachment in Attachment::find_by_cipher(&cipher_uuid, &conn) {
             attachment.delete(&conn)?;
         }
         Ok(())
     }
 
+    /// Replaces the attachments of the cipher with the ones uploaded for its share, once the share is stored.
+    /// Returns the number of replaced attachments
+    pub fn commit_replacements(cipher_uuid: &str, conn: &DbConn) -> ApiResult<usize> {
+        let _span = telemetry::span("db.attachment.commit_replacements");
+        let mut replaced = 0;
+
+        for mut attachment in Attachment::find_pending_by_cipher(cipher_uuid, conn) {
+            let old = attachment
+                .replaced_id
+                .as_ref()
+                .and_then(|id| Attachment::find_by_id(id, conn));
+            if let Some(old) = old {
+                old.delete(conn)?;
+                replaced += 1;
+            }
+
+            attachment.replaced_id = None;
+            attachment.uploaded_at = None;
+            attachment.save(conn)?;
+        }
+
+        Ok(replaced)
+    }
+
+    /// Removes the replacements of the attachment uploaded before, when it's uploaded again for another try
+    pub fn delete_pending_replacements(replaced_id: &str, except_id: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete_pending_replacements");
+        let pending = attachments::table
+            .filter(attachments::replaced_id.eq(replaced_id))
+            .filter(attachments::id.ne(except_id))
+            .load::<Self>(&**conn)
+            .expect("Error loading attachments");
+
+        for attachment in pending {
+            attachment.delete(conn)?;
+        }
+        Ok(())
+    }
+
     pub fn find_by_id(id: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.attachment.find_by_id");
         let id = id.to_lowercase();
 
         attachments::table
@@ -94,16 +327,170 @@ impl Attachment {
     }
 
     pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_by_cipher");
+        attachments::table
+            .filter(attachments::cipher_uuid.eq(cipher_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading attachments")
+    }
+
+    pub fn find_pending_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_pending_by_cipher");
         attachments::table
             .filter(attachments::cipher_uuid.eq(cipher_uuid))
+            .filter(attachments::replaced_id.is_not_null())
+            .load::<Self>(&**conn)
+            .expect("Error loading attachments")
+    }
+
+    /// The replacements uploaded for a share that wasn't stored
+    pub fn find_pending_before(date: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_pending_before");
+        attachments::table
+            .filter(attachments::replaced_id.is_not_null())
+            .filter(attachments::uploaded_at.lt(date))
             .load::<Self>(&**conn)
             .expect("Error loading attachments")
     }
 
     pub fn find_by_ciphers(cipher_uuids: Vec<String>, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_by_ciphers");
         attachments::table
             .filter(attachments::cipher_uuid.eq_any(cipher_uuids))
             .load::<Self>(&**conn)
             .expect("Error loading attachments")
     }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.get_all");
+        attachments::table
+            .load::<Self>(&**conn)
+            .expect("Error loading attachments")
+    }
+
+    /// The total size of the attachments of the personal ciphers of the user, in bytes
+    pub fn size_by_user(user_uuid: &str, conn: &DbConn) -> i64 {
+        let _span = telemetry::span("db.attachment.size_by_user");
+        attachments::table
+            .inner_join(ciphers::table)
+            .filter(ciphers::user_uuid.eq(user_uuid))
+            .select(attachments::file_size)
+            .load::<i32>(&**conn)
+            .expect("Error loading attachments")
+            .into_iter()
+            .map(i64::from)
+            .sum()
+    }
+
+    /// The total size of the attachments of the organization ciphers, in bytes
+    pub fn size_by_org(org_uuid: &str, conn: &DbConn) -> i64 {
+        let _span = telemetry::span("db.attachment.size_by_org");
+        attachments::table
+            .inner_join(ciphers::table)
+            .filter(ciphers::organization_uuid.eq(org_uuid))
+            .select(attachments::file_size)
+            .load::<i32>(&**conn)
+            .expect("Error loading attachments")
+            .into_iter()
+            .map(i64::from)
+            .sum()
+    }
+}
+
+/// Database methods
+impl AttachmentDeletion {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.save");
+        diesel::insert_into(attachment_deletions::table)
+            .values(self)
+            .execute(&**conn)
+            .map_res("Error saving attachment deletion")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete");
+        diesel::delete(attachment_deletions::table.filter(attachment_deletions::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting attachment deletion")
+    }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.get_all");
+        attachment_deletions::table
+            .order(attachment_deletions::created_at)
+            .load::<Self>(&**conn)
+            .expect("Error loading attachment deletions")
+    }
+}
+
+/// Database methods
+impl AttachmentUpload {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.save");
+        replace_into!(attachment_uploads::table, attachment_uploads::id, self, &**conn)
+            .map_res("Error saving attachment upload")
+    }
+
+    /// Removes the upload, and the partial file if it hasn't been turned into an attachment
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete");
+        diesel::delete(attachment_uploads::table.filter(attachment_uploads::id.eq(&self.id)))
+            .execute(&**conn)
+            .map_res("Error deleting attachment upload")?;
+
+        let file_path = self.get_partial_path();
+        if crate::util::file_exists(&file_path) {
+            crate::util::run_file_job(move || crate::util::delete_file(&file_path))?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete_all_by_cipher");
+        for upload in Self::find_by_cipher(&cipher_uuid, &conn) {
+            upload.delete(&conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.attachment.delete_all_by_user");
+        for upload in Self::find_by_user(&user_uuid, &conn) {
+            upload.delete(&conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn find_by_id_and_cipher(id: &str, cipher_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.attachment.find_by_id_and_cipher");
+        attachment_uploads::table
+            .filter(attachment_uploads::id.eq(id.to_lowercase()))
+            .filter(attachment_uploads::cipher_uuid.eq(cipher_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_cipher(cipher_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_by_cipher");
+        attachment_uploads::table
+            .filter(attachment_uploads::cipher_uuid.eq(cipher_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading attachment uploads")
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_by_user");
+        attachment_uploads::table
+            .filter(attachment_uploads::user_uuid.eq(user_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading attachment uploads")
+    }
+
+    pub fn find_created_before(date: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.attachment.find_created_before");
+        attachment_uploads::table
+            .filter(attachment_uploads::created_at.lt(date))
+            .load::<Self>(&**conn)
+            .expect("Error loading attachment uploads")
+    }
 }
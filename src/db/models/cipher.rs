@@ -1,11 +1,15 @@
 use chrono::{NaiveDateTime, Utc};
+use num_traits::FromPrimitive;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 
 use super::{
-    Attachment, CollectionCipher, FolderCipher, Organization, User, UserOrgStatus, UserOrgType, UserOrganization,
+    Attachment, AttachmentResponse, AttachmentUpload, CollectionCipher, Favorite, FolderCipher, Organization, User,
+    UserOrgStatus, UserOrgType, UserOrganization,
 };
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "ciphers"]
 #[belongs_to(User, foreign_key = "user_uuid")]
 #[belongs_to(Organization, foreign_key = "organization_uuid")]
@@ -18,12 +22,7 @@ pub struct Cipher {
     pub user_uuid: Option<String>,
     pub organization_uuid: Option<String>,
 
-    /*
-    Login = 1,
-    SecureNote = 2,
-    Card = 3,
-    Identity = 4
-    */
+    /// One of the `CipherType` values
     pub type_: i32,
     pub name: String,
     pub notes: Option<String>,
@@ -31,13 +30,71 @@ pub struct Cipher {
 
     pub data: String,
 
+    /// Unused, the favorites are kept for each user in their own table. It's left as false,
+    /// as SQLite can't drop the column
     pub favorite: bool,
     pub password_history: Option<String>,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+pub enum CipherType {
+    Login = 1,
+    SecureNote = 2,
+    Card = 3,
+    Identity = 4,
+}
+
+/// The types are received as numbers, or as strings from the old clients with `LEGACY_CLIENT_COMPAT`.
+/// Unknown types are rejected, as the clients can't show a cipher without one of these
+impl<'de> Deserialize<'de> for CipherType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let number = crate::util::deserialize_legacy_number(deserializer)?;
+        Self::from_i32(number).ok_or_else(|| de::Error::custom(format!("Invalid cipher type: {}", number)))
+    }
+}
+
+impl Serialize for CipherType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(*self as i32)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct CipherResponse {
+    pub Id: String,
+    pub Type: i32,
+    pub RevisionDate: String,
+    pub FolderId: Option<String>,
+    pub Favorite: bool,
+    pub OrganizationId: Option<String>,
+    pub Attachments: Vec<AttachmentResponse>,
+    pub OrganizationUseTotp: bool,
+    pub CollectionIds: Vec<String>,
+
+    pub Name: String,
+    pub Notes: Option<String>,
+    pub Fields: Value,
+
+    pub Data: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Login: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub SecureNote: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Card: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub Identity: Option<Value>,
+
+    pub Object: &'static str,
+    pub Edit: bool,
+
+    pub PasswordHistory: Value,
+}
+
 /// Local methods
 impl Cipher {
-    pub fn new(type_: i32, name: String) -> Self {
+    pub fn new(type_: CipherType, name: String) -> Self {
         let now = Utc::now().naive_utc();
 
         Self {
@@ -48,7 +105,7 @@ impl Cipher {
             user_uuid: None,
             organization_uuid: None,
 
-            type_,
+            type_: type_ as i32,
             favorite: false,
             name,
 
@@ -62,22 +119,33 @@ impl Cipher {
 }
 
 use crate::db::schema::*;
-use crate::db::DbConn;
+use crate::db::{DbConn, INSERT_BATCH_SIZE};
 use diesel;
 use diesel::prelude::*;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Cipher {
     pub fn to_json(&self, host: &str, user_uuid: &str, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.cipher.to_json");
+        serde_json::to_value(self.to_response(host, user_uuid, conn)).unwrap()
+    }
+
+    pub fn to_response(&self, host: &str, user_uuid: &str, conn: &DbConn) -> CipherResponse {
         use super::Attachment;
         use crate::util::format_date;
         use serde_json;
 
+        let _span = telemetry::span("db.cipher.to_response");
         let attachments = Attachment::find_by_cipher(&self.uuid, conn);
-        let attachments_json: Vec<Value> = attachments.iter().map(|c| c.to_json(host)).collect();
+        let attachments = attachments
+            .iter()
+            .filter(|a| !a.is_pending())
+            .map(|a| a.to_response(host))
+            .collect();
 
         let fields_json: Value = if let Some(ref fields) = self.fields {
             serde_json::from_str(fields).unwrap()
@@ -96,85 +164,170 @@ impl Cipher {
         // TODO: ******* Backwards compat start **********
         // To remove backwards compatibility, just remove this entire section
         // and remove the compat code from ciphers::update_cipher_from_data
-        if self.type_ == 1 && data_json["Uris"].is_array() {
+        if self.type_ == CipherType::Login as i32 && data_json["Uris"].is_array() {
             let uri = data_json["Uris"][0]["Uri"].clone();
             data_json["Uri"] = uri;
         }
         // TODO: ******* Backwards compat end **********
 
-        let mut json_object = json!({
-            "Id": self.uuid,
-            "Type": self.type_,
-            "RevisionDate": format_date(&self.updated_at),
-            "FolderId": self.get_folder_uuid(&user_uuid, &conn),
-            "Favorite": self.favorite,
-            "OrganizationId": self.organization_uuid,
-            "Attachments": attachments_json,
-            "OrganizationUseTotp": true,
-            "CollectionIds": self.get_collections(user_uuid, &conn),
+        let mut response = CipherResponse {
+            Id: self.uuid.clone(),
+            Type: self.type_,
+            RevisionDate: format_date(&self.updated_at),
+            FolderId: self.get_folder_uuid(&user_uuid, &conn),
+            Favorite: Favorite::is_favorite(&self.uuid, user_uuid, conn),
+            OrganizationId: self.organization_uuid.clone(),
+            Attachments: attachments,
+            OrganizationUseTotp: true,
+            CollectionIds: self.get_collections(user_uuid, &conn),
+
+            Name: self.name.clone(),
+            Notes: self.notes.clone(),
+            Fields: fields_json,
+
+            Data: Value::Null,
+            Login: None,
+            SecureNote: None,
+            Card: None,
+            Identity: None,
+
+            Object: "cipher",
+            Edit: true,
+
+            PasswordHistory: password_history_json,
+        };
 
-            "Name": self.name,
-            "Notes": self.notes,
-            "Fields": fields_json,
+        // The data is sent twice, once as Data and once under the name of the type
+        match CipherType::from_i32(self.type_) {
+            Some(CipherType::Login) => response.Login = Some(data_json.clone()),
+            Some(CipherType::SecureNote) => response.SecureNote = Some(data_json.clone()),
+            Some(CipherType::Card) => response.Card = Some(data_json.clone()),
+            Some(CipherType::Identity) => response.Identity = Some(data_json.clone()),
+            None => error!("Cipher {} has the unknown type {}", self.uuid, self.type_),
+        };
 
-            "Data": data_json,
+        response.Data = data_json;
+        response
+    }
 
-            "Object": "cipher",
-            "Edit": true,
+    /// The cipher in the encrypted JSON format of the vault exports, with the fields in camelCase.
+    /// The values are exported as they are stored, encrypted with the key of the user
+    pub fn to_export_json(&self, user_uuid: &str, conn: &DbConn) -> Value {
+        use crate::util::{format_date, lcase_value};
+
+        let _span = telemetry::span("db.cipher.to_export_json");
+        let parse = |json: &Option<String>| {
+            json.as_ref()
+                .and_then(|j| serde_json::from_str(j).ok())
+                .map(|v: Value| lcase_value(&v))
+                .unwrap_or(Value::Null)
+        };
 
-            "PasswordHistory": password_history_json,
+        let mut data_json = lcase_value(&serde_json::from_str(&self.data).unwrap_or(Value::Null));
+        // The old clients' single uri is also in the list of uris
+        if let Some(data) = data_json.as_object_mut() {
+            data.remove("uri");
+        }
+
+        let mut export = json!({
+            "id": self.uuid,
+            "organizationId": self.organization_uuid,
+            "folderId": self.get_folder_uuid(user_uuid, conn),
+            "type": self.type_,
+            "name": self.name,
+            "notes": self.notes,
+            "favorite": Favorite::is_favorite(&self.uuid, user_uuid, conn),
+            "fields": parse(&self.fields),
+            "passwordHistory": parse(&self.password_history),
+            "collectionIds": null,
+            "revisionDate": format_date(&self.updated_at),
         });
 
-        let key = match self.type_ {
-            1 => "Login",
-            2 => "SecureNote",
-            3 => "Card",
-            4 => "Identity",
-            _ => panic!("Wrong type"),
+        let type_key = match CipherType::from_i32(self.type_) {
+            Some(CipherType::Login) => "login",
+            Some(CipherType::SecureNote) => "secureNote",
+            Some(CipherType::Card) => "card",
+            Some(CipherType::Identity) => "identity",
+            None => return export,
         };
+        export[type_key] = data_json;
+        export
+    }
+
+    /// The cipher as shown in the organization vault and its reports, without the folder and favorite of the user.
+    /// With full access, all the collections of the cipher are included, not only the ones of the user
+    pub fn to_org_details_response(
+        &self,
+        host: &str,
+        user_uuid: &str,
+        full_access: bool,
+        conn: &DbConn,
+    ) -> CipherResponse {
+        let _span = telemetry::span("db.cipher.to_org_details_response");
+        let mut response = self.to_response(host, user_uuid, conn);
+        response.FolderId = None;
+        response.Favorite = false;
+        response.Object = "cipherMiniDetails";
+
+        if full_access {
+            response.CollectionIds = self.get_all_collections(conn);
+        }
 
-        json_object[key] = data_json;
-        json_object
+        response
+    }
+
+    /// The users that have access to the cipher: its owner, or the members of the organization that can see it
+    pub fn find_user_uuids(&self, conn: &DbConn) -> Vec<String> {
+        let _span = telemetry::span("db.cipher.find_user_uuids");
+        match (&self.user_uuid, &self.organization_uuid) {
+            (Some(user_uuid), _) => vec![user_uuid.clone()],
+            (None, Some(org_uuid)) => UserOrganization::find_by_cipher_and_org(&self.uuid, org_uuid, conn)
+                .into_iter()
+                .map(|user_org| user_org.user_uuid)
+                .collect(),
+            (None, None) => Vec::new(),
+        }
     }
 
     pub fn update_users_revision(&self, conn: &DbConn) -> Vec<String> {
-        let mut user_uuids = Vec::new();
-        match self.user_uuid {
-            Some(ref user_uuid) => {
-                User::update_uuid_revision(&user_uuid, conn);
-                user_uuids.push(user_uuid.clone())
-            }
-            None => {
-                // Belongs to Organization, need to update affected users
-                if let Some(ref org_uuid) = self.organization_uuid {
-                    UserOrganization::find_by_cipher_and_org(&self.uuid, &org_uuid, conn)
-                        .iter()
-                        .for_each(|user_org| {
-                            User::update_uuid_revision(&user_org.user_uuid, conn);
-                            user_uuids.push(user_org.user_uuid.clone())
-                        });
-                }
-            }
-        };
+        let _span = telemetry::span("db.cipher.update_users_revision");
+        let user_uuids = self.find_user_uuids(conn);
+        for user_uuid in &user_uuids {
+            User::update_uuid_revision(user_uuid, conn);
+        }
         user_uuids
     }
 
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.save");
         self.update_users_revision(conn);
         self.updated_at = Utc::now().naive_utc();
 
-        diesel::replace_into(ciphers::table)
-            .values(&*self)
-            .execute(&**conn)
-            .map_res("Error saving cipher")
+        replace_into!(ciphers::table, ciphers::uuid, &*self, &**conn).map_res("Error saving cipher")
+    }
+
+    /// Inserts new ciphers in batches. The revisions of the users aren't updated, the caller
+    /// does it once for all of them, like at the end of an import
+    pub fn insert_all(ciphers: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.insert_all");
+        for batch in ciphers.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(ciphers::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error saving ciphers")?;
+        }
+        Ok(())
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.delete");
         self.update_users_revision(conn);
 
         FolderCipher::delete_all_by_cipher(&self.uuid, &conn)?;
         CollectionCipher::delete_all_by_cipher(&self.uuid, &conn)?;
+        Favorite::delete_all_by_cipher(&self.uuid, &conn)?;
         Attachment::delete_all_by_cipher(&self.uuid, &conn)?;
+        AttachmentUpload::delete_all_by_cipher(&self.uuid, &conn)?;
 
         diesel::delete(ciphers::table.filter(ciphers::uuid.eq(&self.uuid)))
             .execute(&**conn)
@@ -182,6 +335,7 @@ impl Cipher {
     }
 
     pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.delete_all_by_organization");
         for cipher in Self::find_by_org(org_uuid, &conn) {
             cipher.delete(&conn)?;
         }
@@ -189,6 +343,7 @@ impl Cipher {
     }
 
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.delete_all_by_user");
         for cipher in Self::find_owned_by_user(user_uuid, &conn) {
             cipher.delete(&conn)?;
         }
@@ -196,6 +351,7 @@ impl Cipher {
     }
 
     pub fn move_to_folder(&self, folder_uuid: Option<String>, user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.cipher.move_to_folder");
         User::update_uuid_revision(user_uuid, &conn);
 
         match (self.get_folder_uuid(&user_uuid, &conn), folder_uuid) {
@@ -223,59 +379,87 @@ impl Cipher {
     }
 
     pub fn is_write_accessible_to_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.cipher.is_write_accessible_to_user");
         ciphers::table
             .filter(ciphers::uuid.eq(&self.uuid))
             .left_join(
                 users_organizations::table.on(ciphers::organization_uuid
                     .eq(users_organizations::org_uuid.nullable())
-                    .and(users_organizations::user_uuid.eq(user_uuid))),
+                    .and(users_organizations::user_uuid.eq(user_uuid))
+                    .and(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))),
             )
             .left_join(ciphers_collections::table)
             .left_join(
                 users_collections::table
                     .on(ciphers_collections::collection_uuid.eq(users_collections::collection_uuid)),
             )
-            .filter(ciphers::user_uuid.eq(user_uuid).or(
-                // Cipher owner
-                users_organizations::access_all.eq(true).or(
-                    // access_all in Organization
-                    users_organizations::type_.le(UserOrgType::Admin as i32).or(
-                        // Org admin or owner
-                        users_collections::user_uuid.eq(user_uuid).and(
-                            users_collections::read_only.eq(false), //R/W access to collection
+            .filter(
+                ciphers::user_uuid.eq(user_uuid).or(
+                    // Cipher owner
+                    users_organizations::access_all.eq(true).or(
+                        // access_all in Organization
+                        users_organizations::type_.le(UserOrgType::Admin as i32).or(
+                            // Org admin or owner
+                            users_collections::user_uuid
+                                .eq(user_uuid)
+                                .and(users_collections::read_only.eq(false)) // R/W access to collection
+                                .and(users_organizations::uuid.is_not_null()), // Only the confirmed members
                         ),
                     ),
                 ),
-            ))
+            )
             .select(ciphers::all_columns)
             .first::<Self>(&**conn)
             .ok()
             .is_some()
     }
 
+    /// The confirmed owners and admins of the organization can manage all its ciphers through the admin routes,
+    /// even the ones in collections they aren't assigned to
+    pub fn is_org_admin(&self, user_uuid: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.cipher.is_org_admin");
+        let org_uuid = match self.organization_uuid {
+            Some(ref org_uuid) => org_uuid,
+            None => return false,
+        };
+
+        match UserOrganization::find_by_user_and_org(user_uuid, org_uuid, conn) {
+            Some(user_org) => {
+                user_org.status == UserOrgStatus::Confirmed as i32 && user_org.type_ >= UserOrgType::Admin
+            }
+            None => false,
+        }
+    }
+
     pub fn is_accessible_to_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.cipher.is_accessible_to_user");
         ciphers::table
             .filter(ciphers::uuid.eq(&self.uuid))
             .left_join(
                 users_organizations::table.on(ciphers::organization_uuid
                     .eq(users_organizations::org_uuid.nullable())
-                    .and(users_organizations::user_uuid.eq(user_uuid))),
+                    .and(users_organizations::user_uuid.eq(user_uuid))
+                    .and(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))),
             )
             .left_join(ciphers_collections::table)
             .left_join(
                 users_collections::table
                     .on(ciphers_collections::collection_uuid.eq(users_collections::collection_uuid)),
             )
-            .filter(ciphers::user_uuid.eq(user_uuid).or(
-                // Cipher owner
-                users_organizations::access_all.eq(true).or(
-                    // access_all in Organization
-                    users_organizations::type_.le(UserOrgType::Admin as i32).or(
-                        // Org admin or owner
-                        users_collections::user_uuid.eq(user_uuid), // Access to Collection
+            .filter(
+                ciphers::user_uuid.eq(user_uuid).or(
+                    // Cipher owner
+                    users_organizations::access_all.eq(true).or(
+                        // access_all in Organization
+                        users_organizations::type_.le(UserOrgType::Admin as i32).or(
+                            // Org admin or owner
+                            users_collections::user_uuid
+                                .eq(user_uuid) // Access to Collection
+                                .and(users_organizations::uuid.is_not_null()), // Only the confirmed members
+                        ),
                     ),
                 ),
-            ))
+            )
             .select(ciphers::all_columns)
             .first::<Self>(&**conn)
             .ok()
@@ -283,6 +467,7 @@ impl Cipher {
     }
 
     pub fn get_folder_uuid(&self, user_uuid: &str, conn: &DbConn) -> Option<String> {
+        let _span = telemetry::span("db.cipher.get_folder_uuid");
         folders_ciphers::table
             .inner_join(folders::table)
             .filter(folders::user_uuid.eq(&user_uuid))
@@ -293,6 +478,7 @@ impl Cipher {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.cipher.find_by_uuid");
         ciphers::table
             .filter(ciphers::uuid.eq(uuid))
             .first::<Self>(&**conn)
@@ -301,6 +487,7 @@ impl Cipher {
 
     // Find all ciphers accessible to user
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.cipher.find_by_user");
         ciphers::table
         .left_join(users_organizations::table.on(
             ciphers::organization_uuid.eq(users_organizations::org_uuid.nullable()).and(
@@ -331,25 +518,56 @@ impl Cipher {
 
     // Find all ciphers directly owned by user
     pub fn find_owned_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.cipher.find_owned_by_user");
         ciphers::table
         .filter(ciphers::user_uuid.eq(user_uuid))
         .load::<Self>(&**conn).expect("Error loading ciphers")
     }
 
     pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.cipher.find_by_org");
         ciphers::table
             .filter(ciphers::organization_uuid.eq(org_uuid))
             .load::<Self>(&**conn).expect("Error loading ciphers")
     }
 
+    pub fn count_by_org_and_type(org_uuid: &str, type_: i32, conn: &DbConn) -> i64 {
+        let _span = telemetry::span("db.cipher.count_by_org_and_type");
+        ciphers::table
+            .filter(ciphers::organization_uuid.eq(org_uuid))
+            .filter(ciphers::type_.eq(type_))
+            .count()
+            .first::<i64>(&**conn)
+            .unwrap_or(0)
+    }
+
     pub fn find_by_folder(folder_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.cipher.find_by_folder");
         folders_ciphers::table.inner_join(ciphers::table)
             .filter(folders_ciphers::folder_uuid.eq(folder_uuid))
             .select(ciphers::all_columns)
             .load::<Self>(&**conn).expect("Error loading ciphers")
     }
 
+    pub fn find_by_collection(collection_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.cipher.find_by_collection");
+        ciphers_collections::table.inner_join(ciphers::table)
+            .filter(ciphers_collections::collection_uuid.eq(collection_uuid))
+            .select(ciphers::all_columns)
+            .load::<Self>(&**conn).expect("Error loading ciphers")
+    }
+
+    pub fn get_all_collections(&self, conn: &DbConn) -> Vec<String> {
+        let _span = telemetry::span("db.cipher.get_all_collections");
+        ciphers_collections::table
+            .filter(ciphers_collections::cipher_uuid.eq(&self.uuid))
+            .select(ciphers_collections::collection_uuid)
+            .load::<String>(&**conn)
+            .unwrap_or_default()
+    }
+
     pub fn get_collections(&self, user_id: &str, conn: &DbConn) -> Vec<String> {
+        let _span = telemetry::span("db.cipher.get_collections");
         ciphers_collections::table
         .inner_join(collections::table.on(
             collections::uuid.eq(ciphers_collections::collection_uuid)
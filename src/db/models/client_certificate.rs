@@ -0,0 +1,118 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::User;
+
+/// A client certificate registered by a user, identified by the SHA-256 fingerprint
+/// that the reverse proxy sends after verifying the certificate
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "client_certificates"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(uuid)]
+pub struct ClientCertificate {
+    pub uuid: String,
+    pub user_uuid: String,
+    pub name: String,
+    pub fingerprint: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Local methods
+impl ClientCertificate {
+    pub fn new(user_uuid: String, name: String, fingerprint: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            user_uuid,
+            name,
+            fingerprint,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    /// Converts a fingerprint to lowercase hex without separators,
+    /// so fingerprints like `AB:CD:...` and `abcd...` are stored and compared the same way.
+    /// Returns None if it's not a valid SHA-256 fingerprint
+    pub fn normalize_fingerprint(fingerprint: &str) -> Option<String> {
+        let normalized: String = fingerprint
+            .chars()
+            .filter(|c| *c != ':' && !c.is_whitespace())
+            .collect::<String>()
+            .to_lowercase();
+
+        if normalized.len() == 64 && normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+            Some(normalized)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Fingerprint": self.fingerprint,
+            "CreationDate": format_date(&self.created_at),
+            "Object": "clientCertificate",
+        })
+    }
+}
+
+use crate::db::schema::client_certificates;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl ClientCertificate {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.client_certificate.save");
+        replace_into!(client_certificates::table, client_certificates::uuid, self, &**conn)
+            .map_res("Error saving client certificate")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.client_certificate.delete");
+        diesel::delete(client_certificates::table.filter(client_certificates::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting client certificate")
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.client_certificate.delete_all_by_user");
+        diesel::delete(client_certificates::table.filter(client_certificates::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting client certificates")
+    }
+
+    pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.client_certificate.find_by_uuid_and_user");
+        client_certificates::table
+            .filter(client_certificates::uuid.eq(uuid))
+            .filter(client_certificates::user_uuid.eq(user_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.client_certificate.find_by_user");
+        client_certificates::table
+            .filter(client_certificates::user_uuid.eq(user_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading client certificates")
+    }
+
+    pub fn find_by_fingerprint(fingerprint: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.client_certificate.find_by_fingerprint");
+        client_certificates::table
+            .filter(client_certificates::fingerprint.eq(fingerprint))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+}
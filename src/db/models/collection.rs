@@ -2,7 +2,8 @@ use serde_json::Value;
 
 use super::{Organization, UserOrgStatus, UserOrgType, UserOrganization};
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "collections"]
 #[belongs_to(Organization, foreign_key = "org_uuid")]
 #[primary_key(uuid)]
@@ -10,6 +11,8 @@ pub struct Collection {
     pub uuid: String,
     pub org_uuid: String,
     pub name: String,
+    pub external_id: Option<String>,
+    pub folder_uuid: Option<String>,
 }
 
 /// Local methods
@@ -20,39 +23,57 @@ impl Collection {
 
             org_uuid,
             name,
+            external_id: None,
+            folder_uuid: None,
         }
     }
 
+    /// The empty external ids are stored as missing, so they aren't compared
+    pub fn set_external_id(&mut self, external_id: Option<String>) {
+        self.external_id = external_id.map(|id| id.trim().to_string()).filter(|id| !id.is_empty());
+    }
+
     pub fn to_json(&self) -> Value {
         json!({
             "Id": self.uuid,
             "OrganizationId": self.org_uuid,
             "Name": self.name,
+            "ExternalId": self.external_id,
             "Object": "collection",
         })
     }
+
+    /// The collection in the JSON format of the organization exports, with the fields in camelCase
+    pub fn to_export_json(&self) -> Value {
+        json!({
+            "id": self.uuid,
+            "organizationId": self.org_uuid,
+            "name": self.name,
+            "externalId": self.external_id,
+        })
+    }
 }
 
 use crate::db::schema::*;
-use crate::db::DbConn;
+use crate::db::{DbConn, INSERT_BATCH_SIZE};
 use diesel;
 use diesel::prelude::*;
 
-use crate::api::EmptyResult;
+use crate::api::{ApiResult, EmptyResult};
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Collection {
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.save");
         self.update_users_revision(conn);
 
-        diesel::replace_into(collections::table)
-            .values(&*self)
-            .execute(&**conn)
-            .map_res("Error saving collection")
+        replace_into!(collections::table, collections::uuid, &*self, &**conn).map_res("Error saving collection")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete");
         self.update_users_revision(conn);
         CollectionCipher::delete_all_by_collection(&self.uuid, &conn)?;
         CollectionUser::delete_all_by_collection(&self.uuid, &conn)?;
@@ -63,6 +84,7 @@ impl Collection {
     }
 
     pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete_all_by_organization");
         for collection in Self::find_by_organization(org_uuid, &conn) {
             collection.delete(&conn)?;
         }
@@ -70,6 +92,7 @@ impl Collection {
     }
 
     pub fn update_users_revision(&self, conn: &DbConn) {
+        let _span = telemetry::span("db.collection.update_users_revision");
         UserOrganization::find_by_collection_and_org(&self.uuid, &self.org_uuid, conn)
             .iter()
             .for_each(|user_org| {
@@ -78,6 +101,7 @@ impl Collection {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_by_uuid");
         collections::table
             .filter(collections::uuid.eq(uuid))
             .first::<Self>(&**conn)
@@ -85,6 +109,7 @@ impl Collection {
     }
 
     pub fn find_by_user_uuid(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_user_uuid");
         collections::table
         .left_join(users_collections::table.on(
             users_collections::collection_uuid.eq(collections::uuid).and(
@@ -108,6 +133,7 @@ impl Collection {
     }
 
     pub fn find_by_organization_and_user_uuid(org_uuid: &str, user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_organization_and_user_uuid");
         Self::find_by_user_uuid(user_uuid, conn)
             .into_iter()
             .filter(|c| c.org_uuid == org_uuid)
@@ -115,13 +141,34 @@ impl Collection {
     }
 
     pub fn find_by_organization(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_organization");
         collections::table
             .filter(collections::org_uuid.eq(org_uuid))
             .load::<Self>(&**conn)
             .expect("Error loading collections")
     }
 
+    pub fn count_by_organization(org_uuid: &str, conn: &DbConn) -> i64 {
+        let _span = telemetry::span("db.collection.count_by_organization");
+        collections::table
+            .filter(collections::org_uuid.eq(org_uuid))
+            .count()
+            .first::<i64>(&**conn)
+            .unwrap_or(0)
+    }
+
+    /// The collection created for the folder when its items were shared with the organization
+    pub fn find_by_folder_and_org(folder_uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_by_folder_and_org");
+        collections::table
+            .filter(collections::folder_uuid.eq(folder_uuid))
+            .filter(collections::org_uuid.eq(org_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
     pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_by_uuid_and_org");
         collections::table
             .filter(collections::uuid.eq(uuid))
             .filter(collections::org_uuid.eq(org_uuid))
@@ -130,7 +177,66 @@ impl Collection {
             .ok()
     }
 
+    /// Finds another collection of the organization with the same external id, ignoring the case.
+    /// The names are encrypted with a random IV on each client, so only the external ids can be compared
+    pub fn find_duplicate_external_id(&self, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_duplicate_external_id");
+        self.external_id.as_ref()?;
+
+        let collections = Self::find_by_organization(&self.org_uuid, conn);
+        let uuid = self.find_duplicate_external_id_in(&collections)?;
+        collections.into_iter().find(|c| c.uuid == uuid)
+    }
+
+    /// Merges the collections of the organization that have the same external id into the first one,
+    /// with the ciphers and the users of all of them. Returns the number of removed collections
+    pub fn merge_duplicates(org_uuid: &str, conn: &DbConn) -> ApiResult<usize> {
+        let _span = telemetry::span("db.collection.merge_duplicates");
+        let mut kept: Vec<Self> = Vec::new();
+        let mut merged = 0;
+
+        for collection in Self::find_by_organization(org_uuid, conn) {
+            let target = match collection.find_duplicate_external_id_in(&kept) {
+                Some(target) => target,
+                None => {
+                    kept.push(collection);
+                    continue;
+                }
+            };
+
+            for coll_cipher in CollectionCipher::find_by_collection(&collection.uuid, conn) {
+                CollectionCipher::save(&coll_cipher.cipher_uuid, &target, conn)?;
+            }
+
+            for coll_user in CollectionUser::find_by_collection(&collection.uuid, conn) {
+                // The user keeps the most permissive access of the merged collections
+                let read_only = match CollectionUser::find_by_collection_and_user(&target, &coll_user.user_uuid, conn) {
+                    Some(existing) => existing.read_only && coll_user.read_only,
+                    None => coll_user.read_only,
+                };
+                CollectionUser::save(&coll_user.user_uuid, &target, read_only, conn)?;
+            }
+
+            collection.delete(conn)?;
+            merged += 1;
+        }
+
+        Ok(merged)
+    }
+
+    /// The uuid of the collection of the list with the same external id, like `find_duplicate_external_id`
+    pub fn find_duplicate_external_id_in(&self, collections: &[Self]) -> Option<String> {
+        let external_id = self.external_id.as_ref()?.to_lowercase();
+
+        collections
+            .iter()
+            .filter(|c| c.uuid != self.uuid)
+            .find(|c| c.external_id.as_ref().map(|id| id.to_lowercase()).as_ref() == Some(&external_id))
+            .map(|c| c.uuid.clone())
+    }
+
     pub fn find_by_uuid_and_user(uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_by_uuid_and_user");
         collections::table
         .left_join(users_collections::table.on(
             users_collections::collection_uuid.eq(collections::uuid).and(
@@ -154,10 +260,12 @@ impl Collection {
     }
 
     pub fn is_writable_by_user(&self, user_uuid: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.collection.is_writable_by_user");
         match UserOrganization::find_by_user_and_org(&user_uuid, &self.org_uuid, &conn) {
             None => false, // Not in Org
+            Some(ref user_org) if user_org.status != UserOrgStatus::Confirmed as i32 => false,
             Some(user_org) => {
-                if user_org.access_all {
+                if user_org.has_full_access() {
                     true
                 } else {
                     users_collections::table
@@ -177,7 +285,7 @@ impl Collection {
 
 use super::User;
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
 #[table_name = "users_collections"]
 #[belongs_to(User, foreign_key = "user_uuid")]
 #[belongs_to(Collection, foreign_key = "collection_uuid")]
@@ -191,6 +299,7 @@ pub struct CollectionUser {
 /// Database methods
 impl CollectionUser {
     pub fn find_by_organization_and_user_uuid(org_uuid: &str, user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_organization_and_user_uuid");
         users_collections::table
             .filter(users_collections::user_uuid.eq(user_uuid))
             .inner_join(collections::table.on(collections::uuid.eq(users_collections::collection_uuid)))
@@ -201,19 +310,26 @@ impl CollectionUser {
     }
 
     pub fn save(user_uuid: &str, collection_uuid: &str, read_only: bool, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.save");
         User::update_uuid_revision(&user_uuid, conn);
 
-        diesel::replace_into(users_collections::table)
-            .values((
-                users_collections::user_uuid.eq(user_uuid),
-                users_collections::collection_uuid.eq(collection_uuid),
-                users_collections::read_only.eq(read_only),
-            ))
-            .execute(&**conn)
-            .map_res("Error adding user to collection")
+        let collection_user = Self {
+            user_uuid: user_uuid.to_string(),
+            collection_uuid: collection_uuid.to_string(),
+            read_only,
+        };
+
+        replace_into!(
+            users_collections::table,
+            (users_collections::user_uuid, users_collections::collection_uuid),
+            &collection_user,
+            &**conn
+        )
+        .map_res("Error adding user to collection")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete");
         User::update_uuid_revision(&self.user_uuid, conn);
 
         diesel::delete(
@@ -226,6 +342,7 @@ impl CollectionUser {
     }
 
     pub fn find_by_collection(collection_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_collection");
         users_collections::table
             .filter(users_collections::collection_uuid.eq(collection_uuid))
             .select(users_collections::all_columns)
@@ -234,6 +351,7 @@ impl CollectionUser {
     }
 
     pub fn find_by_collection_and_user(collection_uuid: &str, user_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.collection.find_by_collection_and_user");
         users_collections::table
             .filter(users_collections::collection_uuid.eq(collection_uuid))
             .filter(users_collections::user_uuid.eq(user_uuid))
@@ -243,6 +361,7 @@ impl CollectionUser {
     }
 
     pub fn delete_all_by_collection(collection_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete_all_by_collection");
         CollectionUser::find_by_collection(&collection_uuid, conn)
             .iter()
             .for_each(|collection| {
@@ -254,7 +373,20 @@ impl CollectionUser {
             .map_res("Error deleting users from collection")
     }
 
+    /// The collections the user has access to that don't exist
+    pub fn find_orphans_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_orphans_by_user");
+        users_collections::table
+            .left_join(collections::table)
+            .filter(users_collections::user_uuid.eq(user_uuid))
+            .filter(collections::uuid.is_null())
+            .select(users_collections::all_columns)
+            .load::<Self>(&**conn)
+            .expect("Error loading users_collections")
+    }
+
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete_all_by_user");
         User::update_uuid_revision(&user_uuid, conn);
 
         diesel::delete(users_collections::table.filter(users_collections::user_uuid.eq(user_uuid)))
@@ -278,17 +410,35 @@ pub struct CollectionCipher {
 /// Database methods
 impl CollectionCipher {
     pub fn save(cipher_uuid: &str, collection_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.save");
         Self::update_users_revision(&collection_uuid, conn);
-        diesel::replace_into(ciphers_collections::table)
-            .values((
+        replace_into!(
+            ciphers_collections::table,
+            (ciphers_collections::cipher_uuid, ciphers_collections::collection_uuid),
+            (
                 ciphers_collections::cipher_uuid.eq(cipher_uuid),
                 ciphers_collections::collection_uuid.eq(collection_uuid),
-            ))
-            .execute(&**conn)
-            .map_res("Error adding cipher to collection")
+            ),
+            &**conn,
+            nothing
+        )
+        .map_res("Error adding cipher to collection")
+    }
+
+    /// Adds the new ciphers to their collections in batches, without updating the revision of the users
+    pub fn insert_all(collection_ciphers: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.insert_all");
+        for batch in collection_ciphers.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(ciphers_collections::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error adding ciphers to collections")?;
+        }
+        Ok(())
     }
 
     pub fn delete(cipher_uuid: &str, collection_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete");
         Self::update_users_revision(&collection_uuid, conn);
         diesel::delete(
             ciphers_collections::table
@@ -300,18 +450,41 @@ impl CollectionCipher {
     }
 
     pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete_all_by_cipher");
         diesel::delete(ciphers_collections::table.filter(ciphers_collections::cipher_uuid.eq(cipher_uuid)))
             .execute(&**conn)
             .map_res("Error removing cipher from collections")
     }
 
+    pub fn find_by_collection(collection_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_by_collection");
+        ciphers_collections::table
+            .filter(ciphers_collections::collection_uuid.eq(collection_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading ciphers_collections")
+    }
+
     pub fn delete_all_by_collection(collection_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.collection.delete_all_by_collection");
         diesel::delete(ciphers_collections::table.filter(ciphers_collections::collection_uuid.eq(collection_uuid)))
             .execute(&**conn)
             .map_res("Error removing ciphers from collection")
     }
 
+    /// The links from the ciphers to collections that don't exist
+    pub fn find_orphans_by_ciphers(cipher_uuids: &[String], conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.collection.find_orphans_by_ciphers");
+        ciphers_collections::table
+            .left_join(collections::table)
+            .filter(ciphers_collections::cipher_uuid.eq_any(cipher_uuids))
+            .filter(collections::uuid.is_null())
+            .select(ciphers_collections::all_columns)
+            .load::<Self>(&**conn)
+            .expect("Error loading ciphers_collections")
+    }
+
     pub fn update_users_revision(collection_uuid: &str, conn: &DbConn) {
+        let _span = telemetry::span("db.collection.update_users_revision");
         if let Some(collection) = Collection::find_by_uuid(collection_uuid, conn) {
             collection.update_users_revision(conn);
         }
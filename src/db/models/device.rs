@@ -1,8 +1,11 @@
 use chrono::{NaiveDateTime, Utc};
+use num_traits::FromPrimitive;
+use serde_json::Value;
 
-use super::User;
+use super::{QueuedNotification, User, UserOrgType};
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "devices"]
 #[belongs_to(User, foreign_key = "user_uuid")]
 #[primary_key(uuid)]
@@ -14,18 +17,65 @@ pub struct Device {
     pub user_uuid: String,
 
     pub name: String,
-    /// https://github.com/bitwarden/core/tree/master/src/Core/Enums
+    /// One of the `DeviceType` values
     pub type_: i32,
     pub push_token: Option<String>,
 
     pub refresh_token: String,
 
     pub twofactor_remember: Option<String>,
+    pub twofactor_remember_at: Option<NaiveDateTime>,
+
+    pub last_ip: Option<String>,
+    pub last_active_at: Option<NaiveDateTime>,
+    pub token_issued_at: Option<NaiveDateTime>,
+    /// When the device first opened a websocket, the notifications are only queued for these devices
+    pub websocket_at: Option<NaiveDateTime>,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/DeviceType.cs
+#[allow(dead_code, non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+pub enum DeviceType {
+    Android = 0,
+    iOS = 1,
+    ChromeExtension = 2,
+    FirefoxExtension = 3,
+    OperaExtension = 4,
+    EdgeExtension = 5,
+    WindowsDesktop = 6,
+    MacOsDesktop = 7,
+    LinuxDesktop = 8,
+    ChromeBrowser = 9,
+    FirefoxBrowser = 10,
+    OperaBrowser = 11,
+    EdgeBrowser = 12,
+    IEBrowser = 13,
+    UnknownBrowser = 14,
+    AndroidAmazon = 15,
+    UWP = 16,
+    SafariBrowser = 17,
+    VivaldiBrowser = 18,
+    VivaldiExtension = 19,
+    SafariExtension = 20,
+}
+
+impl DeviceType {
+    /// The iOS app sends its type as "iOS", the other clients send the number
+    pub fn from_client(value: &str) -> Option<Self> {
+        match value {
+            "iOS" => Some(DeviceType::iOS),
+            _ => value.parse().ok().and_then(Self::from_i32),
+        }
+    }
 }
 
+/// The activity is only stored again after this time, to avoid writing on every request
+const ACTIVITY_UPDATE_SECS: i64 = 300;
+
 /// Local methods
 impl Device {
-    pub fn new(uuid: String, user_uuid: String, name: String, type_: i32) -> Self {
+    pub fn new(uuid: String, user_uuid: String, name: String, type_: DeviceType) -> Self {
         let now = Utc::now().naive_utc();
 
         Self {
@@ -35,26 +85,100 @@ impl Device {
 
             user_uuid,
             name,
-            type_,
+            type_: type_ as i32,
 
             push_token: None,
             refresh_token: String::new(),
             twofactor_remember: None,
+            twofactor_remember_at: None,
+
+            last_ip: None,
+            last_active_at: None,
+            token_issued_at: None,
+            websocket_at: None,
         }
     }
 
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.type_,
+            "Identifier": self.uuid,
+            "CreationDate": format_date(&self.created_at),
+        })
+    }
+
+    pub fn to_json_session(&self, current_device_uuid: &str) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "Type": self.type_,
+            "CreationDate": format_date(&self.created_at),
+            "LastIp": self.last_ip,
+            "LastActivityDate": self.last_active_at.as_ref().map(format_date),
+            "TokenIssuedDate": self.token_issued_at.as_ref().map(format_date),
+            "IsCurrent": self.uuid == current_device_uuid,
+            "Object": "session",
+        })
+    }
+
+    /// Records a request from the device, and returns whether the activity needs to be saved
+    pub fn update_activity(&mut self, ip: &str) -> bool {
+        use chrono::Duration;
+
+        let now = Utc::now().naive_utc();
+        let outdated = match self.last_active_at {
+            Some(last_active_at) => last_active_at + Duration::seconds(ACTIVITY_UPDATE_SECS) < now,
+            None => true,
+        };
+
+        if !outdated && self.last_ip.as_ref().map(String::as_str) == Some(ip) {
+            return false;
+        }
+
+        self.last_ip = Some(ip.to_string());
+        self.last_active_at = Some(now);
+        true
+    }
+
     pub fn refresh_twofactor_remember(&mut self) -> String {
         use crate::crypto;
         use data_encoding::BASE64;
 
         let twofactor_remember = BASE64.encode(&crypto::get_random(vec![0u8; 180]));
         self.twofactor_remember = Some(twofactor_remember.clone());
+        self.twofactor_remember_at = Some(Utc::now().naive_utc());
 
         twofactor_remember
     }
 
     pub fn delete_twofactor_remember(&mut self) {
         self.twofactor_remember = None;
+        self.twofactor_remember_at = None;
+    }
+
+    /// Checks the remember token sent by the client, which is only valid for this device
+    /// and for the number of days set in the config
+    pub fn check_twofactor_remember(&self, token: &str) -> bool {
+        use crate::crypto::ct_eq;
+        use chrono::Duration;
+
+        let days = crate::CONFIG.twofactor_remember_days();
+        if days <= 0 {
+            return false;
+        }
+
+        match (&self.twofactor_remember, self.twofactor_remember_at) {
+            (Some(remember), Some(remember_at)) => {
+                remember_at + Duration::days(days) > Utc::now().naive_utc() && ct_eq(remember, token)
+            }
+            _ => false,
+        }
     }
 
     pub fn refresh_tokens(&mut self, user: &super::User, orgs: Vec<super::UserOrganization>) -> (String, i64) {
@@ -69,11 +193,19 @@ impl Device {
         // Update the expiration of the device and the last update date
         let time_now = Utc::now().naive_utc();
         self.updated_at = time_now;
-
-        let orgowner: Vec<_> = orgs.iter().filter(|o| o.type_ == 0).map(|o| o.org_uuid.clone()).collect();
-        let orgadmin: Vec<_> = orgs.iter().filter(|o| o.type_ == 1).map(|o| o.org_uuid.clone()).collect();
-        let orguser: Vec<_> = orgs.iter().filter(|o| o.type_ == 2).map(|o| o.org_uuid.clone()).collect();
-        let orgmanager: Vec<_> = orgs.iter().filter(|o| o.type_ == 3).map(|o| o.org_uuid.clone()).collect();
+        self.token_issued_at = Some(time_now);
+        self.last_active_at = Some(time_now);
+
+        let orgs_of_type = |type_: UserOrgType| -> Vec<String> {
+            orgs.iter()
+                .filter(|o| o.type_ == type_)
+                .map(|o| o.org_uuid.clone())
+                .collect()
+        };
+        let orgowner = orgs_of_type(UserOrgType::Owner);
+        let orgadmin = orgs_of_type(UserOrgType::Admin);
+        let orguser = orgs_of_type(UserOrgType::User);
+        let orgmanager = orgs_of_type(UserOrgType::Manager);
 
 
         // Create the JWT claims struct, to send to the client
@@ -87,7 +219,7 @@ impl Device {
             premium: true,
             name: user.name.to_string(),
             email: user.email.to_string(),
-            email_verified: true,
+            email_verified: user.is_email_verified(),
 
             orgowner,
             orgadmin,
@@ -105,39 +237,80 @@ impl Device {
 }
 
 use crate::db::schema::devices;
-use crate::db::DbConn;
+use crate::db::{like_pattern, lower, DbConn, LIKE_ESCAPE};
 use diesel;
 use diesel::prelude::*;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Device {
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.save");
         self.updated_at = Utc::now().naive_utc();
 
-        crate::util::retry(
-            || diesel::replace_into(devices::table).values(&*self).execute(&**conn),
-            10,
+        crate::util::retry(|| replace_into!(devices::table, devices::uuid, &*self, &**conn), 10)
+            .map_res("Error saving device")
+    }
+
+    /// Only updates the activity columns, so it doesn't change the revision of the device
+    pub fn save_activity(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.save_activity");
+        diesel::update(devices::table.filter(devices::uuid.eq(&self.uuid)))
+            .set((
+                devices::last_ip.eq(&self.last_ip),
+                devices::last_active_at.eq(&self.last_active_at),
+            ))
+            .execute(&**conn)
+            .map_res("Error saving device activity")
+    }
+
+    /// Records that the device opened a websocket, only the first time
+    pub fn save_websocket_opened(uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.save_websocket_opened");
+        diesel::update(
+            devices::table
+                .filter(devices::uuid.eq(uuid))
+                .filter(devices::websocket_at.is_null()),
         )
-        .map_res("Error saving device")
+        .set(devices::websocket_at.eq(Utc::now().naive_utc()))
+        .execute(&**conn)
+        .map_res("Error saving device websocket")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.delete");
+        QueuedNotification::delete_all_by_device(&self.uuid, conn)?;
+
         diesel::delete(devices::table.filter(devices::uuid.eq(self.uuid)))
             .execute(&**conn)
             .map_res("Error removing device")
     }
 
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.delete_all_by_user");
         for device in Self::find_by_user(user_uuid, &conn) {
             device.delete(&conn)?;
         }
         Ok(())
     }
 
+    /// Revokes the two factor remember tokens of all the devices of the user
+    pub fn delete_twofactor_remember_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.device.delete_twofactor_remember_by_user");
+        diesel::update(devices::table.filter(devices::user_uuid.eq(user_uuid)))
+            .set((
+                devices::twofactor_remember.eq(None::<String>),
+                devices::twofactor_remember_at.eq(None::<NaiveDateTime>),
+            ))
+            .execute(&**conn)
+            .map_res("Error revoking two factor remember tokens")
+    }
+
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.device.find_by_uuid");
         devices::table
             .filter(devices::uuid.eq(uuid))
             .first::<Self>(&**conn)
@@ -145,6 +318,7 @@ impl Device {
     }
 
     pub fn find_by_refresh_token(refresh_token: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.device.find_by_refresh_token");
         devices::table
             .filter(devices::refresh_token.eq(refresh_token))
             .first::<Self>(&**conn)
@@ -152,9 +326,33 @@ impl Device {
     }
 
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.device.find_by_user");
         devices::table
             .filter(devices::user_uuid.eq(user_uuid))
             .load::<Self>(&**conn)
             .expect("Error loading devices")
     }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.device.get_all");
+        devices::table.load::<Self>(&**conn).expect("Error loading devices")
+    }
+
+    /// A page of the devices with the text in their uuid or name, ignoring the case
+    pub fn search(text: &str, offset: i64, limit: i64, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.device.search");
+        let pattern = like_pattern(text);
+        devices::table
+            .filter(
+                lower(devices::uuid)
+                    .like(&pattern)
+                    .escape(LIKE_ESCAPE)
+                    .or(lower(devices::name).like(&pattern).escape(LIKE_ESCAPE)),
+            )
+            .order(devices::name)
+            .offset(offset)
+            .limit(limit)
+            .load::<Self>(&**conn)
+            .expect("Error searching devices")
+    }
 }
@@ -0,0 +1,179 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::Device;
+
+/// An entry of the activity log. The events are kept after the users or ciphers
+/// they refer to are deleted, so there are no foreign keys
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "events"]
+#[primary_key(uuid)]
+pub struct Event {
+    pub uuid: String,
+    pub event_type: i32,
+    pub user_uuid: Option<String>,
+    pub org_uuid: Option<String>,
+    pub cipher_uuid: Option<String>,
+    pub device_type: Option<i32>,
+    pub ip_address: Option<String>,
+    pub event_date: NaiveDateTime,
+    pub org_user_uuid: Option<String>,
+}
+
+/// https://github.com/bitwarden/server/blob/master/src/Core/Enums/EventType.cs
+#[derive(Copy, Clone)]
+pub enum EventType {
+    UserLoggedIn = 1000,
+    UserFailedLogIn = 1005,
+    UserClientExportedVault = 1007,
+
+    CipherCreated = 1100,
+    CipherUpdated = 1101,
+    CipherDeleted = 1102,
+    CipherAttachmentCreated = 1103,
+    CipherAttachmentDeleted = 1104,
+    CipherShared = 1105,
+    CipherUpdatedCollections = 1106,
+    CipherClientViewed = 1107,
+    CipherClientToggledHiddenFieldVisible = 1108,
+    CipherClientToggledPasswordVisible = 1109,
+    CipherClientToggledCardCodeVisible = 1110,
+    CipherClientCopiedPassword = 1111,
+    CipherClientCopiedHiddenField = 1112,
+    CipherClientCopiedCardCode = 1113,
+    CipherClientAutofilled = 1114,
+
+    OrganizationUserInvited = 1500,
+    OrganizationUserConfirmed = 1501,
+    OrganizationUserUpdated = 1502,
+    OrganizationUserRemoved = 1503,
+}
+
+impl EventType {
+    /// The events that the clients can report, the rest are only created by the server
+    pub fn from_client(event_type: i32) -> Option<Self> {
+        match event_type {
+            1107 => Some(EventType::CipherClientViewed),
+            1108 => Some(EventType::CipherClientToggledHiddenFieldVisible),
+            1109 => Some(EventType::CipherClientToggledPasswordVisible),
+            1110 => Some(EventType::CipherClientToggledCardCodeVisible),
+            1111 => Some(EventType::CipherClientCopiedPassword),
+            1112 => Some(EventType::CipherClientCopiedHiddenField),
+            1113 => Some(EventType::CipherClientCopiedCardCode),
+            1114 => Some(EventType::CipherClientAutofilled),
+            _ => None,
+        }
+    }
+}
+
+/// Local methods
+impl Event {
+    pub const PAGE_SIZE: i64 = 100;
+
+    pub fn new(event_type: EventType, user_uuid: Option<String>) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            event_type: event_type as i32,
+            user_uuid,
+            org_uuid: None,
+            cipher_uuid: None,
+            device_type: None,
+            ip_address: None,
+            event_date: Utc::now().naive_utc(),
+            org_user_uuid: None,
+        }
+    }
+
+    /// The guard stores the address of the current request in the device
+    pub fn set_device(&mut self, device: &Device) {
+        self.device_type = Some(device.type_);
+        self.ip_address = device.last_ip.clone();
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Type": self.event_type,
+            "UserId": self.user_uuid,
+            "OrganizationId": self.org_uuid,
+            "CipherId": self.cipher_uuid,
+            "CollectionId": null,
+            "GroupId": null,
+            "OrganizationUserId": self.org_user_uuid,
+            "ActingUserId": self.user_uuid,
+            "Date": format_date(&self.event_date),
+            "DeviceType": self.device_type,
+            "IpAddress": self.ip_address,
+            "Object": "event",
+        })
+    }
+}
+
+use crate::db::schema::events;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl Event {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.event.save");
+        replace_into!(events::table, events::uuid, self, &**conn).map_res("Error saving event")
+    }
+
+    /// The newest events first, at most `PAGE_SIZE` of them
+    pub fn find_by_cipher_and_date(
+        cipher_uuid: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        last_uuid: Option<&str>,
+        conn: &DbConn,
+    ) -> Vec<Self> {
+        let _span = telemetry::span("db.event.find_by_cipher_and_date");
+        // Without a previous page, no uuid is lower than the empty one, so it's the same as `event_date < end`
+        let last_uuid = last_uuid.unwrap_or("");
+        events::table
+            .filter(events::cipher_uuid.eq(cipher_uuid))
+            .filter(events::event_date.ge(start))
+            .filter(
+                events::event_date
+                    .lt(end)
+                    .or(events::event_date.eq(end).and(events::uuid.lt(last_uuid))),
+            )
+            .order((events::event_date.desc(), events::uuid.desc()))
+            .limit(Self::PAGE_SIZE)
+            .load::<Self>(&**conn)
+            .expect("Error loading events")
+    }
+
+    /// The newest events first, at most `PAGE_SIZE` of them, after the last one of the previous page
+    pub fn find_by_org_and_date(
+        org_uuid: &str,
+        start: &NaiveDateTime,
+        end: &NaiveDateTime,
+        last_uuid: Option<&str>,
+        conn: &DbConn,
+    ) -> Vec<Self> {
+        let _span = telemetry::span("db.event.find_by_org_and_date");
+        // Without a previous page, no uuid is lower than the empty one, so it's the same as `event_date < end`
+        let last_uuid = last_uuid.unwrap_or("");
+        events::table
+            .filter(events::org_uuid.eq(org_uuid))
+            .filter(events::event_date.ge(start))
+            .filter(
+                events::event_date
+                    .lt(end)
+                    .or(events::event_date.eq(end).and(events::uuid.lt(last_uuid))),
+            )
+            .order((events::event_date.desc(), events::uuid.desc()))
+            .limit(Self::PAGE_SIZE)
+            .load::<Self>(&**conn)
+            .expect("Error loading events")
+    }
+}
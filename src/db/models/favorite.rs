@@ -0,0 +1,95 @@
+use super::{Cipher, User};
+
+/// The favorites are kept for each user, so the ciphers of an organization can be a favorite of some of its members
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "favorites"]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[belongs_to(Cipher, foreign_key = "cipher_uuid")]
+#[primary_key(user_uuid, cipher_uuid)]
+pub struct Favorite {
+    pub user_uuid: String,
+    pub cipher_uuid: String,
+}
+
+/// Local methods
+impl Favorite {
+    pub fn new(user_uuid: &str, cipher_uuid: &str) -> Self {
+        Self {
+            user_uuid: user_uuid.to_string(),
+            cipher_uuid: cipher_uuid.to_string(),
+        }
+    }
+}
+
+use crate::db::schema::favorites;
+use crate::db::{DbConn, INSERT_BATCH_SIZE};
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl Favorite {
+    pub fn is_favorite(cipher_uuid: &str, user_uuid: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.favorite.is_favorite");
+        favorites::table
+            .filter(favorites::cipher_uuid.eq(cipher_uuid))
+            .filter(favorites::user_uuid.eq(user_uuid))
+            .first::<Self>(&**conn)
+            .is_ok()
+    }
+
+    /// Marks or unmarks the cipher as a favorite of the user. Without a value, it's left as it was
+    pub fn set_favorite(favorite: Option<bool>, cipher_uuid: &str, user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.favorite.set_favorite");
+        match (favorite, Self::is_favorite(cipher_uuid, user_uuid, conn)) {
+            (Some(true), false) => {
+                User::update_uuid_revision(user_uuid, conn);
+                diesel::insert_into(favorites::table)
+                    .values(Self::new(user_uuid, cipher_uuid))
+                    .execute(&**conn)
+                    .map_res("Error adding favorite")
+            }
+            (Some(false), true) => {
+                User::update_uuid_revision(user_uuid, conn);
+                diesel::delete(
+                    favorites::table
+                        .filter(favorites::user_uuid.eq(user_uuid))
+                        .filter(favorites::cipher_uuid.eq(cipher_uuid)),
+                )
+                .execute(&**conn)
+                .map_res("Error removing favorite")
+            }
+            // No changes
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds the favorites of new ciphers in batches, without updating the revision of the user
+    pub fn insert_all(favorites: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.favorite.insert_all");
+        for batch in favorites.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(favorites::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error adding favorites")?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.favorite.delete_all_by_cipher");
+        diesel::delete(favorites::table.filter(favorites::cipher_uuid.eq(cipher_uuid)))
+            .execute(&**conn)
+            .map_res("Error removing favorites by cipher")
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.favorite.delete_all_by_user");
+        diesel::delete(favorites::table.filter(favorites::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error removing favorites by user")
+    }
+}
@@ -3,7 +3,8 @@ use serde_json::Value;
 
 use super::{Cipher, User};
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "folders"]
 #[belongs_to(User, foreign_key = "user_uuid")]
 #[primary_key(uuid)]
@@ -50,6 +51,14 @@ impl Folder {
             "Object": "folder",
         })
     }
+
+    /// The folder in the format of the vault exports
+    pub fn to_export_json(&self) -> Value {
+        json!({
+            "id": self.uuid,
+            "name": self.name,
+        })
+    }
 }
 
 impl FolderCipher {
@@ -61,27 +70,39 @@ impl FolderCipher {
     }
 }
 
-use crate::db::schema::{folders, folders_ciphers};
-use crate::db::DbConn;
+use crate::db::schema::{ciphers, folders, folders_ciphers};
+use crate::db::{DbConn, INSERT_BATCH_SIZE};
 use diesel;
 use diesel::prelude::*;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Folder {
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.save");
         User::update_uuid_revision(&self.user_uuid, conn);
         self.updated_at = Utc::now().naive_utc();
 
-        diesel::replace_into(folders::table)
-            .values(&*self)
-            .execute(&**conn)
-            .map_res("Error saving folder")
+        replace_into!(folders::table, folders::uuid, &*self, &**conn).map_res("Error saving folder")
+    }
+
+    /// Inserts new folders in batches, without updating the revision of the users
+    pub fn insert_all(folders: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.insert_all");
+        for batch in folders.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(folders::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error saving folders")?;
+        }
+        Ok(())
     }
 
     pub fn delete(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.delete");
         User::update_uuid_revision(&self.user_uuid, conn);
         FolderCipher::delete_all_by_folder(&self.uuid, &conn)?;
 
@@ -91,6 +112,7 @@ impl Folder {
     }
 
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.delete_all_by_user");
         for folder in Self::find_by_user(user_uuid, &conn) {
             folder.delete(&conn)?;
         }
@@ -98,6 +120,7 @@ impl Folder {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.folder.find_by_uuid");
         folders::table
             .filter(folders::uuid.eq(uuid))
             .first::<Self>(&**conn)
@@ -105,6 +128,7 @@ impl Folder {
     }
 
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.folder.find_by_user");
         folders::table
             .filter(folders::user_uuid.eq(user_uuid))
             .load::<Self>(&**conn)
@@ -114,13 +138,31 @@ impl Folder {
 
 impl FolderCipher {
     pub fn save(&self, conn: &DbConn) -> EmptyResult {
-        diesel::replace_into(folders_ciphers::table)
-            .values(&*self)
-            .execute(&**conn)
-            .map_res("Error adding cipher to folder")
+        let _span = telemetry::span("db.folder.save");
+        replace_into!(
+            folders_ciphers::table,
+            (folders_ciphers::cipher_uuid, folders_ciphers::folder_uuid),
+            &*self,
+            &**conn,
+            nothing
+        )
+        .map_res("Error adding cipher to folder")
+    }
+
+    /// Adds the new ciphers to their folders in batches
+    pub fn insert_all(folder_ciphers: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.insert_all");
+        for batch in folder_ciphers.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(folders_ciphers::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error adding ciphers to folders")?;
+        }
+        Ok(())
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.delete");
         diesel::delete(
             folders_ciphers::table
                 .filter(folders_ciphers::cipher_uuid.eq(self.cipher_uuid))
@@ -131,18 +173,21 @@ impl FolderCipher {
     }
 
     pub fn delete_all_by_cipher(cipher_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.delete_all_by_cipher");
         diesel::delete(folders_ciphers::table.filter(folders_ciphers::cipher_uuid.eq(cipher_uuid)))
             .execute(&**conn)
             .map_res("Error removing cipher from folders")
     }
 
     pub fn delete_all_by_folder(folder_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.folder.delete_all_by_folder");
         diesel::delete(folders_ciphers::table.filter(folders_ciphers::folder_uuid.eq(folder_uuid)))
             .execute(&**conn)
             .map_res("Error removing ciphers from folder")
     }
 
     pub fn find_by_folder_and_cipher(folder_uuid: &str, cipher_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.folder.find_by_folder_and_cipher");
         folders_ciphers::table
             .filter(folders_ciphers::folder_uuid.eq(folder_uuid))
             .filter(folders_ciphers::cipher_uuid.eq(cipher_uuid))
@@ -151,9 +196,28 @@ impl FolderCipher {
     }
 
     pub fn find_by_folder(folder_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.folder.find_by_folder");
         folders_ciphers::table
             .filter(folders_ciphers::folder_uuid.eq(folder_uuid))
             .load::<Self>(&**conn)
             .expect("Error loading folders")
     }
+
+    /// The links from the folders of the user to ciphers that don't exist,
+    /// and from the ciphers of the user to folders that don't exist
+    pub fn find_orphans_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.folder.find_orphans_by_user");
+        folders_ciphers::table
+            .left_join(folders::table)
+            .left_join(ciphers::table)
+            .filter(
+                folders::user_uuid
+                    .eq(user_uuid)
+                    .and(ciphers::uuid.is_null())
+                    .or(ciphers::user_uuid.eq(user_uuid).and(folders::uuid.is_null())),
+            )
+            .select(folders_ciphers::all_columns)
+            .load::<Self>(&**conn)
+            .expect("Error loading folders")
+    }
 }
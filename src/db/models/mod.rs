@@ -1,19 +1,33 @@
 mod attachment;
 mod cipher;
+mod client_certificate;
 mod device;
+mod event;
+mod favorite;
 mod folder;
+mod notification;
 mod user;
 
 mod collection;
+mod org_domain;
 mod organization;
+mod provider;
 mod two_factor;
 
-pub use self::attachment::Attachment;
-pub use self::cipher::Cipher;
+pub use self::attachment::{Attachment, AttachmentDeletion, AttachmentResponse, AttachmentUpload};
+pub use self::cipher::{Cipher, CipherResponse, CipherType};
+pub use self::client_certificate::ClientCertificate;
 pub use self::collection::{Collection, CollectionCipher, CollectionUser};
-pub use self::device::Device;
+pub use self::device::{Device, DeviceType};
+pub use self::event::{Event, EventType};
+pub use self::favorite::Favorite;
 pub use self::folder::{Folder, FolderCipher};
+pub use self::notification::QueuedNotification;
+pub use self::org_domain::OrgDomain;
 pub use self::organization::Organization;
 pub use self::organization::{UserOrgStatus, UserOrgType, UserOrganization};
+pub use self::provider::{
+    Provider, ProviderOrganization, ProviderStatus, ProviderUser, ProviderUserStatus, ProviderUserType,
+};
 pub use self::two_factor::{TwoFactor, TwoFactorType};
 pub use self::user::{Invitation, User};
@@ -0,0 +1,137 @@
+use std::sync::Mutex;
+
+use chrono::{NaiveDateTime, Utc};
+
+use super::Device;
+
+/// A websocket notification that couldn't be delivered to a device because it wasn't connected,
+/// kept to send it when the device connects again
+#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[table_name = "queued_notifications"]
+#[belongs_to(Device, foreign_key = "device_uuid")]
+#[primary_key(uuid)]
+pub struct QueuedNotification {
+    pub uuid: String,
+    pub device_uuid: String,
+    /// Microseconds since the epoch, increased when needed so the notifications are replayed in the same order
+    pub seq: i64,
+    /// The framed message, as it would have been sent to the websocket
+    pub data: Vec<u8>,
+}
+
+/// The oldest notifications of a device are dropped over this limit, the client does a full sync
+/// when it gets a Vault update anyway
+const MAX_QUEUED_PER_DEVICE: i64 = 100;
+
+lazy_static! {
+    static ref LAST_SEQ: Mutex<i64> = Mutex::new(0);
+}
+
+fn next_seq() -> i64 {
+    let mut last = LAST_SEQ.lock().unwrap();
+    *last = to_seq(&Utc::now().naive_utc()).max(*last + 1);
+    *last
+}
+
+fn to_seq(date: &NaiveDateTime) -> i64 {
+    date.timestamp() * 1_000_000 + i64::from(date.timestamp_subsec_micros())
+}
+
+/// Local methods
+impl QueuedNotification {
+    pub fn new(device_uuid: String, data: Vec<u8>) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            device_uuid,
+            seq: next_seq(),
+            data,
+        }
+    }
+}
+
+use crate::db::schema::queued_notifications;
+use crate::db::{DbConn, INSERT_BATCH_SIZE};
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl QueuedNotification {
+    /// Saves the notifications in batches, and drops the oldest ones of the devices that go over the limit
+    pub fn insert_all(notifications: &[Self], conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.insert_all");
+        for batch in notifications.chunks(INSERT_BATCH_SIZE) {
+            diesel::insert_into(queued_notifications::table)
+                .values(batch)
+                .execute(&**conn)
+                .map_res("Error saving queued notifications")?;
+        }
+
+        let mut device_uuids: Vec<&str> = notifications.iter().map(|n| n.device_uuid.as_str()).collect();
+        device_uuids.sort();
+        device_uuids.dedup();
+        device_uuids
+            .into_iter()
+            .try_for_each(|device_uuid| Self::delete_over_limit(device_uuid, conn))
+    }
+
+    fn delete_over_limit(device_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.delete_over_limit");
+        let oldest_kept = queued_notifications::table
+            .filter(queued_notifications::device_uuid.eq(device_uuid))
+            .select(queued_notifications::seq)
+            .order(queued_notifications::seq.desc())
+            .offset(MAX_QUEUED_PER_DEVICE - 1)
+            .first::<i64>(&**conn)
+            .ok();
+
+        match oldest_kept {
+            Some(seq) => Self::delete_by_device_before(device_uuid, seq, conn),
+            None => Ok(()),
+        }
+    }
+
+    fn delete_by_device_before(device_uuid: &str, seq: i64, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.delete_by_device_before");
+        diesel::delete(
+            queued_notifications::table
+                .filter(queued_notifications::device_uuid.eq(device_uuid))
+                .filter(queued_notifications::seq.lt(seq)),
+        )
+        .execute(&**conn)
+        .map_res("Error deleting queued notifications")
+    }
+
+    /// Deletes the notifications that have been replayed, the ones queued in the meantime are kept
+    pub fn delete_by_device_until(device_uuid: &str, seq: i64, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.delete_by_device_until");
+        Self::delete_by_device_before(device_uuid, seq + 1, conn)
+    }
+
+    pub fn delete_all_by_device(device_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.delete_all_by_device");
+        diesel::delete(queued_notifications::table.filter(queued_notifications::device_uuid.eq(device_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting queued notifications")
+    }
+
+    pub fn delete_older_than(date: &NaiveDateTime, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.notification.delete_older_than");
+        diesel::delete(queued_notifications::table.filter(queued_notifications::seq.lt(to_seq(date))))
+            .execute(&**conn)
+            .map_res("Error deleting old queued notifications")
+    }
+
+    /// The notifications of the device, oldest first
+    pub fn find_by_device(device_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.notification.find_by_device");
+        queued_notifications::table
+            .filter(queued_notifications::device_uuid.eq(device_uuid))
+            .order(queued_notifications::seq.asc())
+            .load::<Self>(&**conn)
+            .expect("Error loading queued notifications")
+    }
+}
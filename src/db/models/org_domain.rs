@@ -0,0 +1,129 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::Organization;
+
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "org_domains"]
+#[belongs_to(Organization, foreign_key = "org_uuid")]
+#[primary_key(uuid)]
+pub struct OrgDomain {
+    pub uuid: String,
+    pub org_uuid: String,
+    pub domain_name: String,
+    pub txt_token: String,
+    pub created_at: NaiveDateTime,
+    pub verified_at: Option<NaiveDateTime>,
+    pub last_checked_at: Option<NaiveDateTime>,
+}
+
+/// Local methods
+impl OrgDomain {
+    pub const TXT_PREFIX: &'static str = "bitwarden_rs-verification=";
+
+    pub fn new(org_uuid: String, domain_name: String) -> Self {
+        use crate::crypto;
+        use data_encoding::HEXLOWER;
+
+        Self {
+            uuid: crate::util::get_uuid(),
+            org_uuid,
+            domain_name: domain_name.trim().to_lowercase(),
+            txt_token: HEXLOWER.encode(&crypto::get_random(vec![0u8; 20])),
+            created_at: Utc::now().naive_utc(),
+            verified_at: None,
+            last_checked_at: None,
+        }
+    }
+
+    /// The full value of the TXT record that needs to be published on the domain
+    pub fn txt_record(&self) -> String {
+        format!("{}{}", Self::TXT_PREFIX, self.txt_token)
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "OrganizationId": self.org_uuid,
+            "DomainName": self.domain_name,
+            "Txt": self.txt_record(),
+            "CreationDate": format_date(&self.created_at),
+            "VerifiedDate": self.verified_at.as_ref().map(format_date),
+            "LastCheckedDate": self.last_checked_at.as_ref().map(format_date),
+            "Object": "organizationDomain",
+        })
+    }
+}
+
+use crate::db::schema::org_domains;
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl OrgDomain {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.org_domain.save");
+        replace_into!(org_domains::table, org_domains::uuid, self, &**conn).map_res("Error saving organization domain")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.org_domain.delete");
+        diesel::delete(org_domains::table.filter(org_domains::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting organization domain")
+    }
+
+    pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.org_domain.delete_all_by_organization");
+        diesel::delete(org_domains::table.filter(org_domains::org_uuid.eq(org_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting organization domains")
+    }
+
+    pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.org_domain.find_by_uuid_and_org");
+        org_domains::table
+            .filter(org_domains::uuid.eq(uuid))
+            .filter(org_domains::org_uuid.eq(org_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.org_domain.find_by_org");
+        org_domains::table
+            .filter(org_domains::org_uuid.eq(org_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading organization domains")
+    }
+
+    pub fn find_by_org_and_domain(org_uuid: &str, domain_name: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.org_domain.find_by_org_and_domain");
+        org_domains::table
+            .filter(org_domains::org_uuid.eq(org_uuid))
+            .filter(org_domains::domain_name.eq(domain_name))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_verified_by_domain(domain_name: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.org_domain.find_verified_by_domain");
+        org_domains::table
+            .filter(org_domains::domain_name.eq(domain_name.to_lowercase()))
+            .filter(org_domains::verified_at.is_not_null())
+            .load::<Self>(&**conn)
+            .expect("Error loading organization domains")
+    }
+}
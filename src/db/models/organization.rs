@@ -1,18 +1,23 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use std::cmp::Ordering;
 
 use super::{CollectionUser, User};
 
-#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "organizations"]
 #[primary_key(uuid)]
 pub struct Organization {
     pub uuid: String,
     pub name: String,
     pub billing_email: String,
+    pub allowed_domains: String,
+    pub use_events: bool,
 }
 
-#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "users_organizations"]
 #[primary_key(uuid)]
 pub struct UserOrganization {
@@ -27,6 +32,8 @@ pub struct UserOrganization {
 }
 
 pub enum UserOrgStatus {
+    /// Disabled by the directory sync. The member is kept, but without access until it's enabled again
+    Revoked = -1,
     Invited = 0,
     Accepted = 1,
     Confirmed = 2,
@@ -125,6 +132,26 @@ impl PartialOrd<UserOrgType> for i32 {
     }
 }
 
+/// The clients send the type as a number, but the names are accepted too
+impl<'de> Deserialize<'de> for UserOrgType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let type_ = match value {
+            Value::Number(ref n) => n.to_string(),
+            Value::String(ref s) => s.clone(),
+            _ => String::new(),
+        };
+
+        Self::from_str(&type_).ok_or_else(|| de::Error::custom(format!("Invalid member type: {}", value)))
+    }
+}
+
+impl Serialize for UserOrgType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(*self as i32)
+    }
+}
+
 impl UserOrgType {
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
@@ -155,7 +182,38 @@ impl Organization {
 
             name,
             billing_email,
+            allowed_domains: "[]".to_string(),
+            use_events: false,
+        }
+    }
+
+    pub fn get_allowed_domains(&self) -> Vec<String> {
+        serde_json::from_str(&self.allowed_domains).unwrap_or_default()
+    }
+
+    pub fn set_allowed_domains(&mut self, domains: &[String]) {
+        let domains: Vec<String> = domains
+            .iter()
+            .map(|d| d.trim().trim_start_matches('@').to_lowercase())
+            .filter(|d| !d.is_empty())
+            .collect();
+
+        self.allowed_domains = serde_json::to_string(&domains).unwrap_or_else(|_| "[]".to_string());
+    }
+
+    /// Checks the email against the allowed domains, an empty list allows every domain
+    pub fn is_email_domain_allowed(&self, email: &str) -> bool {
+        let domains = self.get_allowed_domains();
+        if domains.is_empty() {
+            return true;
         }
+
+        let domain = match email.rfind('@') {
+            Some(pos) => &email[pos + 1..],
+            None => return false,
+        };
+
+        domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
     }
 
     pub fn to_json(&self) -> Value {
@@ -167,7 +225,7 @@ impl Organization {
             "MaxStorageGb": 10, // The value doesn't matter, we don't check server-side
             "Use2fa": true,
             "UseDirectory": false,
-            "UseEvents": false,
+            "UseEvents": self.use_events,
             "UseGroups": false,
             "UseTotp": true,
 
@@ -179,6 +237,7 @@ impl Organization {
             "BusinessTaxNumber": null,
 
             "BillingEmail": self.billing_email,
+            "AllowedDomains": self.get_allowed_domains(),
             "Plan": "TeamsAnnually",
             "PlanType": 5, // TeamsAnnually plan
             "UsersGetPremium": true,
@@ -204,34 +263,36 @@ impl UserOrganization {
 }
 
 use crate::db::schema::{ciphers_collections, organizations, users_collections, users_organizations};
-use crate::db::DbConn;
+use crate::db::{like_pattern, lower, DbConn, LIKE_ESCAPE};
 use diesel;
 use diesel::prelude::*;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl Organization {
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.organization.save");
         UserOrganization::find_by_org(&self.uuid, conn)
             .iter()
             .for_each(|user_org| {
                 User::update_uuid_revision(&user_org.user_uuid, conn);
             });
 
-        diesel::replace_into(organizations::table)
-            .values(&*self)
-            .execute(&**conn)
-            .map_res("Error saving organization")
+        replace_into!(organizations::table, organizations::uuid, &*self, &**conn).map_res("Error saving organization")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
-        use super::{Cipher, Collection};
+        use super::{Cipher, Collection, OrgDomain, ProviderOrganization};
 
+        let _span = telemetry::span("db.organization.delete");
         Cipher::delete_all_by_organization(&self.uuid, &conn)?;
         Collection::delete_all_by_organization(&self.uuid, &conn)?;
         UserOrganization::delete_all_by_organization(&self.uuid, &conn)?;
+        OrgDomain::delete_all_by_organization(&self.uuid, &conn)?;
+        ProviderOrganization::delete_by_organization(&self.uuid, &conn)?;
 
         diesel::delete(organizations::table.filter(organizations::uuid.eq(self.uuid)))
             .execute(&**conn)
@@ -239,15 +300,36 @@ impl Organization {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.organization.find_by_uuid");
         organizations::table
             .filter(organizations::uuid.eq(uuid))
             .first::<Self>(&**conn)
             .ok()
     }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.get_all");
+        organizations::table
+            .load::<Self>(&**conn)
+            .expect("Error loading organizations")
+    }
+
+    /// A page of the organizations with the text in their name, ignoring the case
+    pub fn search(text: &str, offset: i64, limit: i64, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.search");
+        organizations::table
+            .filter(lower(organizations::name).like(like_pattern(text)).escape(LIKE_ESCAPE))
+            .order(organizations::name)
+            .offset(offset)
+            .limit(limit)
+            .load::<Self>(&**conn)
+            .expect("Error searching organizations")
+    }
 }
 
 impl UserOrganization {
     pub fn to_json(&self, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.organization.to_json");
         let org = Organization::find_by_uuid(&self.org_uuid, conn).unwrap();
 
         json!({
@@ -259,7 +341,7 @@ impl UserOrganization {
 
             "Use2fa": true,
             "UseDirectory": false,
-            "UseEvents": false,
+            "UseEvents": org.use_events,
             "UseGroups": false,
             "UseTotp": true,
 
@@ -275,10 +357,11 @@ impl UserOrganization {
         })
     }
 
-    pub fn to_json_user_details(&self, conn: &DbConn) -> Value {
+    pub fn to_json_user_details(&self, include_twofactor: bool, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.organization.to_json_user_details");
         let user = User::find_by_uuid(&self.user_uuid, conn).unwrap();
 
-        json!({
+        let mut json = json!({
             "Id": self.uuid,
             "UserId": self.user_uuid,
             "Name": user.name,
@@ -289,7 +372,22 @@ impl UserOrganization {
             "AccessAll": self.access_all,
 
             "Object": "organizationUserUserDetails",
-        })
+        });
+
+        if include_twofactor {
+            json["TwoFactorEnabled"] = json!(self.has_twofactor(conn));
+        }
+
+        json
+    }
+
+    /// Checks if the user has any two factor method enabled
+    pub fn has_twofactor(&self, conn: &DbConn) -> bool {
+        use super::TwoFactor;
+
+        let _span = telemetry::span("db.organization.has_twofactor");
+        let twofactors = TwoFactor::find_by_user(&self.user_uuid, conn);
+        twofactors.iter().any(|tf| tf.enabled)
     }
 
     pub fn to_json_collection_user_details(&self, read_only: bool) -> Value {
@@ -300,6 +398,7 @@ impl UserOrganization {
     }
 
     pub fn to_json_details(&self, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.organization.to_json_details");
         let coll_uuids = if self.access_all {
             vec![] // If we have complete access, no need to fill the array
         } else {
@@ -324,15 +423,15 @@ impl UserOrganization {
     }
 
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.organization.save");
         User::update_uuid_revision(&self.user_uuid, conn);
 
-        diesel::replace_into(users_organizations::table)
-            .values(&*self)
-            .execute(&**conn)
+        replace_into!(users_organizations::table, users_organizations::uuid, &*self, &**conn)
             .map_res("Error adding user to organization")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.organization.delete");
         User::update_uuid_revision(&self.user_uuid, conn);
 
         CollectionUser::delete_all_by_user(&self.user_uuid, &conn)?;
@@ -343,6 +442,7 @@ impl UserOrganization {
     }
 
     pub fn delete_all_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.organization.delete_all_by_organization");
         for user_org in Self::find_by_org(&org_uuid, &conn) {
             user_org.delete(&conn)?;
         }
@@ -350,6 +450,7 @@ impl UserOrganization {
     }
 
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.organization.delete_all_by_user");
         for user_org in Self::find_any_state_by_user(&user_uuid, &conn) {
             user_org.delete(&conn)?;
         }
@@ -361,6 +462,7 @@ impl UserOrganization {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.organization.find_by_uuid");
         users_organizations::table
             .filter(users_organizations::uuid.eq(uuid))
             .first::<Self>(&**conn)
@@ -368,6 +470,7 @@ impl UserOrganization {
     }
 
     pub fn find_by_uuid_and_org(uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.organization.find_by_uuid_and_org");
         users_organizations::table
             .filter(users_organizations::uuid.eq(uuid))
             .filter(users_organizations::org_uuid.eq(org_uuid))
@@ -376,6 +479,7 @@ impl UserOrganization {
     }
 
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_by_user");
         users_organizations::table
             .filter(users_organizations::user_uuid.eq(user_uuid))
             .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
@@ -384,6 +488,7 @@ impl UserOrganization {
     }
 
     pub fn find_invited_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_invited_by_user");
         users_organizations::table
             .filter(users_organizations::user_uuid.eq(user_uuid))
             .filter(users_organizations::status.eq(UserOrgStatus::Invited as i32))
@@ -392,6 +497,7 @@ impl UserOrganization {
     }
 
     pub fn find_any_state_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_any_state_by_user");
         users_organizations::table
             .filter(users_organizations::user_uuid.eq(user_uuid))
             .load::<Self>(&**conn)
@@ -399,13 +505,25 @@ impl UserOrganization {
     }
 
     pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_by_org");
         users_organizations::table
             .filter(users_organizations::org_uuid.eq(org_uuid))
             .load::<Self>(&**conn)
             .expect("Error loading user organizations")
     }
 
+    pub fn count_by_org_and_status(org_uuid: &str, status: i32, conn: &DbConn) -> i64 {
+        let _span = telemetry::span("db.organization.count_by_org_and_status");
+        users_organizations::table
+            .filter(users_organizations::org_uuid.eq(org_uuid))
+            .filter(users_organizations::status.eq(status))
+            .count()
+            .first::<i64>(&**conn)
+            .unwrap_or(0)
+    }
+
     pub fn find_by_org_and_type(org_uuid: &str, type_: i32, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_by_org_and_type");
         users_organizations::table
             .filter(users_organizations::org_uuid.eq(org_uuid))
             .filter(users_organizations::type_.eq(type_))
@@ -414,6 +532,7 @@ impl UserOrganization {
     }
 
     pub fn find_by_user_and_org(user_uuid: &str, org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.organization.find_by_user_and_org");
         users_organizations::table
             .filter(users_organizations::user_uuid.eq(user_uuid))
             .filter(users_organizations::org_uuid.eq(org_uuid))
@@ -422,6 +541,7 @@ impl UserOrganization {
     }
 
     pub fn find_by_cipher_and_org(cipher_uuid: &str, org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_by_cipher_and_org");
         users_organizations::table
         .filter(users_organizations::org_uuid.eq(org_uuid))
         .left_join(users_collections::table.on(
@@ -432,16 +552,21 @@ impl UserOrganization {
                 ciphers_collections::cipher_uuid.eq(&cipher_uuid)
             )
         ))
+        .filter(users_organizations::status.eq(UserOrgStatus::Confirmed as i32))
         .filter(
             users_organizations::access_all.eq(true).or( // AccessAll..
-                ciphers_collections::cipher_uuid.eq(&cipher_uuid) // ..or access to collection with cipher
+                users_organizations::type_.le(UserOrgType::Admin as i32).or( // ..or admin or owner..
+                    ciphers_collections::cipher_uuid.eq(&cipher_uuid) // ..or access to collection with cipher
+                )
             )
         )
         .select(users_organizations::all_columns)
+        .distinct()
         .load::<Self>(&**conn).expect("Error loading user organizations")
     }
 
     pub fn find_by_collection_and_org(collection_uuid: &str, org_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.organization.find_by_collection_and_org");
         users_organizations::table
         .filter(users_organizations::org_uuid.eq(org_uuid))
         .left_join(users_collections::table.on(
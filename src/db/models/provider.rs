@@ -0,0 +1,420 @@
+use chrono::{NaiveDateTime, Utc};
+use serde_json::Value;
+
+use super::{Organization, User};
+
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "providers"]
+#[primary_key(uuid)]
+pub struct Provider {
+    pub uuid: String,
+    pub name: String,
+    pub business_name: Option<String>,
+    pub billing_email: String,
+    pub status: i32,
+    pub enabled: bool,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "provider_users"]
+#[belongs_to(Provider, foreign_key = "provider_uuid")]
+#[belongs_to(User, foreign_key = "user_uuid")]
+#[primary_key(uuid)]
+pub struct ProviderUser {
+    pub uuid: String,
+    pub provider_uuid: String,
+    pub user_uuid: String,
+
+    pub key: String,
+    pub status: i32,
+    pub type_: i32,
+}
+
+/// A client organization managed by a provider, the key is the organization key encrypted with the provider key
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
+#[table_name = "provider_organizations"]
+#[belongs_to(Provider, foreign_key = "provider_uuid")]
+#[belongs_to(Organization, foreign_key = "org_uuid")]
+#[primary_key(uuid)]
+pub struct ProviderOrganization {
+    pub uuid: String,
+    pub provider_uuid: String,
+    pub org_uuid: String,
+    pub key: String,
+}
+
+pub enum ProviderStatus {
+    Pending = 0,
+    Created = 1,
+}
+
+pub enum ProviderUserStatus {
+    Invited = 0,
+    Accepted = 1,
+    Confirmed = 2,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum ProviderUserType {
+    ProviderAdmin = 0,
+    ServiceUser = 1,
+}
+
+impl ProviderUserType {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "0" | "ProviderAdmin" => Some(ProviderUserType::ProviderAdmin),
+            "1" | "ServiceUser" => Some(ProviderUserType::ServiceUser),
+            _ => None,
+        }
+    }
+
+    pub fn from_i32(i: i32) -> Option<Self> {
+        match i {
+            0 => Some(ProviderUserType::ProviderAdmin),
+            1 => Some(ProviderUserType::ServiceUser),
+            _ => None,
+        }
+    }
+}
+
+/// Local methods
+impl Provider {
+    pub fn new(name: String, billing_email: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            name,
+            business_name: None,
+            billing_email,
+            status: ProviderStatus::Pending as i32,
+            enabled: true,
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        use crate::util::format_date;
+
+        json!({
+            "Id": self.uuid,
+            "Name": self.name,
+            "BusinessName": self.business_name,
+            "BillingEmail": self.billing_email,
+            "Status": self.status,
+            "Enabled": self.enabled,
+            "CreationDate": format_date(&self.created_at),
+            "Object": "provider",
+        })
+    }
+}
+
+impl ProviderUser {
+    pub fn new(provider_uuid: String, user_uuid: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            provider_uuid,
+            user_uuid,
+
+            key: String::new(),
+            status: ProviderUserStatus::Invited as i32,
+            type_: ProviderUserType::ServiceUser as i32,
+        }
+    }
+
+    pub fn is_confirmed(&self) -> bool {
+        self.status == ProviderUserStatus::Confirmed as i32
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.type_ == ProviderUserType::ProviderAdmin as i32
+    }
+}
+
+impl ProviderOrganization {
+    pub fn new(provider_uuid: String, org_uuid: String, key: String) -> Self {
+        Self {
+            uuid: crate::util::get_uuid(),
+            provider_uuid,
+            org_uuid,
+            key,
+        }
+    }
+}
+
+use crate::db::schema::{provider_organizations, provider_users, providers};
+use crate::db::DbConn;
+use diesel;
+use diesel::prelude::*;
+
+use crate::api::EmptyResult;
+use crate::error::MapResult;
+use crate::telemetry;
+
+/// Database methods
+impl Provider {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.save");
+        for provider_user in ProviderUser::find_by_provider(&self.uuid, conn) {
+            User::update_uuid_revision(&provider_user.user_uuid, conn);
+        }
+
+        replace_into!(providers::table, providers::uuid, self, &**conn).map_res("Error saving provider")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete");
+        ProviderOrganization::delete_all_by_provider(&self.uuid, conn)?;
+        ProviderUser::delete_all_by_provider(&self.uuid, conn)?;
+
+        diesel::delete(providers::table.filter(providers::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting provider")
+    }
+
+    pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.provider.find_by_uuid");
+        providers::table
+            .filter(providers::uuid.eq(uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.get_all");
+        providers::table.load::<Self>(&**conn).expect("Error loading providers")
+    }
+}
+
+impl ProviderUser {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.save");
+        User::update_uuid_revision(&self.user_uuid, conn);
+
+        replace_into!(provider_users::table, provider_users::uuid, self, &**conn).map_res("Error saving provider user")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete");
+        User::update_uuid_revision(&self.user_uuid, conn);
+
+        diesel::delete(provider_users::table.filter(provider_users::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting provider user")
+    }
+
+    pub fn delete_all_by_provider(provider_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete_all_by_provider");
+        for provider_user in Self::find_by_provider(provider_uuid, conn) {
+            provider_user.delete(conn)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete_all_by_user");
+        diesel::delete(provider_users::table.filter(provider_users::user_uuid.eq(user_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting provider users")
+    }
+
+    pub fn to_json(&self, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.provider.to_json");
+        let user = User::find_by_uuid(&self.user_uuid, conn).unwrap();
+
+        json!({
+            "Id": self.uuid,
+            "UserId": self.user_uuid,
+            "Name": user.name,
+            "Email": user.email,
+            "Status": self.status,
+            "Type": self.type_,
+            "Object": "providerUserUserDetails",
+        })
+    }
+
+    pub fn to_json_profile(&self, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.provider.to_json_profile");
+        let provider = Provider::find_by_uuid(&self.provider_uuid, conn).unwrap();
+
+        json!({
+            "Id": self.provider_uuid,
+            "Name": provider.name,
+            "Key": self.key,
+            "Status": self.status,
+            "Type": self.type_,
+            "Enabled": provider.enabled,
+            "UserId": self.user_uuid,
+            "UseEvents": false,
+            "Object": "profileProvider",
+        })
+    }
+
+    pub fn find_by_uuid_and_provider(uuid: &str, provider_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.provider.find_by_uuid_and_provider");
+        provider_users::table
+            .filter(provider_users::uuid.eq(uuid))
+            .filter(provider_users::provider_uuid.eq(provider_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_user_and_provider(user_uuid: &str, provider_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.provider.find_by_user_and_provider");
+        provider_users::table
+            .filter(provider_users::user_uuid.eq(user_uuid))
+            .filter(provider_users::provider_uuid.eq(provider_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_provider(provider_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.find_by_provider");
+        provider_users::table
+            .filter(provider_users::provider_uuid.eq(provider_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading provider users")
+    }
+
+    pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.find_by_user");
+        provider_users::table
+            .filter(provider_users::user_uuid.eq(user_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading provider users")
+    }
+
+    pub fn find_confirmed_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.find_confirmed_by_user");
+        provider_users::table
+            .filter(provider_users::user_uuid.eq(user_uuid))
+            .filter(provider_users::status.eq(ProviderUserStatus::Confirmed as i32))
+            .load::<Self>(&**conn)
+            .expect("Error loading provider users")
+    }
+
+    pub fn find_admins_by_provider(provider_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.find_admins_by_provider");
+        provider_users::table
+            .filter(provider_users::provider_uuid.eq(provider_uuid))
+            .filter(provider_users::type_.eq(ProviderUserType::ProviderAdmin as i32))
+            .load::<Self>(&**conn)
+            .expect("Error loading provider users")
+    }
+}
+
+impl ProviderOrganization {
+    pub fn save(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.save");
+        for provider_user in ProviderUser::find_by_provider(&self.provider_uuid, conn) {
+            User::update_uuid_revision(&provider_user.user_uuid, conn);
+        }
+
+        replace_into!(
+            provider_organizations::table,
+            provider_organizations::uuid,
+            self,
+            &**conn
+        )
+        .map_res("Error saving provider organization")
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete");
+        for provider_user in ProviderUser::find_by_provider(&self.provider_uuid, conn) {
+            User::update_uuid_revision(&provider_user.user_uuid, conn);
+        }
+
+        diesel::delete(provider_organizations::table.filter(provider_organizations::uuid.eq(self.uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting provider organization")
+    }
+
+    pub fn delete_all_by_provider(provider_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete_all_by_provider");
+        diesel::delete(provider_organizations::table.filter(provider_organizations::provider_uuid.eq(provider_uuid)))
+            .execute(&**conn)
+            .map_res("Error deleting provider organizations")
+    }
+
+    pub fn delete_by_organization(org_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.provider.delete_by_organization");
+        match Self::find_by_org(org_uuid, conn) {
+            Some(provider_org) => provider_org.delete(conn),
+            None => Ok(()),
+        }
+    }
+
+    pub fn to_json(&self, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.provider.to_json");
+        let org = Organization::find_by_uuid(&self.org_uuid, conn).unwrap();
+
+        json!({
+            "Id": self.uuid,
+            "ProviderId": self.provider_uuid,
+            "OrganizationId": self.org_uuid,
+            "OrganizationName": org.name,
+            "Key": self.key,
+            "Object": "providerOrganization",
+        })
+    }
+
+    /// The organization as seen by the provider users in their profile
+    pub fn to_json_profile(&self, provider: &Provider, conn: &DbConn) -> Value {
+        let _span = telemetry::span("db.provider.to_json_profile");
+        let org = Organization::find_by_uuid(&self.org_uuid, conn).unwrap();
+
+        json!({
+            "Id": self.org_uuid,
+            "Name": org.name,
+            "Seats": 10,
+            "MaxCollections": 10,
+            "UsersGetPremium": true,
+
+            "Use2fa": true,
+            "UseDirectory": false,
+            "UseEvents": false,
+            "UseGroups": false,
+            "UseTotp": true,
+
+            "MaxStorageGb": 10, // The value doesn't matter, we don't check server-side
+
+            "Key": self.key,
+            "Status": ProviderUserStatus::Confirmed as i32,
+            "Type": super::UserOrgType::Admin as i32,
+            "Enabled": provider.enabled,
+            "ProviderId": provider.uuid,
+            "ProviderName": provider.name,
+
+            "Object": "profileProviderOrganization",
+        })
+    }
+
+    pub fn find_by_uuid_and_provider(uuid: &str, provider_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.provider.find_by_uuid_and_provider");
+        provider_organizations::table
+            .filter(provider_organizations::uuid.eq(uuid))
+            .filter(provider_organizations::provider_uuid.eq(provider_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_org(org_uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.provider.find_by_org");
+        provider_organizations::table
+            .filter(provider_organizations::org_uuid.eq(org_uuid))
+            .first::<Self>(&**conn)
+            .ok()
+    }
+
+    pub fn find_by_provider(provider_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.provider.find_by_provider");
+        provider_organizations::table
+            .filter(provider_organizations::provider_uuid.eq(provider_uuid))
+            .load::<Self>(&**conn)
+            .expect("Error loading provider organizations")
+    }
+}
@@ -1,8 +1,10 @@
+use chrono::Utc;
 use serde_json::Value;
 
 use super::User;
 
-#[derive(Debug, Identifiable, Queryable, Insertable, Associations)]
+#[derive(Debug, Identifiable, Queryable, Insertable, AsChangeset, Associations)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "twofactor"]
 #[belongs_to(User, foreign_key = "user_uuid")]
 #[primary_key(uuid)]
@@ -12,6 +14,7 @@ pub struct TwoFactor {
     pub type_: i32,
     pub enabled: bool,
     pub data: String,
+    pub last_used: i32,
 }
 
 #[allow(dead_code)]
@@ -28,6 +31,21 @@ pub enum TwoFactorType {
     // These are implementation details
     U2fRegisterChallenge = 1000,
     U2fLoginChallenge = 1001,
+    EmailVerificationChallenge = 1002,
+    EmailChangeApproval = 1003,
+}
+
+/// Duration of each TOTP code, in seconds
+const TOTP_STEP: i64 = 30;
+
+/// Number of steps outside the accepted window checked for clock drift
+const TOTP_DRIFT_STEPS: i64 = 2;
+
+fn generate_totp(secret: &[u8], step: i64) -> u64 {
+    use oath::{totp_raw_custom_time, HashType};
+
+    let time = (step * TOTP_STEP) as u64;
+    totp_raw_custom_time(secret, 6, 0, TOTP_STEP as u64, time, &HashType::SHA1)
 }
 
 /// Local methods
@@ -39,22 +57,54 @@ impl TwoFactor {
             type_: type_ as i32,
             enabled: true,
             data,
+            last_used: 0,
         }
     }
 
-    pub fn check_totp_code(&self, totp_code: u64) -> bool {
-        let totp_secret = self.data.as_bytes();
+    /// Checks the code against the time steps inside the configured window. Each step can only be used once,
+    /// so on success the step is stored in `last_used`, and the caller needs to save the two factor.
+    pub fn check_totp_code(&mut self, totp_code: u64) -> bool {
+        let secret = match self.get_totp_secret() {
+            Some(secret) => secret,
+            None => return false,
+        };
 
-        use data_encoding::BASE32;
-        use oath::{totp_raw_now, HashType};
+        let window = crate::CONFIG.authenticator_window();
+        let current_step = Utc::now().timestamp() / TOTP_STEP;
 
-        let decoded_secret = match BASE32.decode(totp_secret) {
-            Ok(s) => s,
-            Err(_) => return false,
-        };
+        for step in (current_step - window)..=(current_step + window) {
+            if generate_totp(&secret, step) == totp_code {
+                if step <= i64::from(self.last_used) {
+                    warn!("TOTP code reused for user {}", self.user_uuid);
+                    return false;
+                }
+
+                self.last_used = step as i32;
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Looks for a rejected code in the steps just outside the accepted window, which usually means that
+    /// the clock of the server or the device is wrong. Returns the offset of the matching step in seconds.
+    pub fn find_totp_drift(&self, totp_code: u64) -> Option<i64> {
+        let secret = self.get_totp_secret()?;
+
+        let window = crate::CONFIG.authenticator_window();
+        let current_step = Utc::now().timestamp() / TOTP_STEP;
 
-        let generated = totp_raw_now(&decoded_secret, 6, 0, 30, &HashType::SHA1);
-        generated == totp_code
+        (-(window + TOTP_DRIFT_STEPS)..=(window + TOTP_DRIFT_STEPS))
+            .filter(|offset| offset.abs() > window)
+            .find(|offset| generate_totp(&secret, current_step + offset) == totp_code)
+            .map(|offset| offset * TOTP_STEP)
+    }
+
+    fn get_totp_secret(&self) -> Option<Vec<u8>> {
+        use data_encoding::BASE32;
+
+        BASE32.decode(self.data.as_bytes()).ok()
     }
 
     pub fn to_json(&self) -> Value {
@@ -74,6 +124,7 @@ impl TwoFactor {
     }
 }
 
+use crate::db::repo::Transactional;
 use crate::db::schema::twofactor;
 use crate::db::DbConn;
 use diesel;
@@ -81,23 +132,42 @@ use diesel::prelude::*;
 
 use crate::api::EmptyResult;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl TwoFactor {
+    /// Saves the changes of the two factor, or adds it. Use `replace` for a new one that takes the place of another
     pub fn save(&self, conn: &DbConn) -> EmptyResult {
-        diesel::replace_into(twofactor::table)
-            .values(self)
+        let _span = telemetry::span("db.two_factor.save");
+        replace_into!(twofactor::table, twofactor::uuid, self, &**conn).map_res("Error saving twofactor")
+    }
+
+    /// Saves the two factor as the only one of its type for the user, so a new one replaces the old one
+    pub fn replace(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.two_factor.replace");
+        conn.in_transaction(|| {
+            diesel::delete(
+                twofactor::table
+                    .filter(twofactor::user_uuid.eq(&self.user_uuid))
+                    .filter(twofactor::type_.eq(self.type_))
+                    .filter(twofactor::uuid.ne(&self.uuid)),
+            )
             .execute(&**conn)
-            .map_res("Error saving twofactor")
+            .map_res("Error replacing twofactor")?;
+
+            self.save(conn)
+        })
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.two_factor.delete");
         diesel::delete(twofactor::table.filter(twofactor::uuid.eq(self.uuid)))
             .execute(&**conn)
             .map_res("Error deleting twofactor")
     }
 
     pub fn find_by_user(user_uuid: &str, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.two_factor.find_by_user");
         twofactor::table
             .filter(twofactor::user_uuid.eq(user_uuid))
             .filter(twofactor::type_.lt(1000)) // Filter implementation types
@@ -106,6 +176,7 @@ impl TwoFactor {
     }
 
     pub fn find_by_user_and_type(user_uuid: &str, type_: i32, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.two_factor.find_by_user_and_type");
         twofactor::table
             .filter(twofactor::user_uuid.eq(user_uuid))
             .filter(twofactor::type_.eq(type_))
@@ -114,6 +185,7 @@ impl TwoFactor {
     }
 
     pub fn delete_all_by_user(user_uuid: &str, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.two_factor.delete_all_by_user");
         diesel::delete(twofactor::table.filter(twofactor::user_uuid.eq(user_uuid)))
             .execute(&**conn)
             .map_res("Error deleting twofactors")
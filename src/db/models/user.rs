@@ -4,7 +4,8 @@ use serde_json::Value;
 use crate::crypto;
 use crate::CONFIG;
 
-#[derive(Debug, Identifiable, Queryable, Insertable)]
+#[derive(Debug, Clone, Identifiable, Queryable, Insertable, AsChangeset)]
+#[changeset_options(treat_none_as_null = "true")]
 #[table_name = "users"]
 #[primary_key(uuid)]
 pub struct User {
@@ -35,11 +36,28 @@ pub struct User {
 
     pub client_kdf_type: i32,
     pub client_kdf_iter: i32,
+
+    pub force_password_reset: bool,
+
+    pub login_failures: i32,
+    pub locked_until: Option<NaiveDateTime>,
+
+    pub deleted_at: Option<NaiveDateTime>,
+
+    pub locale: Option<String>,
+
+    pub disabled: bool,
+
+    pub api_key: Option<String>,
+
+    // When the user proved to own the email address, from an emailed link or token
+    pub verified_at: Option<NaiveDateTime>,
 }
 
 /// Local methods
 impl User {
     pub const CLIENT_KDF_TYPE_DEFAULT: i32 = 0; // PBKDF2: 0
+    /// The clients that register without sending the iterations hash the password with their built-in value
     pub const CLIENT_KDF_ITER_DEFAULT: i32 = 5_000;
 
     pub fn new(mail: String) -> Self {
@@ -72,6 +90,21 @@ impl User {
 
             client_kdf_type: Self::CLIENT_KDF_TYPE_DEFAULT,
             client_kdf_iter: Self::CLIENT_KDF_ITER_DEFAULT,
+
+            force_password_reset: false,
+
+            login_failures: 0,
+            locked_until: None,
+
+            deleted_at: None,
+
+            locale: None,
+
+            disabled: false,
+
+            api_key: None,
+
+            verified_at: None,
         }
     }
 
@@ -92,65 +125,126 @@ impl User {
         }
     }
 
+    pub fn check_valid_api_key(&self, key: &str) -> bool {
+        match self.api_key {
+            Some(ref api_key) => crypto::ct_eq(key, api_key),
+            None => false,
+        }
+    }
+
+    /// The personal API key, used by the CLI to log in with the client_credentials grant
+    pub fn rotate_api_key(&mut self) {
+        self.api_key = Some(crypto::get_random_alphanum(30));
+    }
+
     pub fn set_password(&mut self, password: &str) {
+        self.password_iterations = CONFIG.password_iterations();
         self.password_hash = crypto::hash_password(password.as_bytes(), &self.salt, self.password_iterations as u32);
+        self.force_password_reset = false;
+    }
+
+    /// The stored hash was generated with a different iteration count than the one currently configured
+    pub fn password_rehash_required(&self) -> bool {
+        self.password_iterations != CONFIG.password_iterations()
+    }
+
+    /// Without the mail service the addresses can't be verified, so they are reported as verified to the clients.
+    /// The domain verification of the organizations still needs a real one
+    pub fn is_email_verified(&self) -> bool {
+        self.verified_at.is_some() || !CONFIG.mail_enabled()
     }
 
     pub fn reset_security_stamp(&mut self) {
         self.security_stamp = crate::util::get_uuid();
     }
+
+    pub fn is_locked(&self) -> bool {
+        match self.locked_until {
+            Some(until) => until > Utc::now().naive_utc(),
+            None => false,
+        }
+    }
+
+    /// Deleted accounts are kept disabled during the retention period, and can be restored by the admin
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
 }
 
-use super::{Cipher, Device, Folder, TwoFactor, UserOrgType, UserOrganization};
+use super::{
+    AttachmentUpload, Cipher, ClientCertificate, Device, Favorite, Folder, ProviderUser, TwoFactor, UserOrgType,
+    UserOrganization,
+};
 use crate::db::schema::{invitations, users};
-use crate::db::DbConn;
+use crate::db::{like_pattern, lower, DbConn, LIKE_ESCAPE};
 use diesel;
 use diesel::prelude::*;
 
 use crate::api::EmptyResult;
+use crate::cache::SYNC_CACHE;
 use crate::error::MapResult;
+use crate::telemetry;
 
 /// Database methods
 impl User {
     pub fn to_json(&self, conn: &DbConn) -> Value {
-        use super::{TwoFactor, UserOrganization};
+        use super::{Provider, ProviderOrganization, ProviderUser, TwoFactor, UserOrganization};
 
+        let _span = telemetry::span("db.user.to_json");
         let orgs = UserOrganization::find_by_user(&self.uuid, conn);
         let orgs_json: Vec<Value> = orgs.iter().map(|c| c.to_json(&conn)).collect();
         let twofactor_enabled = !TwoFactor::find_by_user(&self.uuid, conn).is_empty();
 
+        let provider_users = ProviderUser::find_by_user(&self.uuid, conn);
+        let providers_json: Vec<Value> = provider_users.iter().map(|p| p.to_json_profile(&conn)).collect();
+
+        let mut provider_orgs_json = Vec::new();
+        for provider_user in provider_users.iter().filter(|p| p.is_confirmed()) {
+            if let Some(provider) = Provider::find_by_uuid(&provider_user.provider_uuid, conn) {
+                for provider_org in ProviderOrganization::find_by_provider(&provider.uuid, conn) {
+                    provider_orgs_json.push(provider_org.to_json_profile(&provider, conn));
+                }
+            }
+        }
+
         json!({
             "_Enabled": !self.password_hash.is_empty(),
+            "_DeletedDate": self.deleted_at.as_ref().map(crate::util::format_date),
+            "_Disabled": self.disabled,
             "Id": self.uuid,
             "Name": self.name,
             "Email": self.email,
-            "EmailVerified": true,
+            "EmailVerified": self.is_email_verified(),
             "Premium": true,
             "MasterPasswordHint": self.password_hint,
-            "Culture": "en-US",
+            "Culture": self.locale.clone().unwrap_or_else(|| CONFIG.locale()),
             "TwoFactorEnabled": twofactor_enabled,
             "Key": self.key,
             "PrivateKey": self.private_key,
             "SecurityStamp": self.security_stamp,
+            "ForcePasswordReset": self.force_password_reset,
             "Organizations": orgs_json,
+            "Providers": providers_json,
+            "ProviderOrganizations": provider_orgs_json,
             "Object": "profile"
         })
     }
 
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.save");
         if self.email.trim().is_empty() {
             err!("User email can't be empty")
         }
 
         self.updated_at = Utc::now().naive_utc();
+        SYNC_CACHE.invalidate_user_after_writes(&self.uuid);
 
-        diesel::replace_into(users::table) // Insert or update
-            .values(&*self)
-            .execute(&**conn)
+        replace_into!(users::table, users::uuid, &*self, &**conn) // Insert or update
             .map_res("Error saving user")
     }
 
-    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+    fn check_not_last_owner(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.check_not_last_owner");
         for user_org in UserOrganization::find_by_user(&self.uuid, &*conn) {
             if user_org.type_ == UserOrgType::Owner {
                 let owner_type = UserOrgType::Owner as i32;
@@ -159,8 +253,35 @@ impl User {
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Disables the account and logs out all its sessions, the data is removed later by the scheduler
+    pub fn soft_delete(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.soft_delete");
+        self.check_not_last_owner(conn)?;
+
+        Device::delete_all_by_user(&self.uuid, &*conn)?;
+        self.reset_security_stamp();
+        self.deleted_at = Some(Utc::now().naive_utc());
+        self.save(conn)
+    }
+
+    pub fn restore(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.restore");
+        self.deleted_at = None;
+        self.save(conn)
+    }
+
+    pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.delete");
+        self.check_not_last_owner(conn)?;
 
         UserOrganization::delete_all_by_user(&self.uuid, &*conn)?;
+        AttachmentUpload::delete_all_by_user(&self.uuid, &*conn)?;
+        ProviderUser::delete_all_by_user(&self.uuid, &*conn)?;
+        ClientCertificate::delete_all_by_user(&self.uuid, &*conn)?;
+        Favorite::delete_all_by_user(&self.uuid, &*conn)?;
         Cipher::delete_all_by_user(&self.uuid, &*conn)?;
         Folder::delete_all_by_user(&self.uuid, &*conn)?;
         Device::delete_all_by_user(&self.uuid, &*conn)?;
@@ -172,19 +293,36 @@ impl User {
             .map_res("Error deleting user")
     }
 
+    /// Saves only the lockout state, without touching the revision date
+    pub fn save_lockout(&self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.save_lockout");
+        diesel::update(users::table.filter(users::uuid.eq(&self.uuid)))
+            .set((
+                users::login_failures.eq(self.login_failures),
+                users::locked_until.eq(self.locked_until),
+            ))
+            .execute(&**conn)
+            .map_res("Error saving user lockout")
+    }
+
     pub fn update_uuid_revision(uuid: &str, conn: &DbConn) {
+        let _span = telemetry::span("db.user.update_uuid_revision");
         if let Err(e) = Self::_update_revision(uuid, &Utc::now().naive_utc(), conn) {
             warn!("Failed to update revision for {}: {:#?}", uuid, e);
         }
     }
 
     pub fn update_revision(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.update_revision");
         self.updated_at = Utc::now().naive_utc();
 
         Self::_update_revision(&self.uuid, &self.updated_at, conn)
     }
 
     fn _update_revision(uuid: &str, date: &NaiveDateTime, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user._update_revision");
+        SYNC_CACHE.invalidate_user_after_writes(uuid);
+
         crate::util::retry(
             || {
                 diesel::update(users::table.filter(users::uuid.eq(uuid)))
@@ -197,6 +335,7 @@ impl User {
     }
 
     pub fn find_by_mail(mail: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.user.find_by_mail");
         let lower_mail = mail.to_lowercase();
         users::table
             .filter(users::email.eq(lower_mail))
@@ -205,12 +344,40 @@ impl User {
     }
 
     pub fn find_by_uuid(uuid: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.user.find_by_uuid");
         users::table.filter(users::uuid.eq(uuid)).first::<Self>(&**conn).ok()
     }
 
     pub fn get_all(conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.user.get_all");
         users::table.load::<Self>(&**conn).expect("Error loading users")
     }
+
+    /// A page of the users with the text in their email or name, ignoring the case
+    pub fn search(text: &str, offset: i64, limit: i64, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.user.search");
+        let pattern = like_pattern(text);
+        users::table
+            .filter(
+                lower(users::email)
+                    .like(&pattern)
+                    .escape(LIKE_ESCAPE)
+                    .or(lower(users::name).like(&pattern).escape(LIKE_ESCAPE)),
+            )
+            .order(users::email)
+            .offset(offset)
+            .limit(limit)
+            .load::<Self>(&**conn)
+            .expect("Error searching users")
+    }
+
+    pub fn find_deleted_before(date: &NaiveDateTime, conn: &DbConn) -> Vec<Self> {
+        let _span = telemetry::span("db.user.find_deleted_before");
+        users::table
+            .filter(users::deleted_at.lt(date))
+            .load::<Self>(&**conn)
+            .expect("Error loading users")
+    }
 }
 
 #[derive(Debug, Identifiable, Queryable, Insertable)]
@@ -226,23 +393,24 @@ impl Invitation {
     }
 
     pub fn save(&mut self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.save");
         if self.email.trim().is_empty() {
             err!("Invitation email can't be empty")
         }
 
-        diesel::replace_into(invitations::table)
-            .values(&*self)
-            .execute(&**conn)
+        replace_into!(invitations::table, invitations::email, &*self, &**conn, nothing)
             .map_res("Error saving invitation")
     }
 
     pub fn delete(self, conn: &DbConn) -> EmptyResult {
+        let _span = telemetry::span("db.user.delete");
         diesel::delete(invitations::table.filter(invitations::email.eq(self.email)))
             .execute(&**conn)
             .map_res("Error deleting invitation")
     }
 
     pub fn find_by_mail(mail: &str, conn: &DbConn) -> Option<Self> {
+        let _span = telemetry::span("db.user.find_by_mail");
         let lower_mail = mail.to_lowercase();
         invitations::table
             .filter(invitations::email.eq(lower_mail))
@@ -251,6 +419,7 @@ impl Invitation {
     }
 
     pub fn take(mail: &str, conn: &DbConn) -> bool {
+        let _span = telemetry::span("db.user.take");
         CONFIG.invitations_allowed()
             && match Self::find_by_mail(mail, &conn) {
                 Some(invitation) => invitation.delete(&conn).is_ok(),
@@ -0,0 +1,624 @@
+//
+// The persistence used by the service layer, behind traits so it doesn't depend on Diesel.
+// `DbConn` implements them with the models, other implementations can keep the data in memory
+//
+use diesel::Connection;
+
+use crate::api::{ApiResult, EmptyResult};
+use crate::db::models::{Cipher, Collection, CollectionCipher, Folder, User, UserOrgType, UserOrganization};
+use crate::db::DbConn;
+
+pub trait Transactional {
+    /// Runs the closure in a transaction, which is rolled back when it returns an error
+    fn in_transaction<T, F: FnOnce() -> ApiResult<T>>(&self, f: F) -> ApiResult<T>;
+}
+
+pub trait CipherRepo {
+    fn find_cipher(&self, uuid: &str) -> Option<Cipher>;
+    fn is_cipher_writable(&self, cipher: &Cipher, user_uuid: &str) -> bool;
+    /// Whether the user is a confirmed owner or admin of the organization of the cipher
+    fn is_cipher_org_admin(&self, cipher: &Cipher, user_uuid: &str) -> bool;
+    /// The uuids of the collections of the cipher that the user can see
+    fn find_cipher_collections(&self, cipher: &Cipher, user_uuid: &str) -> Vec<String>;
+    fn find_all_cipher_collections(&self, cipher: &Cipher) -> Vec<String>;
+    fn delete_cipher(&self, cipher: &Cipher) -> EmptyResult;
+}
+
+pub trait CollectionRepo {
+    fn find_collection(&self, uuid: &str) -> Option<Collection>;
+    fn find_collection_in_org(&self, uuid: &str, org_uuid: &str) -> Option<Collection>;
+    fn is_collection_writable(&self, collection: &Collection, user_uuid: &str) -> bool;
+    fn add_to_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult;
+    fn remove_from_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult;
+}
+
+pub trait FolderRepo: Transactional {
+    fn find_folder(&self, uuid: &str) -> Option<Folder>;
+    fn save_folder(&self, folder: &mut Folder) -> EmptyResult;
+    fn delete_folder(&self, folder: &Folder) -> EmptyResult;
+}
+
+pub trait MemberRepo {
+    fn find_member(&self, uuid: &str, org_uuid: &str) -> Option<UserOrganization>;
+    fn find_member_by_user(&self, user_uuid: &str, org_uuid: &str) -> Option<UserOrganization>;
+    fn count_members_of_type(&self, org_uuid: &str, type_: UserOrgType) -> usize;
+    fn delete_member(&self, member: UserOrganization) -> EmptyResult;
+}
+
+pub trait UserRepo {
+    fn find_user(&self, uuid: &str) -> Option<User>;
+    fn find_user_by_mail(&self, mail: &str) -> Option<User>;
+    fn save_user(&self, user: &mut User) -> EmptyResult;
+}
+
+impl Transactional for DbConn {
+    fn in_transaction<T, F: FnOnce() -> ApiResult<T>>(&self, f: F) -> ApiResult<T> {
+        self.transaction(f)
+    }
+}
+
+impl CipherRepo for DbConn {
+    fn find_cipher(&self, uuid: &str) -> Option<Cipher> {
+        Cipher::find_by_uuid(uuid, self)
+    }
+
+    fn is_cipher_writable(&self, cipher: &Cipher, user_uuid: &str) -> bool {
+        cipher.is_write_accessible_to_user(user_uuid, self)
+    }
+
+    fn is_cipher_org_admin(&self, cipher: &Cipher, user_uuid: &str) -> bool {
+        cipher.is_org_admin(user_uuid, self)
+    }
+
+    fn find_cipher_collections(&self, cipher: &Cipher, user_uuid: &str) -> Vec<String> {
+        cipher.get_collections(user_uuid, self)
+    }
+
+    fn find_all_cipher_collections(&self, cipher: &Cipher) -> Vec<String> {
+        cipher.get_all_collections(self)
+    }
+
+    fn delete_cipher(&self, cipher: &Cipher) -> EmptyResult {
+        cipher.delete(self)
+    }
+}
+
+impl CollectionRepo for DbConn {
+    fn find_collection(&self, uuid: &str) -> Option<Collection> {
+        Collection::find_by_uuid(uuid, self)
+    }
+
+    fn find_collection_in_org(&self, uuid: &str, org_uuid: &str) -> Option<Collection> {
+        Collection::find_by_uuid_and_org(uuid, org_uuid, self)
+    }
+
+    fn is_collection_writable(&self, collection: &Collection, user_uuid: &str) -> bool {
+        collection.is_writable_by_user(user_uuid, self)
+    }
+
+    fn add_to_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult {
+        CollectionCipher::save(cipher_uuid, collection_uuid, self)
+    }
+
+    fn remove_from_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult {
+        CollectionCipher::delete(cipher_uuid, collection_uuid, self)
+    }
+}
+
+impl FolderRepo for DbConn {
+    fn find_folder(&self, uuid: &str) -> Option<Folder> {
+        Folder::find_by_uuid(uuid, self)
+    }
+
+    fn save_folder(&self, folder: &mut Folder) -> EmptyResult {
+        folder.save(self)
+    }
+
+    fn delete_folder(&self, folder: &Folder) -> EmptyResult {
+        folder.delete(self)
+    }
+}
+
+impl MemberRepo for DbConn {
+    fn find_member(&self, uuid: &str, org_uuid: &str) -> Option<UserOrganization> {
+        UserOrganization::find_by_uuid_and_org(uuid, org_uuid, self)
+    }
+
+    fn find_member_by_user(&self, user_uuid: &str, org_uuid: &str) -> Option<UserOrganization> {
+        UserOrganization::find_by_user_and_org(user_uuid, org_uuid, self)
+    }
+
+    fn count_members_of_type(&self, org_uuid: &str, type_: UserOrgType) -> usize {
+        UserOrganization::find_by_org_and_type(org_uuid, type_ as i32, self).len()
+    }
+
+    fn delete_member(&self, member: UserOrganization) -> EmptyResult {
+        member.delete(self)
+    }
+}
+
+impl UserRepo for DbConn {
+    fn find_user(&self, uuid: &str) -> Option<User> {
+        User::find_by_uuid(uuid, self)
+    }
+
+    fn find_user_by_mail(&self, mail: &str) -> Option<User> {
+        User::find_by_mail(mail, self)
+    }
+
+    fn save_user(&self, user: &mut User) -> EmptyResult {
+        user.save(self)
+    }
+}
+
+/// Keeps the data in vectors, for the tests of the service layer. The permissions follow the same
+/// rules as the queries of the models, the tests below run the same checks on both to keep them in line
+#[cfg(test)]
+pub mod memory {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::db::models::UserOrgStatus;
+
+    #[derive(Clone, Default)]
+    pub struct MemoryData {
+        pub users: Vec<User>,
+        pub ciphers: Vec<Cipher>,
+        pub collections: Vec<Collection>,
+        /// The (cipher, collection) pairs
+        pub collection_ciphers: Vec<(String, String)>,
+        pub folders: Vec<Folder>,
+        pub members: Vec<UserOrganization>,
+        /// The (user, collection, read only) assignments of the members without full access
+        pub user_collections: Vec<(String, String, bool)>,
+    }
+
+    /// All the data is in one cell, so a transaction can restore all of it on an error
+    #[derive(Default)]
+    pub struct MemoryRepo {
+        pub data: RefCell<MemoryData>,
+    }
+
+    impl MemoryRepo {
+        pub fn add_cipher(&self, cipher: Cipher) {
+            self.data.borrow_mut().ciphers.push(cipher);
+        }
+
+        pub fn add_collection(&self, collection: Collection) {
+            self.data.borrow_mut().collections.push(collection);
+        }
+
+        pub fn add_member(&self, member: UserOrganization) {
+            self.data.borrow_mut().members.push(member);
+        }
+
+        pub fn assign_collection(&self, user_uuid: &str, collection_uuid: &str, read_only: bool) {
+            let assignment = (user_uuid.to_string(), collection_uuid.to_string(), read_only);
+            self.data.borrow_mut().user_collections.push(assignment);
+        }
+
+        pub fn cipher_collections(&self, cipher_uuid: &str) -> Vec<String> {
+            self.data
+                .borrow()
+                .collection_ciphers
+                .iter()
+                .filter(|(cipher, _)| cipher == cipher_uuid)
+                .map(|(_, collection)| collection.clone())
+                .collect()
+        }
+
+        fn confirmed_member(&self, user_uuid: &str, org_uuid: &str) -> Option<UserOrganization> {
+            self.find_member_by_user(user_uuid, org_uuid)
+                .filter(|m| m.status == UserOrgStatus::Confirmed as i32)
+        }
+
+        fn assignment(&self, user_uuid: &str, collection_uuid: &str) -> Option<bool> {
+            self.data
+                .borrow()
+                .user_collections
+                .iter()
+                .find(|(user, collection, _)| user == user_uuid && collection == collection_uuid)
+                .map(|(_, _, read_only)| *read_only)
+        }
+    }
+
+    impl Transactional for MemoryRepo {
+        fn in_transaction<T, F: FnOnce() -> ApiResult<T>>(&self, f: F) -> ApiResult<T> {
+            let snapshot = self.data.borrow().clone();
+
+            let result = f();
+            if result.is_err() {
+                *self.data.borrow_mut() = snapshot;
+            }
+            result
+        }
+    }
+
+    impl CipherRepo for MemoryRepo {
+        fn find_cipher(&self, uuid: &str) -> Option<Cipher> {
+            self.data.borrow().ciphers.iter().find(|c| c.uuid == uuid).cloned()
+        }
+
+        fn is_cipher_writable(&self, cipher: &Cipher, user_uuid: &str) -> bool {
+            if cipher.user_uuid.as_ref().map(String::as_str) == Some(user_uuid) {
+                return true;
+            }
+
+            let member = match cipher.organization_uuid {
+                Some(ref org_uuid) => self.confirmed_member(user_uuid, org_uuid),
+                None => None,
+            };
+
+            match member {
+                Some(member) => {
+                    member.has_full_access()
+                        || self
+                            .cipher_collections(&cipher.uuid)
+                            .iter()
+                            .any(|c| self.assignment(user_uuid, c) == Some(false))
+                }
+                None => false,
+            }
+        }
+
+        fn is_cipher_org_admin(&self, cipher: &Cipher, user_uuid: &str) -> bool {
+            let member = match cipher.organization_uuid {
+                Some(ref org_uuid) => self.confirmed_member(user_uuid, org_uuid),
+                None => None,
+            };
+
+            match member {
+                Some(member) => member.type_ >= UserOrgType::Admin,
+                None => false,
+            }
+        }
+
+        fn find_cipher_collections(&self, cipher: &Cipher, user_uuid: &str) -> Vec<String> {
+            self.cipher_collections(&cipher.uuid)
+                .into_iter()
+                .filter(|c| {
+                    let org_uuid = match self.find_collection(c) {
+                        Some(collection) => collection.org_uuid,
+                        None => return false,
+                    };
+
+                    match self.find_member_by_user(user_uuid, &org_uuid) {
+                        Some(member) => member.has_full_access() || self.assignment(user_uuid, c).is_some(),
+                        None => false,
+                    }
+                })
+                .collect()
+        }
+
+        fn find_all_cipher_collections(&self, cipher: &Cipher) -> Vec<String> {
+            self.cipher_collections(&cipher.uuid)
+        }
+
+        fn delete_cipher(&self, cipher: &Cipher) -> EmptyResult {
+            let mut data = self.data.borrow_mut();
+            data.ciphers.retain(|c| c.uuid != cipher.uuid);
+            data.collection_ciphers.retain(|(c, _)| c != &cipher.uuid);
+            Ok(())
+        }
+    }
+
+    impl CollectionRepo for MemoryRepo {
+        fn find_collection(&self, uuid: &str) -> Option<Collection> {
+            self.data.borrow().collections.iter().find(|c| c.uuid == uuid).cloned()
+        }
+
+        fn find_collection_in_org(&self, uuid: &str, org_uuid: &str) -> Option<Collection> {
+            self.find_collection(uuid).filter(|c| c.org_uuid == org_uuid)
+        }
+
+        fn is_collection_writable(&self, collection: &Collection, user_uuid: &str) -> bool {
+            match self.confirmed_member(user_uuid, &collection.org_uuid) {
+                Some(member) => member.has_full_access() || self.assignment(user_uuid, &collection.uuid) == Some(false),
+                None => false,
+            }
+        }
+
+        fn add_to_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult {
+            let pair = (cipher_uuid.to_string(), collection_uuid.to_string());
+            let mut data = self.data.borrow_mut();
+            if !data.collection_ciphers.contains(&pair) {
+                data.collection_ciphers.push(pair);
+            }
+            Ok(())
+        }
+
+        fn remove_from_collection(&self, cipher_uuid: &str, collection_uuid: &str) -> EmptyResult {
+            self.data
+                .borrow_mut()
+                .collection_ciphers
+                .retain(|(cipher, collection)| cipher != cipher_uuid || collection != collection_uuid);
+            Ok(())
+        }
+    }
+
+    impl FolderRepo for MemoryRepo {
+        fn find_folder(&self, uuid: &str) -> Option<Folder> {
+            self.data.borrow().folders.iter().find(|f| f.uuid == uuid).cloned()
+        }
+
+        fn save_folder(&self, folder: &mut Folder) -> EmptyResult {
+            let mut data = self.data.borrow_mut();
+            data.folders.retain(|f| f.uuid != folder.uuid);
+            data.folders.push(folder.clone());
+            Ok(())
+        }
+
+        fn delete_folder(&self, folder: &Folder) -> EmptyResult {
+            self.data.borrow_mut().folders.retain(|f| f.uuid != folder.uuid);
+            Ok(())
+        }
+    }
+
+    impl MemberRepo for MemoryRepo {
+        fn find_member(&self, uuid: &str, org_uuid: &str) -> Option<UserOrganization> {
+            let data = self.data.borrow();
+            data.members
+                .iter()
+                .find(|m| m.uuid == uuid && m.org_uuid == org_uuid)
+                .cloned()
+        }
+
+        fn find_member_by_user(&self, user_uuid: &str, org_uuid: &str) -> Option<UserOrganization> {
+            let data = self.data.borrow();
+            data.members
+                .iter()
+                .find(|m| m.user_uuid == user_uuid && m.org_uuid == org_uuid)
+                .cloned()
+        }
+
+        fn count_members_of_type(&self, org_uuid: &str, type_: UserOrgType) -> usize {
+            let data = self.data.borrow();
+            data.members
+                .iter()
+                .filter(|m| m.org_uuid == org_uuid && m.type_ == type_)
+                .count()
+        }
+
+        fn delete_member(&self, member: UserOrganization) -> EmptyResult {
+            let mut data = self.data.borrow_mut();
+            data.members.retain(|m| m.uuid != member.uuid);
+            data.user_collections.retain(|(user, _, _)| user != &member.user_uuid);
+            Ok(())
+        }
+    }
+
+    impl UserRepo for MemoryRepo {
+        fn find_user(&self, uuid: &str) -> Option<User> {
+            self.data.borrow().users.iter().find(|u| u.uuid == uuid).cloned()
+        }
+
+        fn find_user_by_mail(&self, mail: &str) -> Option<User> {
+            let lower_mail = mail.to_lowercase();
+            self.data.borrow().users.iter().find(|u| u.email == lower_mail).cloned()
+        }
+
+        fn save_user(&self, user: &mut User) -> EmptyResult {
+            if user.email.trim().is_empty() {
+                err!("User email can't be empty")
+            }
+
+            let mut data = self.data.borrow_mut();
+            data.users.retain(|u| u.uuid != user.uuid);
+            data.users.push(user.clone());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::memory::MemoryRepo;
+    use super::*;
+    use crate::db::models::{CipherType, UserOrgStatus};
+    #[cfg(feature = "sqlite")]
+    use crate::db::models::{CollectionUser, Organization};
+
+    /// Creates the rows needed by the checks, so that they run both on the mock and on the queries of the models.
+    /// The database needs the users and organizations to exist, the mock doesn't look at them
+    trait Fixture: CipherRepo + CollectionRepo + FolderRepo + MemberRepo + UserRepo {
+        fn create_user(&self, name: &str) -> String;
+        fn create_org(&self) -> String;
+        fn create_member(&self, user_uuid: &str, org_uuid: &str, type_: UserOrgType);
+        fn create_cipher(&self, cipher: Cipher) -> Cipher;
+        fn create_collection(&self, org_uuid: &str) -> Collection;
+        fn create_assignment(&self, user_uuid: &str, collection_uuid: &str, read_only: bool);
+    }
+
+    fn new_member(user_uuid: &str, org_uuid: &str, type_: UserOrgType) -> UserOrganization {
+        let mut member = UserOrganization::new(user_uuid.to_string(), org_uuid.to_string());
+        member.status = UserOrgStatus::Confirmed as i32;
+        member.type_ = type_ as i32;
+        member
+    }
+
+    impl Fixture for MemoryRepo {
+        fn create_user(&self, name: &str) -> String {
+            let mut user = User::new(format!("{}@example.com", name));
+            self.save_user(&mut user).unwrap();
+            user.uuid
+        }
+
+        fn create_org(&self) -> String {
+            crate::util::get_uuid()
+        }
+
+        fn create_member(&self, user_uuid: &str, org_uuid: &str, type_: UserOrgType) {
+            self.add_member(new_member(user_uuid, org_uuid, type_));
+        }
+
+        fn create_cipher(&self, cipher: Cipher) -> Cipher {
+            self.add_cipher(cipher.clone());
+            cipher
+        }
+
+        fn create_collection(&self, org_uuid: &str) -> Collection {
+            let collection = Collection::new(org_uuid.to_string(), "collection".into());
+            self.add_collection(collection.clone());
+            collection
+        }
+
+        fn create_assignment(&self, user_uuid: &str, collection_uuid: &str, read_only: bool) {
+            self.assign_collection(user_uuid, collection_uuid, read_only);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    impl Fixture for DbConn {
+        fn create_user(&self, name: &str) -> String {
+            let mut user = User::new(format!("{}@example.com", name));
+            self.save_user(&mut user).unwrap();
+            user.uuid
+        }
+
+        fn create_org(&self) -> String {
+            let mut org = Organization::new("org".into(), "org@example.com".into());
+            org.save(self).unwrap();
+            org.uuid
+        }
+
+        fn create_member(&self, user_uuid: &str, org_uuid: &str, type_: UserOrgType) {
+            new_member(user_uuid, org_uuid, type_).save(self).unwrap();
+        }
+
+        fn create_cipher(&self, mut cipher: Cipher) -> Cipher {
+            cipher.save(self).unwrap();
+            cipher
+        }
+
+        fn create_collection(&self, org_uuid: &str) -> Collection {
+            let mut collection = Collection::new(org_uuid.to_string(), "collection".into());
+            collection.save(self).unwrap();
+            collection
+        }
+
+        fn create_assignment(&self, user_uuid: &str, collection_uuid: &str, read_only: bool) {
+            CollectionUser::save(user_uuid, collection_uuid, read_only, self).unwrap();
+        }
+    }
+
+    fn org_cipher<R: Fixture>(repo: &R, org_uuid: &str) -> Cipher {
+        let mut cipher = Cipher::new(CipherType::Login, "cipher".into());
+        cipher.organization_uuid = Some(org_uuid.to_string());
+        repo.create_cipher(cipher)
+    }
+
+    fn transaction_is_rolled_back_on_error<R: Fixture>(repo: &R) {
+        let user_uuid = repo.create_user("user");
+        let org_uuid = repo.create_org();
+        let collection = repo.create_collection(&org_uuid);
+        let cipher = org_cipher(repo, &org_uuid);
+        let mut folder = Folder::new(user_uuid.clone(), "name".into());
+
+        let result: ApiResult<()> = repo.in_transaction(|| {
+            repo.save_folder(&mut folder)?;
+            repo.add_to_collection(&cipher.uuid, &collection.uuid)?;
+            repo.delete_cipher(&cipher)?;
+            err!("Failed")
+        });
+
+        assert!(result.is_err());
+        assert!(repo.find_folder(&folder.uuid).is_none());
+        assert!(repo.find_cipher(&cipher.uuid).is_some());
+        assert!(repo.find_all_cipher_collections(&cipher).is_empty());
+
+        repo.in_transaction(|| repo.save_folder(&mut folder)).unwrap();
+        assert!(repo.find_folder(&folder.uuid).is_some());
+    }
+
+    fn personal_cipher_is_only_writable_by_its_owner<R: Fixture>(repo: &R) {
+        let owner_uuid = repo.create_user("owner");
+        let other_uuid = repo.create_user("other");
+        let mut cipher = Cipher::new(CipherType::Login, "cipher".into());
+        cipher.user_uuid = Some(owner_uuid.clone());
+        let cipher = repo.create_cipher(cipher);
+
+        assert!(repo.is_cipher_writable(&cipher, &owner_uuid));
+        assert!(!repo.is_cipher_writable(&cipher, &other_uuid));
+        assert!(!repo.is_cipher_org_admin(&cipher, &owner_uuid));
+    }
+
+    fn read_only_collection_doesnt_give_write_access<R: Fixture>(repo: &R) {
+        let user_uuid = repo.create_user("user");
+        let org_uuid = repo.create_org();
+        let collection = repo.create_collection(&org_uuid);
+        let cipher = org_cipher(repo, &org_uuid);
+        repo.create_member(&user_uuid, &org_uuid, UserOrgType::User);
+        repo.add_to_collection(&cipher.uuid, &collection.uuid).unwrap();
+
+        assert!(!repo.is_cipher_writable(&cipher, &user_uuid));
+        assert!(repo.find_cipher_collections(&cipher, &user_uuid).is_empty());
+
+        repo.create_assignment(&user_uuid, &collection.uuid, true);
+        assert!(!repo.is_cipher_writable(&cipher, &user_uuid));
+        assert!(!repo.is_collection_writable(&collection, &user_uuid));
+        assert_eq!(
+            repo.find_cipher_collections(&cipher, &user_uuid),
+            vec![collection.uuid.clone()]
+        );
+    }
+
+    fn admins_have_full_access<R: Fixture>(repo: &R) {
+        let admin_uuid = repo.create_user("admin");
+        let manager_uuid = repo.create_user("manager");
+        let org_uuid = repo.create_org();
+        let collection = repo.create_collection(&org_uuid);
+        let cipher = org_cipher(repo, &org_uuid);
+        repo.create_member(&admin_uuid, &org_uuid, UserOrgType::Admin);
+        repo.create_member(&manager_uuid, &org_uuid, UserOrgType::Manager);
+
+        assert!(repo.is_cipher_org_admin(&cipher, &admin_uuid));
+        assert!(repo.is_cipher_writable(&cipher, &admin_uuid));
+        assert!(repo.is_collection_writable(&collection, &admin_uuid));
+
+        assert!(!repo.is_cipher_org_admin(&cipher, &manager_uuid));
+        assert!(!repo.is_collection_writable(&collection, &manager_uuid));
+    }
+
+    fn users_are_found_by_their_lowercase_mail<R: Fixture>(repo: &R) {
+        let user_uuid = repo.create_user("user");
+
+        assert_eq!(
+            repo.find_user_by_mail("USER@example.com").map(|u| u.uuid),
+            Some(user_uuid.clone())
+        );
+        assert!(repo.find_user(&user_uuid).is_some());
+        assert!(repo.find_user_by_mail("other@example.com").is_none());
+    }
+
+    /// Runs each check on the mock, and on an in-memory SQLite database with the migrations when it's the backend
+    macro_rules! repo_tests {
+        ($($check:ident),+) => {
+            mod memory_repo {
+                $(
+                    #[test]
+                    fn $check() {
+                        super::$check(&super::MemoryRepo::default());
+                    }
+                )+
+            }
+
+            #[cfg(feature = "sqlite")]
+            mod sqlite_repo {
+                $(
+                    #[test]
+                    fn $check() {
+                        super::$check(&crate::db::test_conn());
+                    }
+                )+
+            }
+        };
+    }
+
+    repo_tests!(
+        transaction_is_rolled_back_on_error,
+        personal_cipher_is_only_writable_by_its_owner,
+        read_only_collection_doesnt_give_write_access,
+        admins_have_full_access,
+        users_are_found_by_their_lowercase_mail
+    );
+}
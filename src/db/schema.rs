@@ -5,6 +5,29 @@ table! {
         file_name -> Text,
         file_size -> Integer,
         key -> Nullable<Text>,
+        checksum -> Nullable<Text>,
+        replaced_id -> Nullable<Text>,
+        uploaded_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    attachment_deletions (uuid) {
+        uuid -> Text,
+        file_path -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    attachment_uploads (id) {
+        id -> Text,
+        cipher_uuid -> Text,
+        user_uuid -> Text,
+        file_name -> Text,
+        file_size -> Integer,
+        key -> Nullable<Text>,
+        created_at -> Timestamp,
     }
 }
 
@@ -33,11 +56,23 @@ table! {
     }
 }
 
+table! {
+    client_certificates (uuid) {
+        uuid -> Text,
+        user_uuid -> Text,
+        name -> Text,
+        fingerprint -> Text,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     collections (uuid) {
         uuid -> Text,
         org_uuid -> Text,
         name -> Text,
+        external_id -> Nullable<Text>,
+        folder_uuid -> Nullable<Text>,
     }
 }
 
@@ -53,6 +88,32 @@ table! {
         push_token -> Nullable<Text>,
         refresh_token -> Text,
         twofactor_remember -> Nullable<Text>,
+        twofactor_remember_at -> Nullable<Timestamp>,
+        last_ip -> Nullable<Text>,
+        last_active_at -> Nullable<Timestamp>,
+        token_issued_at -> Nullable<Timestamp>,
+        websocket_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    events (uuid) {
+        uuid -> Text,
+        event_type -> Integer,
+        user_uuid -> Nullable<Text>,
+        org_uuid -> Nullable<Text>,
+        cipher_uuid -> Nullable<Text>,
+        device_type -> Nullable<Integer>,
+        ip_address -> Nullable<Text>,
+        event_date -> Timestamp,
+        org_user_uuid -> Nullable<Text>,
+    }
+}
+
+table! {
+    favorites (user_uuid, cipher_uuid) {
+        user_uuid -> Text,
+        cipher_uuid -> Text,
     }
 }
 
@@ -79,11 +140,67 @@ table! {
     }
 }
 
+table! {
+    org_domains (uuid) {
+        uuid -> Text,
+        org_uuid -> Text,
+        domain_name -> Text,
+        txt_token -> Text,
+        created_at -> Timestamp,
+        verified_at -> Nullable<Timestamp>,
+        last_checked_at -> Nullable<Timestamp>,
+    }
+}
+
 table! {
     organizations (uuid) {
         uuid -> Text,
         name -> Text,
         billing_email -> Text,
+        allowed_domains -> Text,
+        use_events -> Bool,
+    }
+}
+
+table! {
+    provider_organizations (uuid) {
+        uuid -> Text,
+        provider_uuid -> Text,
+        org_uuid -> Text,
+        key -> Text,
+    }
+}
+
+table! {
+    provider_users (uuid) {
+        uuid -> Text,
+        provider_uuid -> Text,
+        user_uuid -> Text,
+        key -> Text,
+        status -> Integer,
+        #[sql_name = "type"]
+        type_ -> Integer,
+    }
+}
+
+table! {
+    providers (uuid) {
+        uuid -> Text,
+        name -> Text,
+        business_name -> Nullable<Text>,
+        billing_email -> Text,
+        status -> Integer,
+        enabled -> Bool,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    queued_notifications (uuid) {
+        uuid -> Text,
+        device_uuid -> Text,
+        seq -> BigInt,
+        data -> Binary,
     }
 }
 
@@ -95,6 +212,7 @@ table! {
         type_ -> Integer,
         enabled -> Bool,
         data -> Text,
+        last_used -> Integer,
     }
 }
 
@@ -119,6 +237,14 @@ table! {
         excluded_globals -> Text,
         client_kdf_type -> Integer,
         client_kdf_iter -> Integer,
+        force_password_reset -> Bool,
+        login_failures -> Integer,
+        locked_until -> Nullable<Timestamp>,
+        deleted_at -> Nullable<Timestamp>,
+        locale -> Nullable<Text>,
+        disabled -> Bool,
+        api_key -> Nullable<Text>,
+        verified_at -> Nullable<Timestamp>,
     }
 }
 
@@ -143,16 +269,27 @@ table! {
     }
 }
 
+joinable!(attachment_uploads -> ciphers (cipher_uuid));
+joinable!(attachment_uploads -> users (user_uuid));
 joinable!(attachments -> ciphers (cipher_uuid));
 joinable!(ciphers -> organizations (organization_uuid));
 joinable!(ciphers -> users (user_uuid));
 joinable!(ciphers_collections -> ciphers (cipher_uuid));
 joinable!(ciphers_collections -> collections (collection_uuid));
+joinable!(client_certificates -> users (user_uuid));
 joinable!(collections -> organizations (org_uuid));
 joinable!(devices -> users (user_uuid));
+joinable!(favorites -> ciphers (cipher_uuid));
+joinable!(favorites -> users (user_uuid));
 joinable!(folders -> users (user_uuid));
 joinable!(folders_ciphers -> ciphers (cipher_uuid));
 joinable!(folders_ciphers -> folders (folder_uuid));
+joinable!(org_domains -> organizations (org_uuid));
+joinable!(provider_organizations -> organizations (org_uuid));
+joinable!(provider_organizations -> providers (provider_uuid));
+joinable!(provider_users -> providers (provider_uuid));
+joinable!(provider_users -> users (user_uuid));
+joinable!(queued_notifications -> devices (device_uuid));
 joinable!(twofactor -> users (user_uuid));
 joinable!(users_collections -> collections (collection_uuid));
 joinable!(users_collections -> users (user_uuid));
@@ -160,15 +297,25 @@ joinable!(users_organizations -> organizations (org_uuid));
 joinable!(users_organizations -> users (user_uuid));
 
 allow_tables_to_appear_in_same_query!(
+    attachment_deletions,
+    attachment_uploads,
     attachments,
     ciphers,
     ciphers_collections,
+    client_certificates,
     collections,
     devices,
+    events,
+    favorites,
     folders,
     folders_ciphers,
     invitations,
+    org_domains,
     organizations,
+    provider_organizations,
+    provider_users,
+    providers,
+    queued_notifications,
     twofactor,
     users,
     users_collections,
@@ -32,6 +32,7 @@ macro_rules! make_error {
     };
 }
 
+use crate::ratelimit::RateLimited as RateErr;
 use diesel::result::Error as DieselErr;
 use handlebars::RenderError as HbErr;
 use jsonwebtoken::errors::Error as JWTErr;
@@ -40,6 +41,7 @@ use reqwest::Error as ReqErr;
 use serde_json::{Error as SerdeErr, Value};
 use std::io::Error as IOErr;
 use std::time::SystemTimeError as TimeErr;
+use trust_dns_resolver::error::ResolveError as DnsErr;
 use u2f::u2ferror::U2fError as U2fErr;
 use yubico::yubicoerror::YubicoError as YubiErr;
 
@@ -53,6 +55,8 @@ make_error! {
     SimpleError(String):  _no_source,  _api_error,
     // Used for special return values, like 2FA errors
     JsonError(Value):     _no_source,  _serialize,
+    // Used by err_limit! calls, sent with a 429 status
+    RateLimitError(RateErr): _no_source, _api_error,
     DbError(DieselErr):   _has_source, _api_error,
     U2fError(U2fErr):     _has_source, _api_error,
     SerdeError(SerdeErr): _has_source, _api_error,
@@ -64,6 +68,7 @@ make_error! {
     ReqError(ReqErr):     _has_source, _api_error,
     RegexError(RegexErr): _has_source, _api_error,
     YubiError(YubiErr):   _has_source, _api_error,
+    DnsError(DnsErr):     _has_source, _api_error,
 }
 
 impl std::fmt::Debug for Error {
@@ -84,6 +89,11 @@ impl Error {
         self.message = msg.into();
         self
     }
+
+    /// The message meant for the user
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 }
 
 pub trait MapResult<S> {
@@ -138,12 +148,17 @@ use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 
 impl<'r> Responder<'r> for Error {
-    fn respond_to(self, _: &Request) -> response::Result<'r> {
-        let usr_msg = format!("{}", self);
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
         error!("{:#?}", self);
+        let status = match self.error {
+            ErrorKind::RateLimitError(_) => Status::TooManyRequests,
+            _ => Status::BadRequest,
+        };
+        let message = crate::i18n::translate(req, &self.message);
+        let usr_msg = format!("{}", self.with_msg(message));
 
         Response::build()
-            .status(Status::BadRequest)
+            .status(status)
             .header(ContentType::JSON)
             .sized_body(Cursor::new(usr_msg))
             .ok()
@@ -163,6 +178,14 @@ macro_rules! err {
     }};
 }
 
+#[macro_export]
+macro_rules! err_limit {
+    ($usr_msg:expr, $log_value:expr) => {{
+        let limited = crate::ratelimit::RateLimited($log_value.into());
+        return Err(crate::error::Error::from(($usr_msg, limited)));
+    }};
+}
+
 #[macro_export]
 macro_rules! err_json {
     ($expr:expr) => {{
@@ -0,0 +1,98 @@
+//
+// Localization of the emails and of the error messages shown to the users
+//
+use std::collections::HashMap;
+
+use rocket::Request;
+
+use crate::CONFIG;
+
+/// The languages that have translations, English is always available
+const LANGUAGES: &[&str] = &["en", "es"];
+
+lazy_static! {
+    static ref MESSAGES: HashMap<&'static str, HashMap<String, String>> = {
+        let mut messages = HashMap::new();
+        messages.insert("es", load_messages(include_str!("static/locales/es.json")));
+        messages
+    };
+}
+
+fn load_messages(json: &str) -> HashMap<String, String> {
+    serde_json::from_str(json).expect("Error parsing the translated messages")
+}
+
+/// Finds the language for a locale like `es-ES`, using the configured locale when
+/// there isn't one or it isn't supported
+pub fn language(locale: Option<&str>) -> &'static str {
+    locale
+        .and_then(find_language)
+        .or_else(|| find_language(&CONFIG.locale()))
+        .unwrap_or("en")
+}
+
+fn find_language(locale: &str) -> Option<&'static str> {
+    let locale = locale.to_lowercase().replace('_', "-");
+    let primary = locale.split('-').next().unwrap_or_default();
+
+    LANGUAGES.iter().cloned().find(|l| *l == locale || *l == primary)
+}
+
+/// Returns the locale if it looks like a valid one, so it can be stored in the profile
+pub fn parse_locale(locale: &str) -> Option<String> {
+    let locale = locale.trim();
+    let valid = !locale.is_empty()
+        && locale.len() <= 16
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Some(locale.to_string())
+    } else {
+        None
+    }
+}
+
+/// The translated version of a template like `email/send_org_invite` is registered
+/// as `email/es/send_org_invite`, the default one is used when it doesn't exist
+pub fn template_name(name: &str, language: &str) -> String {
+    if language != "en" {
+        let localized = name.replacen("email/", &format!("email/{}/", language), 1);
+        if CONFIG.has_template(&localized) {
+            return localized;
+        }
+    }
+    name.to_string()
+}
+
+/// The locale of the user making the request, stored by the authentication guard
+pub struct RequestLocale(Option<String>);
+
+pub fn set_request_locale(request: &Request, locale: Option<String>) {
+    request.local_cache(|| RequestLocale(locale));
+}
+
+/// The preferred language sent by the browser, for the requests without a logged in user, like the logins
+fn accept_language(request: &Request) -> Option<String> {
+    let header = request.headers().get_one("Accept-Language")?;
+    let first = header.split(',').next()?.split(';').next()?;
+    parse_locale(first)
+}
+
+/// Translates an error message to the language of the user making the request,
+/// the messages without a translation are returned unchanged
+pub fn translate(request: &Request, message: &str) -> String {
+    let locale = request
+        .local_cache(|| RequestLocale(None))
+        .0
+        .clone()
+        .or_else(|| accept_language(request));
+    let language = language(locale.as_ref().map(String::as_str));
+
+    MESSAGES
+        .get(language)
+        .and_then(|messages| messages.get(message))
+        .cloned()
+        .unwrap_or_else(|| message.to_string())
+}
@@ -0,0 +1,708 @@
+//
+// Single port mode: a small listener on the public port, on a Unix socket, on the socket passed by systemd
+// or on several addresses, in front of Rocket and the websocket server, which both listen on the loopback interface. The websocket
+// connections to /notifications/hub are passed to the websocket server, and the rest to Rocket,
+// so the proxy doesn't need any extra rules. On a Unix socket, Rocket gets its connections in the process instead,
+// so it doesn't listen on the loopback interface
+//
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use rocket::config::{Config, LoggingLevel};
+use rocket::http::hyper::{
+    self,
+    net::{NetworkListener, NetworkStream},
+};
+use rocket::Rocket;
+
+use crate::auth;
+use crate::crypto;
+use crate::systemd;
+use crate::util;
+use crate::CONFIG;
+
+const LOOPBACK: &str = "127.0.0.1";
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static IN_PROCESS: AtomicBool = AtomicBool::new(false);
+
+// Each connection has its own threads, so the number of open connections is limited.
+// The websockets stay open, so they have their own limit and can't take the slots of the requests
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static WEBSOCKETS: AtomicUsize = AtomicUsize::new(0);
+
+/// Header with the secret of this process, added to every request passed to Rocket. Rocket can also be reached
+/// directly on the loopback interface, so it only trusts the requests that carry it
+pub const SECRET_HEADER: &str = "X-Listener-Secret";
+
+lazy_static! {
+    static ref SECRET: String = data_encoding::HEXLOWER.encode(&crypto::get_random(vec![0u8; 32]));
+    // The connections passed to Rocket in the process, its workers take them from the receiver
+    static ref ROCKET_CONNECTIONS: (Mutex<Sender<RocketStream>>, Arc<Mutex<Receiver<RocketStream>>>) = {
+        let (sender, receiver) = mpsc::channel();
+        (Mutex::new(sender), Arc::new(Mutex::new(receiver)))
+    };
+}
+
+// The request head is read before choosing where to send it, up to this size
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+// The longest a client can go without sending anything while the request body is read
+const BODY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The listener can accept connections before Rocket is listening, like with socket activation,
+// so they are retried for a while
+const BACKEND_CONNECT_ATTEMPTS: u32 = 50;
+const BACKEND_CONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// The connections accepted by the listener, which are copied to the loopback servers or passed to Rocket
+pub(crate) trait ClientStream: Read + Write + Send + 'static {
+    fn try_clone(&self) -> io::Result<Self>
+    where
+        Self: Sized;
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// The Unix socket clients don't have one, the proxy in front sends it instead
+    fn client_ip(&self) -> Option<IpAddr>;
+}
+
+impl ClientStream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn client_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn client_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Creates the Rocket instance listening on the loopback interface, and starts the listener on the socket
+/// passed by systemd, on the Unix socket, or else on the address and port that were configured for Rocket.
+/// On a Unix socket, Rocket needs to be launched with `launch_in_process` instead
+pub fn ignite_single_port() -> Rocket {
+    let mut config = match Config::active() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Error reading the Rocket configuration: {:?}", e);
+            exit(1);
+        }
+    };
+
+    // The listener can't see the path of the encrypted requests, TLS needs to be terminated by the proxy
+    if config.tls_enabled() {
+        error!("The single port mode can't be used with the Rocket TLS support, use a reverse proxy for TLS");
+        exit(1);
+    }
+
+    let rocket_addr = SocketAddr::new(LOOPBACK.parse().unwrap(), CONFIG.websocket_single_port_rocket_port());
+    // Without websockets the hub requests go to Rocket, which answers them with an error. With only
+    // BIND_ADDRESSES, the websocket server keeps its own port
+    let proxies_ws = CONFIG.websocket_single_port() || CONFIG.unix_socket_path().is_some();
+    let ws_addr = if CONFIG.websocket_enabled() && proxies_ws {
+        SocketAddr::new(LOOPBACK.parse().unwrap(), CONFIG.websocket_port())
+    } else {
+        rocket_addr
+    };
+
+    ACTIVE.store(true, Ordering::Relaxed);
+    if spawn_activated_listener(rocket_addr, ws_addr) {
+        info!("Listening on the socket passed by systemd");
+    } else {
+        spawn_public_listener(&config, rocket_addr, ws_addr);
+    }
+
+    config.address = LOOPBACK.to_string();
+    config.port = rocket_addr.port();
+    // Every connection carries a single request, so each one is sent to the right server,
+    // with the address of its own client
+    config.keep_alive = None;
+
+    rocket::custom(config)
+}
+
+#[cfg(unix)]
+fn spawn_activated_listener(rocket_addr: SocketAddr, ws_addr: SocketAddr) -> bool {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    let fd = match systemd::take_activated_socket() {
+        Some(fd) => fd,
+        None => return false,
+    };
+
+    // The socket can be a TCP or a Unix one, only the TCP sockets have an address
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    if listener.local_addr().is_ok() {
+        let accept = move || listener.accept().map(|(client, _)| client);
+        spawn_listener(accept, move |client, slot| {
+            handle_connection(client, slot, rocket_addr, ws_addr)
+        });
+    } else {
+        let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+        IN_PROCESS.store(true, Ordering::Relaxed);
+        let accept = move || listener.accept().map(|(client, _)| client);
+        spawn_listener(accept, move |client, slot| {
+            handle_connection(client, slot, rocket_addr, ws_addr)
+        });
+    }
+    true
+}
+
+#[cfg(not(unix))]
+fn spawn_activated_listener(_rocket_addr: SocketAddr, _ws_addr: SocketAddr) -> bool {
+    false
+}
+
+fn spawn_public_listener(config: &Config, rocket_addr: SocketAddr, ws_addr: SocketAddr) {
+    match CONFIG.unix_socket_path() {
+        Some(path) => {
+            let listener = bind_unix_socket(&path);
+            IN_PROCESS.store(true, Ordering::Relaxed);
+            let accept = move || listener.accept().map(|(client, _)| client);
+            spawn_listener(accept, move |client, slot| {
+                handle_connection(client, slot, rocket_addr, ws_addr)
+            });
+            info!("Listening on the Unix socket {}", path);
+        }
+        None if CONFIG.bind_addresses().is_some() => {
+            let addresses = bind_addresses();
+            for ip in &addresses {
+                let public_addr = SocketAddr::new(*ip, config.port);
+                let listener = match TcpListener::bind(public_addr) {
+                    Ok(listener) => listener,
+                    Err(ref e) if is_covered_by_dual_stack(*ip, &addresses, e) => {
+                        info!("{} is already served by the IPv6 socket", public_addr);
+                        continue;
+                    }
+                    Err(e) => {
+                        error!("Error listening on {}: {:?}", public_addr, e);
+                        exit(1);
+                    }
+                };
+                let accept = move || listener.accept().map(|(client, _)| client);
+                spawn_listener(accept, move |client, slot| {
+                    handle_connection(client, slot, rocket_addr, ws_addr)
+                });
+                info!("Listening on {}", public_addr);
+            }
+        }
+        None => {
+            let public_addr = format!("{}:{}", config.address, config.port);
+            let listener = match TcpListener::bind(&public_addr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Error listening on {}: {:?}", public_addr, e);
+                    exit(1);
+                }
+            };
+            let accept = move || listener.accept().map(|(client, _)| client);
+            spawn_listener(accept, move |client, slot| {
+                handle_connection(client, slot, rocket_addr, ws_addr)
+            });
+            info!("Listening on {} in single port mode", public_addr);
+        }
+    }
+}
+
+/// The addresses of BIND_ADDRESSES, with the IPv6 ones first, so the dual-stack sockets are bound
+/// before the IPv4 ones they cover
+pub fn bind_addresses() -> Vec<IpAddr> {
+    let mut addresses = CONFIG
+        .bind_addresses()
+        .and_then(|list| util::parse_ip_list(&list))
+        .unwrap_or_default();
+    addresses.sort_by_key(IpAddr::is_ipv4);
+    addresses
+}
+
+/// On most systems a socket on the unspecified IPv6 address also accepts the IPv4 connections,
+/// so the IPv4 addresses are already in use, which isn't an error
+pub fn is_covered_by_dual_stack(ip: IpAddr, addresses: &[IpAddr], e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::AddrInUse && ip.is_ipv4() && addresses.iter().any(|a| a.is_ipv6() && a.is_unspecified())
+}
+
+/// Passes all the connections of the Unix socket to the websocket server, for a proxy that keeps
+/// the websocket rules apart from the rest
+pub fn start_websocket_socket() {
+    let path = match CONFIG.websocket_unix_socket_path() {
+        Some(path) => path,
+        None => return,
+    };
+
+    let ws_addr = SocketAddr::new(LOOPBACK.parse().unwrap(), CONFIG.websocket_port());
+    let listener = bind_unix_socket(&path);
+    let accept = move || listener.accept().map(|(client, _)| client);
+    spawn_listener(accept, move |client, slot| {
+        let _slot = slot.into_websocket()?;
+        let mut backend = connect_backend(ws_addr)?;
+        copy_both(client, &mut backend, websocket_timeout())
+    });
+    info!("Listening for websockets on the Unix socket {}", path);
+}
+
+#[cfg(unix)]
+fn bind_unix_socket(path: &str) -> UnixListener {
+    use std::fs::{self, DirBuilder, Permissions};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+    // The socket file of a previous run is left behind when the server isn't stopped cleanly,
+    // any other file at the path is most likely a mistake in the configuration
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => {
+            if let Err(e) = fs::remove_file(path) {
+                error!("Error removing the old Unix socket {}: {:?}", path, e);
+                exit(1);
+            }
+        }
+        Ok(_) => {
+            error!("{} already exists and isn't a Unix socket", path);
+            exit(1);
+        }
+        Err(_) => (),
+    }
+
+    // The permissions of the umask are usually open to every user of the host, so the socket is created
+    // in a private directory, and only moved to the path once it has its own permissions
+    let bind_dir = format!("{}.bind", path);
+    let bind_path = format!("{}/socket", bind_dir);
+    fs::remove_dir_all(&bind_dir).ok();
+    if let Err(e) = DirBuilder::new().mode(0o700).create(&bind_dir) {
+        error!("Error creating the directory {} for the Unix socket: {:?}", bind_dir, e);
+        exit(1);
+    }
+
+    let listener = match UnixListener::bind(&bind_path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Error listening on the Unix socket {}: {:?}", path, e);
+            exit(1);
+        }
+    };
+
+    let mode = u32::from_str_radix(&CONFIG.unix_socket_mode(), 8).unwrap_or(0o660);
+    if let Err(e) = fs::set_permissions(&bind_path, Permissions::from_mode(mode)) {
+        error!("Error setting the permissions of the Unix socket {}: {:?}", path, e);
+        exit(1);
+    }
+
+    if let Err(e) = fs::rename(&bind_path, path) {
+        error!("Error moving the Unix socket to {}: {:?}", path, e);
+        exit(1);
+    }
+    fs::remove_dir(&bind_dir).ok();
+
+    listener
+}
+
+#[cfg(not(unix))]
+fn bind_unix_socket(path: &str) -> TcpListener {
+    error!("The Unix sockets are only supported on Unix: {}", path);
+    exit(1);
+}
+
+/// Counts an open connection until it's dropped
+struct ConnectionSlot(&'static AtomicUsize);
+
+impl ConnectionSlot {
+    /// Returns None when there are already too many open connections
+    fn take(counter: &'static AtomicUsize, max: usize) -> Option<Self> {
+        if counter.fetch_add(1, Ordering::SeqCst) >= max {
+            counter.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ConnectionSlot(counter))
+    }
+
+    /// Moves the connection to the websocket limit, and frees its slot of the requests
+    fn into_websocket(self) -> io::Result<Self> {
+        match ConnectionSlot::take(&WEBSOCKETS, CONFIG.single_port_max_websockets()) {
+            Some(slot) => Ok(slot),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Too many open websockets")),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The websocket clients answer the pings, so they are never quiet for much longer than the interval
+fn websocket_timeout() -> Duration {
+    Duration::from_secs(3 * CONFIG.websocket_ping_interval())
+}
+
+/// Accepts the connections in its own thread, and handles each one in a new thread.
+/// The connections over the limit are closed right away
+fn spawn_listener<A, S, F>(accept: A, handler: F)
+where
+    A: Fn() -> io::Result<S> + Send + 'static,
+    S: ClientStream,
+    F: Fn(S, ConnectionSlot) -> io::Result<()> + Send + Copy + 'static,
+{
+    thread::Builder::new()
+        .name("single-port".into())
+        .spawn(move || loop {
+            let client = match accept() {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Error accepting a connection: {:?}", e);
+                    continue;
+                }
+            };
+
+            let slot = match ConnectionSlot::take(&CONNECTIONS, CONFIG.single_port_max_connections()) {
+                Some(slot) => slot,
+                None => {
+                    warn!("Too many open connections, closing the new one");
+                    continue;
+                }
+            };
+
+            let spawned = thread::Builder::new().spawn(move || {
+                if let Err(e) = handler(client, slot) {
+                    debug!("Single port connection closed: {:?}", e);
+                }
+            });
+            if let Err(e) = spawned {
+                warn!("Error starting the thread of a connection: {:?}", e);
+            }
+        })
+        .expect("Error starting the single port listener");
+}
+
+fn handle_connection<S: ClientStream>(
+    mut client: S,
+    slot: ConnectionSlot,
+    rocket_addr: SocketAddr,
+    ws_addr: SocketAddr,
+) -> io::Result<()> {
+    client.set_read_timeout(Some(HEAD_TIMEOUT))?;
+    let head = read_head(&mut client)?;
+
+    let head_str = String::from_utf8_lossy(&head);
+    let mut request_line = head_str.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    // The long polling requests to the hub are handled by Rocket
+    let is_upgrade = head_str
+        .lines()
+        .any(|line| line.to_lowercase().replace(' ', "") == "upgrade:websocket");
+    let is_hub = method == "GET" && (path == "/notifications/hub" || path.starts_with("/notifications/hub?"));
+    let is_hub = is_hub && is_upgrade;
+    let backend_addr = if is_hub { ws_addr } else { rocket_addr };
+
+    // Without websockets the hub requests go to Rocket, which answers them right away
+    let is_websocket = is_hub && ws_addr != rocket_addr;
+    let (slot, timeout) = if is_websocket {
+        (slot.into_websocket()?, websocket_timeout())
+    } else {
+        (slot, BODY_TIMEOUT)
+    };
+
+    // The clients of the Unix socket are on the same host, they're trusted when the loopback address is
+    let trusted = match client.client_ip() {
+        Some(ip) => auth::is_trusted_proxy(ip),
+        None => auth::is_trusted_proxy(LOOPBACK.parse().unwrap()) || auth::is_trusted_proxy("::1".parse().unwrap()),
+    };
+
+    let mut head = remove_header(&head, SECRET_HEADER);
+    if let Some(ref header) = CONFIG.trusted_header() {
+        if !trusted {
+            head = remove_header(&head, header);
+        }
+    }
+    if let Some(ip) = client.client_ip() {
+        head = add_client_ip(&head, ip);
+    }
+    let head = add_header(&head, SECRET_HEADER, &SECRET);
+
+    if !is_websocket && IN_PROCESS.load(Ordering::Relaxed) {
+        client.set_read_timeout(Some(timeout))?;
+        return pass_to_rocket(client, head, slot);
+    }
+
+    let _slot = slot;
+    let mut backend = connect_backend(backend_addr)?;
+    backend.write_all(&head)?;
+    copy_both(client, &mut backend, timeout)
+}
+
+fn connect_backend(addr: SocketAddr) -> io::Result<TcpStream> {
+    let mut attempt = 1;
+    loop {
+        match TcpStream::connect(addr) {
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused && attempt < BACKEND_CONNECT_ATTEMPTS => {
+                attempt += 1;
+                thread::sleep(BACKEND_CONNECT_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Copies both directions until the backend closes the connection. A client that is quiet for longer
+/// than the timeout, or that closes its side, ends the request sent to the backend
+pub(crate) fn copy_both<S: ClientStream>(mut client: S, backend: &mut TcpStream, timeout: Duration) -> io::Result<()> {
+    client.set_read_timeout(Some(timeout))?;
+
+    let (mut client_read, mut backend_write) = (client.try_clone()?, backend.try_clone()?);
+    let upload = thread::spawn(move || {
+        io::copy(&mut client_read, &mut backend_write).ok();
+        backend_write.shutdown(Shutdown::Write).ok();
+    });
+
+    // Shutting down both sides also wakes up the upload, when it's still waiting for the client
+    io::copy(backend, &mut client).ok();
+    client.shutdown(Shutdown::Both).ok();
+    backend.shutdown(Shutdown::Both).ok();
+    upload.join().ok();
+
+    Ok(())
+}
+
+/// Passes the connection to Rocket in the process, the head that was already read is the first thing it reads
+fn pass_to_rocket<S: ClientStream>(client: S, head: Vec<u8>, slot: ConnectionSlot) -> io::Result<()> {
+    // Like with the loopback interface, the clients of the Unix socket have the loopback address
+    let ip = client.client_ip().unwrap_or_else(|| LOOPBACK.parse().unwrap());
+
+    let stream = RocketStream(Arc::new(Mutex::new(RocketConnection {
+        head: io::Cursor::new(head),
+        client: Box::new(client),
+        peer: SocketAddr::new(ip, 0),
+        _slot: slot,
+    })));
+
+    let sent = ROCKET_CONNECTIONS.0.lock().unwrap().send(stream);
+    sent.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Rocket isn't running"))
+}
+
+/// Whether Rocket gets its connections from the listener, so it needs to be launched with `launch_in_process`
+pub fn is_in_process() -> bool {
+    IN_PROCESS.load(Ordering::Relaxed)
+}
+
+/// Serves Rocket with the connections passed by the listener, and only returns if it can't be started.
+/// The launch fairings aren't run, the caller runs what they do instead
+pub fn launch_in_process(rocket: Rocket) -> hyper::Error {
+    // Like the Rocket launch, go back to the configured log level after the messages of the launch
+    if !CONFIG.extended_logging() {
+        log::set_max_level(match rocket.config().log_level {
+            LoggingLevel::Critical => log::LevelFilter::Warn,
+            LoggingLevel::Normal => log::LevelFilter::Info,
+            LoggingLevel::Debug => log::LevelFilter::Trace,
+            LoggingLevel::Off => log::LevelFilter::Off,
+        });
+    }
+
+    let workers = rocket.config().workers as usize;
+    let mut server = hyper::Server::new(RocketListener(ROCKET_CONNECTIONS.1.clone()));
+    server.keep_alive(None);
+    server.set_read_timeout(Some(BODY_TIMEOUT));
+
+    match server.handle_threads(rocket, workers) {
+        Ok(listening) => {
+            info!("Rocket is serving the connections of the Unix socket");
+            // It waits for the workers, which never stop
+            drop(listening);
+            unreachable!("The Rocket workers have stopped")
+        }
+        Err(e) => e,
+    }
+}
+
+/// A connection passed to Rocket, which reads the head first. Rocket uses several handles of the same connection
+#[derive(Clone)]
+struct RocketStream(Arc<Mutex<RocketConnection>>);
+
+struct RocketConnection {
+    head: io::Cursor<Vec<u8>>,
+    client: Box<dyn ClientStream>,
+    peer: SocketAddr,
+    _slot: ConnectionSlot,
+}
+
+impl Read for RocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.0.lock().unwrap();
+        if conn.head.position() < conn.head.get_ref().len() as u64 {
+            conn.head.read(buf)
+        } else {
+            conn.client.read(buf)
+        }
+    }
+}
+
+impl Write for RocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().client.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().client.flush()
+    }
+}
+
+impl NetworkStream for RocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(self.0.lock().unwrap().peer)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().client.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().client.set_write_timeout(timeout)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().client.shutdown(how)
+    }
+}
+
+#[derive(Clone)]
+struct RocketListener(Arc<Mutex<Receiver<RocketStream>>>);
+
+impl NetworkListener for RocketListener {
+    type Stream = RocketStream;
+
+    fn accept(&mut self) -> Result<RocketStream, hyper::Error> {
+        let received = self.0.lock().unwrap().recv();
+        received.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The listener has stopped").into())
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::new(LOOPBACK.parse().unwrap(), 0))
+    }
+
+    // The timeouts are set on each connection
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) {}
+
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) {}
+}
+
+/// Whether Rocket is behind the listener, so only the requests with its secret come from it
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Whether the value of the secret header is the one added by the listener
+pub fn is_secret(value: &str) -> bool {
+    crypto::ct_eq(value, SECRET.as_str())
+}
+
+/// Reads until the end of the request headers, the body, if any, is copied afterwards
+pub(crate) fn read_head<R: Read>(client: &mut R) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Request head too big"));
+        }
+
+        match client.read(&mut buffer)? {
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed")),
+            n => head.extend_from_slice(&buffer[..n]),
+        }
+    }
+
+    Ok(head)
+}
+
+/// Adds the X-Real-IP header after the request line, as otherwise Rocket only sees the loopback address.
+/// The header sent by the client is replaced, unless the client is one of the proxies of REAL_IP_PROXIES
+fn add_client_ip(head: &[u8], ip: IpAddr) -> Vec<u8> {
+    let head_str = String::from_utf8_lossy(head);
+    let has_real_ip = head_str
+        .lines()
+        .any(|line| line.to_lowercase().starts_with("x-real-ip:"));
+    if has_real_ip && is_real_ip_proxy(ip) {
+        return head.to_vec();
+    }
+
+    let head = remove_header(head, "X-Real-IP");
+    add_header(&head, "X-Real-IP", &ip.to_string())
+}
+
+/// Adds the header after the request line
+pub(crate) fn add_header(head: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let line_end = match head.windows(2).position(|w| w == b"\r\n") {
+        Some(pos) => pos + 2,
+        None => return head.to_vec(),
+    };
+
+    let mut new_head = head[..line_end].to_vec();
+    new_head.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    new_head.extend_from_slice(&head[line_end..]);
+    new_head
+}
+
+fn is_real_ip_proxy(ip: IpAddr) -> bool {
+    util::parse_ip_list(&CONFIG.real_ip_proxies()).map_or(false, |proxies| proxies.contains(&ip))
+}
+
+/// Removes the header from the request head, so Rocket doesn't trust it. The body, if it was already read, is kept as is
+pub(crate) fn remove_header(head: &[u8], name: &str) -> Vec<u8> {
+    let head_end = match head.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => pos + 4,
+        None => return head.to_vec(),
+    };
+
+    let prefix = format!("{}:", name.to_lowercase());
+    let lines: Vec<&[u8]> = head[..head_end]
+        .split(|&b| b == b'\n')
+        .filter(|line| !String::from_utf8_lossy(line).to_lowercase().starts_with(&prefix))
+        .collect();
+
+    let mut new_head = lines.join(&b'\n');
+    new_head.extend_from_slice(&head[head_end..]);
+    new_head
+}
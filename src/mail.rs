@@ -4,9 +4,17 @@ use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, SmtpTransport, Tra
 use lettre_email::EmailBuilder;
 use native_tls::{Protocol, TlsConnector};
 
+use chrono::NaiveDateTime;
+
 use crate::api::EmptyResult;
-use crate::auth::{encode_jwt, generate_invite_claims};
+use crate::auth::{
+    encode_jwt, generate_device_approval_claims, generate_email_change_approval_claims, generate_invite_claims,
+    generate_unlock_claims, generate_verify_email_claims,
+};
+use crate::db::models::Device;
 use crate::error::Error;
+use crate::i18n;
+use crate::telemetry;
 use crate::CONFIG;
 
 fn mailer() -> SmtpTransport {
@@ -36,9 +44,15 @@ fn mailer() -> SmtpTransport {
         .transport()
 }
 
-fn get_text(template_name: &'static str, data: serde_json::Value) -> Result<(String, String, String), Error> {
+fn get_text(
+    template_name: &'static str,
+    locale: Option<&str>,
+    data: serde_json::Value,
+) -> Result<(String, String, String), Error> {
+    let template_name = i18n::template_name(template_name, i18n::language(locale));
+
     let (subject_html, body_html) = get_template(&format!("{}.html", template_name), &data)?;
-    let (_subject_text, body_text) = get_template(template_name, &data)?;
+    let (_subject_text, body_text) = get_template(&template_name, &data)?;
     Ok((subject_html, body_html, body_text))
 }
 
@@ -66,7 +80,8 @@ pub fn send_password_hint(address: &str, hint: Option<String>) -> EmptyResult {
         "email/pw_hint_none"
     };
 
-    let (subject, body_html, body_text) = get_text(template_name, json!({ "hint": hint, "url": CONFIG.domain() }))?;
+    let (subject, body_html, body_text) =
+        get_text(template_name, None, json!({ "hint": hint, "url": CONFIG.domain() }))?;
  
     send_email(&address, &subject, &body_html, &body_text)
 }
@@ -78,6 +93,7 @@ pub fn send_invite(
     org_user_id: Option<String>,
     org_name: &str,
     invited_by_email: Option<String>,
+    locale: Option<&str>,
 ) -> EmptyResult {
     let claims = generate_invite_claims(
         uuid.to_string(),
@@ -90,6 +106,7 @@ pub fn send_invite(
 
     let (subject, body_html, body_text) = get_text(
         "email/send_org_invite",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "org_id": org_id.unwrap_or_else(|| "_".to_string()),
@@ -103,9 +120,10 @@ pub fn send_invite(
     send_email(&address, &subject, &body_html, &body_text)
 }
 
-pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str) -> EmptyResult {
+pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str, locale: Option<&str>) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/invite_accepted",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "email": new_user_email,
@@ -116,9 +134,10 @@ pub fn send_invite_accepted(new_user_email: &str, address: &str, org_name: &str)
     send_email(&address, &subject, &body_html, &body_text)
 }
 
-pub fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
+pub fn send_invite_confirmed(address: &str, org_name: &str, locale: Option<&str>) -> EmptyResult {
     let (subject, body_html, body_text) = get_text(
         "email/invite_confirmed",
+        locale,
         json!({
             "url": CONFIG.domain(),
             "org_name": org_name,
@@ -128,6 +147,135 @@ pub fn send_invite_confirmed(address: &str, org_name: &str) -> EmptyResult {
     send_email(&address, &subject, &body_html, &body_text)
 }
 
+pub fn send_account_locked(
+    address: &str,
+    uuid: &str,
+    locked_until: &NaiveDateTime,
+    ip: &str,
+    locale: Option<&str>,
+) -> EmptyResult {
+    let claims = generate_unlock_claims(uuid.to_string(), locked_until);
+    let unlock_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/account_locked",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "token": unlock_token,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_new_device_logged_in(
+    address: &str,
+    ip: &str,
+    date: &NaiveDateTime,
+    device_name: &str,
+    locale: Option<&str>,
+) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/new_device_logged_in",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device_name,
+            "datetime": date.format("%A, %B %_d, %Y at %H:%M UTC").to_string(),
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_device_approval(address: &str, uuid: &str, ip: &str, device: &Device, locale: Option<&str>) -> EmptyResult {
+    let claims =
+        generate_device_approval_claims(uuid.to_string(), device.uuid.clone(), device.name.clone(), device.type_);
+    let approval_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/device_approval",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "ip": ip,
+            "device": device.name,
+            "token": approval_token,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_token(address: &str, token: &str, locale: Option<&str>) -> EmptyResult {
+    let (subject, body_html, body_text) = get_text(
+        "email/twofactor_email",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "token": token,
+            "minutes": CONFIG.email_expiration_time() / 60,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_verify_email(address: &str, uuid: &str, locale: Option<&str>) -> EmptyResult {
+    let claims = generate_verify_email_claims(uuid.to_string(), address.to_lowercase());
+    let verify_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/verify_email",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "email": address,
+            "user_id": uuid,
+            "token": verify_token,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_verify_email_change(address: &str, uuid: &str) -> EmptyResult {
+    let claims = generate_verify_email_claims(uuid.to_string(), address.to_lowercase());
+    let verify_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/verify_email_change",
+        None,
+        json!({
+            "url": CONFIG.domain(),
+            "email": address,
+            "token": verify_token,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
+pub fn send_approve_email_change(address: &str, uuid: &str, new_email: &str, locale: Option<&str>) -> EmptyResult {
+    let claims = generate_email_change_approval_claims(uuid.to_string(), new_email.to_lowercase());
+    let approval_token = encode_jwt(&claims);
+
+    let (subject, body_html, body_text) = get_text(
+        "email/approve_email_change",
+        locale,
+        json!({
+            "url": CONFIG.domain(),
+            "email": new_email,
+            "token": approval_token,
+        }),
+    )?;
+
+    send_email(&address, &subject, &body_html, &body_text)
+}
+
 fn send_email(address: &str, subject: &str, body_html: &str, body_text: &str) -> EmptyResult {
     let email = EmailBuilder::new()
         .to(address)
@@ -137,8 +285,9 @@ fn send_email(address: &str, subject: &str, body_html: &str, body_text: &str) ->
         .build()
         .map_err(|e| Error::new("Error building email", e.to_string()))?;
 
-    mailer()
-        .send(email.into())
+    let span = telemetry::client_span("smtp.send");
+    let result = mailer().send(email.into());
+    span.record(result)
         .map_err(|e| Error::new("Error sending email", e.to_string()))
         .and(Ok(()))
 }
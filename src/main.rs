@@ -31,18 +31,34 @@ use std::{
 mod error;
 mod api;
 mod auth;
+mod bus;
+mod cache;
 mod config;
 mod crypto;
 mod db;
+mod i18n;
+mod listener;
 mod mail;
+mod ratelimit;
+mod scheduler;
+mod service;
+mod systemd;
+mod telemetry;
 mod util;
 
 pub use config::CONFIG;
 pub use error::{Error, MapResult};
 
 fn launch_rocket() {
+    let proxied =
+        CONFIG.websocket_single_port() || CONFIG.unix_socket_path().is_some() || CONFIG.bind_addresses().is_some();
+
     // Create Rocket object, this stores current log level and sets it's own
-    let rocket = rocket::ignite();
+    let rocket = if proxied || systemd::is_socket_activated() {
+        listener::ignite_single_port()
+    } else {
+        rocket::ignite()
+    };
 
     // If we aren't logging the mounts, we force the logging level down
     if !CONFIG.log_mounts() {
@@ -53,20 +69,50 @@ fn launch_rocket() {
         .mount("/", api::web_routes())
         .mount("/api", api::core_routes())
         .mount("/admin", api::admin_routes())
+        .mount("/events", api::events_routes())
         .mount("/identity", api::identity_routes())
         .mount("/icons", api::icons_routes())
-        .mount("/notifications", api::notifications_routes());
+        .mount("/notifications", api::notifications_routes())
+        .register(api::catchers());
 
     // Force the level up for the fairings, managed state and lauch
     if !CONFIG.log_mounts() {
         log::set_max_level(log::LevelFilter::max());
     }
 
+    let pool = db::init_pool();
+    telemetry::start();
+    scheduler::start(pool.clone());
+
+    let notifications = api::start_notification_server(pool.clone());
+    let event_log = api::EventLog::new(pool.clone());
+    listener::start_websocket_socket();
+
+    let watchdog_pool = pool.clone();
     let rocket = rocket
-        .manage(db::init_pool())
-        .manage(api::start_notification_server())
+        .manage(db::init_read_pool(&pool))
+        .manage(pool)
+        .manage(bus::start(vec![Box::new(notifications), Box::new(event_log)]))
+        .attach(telemetry::RequestTracing())
         .attach(util::AppHeaders())
-        .attach(AdHoc::on_launch("Launch Info", launch_info));
+        .attach(util::PathNormalizer())
+        .attach(api::RejectedIds());
+
+    api::limit_long_polls(rocket.config().workers as usize);
+
+    // On a Unix socket, the listener passes the connections to Rocket, which doesn't listen on its own port
+    if listener::is_in_process() {
+        launch_info(&rocket);
+        systemd::notify_ready(watchdog_pool, None);
+        error!("Launch error {:?}", listener::launch_in_process(rocket));
+        return;
+    }
+
+    let rocket = rocket
+        .attach(AdHoc::on_launch("Launch Info", launch_info))
+        .attach(AdHoc::on_launch("Systemd Notify", move |rocket| {
+            systemd::notify_ready(watchdog_pool.clone(), Some(systemd::alive_url(rocket.config())))
+        }));
 
     // Launch and print error if there is one
     // The launch will restore the original logging level
@@ -76,9 +122,15 @@ fn launch_rocket() {
 // Embed the migrations from the migrations folder into the application
 // This way, the program automatically migrates the database to the latest version
 // https://docs.rs/diesel_migrations/*/diesel_migrations/macro.embed_migrations.html
+// Each database backend has its own folder, as the SQL dialects are different
 #[allow(unused_imports)]
 mod migrations {
-    embed_migrations!();
+    #[cfg(feature = "sqlite")]
+    embed_migrations!("migrations/sqlite");
+    #[cfg(feature = "postgresql")]
+    embed_migrations!("migrations/postgresql");
+    #[cfg(feature = "mysql")]
+    embed_migrations!("migrations/mysql");
 
     pub fn run_migrations() {
         // Make sure the database is up to date (create if it doesn't exist, or run the migrations)
@@ -87,6 +139,12 @@ mod migrations {
         use std::io::stdout;
         embedded_migrations::run_with_output(&connection, &mut stdout()).expect("Can't run migrations");
     }
+
+    /// Creates the tables in the database of the tests
+    #[cfg(test)]
+    pub fn run_test_migrations(connection: &crate::db::DbConn) {
+        embedded_migrations::run(&**connection).expect("Can't run migrations");
+    }
 }
 
 fn main() {
@@ -96,6 +154,7 @@ fn main() {
 
     check_db();
     check_rsa_keys();
+    check_crypto();
     check_web_vault();
     migrations::run_migrations();
 
@@ -155,6 +214,7 @@ fn chain_syslog(logger: fern::Dispatch) -> fern::Dispatch {
     }
 }
 
+#[cfg(feature = "sqlite")]
 fn check_db() {
     let url = CONFIG.database_url();
     let path = Path::new(&url);
@@ -177,6 +237,15 @@ fn check_db() {
     }
 }
 
+#[cfg(not(feature = "sqlite"))]
+fn check_db() {
+    // The database has to be created beforehand, only check that it's reachable
+    if let Err(e) = db::get_connection() {
+        error!("Can't connect to the database: {:?}", e);
+        exit(1);
+    }
+}
+
 fn check_rsa_keys() {
     // If the RSA keys don't exist, try to create them
     if !util::file_exists(&CONFIG.private_rsa_key()) || !util::file_exists(&CONFIG.public_rsa_key()) {
@@ -236,6 +305,42 @@ fn check_rsa_keys() {
     }
 }
 
+fn check_crypto() {
+    if let Err(e) = crypto::self_test() {
+        error!(
+            "The crypto self-test failed: {}. Make sure the binary was built for this platform",
+            e
+        );
+        exit(1);
+    }
+
+    if let Err(e) = auth::self_test() {
+        error!("The JWT self-test failed: {}", e);
+        error!("If the RSA keys are corrupted, delete the files and they will be created again on the next start, which logs out all the users");
+        exit(1);
+    }
+
+    check_rsa_key_permissions();
+}
+
+#[cfg(unix)]
+fn check_rsa_key_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let private_key = CONFIG.private_rsa_key();
+    if let Ok(metadata) = std::fs::metadata(&private_key) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "The private RSA key {} can be read by other users, restrict it with 'chmod 600'",
+                private_key
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn check_rsa_key_permissions() {}
+
 fn check_web_vault() {
     if !CONFIG.web_vault_enabled() {
         return;
@@ -0,0 +1,123 @@
+//
+// In-memory rate limiting of the requests, per IP address or per account
+//
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::api::EmptyResult;
+use crate::auth::ClientIp;
+use crate::CONFIG;
+
+lazy_static! {
+    pub static ref PASSWORD_HINT_LIMITER: RateLimiter = RateLimiter::new(3600);
+    pub static ref EMAIL_TOKEN_LIMITER: RateLimiter = RateLimiter::new(3600);
+    pub static ref LOGIN_LIMITER: RateLimiter = RateLimiter::new(CONFIG.login_ratelimit_seconds());
+    pub static ref TWOFACTOR_LIMITER: RateLimiter = RateLimiter::new(CONFIG.login_ratelimit_seconds());
+}
+
+/// The error of the requests refused by a limiter, which are answered with a 429 status.
+/// It contains the message that is logged
+pub struct RateLimited(pub String);
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Counts the attempts of each key in a fixed window. The counters are only kept in memory,
+/// so they are reset on restart and aren't shared between multiple instances
+pub struct RateLimiter {
+    window: Duration,
+    entries: Mutex<HashMap<String, (NaiveDateTime, u32)>>,
+}
+
+impl RateLimiter {
+    fn new(window_secs: i64) -> Self {
+        Self {
+            window: Duration::seconds(window_secs),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Registers an attempt, and returns false when the key went over the limit in the current window.
+    /// A limit of 0 disables the check
+    pub fn check(&self, key: &str, max_attempts: u32) -> bool {
+        if max_attempts == 0 {
+            return true;
+        }
+
+        let count = self.register(key);
+        count <= max_attempts
+    }
+
+    /// Whether the key already went over the limit in the current window, without registering an attempt
+    pub fn is_limited(&self, key: &str, max_attempts: u32) -> bool {
+        if max_attempts == 0 {
+            return false;
+        }
+
+        let now = Utc::now().naive_utc();
+        let entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((start, count)) => now - *start < self.window && *count >= max_attempts,
+            None => false,
+        }
+    }
+
+    /// Registers an attempt of the key, and returns the number of attempts in the current window
+    pub fn register(&self, key: &str) -> u32 {
+        let now = Utc::now().naive_utc();
+        let mut entries = self.entries.lock().unwrap();
+
+        let entry = entries.entry(key.to_string()).or_insert((now, 0));
+        // The expired windows are only pruned from time to time, so an old entry starts a new window here
+        if now - entry.0 >= self.window {
+            *entry = (now, 0);
+        }
+        entry.1 += 1;
+        entry.1
+    }
+
+    /// Drops the keys whose window has ended, so the map doesn't keep growing
+    fn prune(&self) {
+        let now = Utc::now().naive_utc();
+        let window = self.window;
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, (start, _)| now - *start < window);
+    }
+}
+
+/// Prunes all the limiters, it's run by the scheduler instead of on every attempt
+pub fn prune_all() {
+    for limiter in &[
+        &*PASSWORD_HINT_LIMITER,
+        &*EMAIL_TOKEN_LIMITER,
+        &*LOGIN_LIMITER,
+        &*TWOFACTOR_LIMITER,
+    ] {
+        limiter.prune();
+    }
+}
+
+/// Refuses the logins from an IP address that failed too many times in the current window.
+/// It's keyed by IP address only, so nobody can lock out a victim by failing logins with their email,
+/// the accounts themselves are protected by the login lockout
+pub fn check_login(email: &str, ip: &ClientIp) -> EmptyResult {
+    if LOGIN_LIMITER.is_limited(&ip.ip.to_string(), CONFIG.login_ratelimit_attempts()) {
+        err_limit!(
+            "Too many login attempts. Try again later",
+            format!("IP: {}. Username: {}.", ip.ip, email)
+        )
+    }
+    Ok(())
+}
+
+/// Counts a failed login of the IP address, the successful logins don't use up the limit
+pub fn register_login_failure(ip: &ClientIp) {
+    LOGIN_LIMITER.register(&ip.ip.to_string());
+}
@@ -0,0 +1,130 @@
+//
+// Background jobs, run periodically in their own thread
+//
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::api::EmptyResult;
+use crate::db::models::{Attachment, AttachmentDeletion, AttachmentUpload, QueuedNotification, User};
+use crate::db::{DbConn, Pool};
+use crate::telemetry;
+use crate::CONFIG;
+
+type Job = fn(&DbConn) -> EmptyResult;
+
+const JOBS: &[(&str, Job)] = &[
+    ("Purge deleted users", purge_deleted_users),
+    ("Delete removed attachment files", delete_attachment_files),
+    ("Remove abandoned attachment uploads", purge_attachment_uploads),
+    ("Verify attachment files", verify_attachment_files),
+    ("Purge old queued notifications", purge_queued_notifications),
+    ("Prune expired rate limits", prune_rate_limits),
+];
+
+pub fn start(pool: Pool) {
+    thread::Builder::new()
+        .name("scheduler".into())
+        .spawn(move || loop {
+            thread::sleep(Duration::from_secs(CONFIG.scheduler_interval()));
+
+            let conn = match pool.get() {
+                Ok(conn) => DbConn(conn),
+                Err(e) => {
+                    error!("Scheduler can't get a database connection: {:#?}", e);
+                    continue;
+                }
+            };
+
+            for (name, job) in JOBS {
+                let span = telemetry::span(name);
+                if let Err(e) = span.record(job(&conn)) {
+                    error!("Scheduled job '{}' failed: {:#?}", name, e);
+                }
+            }
+        })
+        .expect("Error starting the scheduler");
+}
+
+/// Removes the accounts whose retention period has ended
+fn purge_deleted_users(conn: &DbConn) -> EmptyResult {
+    let limit = Utc::now().naive_utc() - chrono::Duration::days(CONFIG.user_delete_grace_days());
+
+    // An account that can't be removed is tried again on the next run, without holding back the others
+    for user in User::find_deleted_before(&limit, conn) {
+        info!("Removing the data of the deleted account {}", user.email);
+        let email = user.email.clone();
+        if let Err(e) = user.delete(conn) {
+            error!("Error removing the data of the deleted account {}: {:#?}", email, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the files of the removed attachments. The ones that fail are kept, to try again on the next run
+fn delete_attachment_files(conn: &DbConn) -> EmptyResult {
+    for deletion in AttachmentDeletion::get_all(conn) {
+        if let Err(e) = deletion.delete_file() {
+            error!("Error deleting the attachment file {}: {:?}", deletion.file_path, e);
+            continue;
+        }
+
+        deletion.delete(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Removes the chunked uploads that weren't finished in time, with their partial files,
+/// and the attachments uploaded for a share that wasn't stored
+fn purge_attachment_uploads(conn: &DbConn) -> EmptyResult {
+    let limit = Utc::now().naive_utc() - chrono::Duration::hours(CONFIG.attachment_upload_expiration_hours());
+
+    for attachment in Attachment::find_pending_before(&limit, conn) {
+        attachment.delete(conn)?;
+    }
+
+    for upload in AttachmentUpload::find_created_before(&limit, conn) {
+        // A chunk that is being saved right now is left alone, the upload is removed on the next run
+        let path = std::path::PathBuf::from(upload.get_partial_path());
+        if let Some(_lock) = crate::util::try_lock_path(&path) {
+            upload.delete(conn)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the stored files against the digests computed when they were uploaded, so corruption is noticed
+/// before someone needs the file, without reading it again on every download
+fn verify_attachment_files(conn: &DbConn) -> EmptyResult {
+    if !CONFIG.attachments_verify() {
+        return Ok(());
+    }
+
+    for attachment in Attachment::get_all(conn) {
+        if let Err(msg) = attachment.verify_checksum() {
+            error!(
+                "Attachment {} of cipher {} failed the verification: {}",
+                attachment.id, attachment.cipher_uuid, msg
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the notifications that are older than the replay period, the devices that weren't connected
+/// since then get the changes in their next full sync
+fn purge_queued_notifications(conn: &DbConn) -> EmptyResult {
+    let limit = Utc::now().naive_utc() - chrono::Duration::hours(CONFIG.websocket_replay_hours());
+    QueuedNotification::delete_older_than(&limit, conn)
+}
+
+/// Drops the rate limit counters of the windows that have ended
+fn prune_rate_limits(_conn: &DbConn) -> EmptyResult {
+    crate::ratelimit::prune_all();
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::collections::HashSet;
+
+use crate::api::ApiResult;
+use crate::db::models::Cipher;
+use crate::db::repo::{CipherRepo, CollectionRepo};
+
+/// Whether the user can edit the cipher. With the admin routes, the owners and admins
+/// of the organization can edit any of its ciphers
+fn can_write<R: CipherRepo>(cipher: &Cipher, user_uuid: &str, as_admin: bool, repo: &R) -> bool {
+    repo.is_cipher_writable(cipher, user_uuid) || (as_admin && repo.is_cipher_org_admin(cipher, user_uuid))
+}
+
+/// Finds a cipher that the user can edit
+pub fn find_writable<R: CipherRepo>(cipher_uuid: &str, user_uuid: &str, as_admin: bool, repo: &R) -> ApiResult<Cipher> {
+    match repo.find_cipher(cipher_uuid) {
+        Some(cipher) => {
+            if can_write(&cipher, user_uuid, as_admin, repo) {
+                Ok(cipher)
+            } else {
+                err!("Cipher is not write accessible")
+            }
+        }
+        None => err!("Cipher doesn't exist"),
+    }
+}
+
+/// Adds the cipher to the posted collections and removes it from the rest of the ones the user can see.
+/// The user needs to be able to edit the cipher and every collection that changes, except the organization
+/// admins using the admin routes, who manage all the collections of the cipher
+pub fn update_collections<R: CipherRepo + CollectionRepo>(
+    cipher_uuid: &str,
+    collection_uuids: &[String],
+    user_uuid: &str,
+    as_admin: bool,
+    repo: &R,
+) -> ApiResult<Cipher> {
+    let cipher = find_writable(cipher_uuid, user_uuid, as_admin, repo)?;
+    let org_admin = as_admin && repo.is_cipher_org_admin(&cipher, user_uuid);
+
+    let current_collections = if org_admin {
+        repo.find_all_cipher_collections(&cipher)
+    } else {
+        repo.find_cipher_collections(&cipher, user_uuid)
+    };
+
+    let posted_collections: HashSet<String> = collection_uuids.iter().cloned().collect();
+    let current_collections: HashSet<String> = current_collections.into_iter().collect();
+
+    for collection in posted_collections.symmetric_difference(&current_collections) {
+        match repo.find_collection(collection) {
+            None => err!("Invalid collection ID provided"),
+            Some(collection) => {
+                let writable = if org_admin {
+                    cipher.organization_uuid.as_ref() == Some(&collection.org_uuid)
+                } else {
+                    repo.is_collection_writable(&collection, user_uuid)
+                };
+
+                if writable {
+                    if posted_collections.contains(&collection.uuid) {
+                        // Add to collection
+                        repo.add_to_collection(&cipher.uuid, &collection.uuid)?;
+                    } else {
+                        // Remove from collection
+                        repo.remove_from_collection(&cipher.uuid, &collection.uuid)?;
+                    }
+                } else {
+                    err!("No rights to modify the collection")
+                }
+            }
+        }
+    }
+
+    Ok(cipher)
+}
+
+/// Adds the ciphers to some collections of their organization and removes them from others, with the same
+/// permissions as `update_collections`. The rest of the collections of the ciphers are kept
+pub fn update_collections_bulk<R: CipherRepo + CollectionRepo>(
+    cipher_uuids: &[String],
+    add_uuids: &[String],
+    remove_uuids: &[String],
+    user_uuid: &str,
+    as_admin: bool,
+    repo: &R,
+) -> ApiResult<Vec<Cipher>> {
+    if add_uuids.iter().any(|uuid| remove_uuids.contains(uuid)) {
+        err!("A collection can't be added and removed at the same time")
+    }
+
+    let changes: Vec<(&String, bool)> = add_uuids
+        .iter()
+        .map(|uuid| (uuid, true))
+        .chain(remove_uuids.iter().map(|uuid| (uuid, false)))
+        .collect();
+
+    let mut ciphers = Vec::new();
+    for cipher_uuid in cipher_uuids {
+        let cipher = find_writable(cipher_uuid, user_uuid, as_admin, repo)?;
+        let org_admin = as_admin && repo.is_cipher_org_admin(&cipher, user_uuid);
+
+        let org_uuid = match cipher.organization_uuid {
+            Some(ref org_uuid) => org_uuid.clone(),
+            None => err!("The cipher doesn't belong to an organization"),
+        };
+
+        let current_collections: HashSet<String> = repo.find_all_cipher_collections(&cipher).into_iter().collect();
+
+        for (collection_uuid, add) in &changes {
+            let collection = match repo.find_collection_in_org(collection_uuid, &org_uuid) {
+                Some(collection) => collection,
+                None => err!("Invalid collection ID provided"),
+            };
+
+            if !org_admin && !repo.is_collection_writable(&collection, user_uuid) {
+                err!("No rights to modify the collection")
+            }
+
+            let present = current_collections.contains(&collection.uuid);
+            if *add && !present {
+                repo.add_to_collection(&cipher.uuid, &collection.uuid)?;
+            } else if !*add && present {
+                repo.remove_from_collection(&cipher.uuid, &collection.uuid)?;
+            }
+        }
+
+        ciphers.push(cipher);
+    }
+
+    Ok(ciphers)
+}
+
+/// Adds a cipher that is being shared to the collections of the organization, which the user needs to be able to edit.
+/// Returns whether it was added to any
+pub fn share_to_collections<R: CollectionRepo>(
+    cipher: &Cipher,
+    org_uuid: &str,
+    collection_uuids: &[String],
+    user_uuid: &str,
+    repo: &R,
+) -> ApiResult<bool> {
+    let mut shared_to_collection = false;
+
+    for uuid in collection_uuids {
+        match repo.find_collection_in_org(uuid, org_uuid) {
+            None => err!("Invalid collection ID provided"),
+            Some(collection) => {
+                if repo.is_collection_writable(&collection, user_uuid) {
+                    repo.add_to_collection(&cipher.uuid, &collection.uuid)?;
+                    shared_to_collection = true;
+                } else {
+                    err!("No rights to modify the collection")
+                }
+            }
+        }
+    }
+
+    Ok(shared_to_collection)
+}
+
+/// Deletes a cipher that the user can edit, it's returned so the caller can notify its users
+pub fn delete<R: CipherRepo>(cipher_uuid: &str, user_uuid: &str, as_admin: bool, repo: &R) -> ApiResult<Cipher> {
+    let cipher = match repo.find_cipher(cipher_uuid) {
+        Some(cipher) => cipher,
+        None => err!("Cipher doesn't exist"),
+    };
+
+    if !can_write(&cipher, user_uuid, as_admin, repo) {
+        err!("Cipher can't be deleted by user")
+    }
+
+    repo.delete_cipher(&cipher)?;
+    Ok(cipher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::{CipherType, Collection, UserOrgStatus, UserOrgType, UserOrganization};
+    use crate::db::repo::memory::MemoryRepo;
+
+    const ORG: &str = "org";
+
+    fn add_member(repo: &MemoryRepo, user_uuid: &str, type_: UserOrgType) {
+        let mut member = UserOrganization::new(user_uuid.to_string(), ORG.to_string());
+        member.status = UserOrgStatus::Confirmed as i32;
+        member.type_ = type_ as i32;
+        repo.add_member(member);
+    }
+
+    fn add_collection(repo: &MemoryRepo) -> String {
+        let collection = Collection::new(ORG.to_string(), "collection".into());
+        let uuid = collection.uuid.clone();
+        repo.add_collection(collection);
+        uuid
+    }
+
+    /// An organization cipher in the `writable` collection, with a `user` that can edit that collection
+    /// and only read the `read_only` one, and an `admin` of the organization
+    fn setup() -> (MemoryRepo, String, String, String) {
+        let repo = MemoryRepo::default();
+        let writable = add_collection(&repo);
+        let read_only = add_collection(&repo);
+
+        let mut cipher = Cipher::new(CipherType::Login, "cipher".into());
+        cipher.organization_uuid = Some(ORG.to_string());
+        let cipher_uuid = cipher.uuid.clone();
+        repo.add_cipher(cipher);
+        repo.add_to_collection(&cipher_uuid, &writable).unwrap();
+
+        add_member(&repo, "user", UserOrgType::User);
+        repo.assign_collection("user", &writable, false);
+        repo.assign_collection("user", &read_only, true);
+        add_member(&repo, "admin", UserOrgType::Admin);
+
+        (repo, cipher_uuid, writable, read_only)
+    }
+
+    #[test]
+    fn find_writable_checks_the_access() {
+        let (repo, cipher_uuid, writable, _) = setup();
+
+        assert!(find_writable(&cipher_uuid, "user", false, &repo).is_ok());
+        assert!(find_writable(&cipher_uuid, "stranger", false, &repo).is_err());
+        assert!(find_writable("missing", "user", false, &repo).is_err());
+
+        repo.remove_from_collection(&cipher_uuid, &writable).unwrap();
+        assert!(find_writable(&cipher_uuid, "user", false, &repo).is_err());
+    }
+
+    #[test]
+    fn update_collections_needs_write_access_to_the_collections() {
+        let (repo, cipher_uuid, writable, read_only) = setup();
+
+        let posted = vec![writable.clone(), read_only.clone()];
+        assert!(update_collections(&cipher_uuid, &posted, "user", false, &repo).is_err());
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![writable.clone()]);
+
+        let other = add_collection(&repo);
+        repo.assign_collection("user", &other, false);
+        let posted = vec![writable.clone(), other.clone()];
+        update_collections(&cipher_uuid, &posted, "user", false, &repo).unwrap();
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![writable, other]);
+    }
+
+    #[test]
+    fn update_collections_keeps_the_collections_hidden_from_the_user() {
+        let (repo, cipher_uuid, writable, _) = setup();
+        let hidden = add_collection(&repo);
+        repo.add_to_collection(&cipher_uuid, &hidden).unwrap();
+
+        update_collections(&cipher_uuid, &[], "user", false, &repo).unwrap();
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![hidden.clone()]);
+
+        let posted = vec![writable.clone()];
+        update_collections(&cipher_uuid, &posted, "admin", true, &repo).unwrap();
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![writable]);
+    }
+
+    #[test]
+    fn update_collections_bulk_checks_every_collection() {
+        let (repo, cipher_uuid, writable, read_only) = setup();
+        let ciphers = vec![cipher_uuid.clone()];
+
+        let same = vec![writable.clone()];
+        assert!(update_collections_bulk(&ciphers, &same, &same, "user", false, &repo).is_err());
+
+        let add = vec![read_only.clone()];
+        assert!(update_collections_bulk(&ciphers, &add, &[], "user", false, &repo).is_err());
+        assert!(update_collections_bulk(&ciphers, &add, &[], "admin", true, &repo).is_ok());
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![writable, read_only]);
+    }
+
+    #[test]
+    fn update_collections_bulk_rejects_personal_ciphers() {
+        let (repo, _, writable, _) = setup();
+        let mut cipher = Cipher::new(CipherType::Login, "personal".into());
+        cipher.user_uuid = Some("user".into());
+        let ciphers = vec![cipher.uuid.clone()];
+        repo.add_cipher(cipher);
+
+        assert!(update_collections_bulk(&ciphers, &[writable], &[], "user", false, &repo).is_err());
+    }
+
+    #[test]
+    fn share_to_collections_needs_write_access() {
+        let (repo, cipher_uuid, writable, read_only) = setup();
+        let cipher = repo.find_cipher(&cipher_uuid).unwrap();
+
+        assert!(share_to_collections(&cipher, ORG, &[read_only], "user", &repo).is_err());
+        assert!(share_to_collections(&cipher, "other-org", &[writable.clone()], "user", &repo).is_err());
+        assert!(!share_to_collections(&cipher, ORG, &[], "user", &repo).unwrap());
+        assert!(share_to_collections(&cipher, ORG, &[writable], "user", &repo).unwrap());
+    }
+
+    #[test]
+    fn delete_needs_write_access() {
+        let (repo, cipher_uuid, _, _) = setup();
+
+        assert!(delete(&cipher_uuid, "stranger", false, &repo).is_err());
+        assert!(repo.find_cipher(&cipher_uuid).is_some());
+
+        delete(&cipher_uuid, "user", false, &repo).unwrap();
+        assert!(repo.find_cipher(&cipher_uuid).is_none());
+        assert!(repo.cipher_collections(&cipher_uuid).is_empty());
+    }
+
+    #[test]
+    fn managers_and_users_follow_the_collection_access() {
+        let (repo, cipher_uuid, writable, read_only) = setup();
+        add_member(&repo, "manager", UserOrgType::Manager);
+        repo.assign_collection("manager", &read_only, true);
+        repo.remove_from_collection(&cipher_uuid, &writable).unwrap();
+        repo.add_to_collection(&cipher_uuid, &read_only).unwrap();
+
+        assert!(find_writable(&cipher_uuid, "manager", false, &repo).is_err());
+        assert!(find_writable(&cipher_uuid, "manager", true, &repo).is_err());
+        assert!(find_writable(&cipher_uuid, "user", false, &repo).is_err());
+        assert!(delete(&cipher_uuid, "manager", true, &repo).is_err());
+
+        repo.assign_collection("manager", &writable, false);
+        repo.add_to_collection(&cipher_uuid, &writable).unwrap();
+        assert!(find_writable(&cipher_uuid, "manager", false, &repo).is_ok());
+
+        // The manager can't take the cipher out of the read only collection
+        let posted = vec![writable.clone()];
+        assert!(update_collections(&cipher_uuid, &posted, "manager", false, &repo).is_err());
+    }
+
+    #[test]
+    fn admins_and_owners_edit_every_cipher_of_the_organization() {
+        let (repo, cipher_uuid, _, read_only) = setup();
+        add_member(&repo, "owner", UserOrgType::Owner);
+        let hidden = add_collection(&repo);
+        repo.add_to_collection(&cipher_uuid, &hidden).unwrap();
+
+        for admin in &["admin", "owner"] {
+            assert!(find_writable(&cipher_uuid, admin, false, &repo).is_ok());
+            assert!(find_writable(&cipher_uuid, admin, true, &repo).is_ok());
+        }
+
+        let posted = vec![read_only.clone()];
+        update_collections(&cipher_uuid, &posted, "owner", true, &repo).unwrap();
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![read_only]);
+
+        delete(&cipher_uuid, "admin", true, &repo).unwrap();
+        assert!(repo.find_cipher(&cipher_uuid).is_none());
+    }
+
+    #[test]
+    fn only_confirmed_members_have_access() {
+        let (repo, cipher_uuid, writable, _) = setup();
+        let mut invited = UserOrganization::new("invited".into(), ORG.to_string());
+        invited.type_ = UserOrgType::Admin as i32;
+        repo.add_member(invited);
+        repo.assign_collection("invited", &writable, false);
+
+        assert!(find_writable(&cipher_uuid, "invited", false, &repo).is_err());
+        assert!(find_writable(&cipher_uuid, "invited", true, &repo).is_err());
+    }
+
+    #[test]
+    fn access_all_members_edit_every_cipher() {
+        let (repo, cipher_uuid, _, read_only) = setup();
+        let mut member = UserOrganization::new("access-all".into(), ORG.to_string());
+        member.status = UserOrgStatus::Confirmed as i32;
+        member.access_all = true;
+        repo.add_member(member);
+
+        assert!(find_writable(&cipher_uuid, "access-all", false, &repo).is_ok());
+        let posted = vec![read_only.clone()];
+        update_collections(&cipher_uuid, &posted, "access-all", false, &repo).unwrap();
+        assert_eq!(repo.cipher_collections(&cipher_uuid), vec![read_only]);
+    }
+}
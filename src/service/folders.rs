@@ -0,0 +1,64 @@
+use crate::api::{ApiResult, EmptyResult};
+use crate::db::models::Folder;
+use crate::db::repo::FolderRepo;
+
+/// Finds a folder of the user
+pub fn find_owned<R: FolderRepo>(folder_uuid: &str, user_uuid: &str, repo: &R) -> ApiResult<Folder> {
+    let folder = match repo.find_folder(folder_uuid) {
+        Some(folder) => folder,
+        _ => err!("Invalid folder"),
+    };
+
+    if folder.user_uuid != user_uuid {
+        err!("Folder belongs to another user")
+    }
+
+    Ok(folder)
+}
+
+/// The folder and the revision of the user are saved in the same transaction, and the notification
+/// is only sent by the caller after the commit, so the clients never sync a half done change
+pub fn save<R: FolderRepo>(folder: &mut Folder, repo: &R) -> EmptyResult {
+    repo.in_transaction(|| repo.save_folder(folder))
+}
+
+/// Deletes a folder of the user, together with the links to its ciphers
+pub fn delete<R: FolderRepo>(folder_uuid: &str, user_uuid: &str, repo: &R) -> ApiResult<Folder> {
+    let folder = find_owned(folder_uuid, user_uuid, repo)?;
+
+    repo.in_transaction(|| repo.delete_folder(&folder))?;
+    Ok(folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repo::memory::MemoryRepo;
+
+    fn setup() -> (MemoryRepo, String) {
+        let repo = MemoryRepo::default();
+        let mut folder = Folder::new("user".into(), "folder".into());
+        save(&mut folder, &repo).unwrap();
+        (repo, folder.uuid)
+    }
+
+    #[test]
+    fn find_owned_only_finds_the_folders_of_the_user() {
+        let (repo, folder_uuid) = setup();
+
+        assert!(find_owned(&folder_uuid, "user", &repo).is_ok());
+        assert!(find_owned(&folder_uuid, "other", &repo).is_err());
+        assert!(find_owned("missing", "user", &repo).is_err());
+    }
+
+    #[test]
+    fn delete_only_deletes_the_folders_of_the_user() {
+        let (repo, folder_uuid) = setup();
+
+        assert!(delete(&folder_uuid, "other", &repo).is_err());
+        assert!(repo.find_folder(&folder_uuid).is_some());
+
+        delete(&folder_uuid, "user", &repo).unwrap();
+        assert!(repo.find_folder(&folder_uuid).is_none());
+    }
+}
@@ -0,0 +1,8 @@
+//
+// Business logic of the API, kept apart from the Rocket handlers. The functions take the
+// acting user and plain data, the handlers parse the requests and send the notifications.
+// The data is accessed through the traits in `db::repo`, the handlers pass their `DbConn`
+//
+pub mod ciphers;
+pub mod folders;
+pub mod organizations;
@@ -0,0 +1,135 @@
+use crate::api::EmptyResult;
+use crate::db::models::{UserOrgType, UserOrganization};
+use crate::db::repo::MemberRepo;
+
+/// Fails with the message when the member is the only owner of the organization
+fn check_other_owners<R: MemberRepo>(member: &UserOrganization, msg: &str, repo: &R) -> EmptyResult {
+    if member.type_ == UserOrgType::Owner {
+        let num_owners = repo.count_members_of_type(&member.org_uuid, UserOrgType::Owner);
+
+        if num_owners <= 1 {
+            err!(msg)
+        }
+    }
+    Ok(())
+}
+
+/// Removes the user from the organization, unless they are its last owner
+pub fn leave<R: MemberRepo>(org_uuid: &str, user_uuid: &str, repo: &R) -> EmptyResult {
+    match repo.find_member_by_user(user_uuid, org_uuid) {
+        None => err!("User not part of organization"),
+        Some(user_org) => {
+            check_other_owners(&user_org, "The last owner can't leave", repo)?;
+            repo.delete_member(user_org)
+        }
+    }
+}
+
+/// Removes a member, only the owners can remove the members that aren't plain users
+pub fn remove_member<R: MemberRepo>(
+    org_uuid: &str,
+    org_user_uuid: &str,
+    acting_type: UserOrgType,
+    repo: &R,
+) -> EmptyResult {
+    let user_to_delete = match repo.find_member(org_user_uuid, org_uuid) {
+        Some(user) => user,
+        None => err!("User to delete isn't member of the organization"),
+    };
+
+    if user_to_delete.type_ != UserOrgType::User && acting_type != UserOrgType::Owner {
+        err!("Only Owners can delete Admins or Owners")
+    }
+
+    check_other_owners(&user_to_delete, "Can't delete the last owner", repo)?;
+    repo.delete_member(user_to_delete)
+}
+
+/// Checks that a member with the acting type can change the type of another member to the new one
+pub fn check_type_change<R: MemberRepo>(
+    member: &UserOrganization,
+    new_type: UserOrgType,
+    acting_type: UserOrgType,
+    repo: &R,
+) -> EmptyResult {
+    if new_type != member.type_
+        && (member.type_ >= UserOrgType::Admin || new_type >= UserOrgType::Admin)
+        && acting_type != UserOrgType::Owner
+    {
+        err!("Only Owners can grant and remove Admin or Owner privileges")
+    }
+
+    if member.type_ == UserOrgType::Owner && acting_type != UserOrgType::Owner {
+        err!("Only Owners can edit Owner users")
+    }
+
+    if new_type != UserOrgType::Owner {
+        // Removing owner permmission, check that there are at least another owner
+        check_other_owners(member, "Can't delete the last owner", repo)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repo::memory::MemoryRepo;
+
+    const ORG: &str = "org";
+
+    fn add_member(repo: &MemoryRepo, user_uuid: &str, type_: UserOrgType) -> UserOrganization {
+        let mut member = UserOrganization::new(user_uuid.to_string(), ORG.to_string());
+        member.type_ = type_ as i32;
+        repo.add_member(member.clone());
+        member
+    }
+
+    #[test]
+    fn the_last_owner_cant_leave() {
+        let repo = MemoryRepo::default();
+        add_member(&repo, "owner", UserOrgType::Owner);
+        add_member(&repo, "user", UserOrgType::User);
+
+        assert!(leave(ORG, "owner", &repo).is_err());
+        assert!(leave(ORG, "stranger", &repo).is_err());
+        assert!(leave(ORG, "user", &repo).is_ok());
+
+        add_member(&repo, "other-owner", UserOrgType::Owner);
+        assert!(leave(ORG, "owner", &repo).is_ok());
+        assert!(repo.find_member_by_user("owner", ORG).is_none());
+    }
+
+    #[test]
+    fn only_owners_remove_admins_and_owners() {
+        let repo = MemoryRepo::default();
+        let owner = add_member(&repo, "owner", UserOrgType::Owner);
+        let admin = add_member(&repo, "admin", UserOrgType::Admin);
+        let user = add_member(&repo, "user", UserOrgType::User);
+
+        assert!(remove_member(ORG, &admin.uuid, UserOrgType::Admin, &repo).is_err());
+        assert!(remove_member(ORG, &owner.uuid, UserOrgType::Owner, &repo).is_err());
+        assert!(remove_member(ORG, &user.uuid, UserOrgType::Admin, &repo).is_ok());
+        assert!(remove_member(ORG, &admin.uuid, UserOrgType::Owner, &repo).is_ok());
+        assert!(remove_member(ORG, &admin.uuid, UserOrgType::Owner, &repo).is_err());
+    }
+
+    #[test]
+    fn only_owners_change_the_admin_types() {
+        let repo = MemoryRepo::default();
+        let owner = add_member(&repo, "owner", UserOrgType::Owner);
+        let admin = add_member(&repo, "admin", UserOrgType::Admin);
+        let user = add_member(&repo, "user", UserOrgType::User);
+
+        assert!(check_type_change(&user, UserOrgType::Manager, UserOrgType::Admin, &repo).is_ok());
+        assert!(check_type_change(&user, UserOrgType::Admin, UserOrgType::Admin, &repo).is_err());
+        assert!(check_type_change(&admin, UserOrgType::User, UserOrgType::Admin, &repo).is_err());
+        assert!(check_type_change(&admin, UserOrgType::User, UserOrgType::Owner, &repo).is_ok());
+        assert!(check_type_change(&owner, UserOrgType::Admin, UserOrgType::Admin, &repo).is_err());
+
+        // The only owner can't stop being one
+        assert!(check_type_change(&owner, UserOrgType::Admin, UserOrgType::Owner, &repo).is_err());
+        add_member(&repo, "other-owner", UserOrgType::Owner);
+        assert!(check_type_change(&owner, UserOrgType::Admin, UserOrgType::Owner, &repo).is_ok());
+    }
+}
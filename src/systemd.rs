@@ -0,0 +1,126 @@
+//
+// Integration with systemd: the readiness and watchdog notifications of `Type=notify` services,
+// and the listening socket passed with socket activation, which systemd creates before starting the server
+//
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use crate::db::Pool;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3)
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Sends a state change to systemd, when the service is started with `NOTIFY_SOCKET`, see sd_notify(3)
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+
+    // The abstract socket addresses can't be used from the standard library
+    if path.starts_with('@') {
+        warn!("The abstract notification sockets aren't supported: {}", path);
+        return;
+    }
+
+    let result = UnixDatagram::unbound().and_then(|socket| socket.send_to(state.as_bytes(), &path));
+    if let Err(e) = result {
+        warn!("Error notifying systemd: {:?}", e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+/// Tells systemd that the server is ready, and starts sending the watchdog pings if they are expected.
+/// Rocket is checked on its `/alive` url, when it listens on its own port
+pub fn notify_ready(pool: Pool, alive_url: Option<String>) {
+    notify("READY=1\nSTATUS=Processing requests");
+
+    let watchdog_usec = env::var("WATCHDOG_USEC").ok().and_then(|v| v.parse::<u64>().ok());
+    let for_this_process = env::var("WATCHDOG_PID")
+        .ok()
+        .map_or(true, |pid| pid == std::process::id().to_string());
+
+    if let (Some(usec), true) = (watchdog_usec, for_this_process) {
+        // Ping twice per interval, as recommended by sd_watchdog_enabled(3)
+        let interval = Duration::from_micros(usec / 2);
+
+        thread::Builder::new()
+            .name("watchdog".into())
+            .spawn(move || loop {
+                // Without a ping, systemd restarts the server once the interval has passed
+                match check_alive(&pool, alive_url.as_ref().map(String::as_str), interval) {
+                    Ok(()) => notify("WATCHDOG=1"),
+                    Err(e) => warn!("The server isn't healthy, the watchdog isn't notified: {}", e),
+                }
+                thread::sleep(interval);
+            })
+            .expect("Error starting the systemd watchdog");
+    }
+}
+
+/// The url of the `/alive` endpoint of Rocket, on the loopback interface when it listens on all of them
+pub fn alive_url(config: &rocket::Config) -> String {
+    let scheme = if config.tls_enabled() { "https" } else { "http" };
+    let address = match config.address.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" | "[::]" => "[::1]",
+        address => address,
+    };
+
+    format!("{}://{}:{}/alive", scheme, address, config.port)
+}
+
+/// A database connection can be taken from the pool, which checks it, and Rocket answers its requests
+fn check_alive(pool: &Pool, alive_url: Option<&str>, timeout: Duration) -> Result<(), String> {
+    pool.get_timeout(timeout)
+        .map_err(|e| format!("No database connection: {}", e))?;
+
+    if let Some(url) = alive_url {
+        // The certificate of the server doesn't need to be valid for the loopback address
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .danger_accept_invalid_certs(true)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let response = client
+            .get(url)
+            .send()
+            .map_err(|e| format!("No answer from the server: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("The server answered with {}", response.status()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether systemd passed a listening socket to this process
+pub fn is_socket_activated() -> bool {
+    let for_this_process = env::var("LISTEN_PID").ok() == Some(std::process::id().to_string());
+    let fds = env::var("LISTEN_FDS").ok().and_then(|v| v.parse::<u32>().ok());
+
+    for_this_process && fds.map_or(false, |fds| fds >= 1)
+}
+
+/// The listening socket passed by systemd, only the first one is used. It should only be taken once
+#[cfg(unix)]
+pub fn take_activated_socket() -> Option<std::os::unix::io::RawFd> {
+    if !is_socket_activated() {
+        return None;
+    }
+
+    // Like sd_listen_fds(3) does, so they aren't inherited by the child processes
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    Some(LISTEN_FDS_START)
+}
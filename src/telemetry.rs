@@ -0,0 +1,320 @@
+//
+// Trace export with OTLP over HTTP, in its JSON encoding, to an OpenTelemetry collector. Rocket handles each
+// request in a single worker thread, so the spans opened while handling it are children of the request span.
+// The spans are sent in batches by a background thread, and dropped when the collector can't keep up
+//
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use data_encoding::HEXLOWER;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use serde_json::Value;
+
+use crate::crypto;
+use crate::CONFIG;
+
+const QUEUE_SIZE: usize = 4096;
+const BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Values of the OTLP SpanKind
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+const KIND_CLIENT: u8 = 3;
+
+// Checked before opening every span, so the disabled tracing doesn't take the lock of the exporter
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<SyncSender<SpanData>>> = Mutex::new(None);
+}
+
+thread_local! {
+    static ACTIVE_SPANS: RefCell<Vec<SpanData>> = RefCell::new(Vec::new());
+}
+
+struct SpanData {
+    trace_id: Vec<u8>,
+    span_id: Vec<u8>,
+    parent_span_id: Option<Vec<u8>>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: bool,
+}
+
+impl SpanData {
+    /// The span is a child of the innermost active span of the thread, or starts a new trace
+    fn new(name: String, kind: u8) -> Self {
+        let parent = ACTIVE_SPANS.with(|spans| {
+            spans
+                .borrow()
+                .last()
+                .map(|parent| (parent.trace_id.clone(), parent.span_id.clone()))
+        });
+
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => (crypto::get_random(vec![0u8; 16]), None),
+        };
+
+        SpanData {
+            trace_id,
+            span_id: crypto::get_random(vec![0u8; 8]),
+            parent_span_id,
+            name,
+            kind,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+            .collect();
+
+        json!({
+            "traceId": HEXLOWER.encode(&self.trace_id),
+            "spanId": HEXLOWER.encode(&self.span_id),
+            "parentSpanId": self.parent_span_id.as_ref().map(|id| HEXLOWER.encode(id)).unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start).to_string(),
+            "endTimeUnixNano": unix_nanos(self.end).to_string(),
+            "attributes": attributes,
+            // 1 is Ok and 2 is Error
+            "status": { "code": if self.error { 2 } else { 1 } },
+        })
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn push_span(span: SpanData) {
+    ACTIVE_SPANS.with(|spans| spans.borrow_mut().push(span));
+}
+
+fn pop_span() -> Option<SpanData> {
+    ACTIVE_SPANS.with(|spans| spans.borrow_mut().pop())
+}
+
+fn export(mut span: SpanData) {
+    span.end = SystemTime::now();
+
+    if let Some(exporter) = EXPORTER.lock().unwrap().as_ref() {
+        if exporter.try_send(span).is_err() {
+            debug!("The trace export queue is full, dropping a span");
+        }
+    }
+}
+
+/// Starts the export thread, when an endpoint is configured. Until then, no spans are recorded
+pub fn start() {
+    let endpoint = match CONFIG.otlp_endpoint() {
+        Some(endpoint) => format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+        None => return,
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    *EXPORTER.lock().unwrap() = Some(sender);
+    ENABLED.store(true, Ordering::Relaxed);
+
+    thread::Builder::new()
+        .name("trace-export".into())
+        .spawn(move || export_loop(&endpoint, &receiver))
+        .expect("Error starting the trace exporter");
+}
+
+fn export_loop(endpoint: &str, receiver: &Receiver<SpanData>) {
+    let client = reqwest::Client::new();
+    let mut batch = Vec::new();
+    let mut last_export = Instant::now();
+
+    loop {
+        let disconnected = match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(span) => {
+                batch.push(span);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        let due = last_export.elapsed() >= EXPORT_INTERVAL;
+        if !batch.is_empty() && (batch.len() >= BATCH_SIZE || due || disconnected) {
+            let spans: Vec<Value> = batch.drain(..).map(|s| s.to_json()).collect();
+            if let Err(e) = client.post(endpoint).json(&export_request(spans)).send() {
+                warn!("Error exporting the traces to {}: {:?}", endpoint, e);
+            }
+            last_export = Instant::now();
+        }
+
+        if disconnected {
+            break;
+        }
+    }
+}
+
+fn export_request(spans: Vec<Value>) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{ "key": "service.name", "value": { "stringValue": CONFIG.otlp_service_name() } }]
+            },
+            "scopeSpans": [{
+                "scope": { "name": "bitwarden_rs" },
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+/// An active span, which ends when it's dropped. It's a no-op when tracing is disabled
+pub struct Span {
+    active: bool,
+}
+
+impl Span {
+    pub fn set_attribute<V: Into<String>>(&self, key: &'static str, value: V) {
+        if self.active {
+            ACTIVE_SPANS.with(|spans| {
+                if let Some(span) = spans.borrow_mut().last_mut() {
+                    span.attributes.push((key, value.into()));
+                }
+            });
+        }
+    }
+
+    /// Marks the span as failed, with the error as an attribute
+    pub fn set_error<E: std::fmt::Debug>(&self, error: &E) {
+        if self.active {
+            ACTIVE_SPANS.with(|spans| {
+                if let Some(span) = spans.borrow_mut().last_mut() {
+                    span.error = true;
+                    span.attributes.push(("exception.message", format!("{:?}", error)));
+                }
+            });
+        }
+    }
+
+    /// Records the error of the result, if any, and returns it back
+    pub fn record<T, E: std::fmt::Debug>(&self, result: Result<T, E>) -> Result<T, E> {
+        if let Err(e) = &result {
+            self.set_error(e);
+        }
+        result
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if self.active {
+            if let Some(span) = pop_span() {
+                export(span);
+            }
+        }
+    }
+}
+
+/// Opens a span for some work done by the server itself, like a database access
+pub fn span(name: &str) -> Span {
+    open_span(name, KIND_INTERNAL)
+}
+
+/// Opens a span for a request sent to another server, like an icon download or an email
+pub fn client_span(name: &str) -> Span {
+    open_span(name, KIND_CLIENT)
+}
+
+fn open_span(name: &str, kind: u8) -> Span {
+    let active = is_enabled();
+    if active {
+        push_span(SpanData::new(name.to_string(), kind));
+    }
+    Span { active }
+}
+
+/// Reads the W3C `traceparent` header, so the request span joins the trace of a proxy in front of the server
+fn parse_traceparent(header: &str) -> Option<(Vec<u8>, Vec<u8>)> {
+    let parts: Vec<&str> = header.trim().split('-').collect();
+    if parts.len() < 4 || parts[1].len() != 32 || parts[2].len() != 16 {
+        return None;
+    }
+
+    let trace_id = HEXLOWER.decode(parts[1].as_bytes()).ok()?;
+    let span_id = HEXLOWER.decode(parts[2].as_bytes()).ok()?;
+    Some((trace_id, span_id))
+}
+
+/// Opens a span for each request, which ends when the response is sent
+pub struct RequestTracing();
+
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, req: &mut Request, _data: &Data) {
+        if !is_enabled() {
+            return;
+        }
+
+        // A span of an earlier request of this thread could be left if it failed before the response
+        ACTIVE_SPANS.with(|spans| spans.borrow_mut().clear());
+
+        let mut span = SpanData::new(format!("{} {}", req.method(), req.uri().path()), KIND_SERVER);
+        if let Some((trace_id, parent_span_id)) = req.headers().get_one("traceparent").and_then(parse_traceparent) {
+            span.trace_id = trace_id;
+            span.parent_span_id = Some(parent_span_id);
+        }
+
+        // The query is left out, as it can contain tokens
+        span.attributes.push(("http.method", req.method().to_string()));
+        span.attributes.push(("http.target", req.uri().path().to_string()));
+        if let Some(ip) = req.client_ip() {
+            span.attributes.push(("net.peer.ip", ip.to_string()));
+        }
+        push_span(span);
+    }
+
+    fn on_response(&self, req: &Request, res: &mut Response) {
+        // The inner spans are dropped before the response, so this is the request span
+        let mut span = match pop_span() {
+            Some(span) => span,
+            None => return,
+        };
+
+        if let Some(route) = req.route() {
+            span.name = format!("{} {}", req.method(), route.uri);
+            span.attributes.push(("http.route", route.uri.to_string()));
+        }
+
+        let status = res.status();
+        span.attributes.push(("http.status_code", status.code.to_string()));
+        span.error = status.code >= 500;
+        export(span);
+    }
+}
@@ -2,8 +2,11 @@
 // Web Headers and caching
 //
 use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::uri::Origin;
 use rocket::response::{self, Responder};
-use rocket::{Request, Response};
+use rocket::{Data, Request, Response};
+
+use crate::CONFIG;
 
 pub struct AppHeaders();
 
@@ -18,10 +21,19 @@ impl Fairing for AppHeaders {
     fn on_response(&self, _req: &Request, res: &mut Response) {
         res.set_raw_header("Feature-Policy", "accelerometer 'none'; ambient-light-sensor 'none'; autoplay 'none'; camera 'none'; encrypted-media 'none'; fullscreen 'none'; geolocation 'none'; gyroscope 'none'; magnetometer 'none'; microphone 'none'; midi 'none'; payment 'none'; picture-in-picture 'none'; sync-xhr 'self' https://haveibeenpwned.com https://twofactorauth.org; usb 'none'; vr 'none'");
         res.set_raw_header("Referrer-Policy", "same-origin");
-        res.set_raw_header("X-Frame-Options", "SAMEORIGIN");
         res.set_raw_header("X-Content-Type-Options", "nosniff");
         res.set_raw_header("X-XSS-Protection", "1; mode=block");
-        let csp = "frame-ancestors 'self' chrome-extension://nngceckbapebfimnlniiiahkandclblb moz-extension://*;";
+
+        // X-Frame-Options can't list other origins, and the browsers that support both apply the stricter one,
+        // so it's only sent when no other origin is allowed to embed the vault
+        let ancestors = CONFIG.allowed_iframe_ancestors();
+        if ancestors.trim().is_empty() {
+            res.set_raw_header("X-Frame-Options", "SAMEORIGIN");
+        }
+        let csp = format!(
+            "frame-ancestors 'self' chrome-extension://nngceckbapebfimnlniiiahkandclblb moz-extension://* {};",
+            ancestors
+        );
         res.set_raw_header("Content-Security-Policy", csp);
 
         // Disable cache unless otherwise specified
@@ -31,6 +43,70 @@ impl Fairing for AppHeaders {
     }
 }
 
+/// Some reverse proxies add a trailing slash or encode the paths again before passing them on,
+/// so the paths are normalized before the routing to make them match the same routes
+pub struct PathNormalizer();
+
+impl Fairing for PathNormalizer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Path Normalizer",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, req: &mut Request, _data: &Data) {
+        let mut path = normalize_path(req.uri().path());
+
+        // Some old clients don't use the same casing as the routes
+        if CONFIG.legacy_client_compat() && (path.starts_with("/api/") || path.starts_with("/identity/")) {
+            path = path.to_lowercase();
+        }
+
+        if path == req.uri().path() {
+            return;
+        }
+
+        let uri = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+
+        match Origin::parse_owned(uri) {
+            Ok(uri) => req.set_uri(uri),
+            Err(e) => warn!("Can't normalize path {}: {:?}", req.uri(), e),
+        }
+    }
+}
+
+/// Removes the empty segments, like the ones from trailing or repeated slashes,
+/// and decodes the escapes that were encoded twice, so `%252F` becomes `%2F`
+fn normalize_path(path: &str) -> String {
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(decode_double_encoding)
+        .collect();
+
+    format!("/{}", segments.join("/"))
+}
+
+/// Only the segments in which every escape was encoded twice are decoded. Otherwise one of them
+/// could be a literal `%` the client escaped, like in a file name, and decoding it would change the path
+fn decode_double_encoding(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let is_double_escape = |i: usize| {
+        bytes.len() >= i + 5 && &bytes[i + 1..i + 3] == b"25" && bytes[i + 3..i + 5].iter().all(u8::is_ascii_hexdigit)
+    };
+
+    let mut escapes = segment.match_indices('%').map(|(i, _)| i).peekable();
+    if escapes.peek().is_some() && escapes.all(is_double_escape) {
+        segment.replace("%25", "%")
+    } else {
+        segment.to_string()
+    }
+}
+
 pub struct Cached<R>(R, &'static str);
 
 impl<R> Cached<R> {
@@ -100,8 +176,8 @@ pub fn delete_file(path: &str) -> IOResult<()> {
 
 const UNITS: [&str; 6] = ["bytes", "KB", "MB", "GB", "TB", "PB"];
 
-pub fn get_display_size(size: i32) -> String {
-    let mut size: f64 = size.into();
+pub fn get_display_size(size: i64) -> String {
+    let mut size = size as f64;
     let mut unit_counter = 0;
 
     loop {
@@ -122,6 +198,247 @@ pub fn get_uuid() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
+//
+// File I/O worker pool
+//
+use std::collections::HashSet;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of file jobs that can be waiting for a free worker before new ones block
+const FILE_JOBS_QUEUE: usize = 64;
+
+trait FileJob: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> FileJob for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+lazy_static! {
+    static ref FILE_WORKERS: Mutex<SyncSender<Box<dyn FileJob>>> = Mutex::new(start_file_workers());
+}
+
+fn start_file_workers() -> SyncSender<Box<dyn FileJob>> {
+    let (sender, receiver) = mpsc::sync_channel::<Box<dyn FileJob>>(FILE_JOBS_QUEUE);
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for i in 0..std::cmp::max(crate::CONFIG.file_workers(), 1) {
+        let receiver = Arc::clone(&receiver);
+
+        thread::Builder::new()
+            .name(format!("file-worker-{}", i))
+            .spawn(move || loop {
+                // The lock is released before running the job, so other workers can take the next one
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                job.run();
+            })
+            .expect("Error starting file worker");
+    }
+
+    sender
+}
+
+/// Runs the function in the file workers and blocks until it has finished, returning its result
+pub fn run_file_job<T, F>(f: F) -> IOResult<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> IOResult<T> + Send + 'static,
+{
+    let (result_sender, result_receiver) = mpsc::channel();
+    let job = move || {
+        result_sender.send(f()).ok();
+    };
+
+    let sender = FILE_WORKERS.lock().unwrap().clone();
+    if sender.send(Box::new(job)).is_err() {
+        return Err(IOError::new(ErrorKind::Other, "The file workers are not running"));
+    }
+
+    match result_receiver.recv() {
+        Ok(result) => result,
+        Err(_) => Err(IOError::new(ErrorKind::Other, "The file job didn't finish")),
+    }
+}
+
+pub fn write_file(path: &Path, contents: &[u8]) -> IOResult<()> {
+    use std::io::Write;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Copies the file without reading it in memory, creating the folder of the copy if needed
+pub fn copy_file(from: &str, to: &Path) -> IOResult<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::copy(from, to)?;
+    Ok(())
+}
+
+/// Size of the pieces a streamed file is written in, which is all of it that is kept in memory
+const STREAM_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Writes everything in the reader to the file, without loading it all in memory, and returns its length and digest.
+/// The data goes to a temporary file, which is only moved to `path` once everything was received.
+/// Returns `None` when there are more than `max_len` bytes, and in that case or on an error nothing is left on disk
+pub fn write_file_stream<R: Read>(path: &Path, reader: R, max_len: Option<u64>) -> IOResult<Option<(u64, String)>> {
+    let temp_path = path.with_extension("tmp");
+
+    let result = _write_file_stream(&temp_path, reader, max_len).and_then(|written| {
+        if written.is_some() {
+            let (temp, dest) = (temp_path.clone(), path.to_path_buf());
+            run_file_job(move || fs::rename(&temp, &dest))?;
+        }
+        Ok(written)
+    });
+
+    if result.as_ref().map_or(true, Option::is_none) {
+        run_file_job(move || fs::remove_file(&temp_path)).ok();
+    }
+
+    result
+}
+
+fn _write_file_stream<R: Read>(path: &Path, mut reader: R, max_len: Option<u64>) -> IOResult<Option<(u64, String)>> {
+    use std::io::Write;
+
+    let file_path = path.to_path_buf();
+    let mut file = run_file_job(move || {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(&file_path)
+    })?;
+
+    let mut digest = crate::crypto::Sha256Context::new();
+    let mut len = 0;
+
+    loop {
+        // Reading one byte more than allowed is enough to know that the file is too big
+        let limit = max_len.map_or(STREAM_CHUNK_SIZE, |max| std::cmp::min(STREAM_CHUNK_SIZE, max + 1 - len));
+
+        let mut chunk = Vec::new();
+        reader.by_ref().take(limit).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        len += chunk.len() as u64;
+        if max_len.map_or(false, |max| len > max) {
+            return Ok(None);
+        }
+
+        // Only the disk writes are done by the file workers, the body is read here
+        digest.update(&chunk);
+        file = run_file_job(move || {
+            file.write_all(&chunk)?;
+            Ok(file)
+        })?;
+    }
+
+    run_file_job(move || file.sync_all())?;
+    Ok(Some((len, digest.finish_hex())))
+}
+
+lazy_static! {
+    static ref LOCKED_PATHS: Mutex<HashSet<PathBuf>> = Mutex::new(HashSet::new());
+}
+
+/// Marks a file as being written until the lock is dropped
+pub struct PathLock(PathBuf);
+
+impl Drop for PathLock {
+    fn drop(&mut self) {
+        LOCKED_PATHS.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// Locks the file for the caller, so only one request at a time can write it.
+/// It doesn't wait for the lock, returns `None` if another request has it
+pub fn try_lock_path(path: &Path) -> Option<PathLock> {
+    if LOCKED_PATHS.lock().unwrap().insert(path.to_path_buf()) {
+        Some(PathLock(path.to_path_buf()))
+    } else {
+        None
+    }
+}
+
+/// Appends the contents to the file, creating it if needed, only if its current length is `expected_len`.
+/// This way, two requests sending the same chunk can't both write it.
+/// Appends exactly `len` bytes of the reader to the file, which needs to be `offset` bytes long, in pieces of
+/// `STREAM_CHUNK_SIZE`. Returns false when the reader has a different length, and then the file is truncated back
+pub fn append_file_stream<R: Read>(path: &Path, offset: u64, mut reader: R, len: u64) -> IOResult<bool> {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    let file_path = path.to_path_buf();
+    let mut file = run_file_job(move || {
+        if let Some(parent) = file_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&file_path)?;
+        if file.metadata()?.len() != offset {
+            return Err(IOError::new(ErrorKind::InvalidInput, "Unexpected file length"));
+        }
+        Ok(file)
+    })?;
+
+    let mut written = 0;
+    let mut result = Ok(());
+    while result.is_ok() {
+        // Reading one byte more than expected is enough to know that the reader is too long
+        let limit = std::cmp::min(STREAM_CHUNK_SIZE, len + 1 - written);
+
+        let mut chunk = Vec::new();
+        if let Err(e) = reader.by_ref().take(limit).read_to_end(&mut chunk) {
+            result = Err(e);
+            break;
+        }
+
+        written += chunk.len() as u64;
+        if chunk.is_empty() || written > len {
+            break;
+        }
+
+        // Only the disk writes are done by the file workers, the body is read here
+        let (returned, write_result) = run_file_job(move || {
+            let write_result = file.write_all(&chunk);
+            Ok((file, write_result))
+        })?;
+        file = returned;
+        result = write_result;
+    }
+
+    // A partial chunk is removed again, so the upload can be resumed from the same offset
+    let complete = result.is_ok() && written == len;
+    run_file_job(move || {
+        if !complete {
+            file.set_len(offset)?;
+        }
+        file.sync_all()
+    })?;
+
+    result.map(|_| complete)
+}
+
 //
 // String util methods
 //
@@ -149,6 +466,27 @@ where
     }
 }
 
+/// Quotes a value for a CSV file. Values that spreadsheets would interpret as formulas
+/// are prefixed with an apostrophe, as they can contain input from any user
+pub fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(|c| c == '=' || c == '+' || c == '-' || c == '@') {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    };
+
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+/// Reads a comma separated list of IP addresses, like `[::],0.0.0.0`. The brackets of the IPv6 addresses are optional
+pub fn parse_ip_list(list: &str) -> Option<Vec<std::net::IpAddr>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(|address| address.trim_start_matches('[').trim_end_matches(']').parse().ok())
+        .collect()
+}
+
 //
 // Env methods
 //
@@ -166,12 +504,22 @@ where
 // Date util methods
 //
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, SecondsFormat, Utc};
 
-const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6fZ";
+/// The dates are stored without a timezone, but they are always in UTC
+pub fn to_utc(date: &NaiveDateTime) -> DateTime<Utc> {
+    DateTime::from_utc(*date, Utc)
+}
 
+/// Formats the date as RFC3339 with an explicit UTC offset, so the clients
+/// don't interpret it in their own timezone
 pub fn format_date(date: &NaiveDateTime) -> String {
-    date.format(DATETIME_FORMAT).to_string()
+    to_utc(date).to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+/// Parses an RFC3339 date from the clients, converting it to UTC
+pub fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc3339(date).ok().map(|d| d.naive_utc())
 }
 
 //
@@ -267,6 +615,50 @@ fn _process_key(key: &str) -> String {
     }
 }
 
+pub fn lcase_first(s: &str) -> String {
+    let mut c = s.chars();
+    match c.next() {
+        None => String::new(),
+        Some(f) => f.to_lowercase().collect::<String>() + c.as_str(),
+    }
+}
+
+/// The reverse of the upcase conversion, for the formats that use camelCase, like the vault exports
+pub fn lcase_value(value: &Value) -> Value {
+    if let Some(map) = value.as_object() {
+        let mut new_value = JsonMap::new();
+
+        for (key, val) in map {
+            let processed_key = if key == "SSN" { "ssn".into() } else { lcase_first(key) };
+            new_value.insert(processed_key, lcase_value(val));
+        }
+        Value::Object(new_value)
+    } else if let Some(array) = value.as_array() {
+        Value::Array(array.iter().map(lcase_value).collect())
+    } else {
+        value.clone()
+    }
+}
+
+/// Old clients send some of the numbers, like the cipher type, as strings,
+/// which are only accepted when `LEGACY_CLIENT_COMPAT` is enabled
+pub fn deserialize_legacy_number<'de, D>(deserializer: D) -> Result<i32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value: Value = serde::Deserialize::deserialize(deserializer)?;
+    let number = match value {
+        Value::Number(ref n) => n.as_i64(),
+        Value::String(ref s) if CONFIG.legacy_client_compat() => s.trim().parse().ok(),
+        _ => None,
+    };
+
+    match number {
+        Some(n) if n >= i64::from(std::i32::MIN) && n <= i64::from(std::i32::MAX) => Ok(n as i32),
+        _ => Err(de::Error::custom(format!("Invalid number: {}", value))),
+    }
+}
+
 //
 // Retry methods
 //